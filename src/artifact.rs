@@ -0,0 +1,90 @@
+//! A json file with the full result of each export, kept for a while
+//! for support, named by when the export ran and its correlation id.
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use failure::{format_err, Error};
+use log::warn;
+use serde::Serialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const PREFIX: &str = "export-";
+const TIME_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+pub struct Artifacts {
+    dir: PathBuf,
+    /// How long to keep an artifact.
+    retention: Duration,
+}
+
+impl Artifacts {
+    pub fn new(dir: impl Into<PathBuf>, retention: Duration) -> Self {
+        Artifacts {
+            dir: dir.into(),
+            retention,
+        }
+    }
+
+    /// Write the result of an export that ran at `now`.
+    pub fn write(
+        &self,
+        correlation_id: &str,
+        now: DateTime<Utc>,
+        result: &impl Serialize,
+    ) -> Result<PathBuf, Error> {
+        let path = self.dir.join(format!(
+            "{}{}-{}.json",
+            PREFIX,
+            now.format(TIME_FORMAT),
+            correlation_id,
+        ));
+        let data = serde_json::to_vec_pretty(result)?;
+        fs::write(&path, data).map_err(|e| format_err!("{}: {}", path.display(), e))?;
+        Ok(path)
+    }
+
+    /// Remove the artifacts older than the retention.  Returns how
+    /// many were removed.  Other files in the directory are left alone.
+    pub fn prune(&self, now: DateTime<Utc>) -> Result<usize, Error> {
+        let oldest = (now - self.retention).naive_utc();
+        let mut removed = 0;
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if written_at(&path).is_some_and(|at| at < oldest) {
+                match fs::remove_file(&path) {
+                    Ok(()) => removed += 1,
+                    Err(e) => warn!("Failed to remove {}: {}", path.display(), e),
+                }
+            }
+        }
+        Ok(removed)
+    }
+}
+
+/// When an artifact was written, by its name.
+fn written_at(path: &Path) -> Option<NaiveDateTime> {
+    let name = path.file_name()?.to_str()?;
+    let time = name.strip_prefix(PREFIX)?.get(..16)?;
+    NaiveDateTime::parse_from_str(time, TIME_FORMAT).ok()
+}
+
+#[test]
+fn test_prune_old_artifacts() {
+    use chrono::TimeZone;
+    let dir = std::env::temp_dir().join(format!("artifacts-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let artifacts = Artifacts::new(&dir, Duration::days(30));
+    let now = Utc.with_ymd_and_hms(2019, 6, 1, 12, 0, 0).unwrap();
+    let old = artifacts
+        .write("17f-0", now - Duration::days(31), &"old")
+        .unwrap();
+    let new = artifacts
+        .write("17f-1", now - Duration::days(29), &"new")
+        .unwrap();
+    fs::write(dir.join("notes.txt"), "keep").unwrap();
+
+    assert_eq!(artifacts.prune(now).unwrap(), 1);
+    assert!(!old.exists());
+    assert!(new.exists());
+    assert!(dir.join("notes.txt").exists());
+    fs::remove_dir_all(&dir).unwrap();
+}
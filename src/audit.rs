@@ -0,0 +1,205 @@
+//! A comparison of the grades in Ladok with the grades from Canvas,
+//! written as csv next to the dumped requests, for auditing an export
+//! in a spreadsheet.
+use crate::ladok::types::{MomentUid, Resultat, Studieresultat};
+use crate::ladok::Ladok;
+use failure::{format_err, Error};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// The grades of one student on one moment.
+#[derive(Debug, PartialEq, Eq)]
+pub struct AuditRow {
+    pub student: String,
+    /// The latest attested grade in Ladok.
+    pub attested: Option<String>,
+    /// The draft grade in Ladok.
+    pub draft: Option<String>,
+    /// The grade from Canvas.
+    pub proposed: Option<String>,
+}
+
+impl AuditRow {
+    pub fn new(
+        ladok: &mut Ladok,
+        student: String,
+        resultat: Option<&Studieresultat>,
+        moment: &MomentUid,
+        proposed: Option<&str>,
+    ) -> Self {
+        let mut code = |r: Option<&Resultat>| {
+            let grade = r?.Betygsgrad?;
+            let scale = resultat?.get_betygsskala()?;
+            Some(
+                ladok
+                    .grade_code(scale, grade)
+                    .unwrap_or_else(|_| grade.to_string()),
+            )
+        };
+        AuditRow {
+            attested: code(resultat.and_then(|r| r.get_senast_attesterat(moment))),
+            draft: code(resultat.and_then(|r| r.get_arbetsunderlag(moment))),
+            proposed: proposed.map(str::to_uppercase),
+            student,
+        }
+    }
+}
+
+/// Write the rows for a moment, or one window of it, to a csv file in
+/// `dir`.
+pub fn write_csv(
+    dir: &Path,
+    correlation_id: &str,
+    moment: &MomentUid,
+    window: Option<u32>,
+    rows: &[AuditRow],
+) -> Result<PathBuf, Error> {
+    let path = dir.join(format!("{}.csv", file_stem(correlation_id, moment, window)));
+    write_file(&path, &to_csv(moment, rows))?;
+    Ok(path)
+}
+
+/// The name of the files dumped for a moment, or for one window of it.
+pub fn file_stem(correlation_id: &str, moment: &MomentUid, window: Option<u32>) -> String {
+    match window {
+        Some(window) => format!("{}-{}-{}", correlation_id, moment, window),
+        None => format!("{}-{}", correlation_id, moment),
+    }
+}
+
+/// Write the number of each grade reported on a moment to a csv file
+/// in `dir`.
+pub fn write_grade_counts(
+    dir: &Path,
+    correlation_id: &str,
+    moment: &MomentUid,
+    counts: &BTreeMap<String, usize>,
+) -> Result<PathBuf, Error> {
+    let path = dir.join(format!("{}-{}-grades.csv", correlation_id, moment));
+    write_file(&path, &grade_counts_csv(moment, counts))?;
+    Ok(path)
+}
+
+/// Write a csv file as UTF-8 with a byte order mark, since Excel
+/// otherwise takes it for the local legacy encoding and garbles names
+/// like "Åsa Öberg".
+fn write_file(path: &Path, csv: &str) -> Result<(), Error> {
+    let mut file = File::create(path).map_err(|e| format_err!("{}: {}", path.display(), e))?;
+    file.write_all(&csv_bytes(csv))?;
+    Ok(())
+}
+
+fn csv_bytes(csv: &str) -> Vec<u8> {
+    let mut bytes = "\u{feff}".as_bytes().to_vec();
+    bytes.extend_from_slice(csv.as_bytes());
+    bytes
+}
+
+fn grade_counts_csv(moment: &MomentUid, counts: &BTreeMap<String, usize>) -> String {
+    let mut csv = "moment,grade,count\n".to_string();
+    for (grade, count) in counts {
+        csv.push_str(&format!(
+            "{},{},{}\n",
+            quote(moment.as_ref()),
+            quote(grade),
+            count
+        ));
+    }
+    csv
+}
+
+fn to_csv(moment: &MomentUid, rows: &[AuditRow]) -> String {
+    let mut csv = "student,moment,attested,draft,proposed\n".to_string();
+    for row in rows {
+        let fields = [
+            Some(row.student.as_str()),
+            Some(moment.as_ref()),
+            row.attested.as_deref(),
+            row.draft.as_deref(),
+            row.proposed.as_deref(),
+        ];
+        let fields = fields
+            .iter()
+            .map(|field| quote(field.unwrap_or("")))
+            .collect::<Vec<_>>();
+        csv.push_str(&fields.join(","));
+        csv.push('\n');
+    }
+    csv
+}
+
+/// Quote a csv field if needed.
+fn quote(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[test]
+fn test_audit_grade_columns() {
+    use crate::ladok::types::{SokresultatStudieresultatResultat, StudentUid};
+    let mut ladok = crate::ladok::test_client_with_scale();
+    let resultat: SokresultatStudieresultatResultat = serde_json::from_str(
+        r#"{"TotaltAntalPoster": 2, "Resultat": [
+            {"Uid": "sr-1", "Student": {"Uid": "st-1"},
+             "Rapporteringskontext": {
+                 "BetygsskalaID": "131657",
+                 "KravPaHanvisningTillBeslutshandling": false,
+                 "KravPaProjekttitel": false,
+                 "UtbildningUID": "u-1",
+                 "UtbildningsinstansUID": "m-1"},
+             "ResultatPaUtbildningar": [
+                 {"SenastAttesteradeResultat":
+                     {"Uid": "r-0", "Betygsgrad": 131668, "UtbildningsinstansUID": "m-1"},
+                  "Arbetsunderlag":
+                     {"Uid": "r-1", "Betygsgrad": 131662, "UtbildningsinstansUID": "m-1"}},
+                 {"SenastAttesteradeResultat":
+                     {"Uid": "r-2", "Betygsgrad": 131661, "UtbildningsinstansUID": "m-2"}}]},
+            {"Uid": "sr-2", "Student": {"Uid": "st-2"},
+             "ResultatPaUtbildningar": []}]}"#,
+    )
+    .unwrap();
+    let moment = MomentUid::new("m-1");
+    let mut row = |label: &str, uid: &str, proposed| {
+        let one = resultat.find_student(&StudentUid::new(uid));
+        AuditRow::new(&mut ladok, label.into(), one, &moment, proposed)
+    };
+    let rows = [
+        row("Anna (17)", "st-1", Some("a")),
+        row("Svensson, Bo (18)", "st-2", None),
+    ];
+    assert_eq!(
+        to_csv(&moment, &rows),
+        "student,moment,attested,draft,proposed\n\
+         Anna (17),m-1,F,B,A\n\
+         \"Svensson, Bo (18)\",m-1,,,\n",
+    );
+}
+
+#[test]
+fn test_grade_counts_csv() {
+    let counts = vec![("A".to_string(), 3), ("F".to_string(), 1)]
+        .into_iter()
+        .collect();
+    assert_eq!(
+        grade_counts_csv(&MomentUid::new("m-1"), &counts),
+        "moment,grade,count\nm-1,A,3\nm-1,F,1\n",
+    );
+}
+
+#[test]
+fn test_csv_is_utf8_with_bom() {
+    let rows = [AuditRow {
+        student: "Åsa Öberg-Väli (17)".into(),
+        attested: None,
+        draft: None,
+        proposed: Some("A".into()),
+    }];
+    let bytes = csv_bytes(&to_csv(&MomentUid::new("m-1"), &rows));
+    assert_eq!(&bytes[..3], b"\xEF\xBB\xBF");
+    assert!(bytes.ends_with(b"\n\xC3\x85sa \xC3\x96berg-V\xC3\xA4li (17),m-1,,,A\n"));
+}
@@ -0,0 +1,319 @@
+//! Administrative batch reporting for many course rooms at once.
+//!
+//! This is for e.g. end of term runs by program coordinators, and is
+//! separate from the interactive flow where a teacher reports a
+//! single course room from Canvas.
+use super::{
+    do_report, normalize_sis_course_id, ExportResults, RunOptions, ServerContext, TokenScope,
+};
+use chrono::{DateTime, Utc};
+use failure::{format_err, Error};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::str::from_utf8;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use warp::filters::body::FullBody;
+use warp::http::{header, Response, StatusCode};
+use warp::Buf;
+
+/// The outcome of reporting one course room in a batch.
+#[derive(Debug, Serialize)]
+pub struct CourseOutcome {
+    pub sis_course_id: String,
+    pub ok: bool,
+    pub error: Option<String>,
+    pub students: usize,
+    /// Students that could not be reported.
+    pub errors: usize,
+    pub created: Option<usize>,
+    pub updated: Option<usize>,
+    pub notice: Option<String>,
+    /// The number of each grade reported, per moment.
+    pub grades: BTreeMap<String, BTreeMap<String, usize>>,
+}
+
+impl CourseOutcome {
+    pub fn new(sis_course_id: &str, result: Result<&ExportResults, &Error>) -> Self {
+        match result {
+            Ok(result) => {
+                let error = match (&result.created, &result.updated) {
+                    _ if !result.unmappable_grades.is_empty() => {
+                        Some(result.unmappable_grades.join("\n"))
+                    }
+                    _ if !result.not_authorized.is_empty() => Some(format!(
+                        "Not authorized to report on moment(s) {}",
+                        result
+                            .not_authorized
+                            .iter()
+                            .map(ToString::to_string)
+                            .collect::<Vec<_>>()
+                            .join(", "),
+                    )),
+                    _ if !result.anonymized.is_empty() => Some(format!(
+                        "Moment(s) {} use anonymous marking, report them manually in Ladok",
+                        result
+                            .anonymized
+                            .iter()
+                            .map(ToString::to_string)
+                            .collect::<Vec<_>>()
+                            .join(", "),
+                    )),
+                    (Err(c), Err(u)) => Some(format!("{}\n{}", c, u)),
+                    (Err(e), _) | (_, Err(e)) => Some(e.clone()),
+                    _ => None,
+                };
+                CourseOutcome {
+                    sis_course_id: sis_course_id.into(),
+                    ok: error.is_none(),
+                    error,
+                    students: result.students.len(),
+                    errors: result.errors,
+                    created: result.created.as_ref().ok().cloned(),
+                    updated: result.updated.as_ref().ok().cloned(),
+                    notice: result.notice.clone(),
+                    grades: result.grade_counts.clone(),
+                }
+            }
+            Err(e) => CourseOutcome {
+                sis_course_id: sis_course_id.into(),
+                ok: false,
+                error: Some(e.to_string()),
+                students: 0,
+                errors: 0,
+                created: None,
+                updated: None,
+                notice: None,
+                grades: BTreeMap::new(),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BatchArgs {
+    /// Only report what is graded or changed since then, for
+    /// incremental runs.
+    since: Option<DateTime<Utc>>,
+}
+
+/// Handler for a batch report request.
+///
+/// The body is either a json array of sis course ids or a csv file
+/// with the sis course id in the first column.
+pub fn report_courses(
+    ctx: Arc<ServerContext>,
+    authorization: Option<String>,
+    args: BatchArgs,
+    body: FullBody,
+) -> Response<Vec<u8>> {
+    if let Some(response) = ctx.refuse(
+        authorization.as_deref(),
+        TokenScope::Service,
+        "Batch report",
+    ) {
+        return response;
+    }
+    let canvas_token = match &ctx.canvas_service_token {
+        Some(token) => token,
+        None => {
+            return json_response(
+                StatusCode::SERVICE_UNAVAILABLE,
+                &"No canvas service token configured",
+            )
+        }
+    };
+    let (courses, rejected) = match from_utf8(body.bytes())
+        .map_err(Error::from)
+        .and_then(parse_course_list)
+    {
+        Ok(courses) => courses,
+        Err(e) => return json_response(StatusCode::BAD_REQUEST, &e.to_string()),
+    };
+    info!(
+        "Batch report for {} course rooms, since {:?}, {} rejected",
+        courses.len(),
+        args.since,
+        rejected.len(),
+    );
+    let mut outcomes = run(&courses, ctx.batch_concurrency, |sis_course_id| {
+        let canvas = ctx.canvas_for_export(ctx.canvas_by_access_token(canvas_token), sis_course_id);
+        let mut ladok = ctx.ladok_client(Some(sis_course_id));
+        let result = do_report(
+            &canvas,
+            &mut ladok,
+            &ctx.report,
+            sis_course_id,
+            &RunOptions {
+                since: args.since,
+                ..RunOptions::default()
+            },
+        );
+        ctx.finished(sis_course_id, &result);
+        result
+    });
+    outcomes.extend(rejected);
+    json_response(StatusCode::OK, &outcomes)
+}
+
+pub fn json_response<T: Serialize>(status: StatusCode, data: &T) -> Response<Vec<u8>> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json; charset=utf-8")
+        .body(serde_json::to_vec(data).unwrap())
+        .unwrap()
+}
+
+/// Parse a list of sis course ids, either as json or csv.
+///
+/// For csv, the first column of each line is used, and a header
+/// line, empty lines and duplicates are ignored.  Each id is
+/// normalized as for an interactive export, and the ids that cannot
+/// be used are returned as failed outcomes instead.
+pub fn parse_course_list(data: &str) -> Result<(Vec<String>, Vec<CourseOutcome>), Error> {
+    let data = data.trim();
+    let ids: Vec<String> = if data.starts_with('[') {
+        serde_json::from_str(data)?
+    } else {
+        data.lines()
+            .filter_map(|line| line.split(',').next())
+            .map(|id| id.trim().trim_matches('"').to_string())
+            .filter(|id| !id.is_empty() && id != "sis_course_id")
+            .collect()
+    };
+    let mut result = Vec::with_capacity(ids.len());
+    let mut rejected = vec![];
+    for id in ids {
+        match normalize_sis_course_id(&id) {
+            Ok(id) if !result.contains(&id) => result.push(id),
+            Ok(_) => (),
+            Err(e) => rejected.push(CourseOutcome::new(
+                &id,
+                Err(&format_err!("Bad sis course id: {}", e)),
+            )),
+        }
+    }
+    if result.is_empty() && rejected.is_empty() {
+        return Err(format_err!("No course ids given"));
+    }
+    Ok((result, rejected))
+}
+
+/// Run `report` for each course, with at most `concurrency` courses
+/// at a time.  The outcomes are returned in the order of `courses`.
+pub fn run<F>(courses: &[String], concurrency: usize, report: F) -> Vec<CourseOutcome>
+where
+    F: Fn(&str) -> Result<ExportResults, Error> + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let outcomes = Mutex::new(Vec::with_capacity(courses.len()));
+    thread::scope(|scope| {
+        for _ in 0..concurrency.max(1).min(courses.len()) {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                if let Some(course) = courses.get(i) {
+                    let outcome = CourseOutcome::new(course, report(course).as_ref());
+                    outcomes.lock().unwrap().push((i, outcome));
+                } else {
+                    break;
+                }
+            });
+        }
+    });
+    let mut outcomes = outcomes.into_inner().unwrap();
+    outcomes.sort_by_key(|(i, _)| *i);
+    outcomes.into_iter().map(|(_, outcome)| outcome).collect()
+}
+
+#[test]
+fn test_parse_json_list() {
+    assert_eq!(
+        parse_course_list(r#"["LT1016VT191", "SF1625HT182", " LT1016VT191"]"#)
+            .unwrap()
+            .0,
+        vec!["LT1016VT191", "SF1625HT182"],
+    );
+}
+
+#[test]
+fn test_parse_csv_list() {
+    assert_eq!(
+        parse_course_list("sis_course_id,name\nLT1016VT191,Foo\n\n\"SF1625HT182\",Bar\n")
+            .unwrap()
+            .0,
+        vec!["LT1016VT191", "SF1625HT182"],
+    );
+}
+
+#[test]
+fn test_rejected_course_ids_fail() {
+    let (courses, rejected) =
+        parse_course_list(r#"["LT1016VT191", "LT1016VT191/../users", "SF1625 HT182"]"#).unwrap();
+    assert_eq!(courses, vec!["LT1016VT191"]);
+    let rejected = rejected
+        .iter()
+        .map(|o| (o.sis_course_id.as_str(), o.ok, o.error.as_deref().unwrap()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        rejected,
+        vec![
+            (
+                "LT1016VT191/../users",
+                false,
+                "Bad sis course id: '/' is not allowed"
+            ),
+            (
+                "SF1625 HT182",
+                false,
+                "Bad sis course id: ' ' is not allowed"
+            ),
+        ],
+    );
+}
+
+#[test]
+fn test_parse_empty_list() {
+    assert!(parse_course_list("sis_course_id\n").is_err());
+}
+
+#[test]
+fn test_run_reports_each_course() {
+    let courses = ["A1", "B2", "C3", "D4", "E5"]
+        .iter()
+        .map(|s| s.to_string())
+        .collect::<Vec<_>>();
+    let running = AtomicUsize::new(0);
+    let max_running = AtomicUsize::new(0);
+    let outcomes = run(&courses, 2, |id| {
+        let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+        max_running.fetch_max(now, Ordering::SeqCst);
+        thread::sleep(std::time::Duration::from_millis(10));
+        running.fetch_sub(1, Ordering::SeqCst);
+        if id == "C3" {
+            Err(format_err!("Canvas room {} is lacking integration id", id))
+        } else {
+            Ok(ExportResults::new())
+        }
+    });
+    assert!(max_running.load(Ordering::SeqCst) <= 2);
+    let summary = outcomes
+        .iter()
+        .map(|o| (o.sis_course_id.as_ref(), o.ok))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        summary,
+        vec![
+            ("A1", true),
+            ("B2", true),
+            ("C3", false),
+            ("D4", true),
+            ("E5", true)
+        ],
+    );
+    assert_eq!(
+        outcomes[2].error.as_ref().map(AsRef::as_ref),
+        Some("Canvas room C3 is lacking integration id"),
+    );
+}
@@ -0,0 +1,110 @@
+//! Bounded reading of response bodies from Canvas and Ladok.
+//!
+//! Reading a body whole, as `Response::json` does, would let a broken
+//! upstream sending a huge response exhaust the memory of the service.
+use failure::{Error, Fail};
+use reqwest::Response;
+use serde::de::DeserializeOwned;
+use std::fmt;
+use std::io::Read;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static MAX_BODY_BYTES: AtomicU64 = AtomicU64::new(64 * 1024 * 1024);
+
+/// Set the largest response body to accept, for clients created
+/// after this.
+pub fn set_max_body_size(bytes: u64) {
+    MAX_BODY_BYTES.store(bytes, Ordering::Relaxed);
+}
+
+/// The largest response body to accept, as set.
+pub fn max_body_size() -> u64 {
+    MAX_BODY_BYTES.load(Ordering::Relaxed)
+}
+
+/// A response body was larger than allowed.
+#[derive(Debug)]
+pub struct TooLarge {
+    pub limit: u64,
+}
+
+impl fmt::Display for TooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Response too large, more than {} bytes", self.limit)
+    }
+}
+
+impl Fail for TooLarge {}
+
+/// Parse the json body of a response, if not larger than `limit`.
+pub fn json<T: DeserializeOwned>(response: &mut Response, limit: u64) -> Result<T, Error> {
+    Ok(serde_json::from_slice(&bytes(response, limit)?)?)
+}
+
+/// The body of a response as text, if not larger than `limit`.
+pub fn text(response: &mut Response, limit: u64) -> Result<String, Error> {
+    Ok(String::from_utf8_lossy(&bytes(response, limit)?).into_owned())
+}
+
+fn bytes(response: &mut Response, limit: u64) -> Result<Vec<u8>, Error> {
+    if response.content_length().is_some_and(|len| len > limit) {
+        return Err(TooLarge { limit }.into());
+    }
+    read_bounded(response, limit)
+}
+
+/// Read all of `reader`, but fail rather than read more than `limit`
+/// bytes, since a content-length may be missing or wrong.
+fn read_bounded(reader: impl Read, limit: u64) -> Result<Vec<u8>, Error> {
+    let mut buf = Vec::new();
+    reader.take(limit + 1).read_to_end(&mut buf)?;
+    if buf.len() as u64 > limit {
+        Err(TooLarge { limit }.into())
+    } else {
+        Ok(buf)
+    }
+}
+
+#[test]
+fn test_oversized_body_rejected() {
+    let body = vec![b'x'; 2048];
+    let e = read_bounded(&body[..], 1024).unwrap_err();
+    assert_eq!(e.downcast_ref::<TooLarge>().unwrap().limit, 1024);
+    assert_eq!(e.to_string(), "Response too large, more than 1024 bytes");
+    assert_eq!(read_bounded(&body[..], 2048).unwrap().len(), 2048);
+}
+
+#[test]
+fn test_oversized_body_from_canvas_and_ladok() {
+    use crate::mock_server::{serve, serve_raw};
+    // The content-length is refused before the body is read, so the
+    // short body that follows it is never parsed.
+    let too_long = serve_raw(|_| {
+        b"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+          Content-Length: 1000000\r\nConnection: close\r\n\r\n{}"
+            .to_vec()
+    });
+    // Without a content-length, reading stops at the limit.
+    let body = format!("{{\"id\": 17, \"name\": \"{}\"}}", "x".repeat(2048));
+    let unknown_length = serve_raw(move |_| {
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+             Connection: close\r\n\r\n{}",
+            body,
+        )
+        .into_bytes()
+    });
+    let small = serve(|_| (200, r#"{"id": 17, "name": "Tenta"}"#.into()));
+    let limit = |e: Error| e.downcast_ref::<TooLarge>().map(|e| e.limit);
+    for server in &[too_long, unknown_length] {
+        let canvas = crate::canvas::Canvas::mock_at(server).with_max_body_size(1024);
+        let e = canvas.get_course("LT1016VT191").unwrap_err();
+        assert_eq!(limit(e), Some(1024), "Canvas at {}", server);
+        let ladok = crate::ladok::mock_at(server).with_max_body_size(1024);
+        let moment = crate::ladok::types::MomentUid::new("m-1");
+        let e = ladok.get_utbildningsinstans(&moment).unwrap_err();
+        assert_eq!(limit(e), Some(1024), "Ladok at {}", server);
+    }
+    let canvas = crate::canvas::Canvas::mock_at(&small).with_max_body_size(1024);
+    assert!(canvas.get_course("LT1016VT191").is_ok());
+}
@@ -0,0 +1,53 @@
+//! Institution branding of the html pages.
+use std::env::var;
+
+/// Who runs this deployment, shown on every page.
+#[derive(Debug)]
+pub struct Branding {
+    pub name: String,
+    pub logo_url: Option<String>,
+    /// A short text, e.g. where to get help, shown in the page footer.
+    pub text: Option<String>,
+}
+
+impl Branding {
+    pub fn from_env() -> Self {
+        Branding {
+            name: var("BRANDING_NAME").unwrap_or_else(|_| Branding::default().name),
+            logo_url: var("BRANDING_LOGO_URL").ok(),
+            text: var("BRANDING_TEXT").ok(),
+        }
+    }
+}
+
+impl Default for Branding {
+    fn default() -> Self {
+        Branding {
+            name: "KTH".into(),
+            logo_url: None,
+            text: None,
+        }
+    }
+}
+
+#[test]
+fn test_branding_in_pages() {
+    use crate::generated::templates;
+    use warp::http::StatusCode;
+    let branding = Branding {
+        name: "Example University".into(),
+        logo_url: Some("https://example.org/logo.svg".into()),
+        text: Some("Questions? Mail lms@example.org".into()),
+    };
+    let mut page = Vec::new();
+    templates::error(&mut page, &branding, StatusCode::BAD_REQUEST, "Oops").unwrap();
+    let page = String::from_utf8(page).unwrap();
+    assert!(page.contains(r#"<img src="https://example.org/logo.svg" alt="Example University"/>"#));
+    assert!(page.contains("Questions? Mail lms@example.org"));
+
+    let mut page = Vec::new();
+    templates::undone(&mut page, &Branding::default(), Ok((1, 2)), true).unwrap();
+    let page = String::from_utf8(page).unwrap();
+    assert!(page.contains(r#"<header class="branding">KTH</header>"#));
+    assert!(!page.contains("<footer"));
+}
@@ -1,15 +1,32 @@
+use crate::bounded;
+use crate::fair::{FairQueue, Permit};
+use crate::logging::pii_url;
+use crate::timing::timed;
 use chrono::{DateTime, FixedOffset};
-use failure::Error;
-use reqwest::Client;
+use failure::{format_err, Error, Fail};
+use log::{debug, info, warn};
+use reqwest::header::HeaderMap;
+use reqwest::{Client, Response, StatusCode};
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct CourseRoom {
     pub integration_id: Option<String>,
+    pub sis_course_id: Option<String>,
+    /// The grading scheme of the course, if any.
+    pub grading_standard_id: Option<i32>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct CourseSection {
+    pub id: i32,
     pub name: Option<String>,
     pub integration_id: Option<String>,
 }
@@ -19,15 +36,110 @@ pub struct Assignment {
     pub id: i32,
     pub name: Option<String>,
     pub integration_id: Option<String>,
+    pub due_at: Option<DateTime<FixedOffset>>,
+    pub assignment_group_id: Option<i32>,
+    /// Graders do not see who they grade, and grades stay hidden
+    /// until posted.
+    pub anonymous_grading: Option<bool>,
+    /// Grades are hidden from students, in the older posting model.
+    pub muted: Option<bool>,
+    /// When the assignment itself was last changed.
+    pub updated_at: Option<DateTime<FixedOffset>>,
+}
+
+impl Assignment {
+    /// True if the grade of a submission is still withheld by
+    /// anonymous grading, and so must not be reported.
+    pub fn withholds_anonymous_grade(&self, submission: &Submission) -> bool {
+        self.anonymous_grading == Some(true)
+            && (self.muted == Some(true) || submission.posted_at.is_none())
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct AssignmentGroup {
+    pub id: i32,
+    pub name: String,
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct Submission {
     pub assignment_id: Option<i32>,
     pub grade: Option<String>,
+    pub user_id: Option<i32>,
     pub user: Option<User>,
     pub graded_at: Option<DateTime<FixedOffset>>,
+    pub submitted_at: Option<DateTime<FixedOffset>>,
+    pub late: Option<bool>,
+    /// How late the submission was, after any late policy adjustment.
+    pub seconds_late: Option<f64>,
+    pub missing: Option<bool>,
+    /// When the grade was released to the student, None while it is
+    /// hidden by a manual posting policy.
+    pub posted_at: Option<DateTime<FixedOffset>>,
+    /// False if the student has resubmitted since the last grading.
+    pub grade_matches_current_submission: Option<bool>,
+    pub workflow_state: Option<String>,
+    #[allow(dead_code)]
     pub grader_id: Option<i32>,
+    /// Points per rubric criterion, only fetched for rubric-graded
+    /// assignments.
+    pub rubric_assessment: Option<BTreeMap<String, RubricRating>>,
+    /// True if the grade is withheld by anonymous grading, as decided
+    /// by the assignment.
+    #[serde(skip)]
+    pub anonymous_unreleased: bool,
+    /// Only fetched when comments may become notes in Ladok.
+    #[serde(default)]
+    pub submission_comments: Vec<SubmissionComment>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct SubmissionComment {
+    pub author_id: Option<i32>,
+    pub comment: Option<String>,
+}
+
+impl Submission {
+    /// The text of the latest comment starting with `prefix`, without
+    /// it.  Comments by the student are ignored.
+    pub fn comment_with_prefix(&self, prefix: &str) -> Option<&str> {
+        self.submission_comments
+            .iter()
+            .rev()
+            .filter(|c| c.author_id.is_none() || c.author_id != self.user_id)
+            .find_map(|c| c.comment.as_deref()?.trim_start().strip_prefix(prefix))
+    }
+
+    /// The grade, unless there is none.  Canvas may keep a cleared
+    /// grade as an empty string rather than null.
+    pub fn given_grade(&self) -> Option<&str> {
+        self.grade
+            .as_deref()
+            .filter(|grade| !grade.trim().is_empty())
+    }
+
+    /// True if the grade is for an earlier submission, since the
+    /// student has resubmitted and is not yet regraded.
+    pub fn needs_regrade(&self) -> bool {
+        self.grade.is_some()
+            && (self.grade_matches_current_submission == Some(false)
+                || self.workflow_state.as_deref() == Some("submitted"))
+    }
+
+    /// The total points of the rubric assessment, if any.
+    pub fn rubric_total(&self) -> Option<f64> {
+        let ratings = self.rubric_assessment.as_ref()?;
+        if ratings.values().all(|r| r.points.is_none()) {
+            return None;
+        }
+        Some(ratings.values().filter_map(|r| r.points).sum())
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct RubricRating {
+    pub points: Option<f64>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -35,90 +147,577 @@ pub struct User {
     pub id: i32,
     pub name: Option<String>,
     pub integration_id: Option<String>,
+    /// A personnummer for some users without an integration_id.
+    pub sis_user_id: Option<String>,
+    /// The enrollments of the user, when included in the request.
+    #[serde(default)]
+    pub enrollments: Vec<Enrollment>,
+}
+
+impl User {
+    /// A user known only by id, e.g. when looking it up failed.
+    pub fn unknown(id: i32) -> User {
+        User {
+            id,
+            name: None,
+            integration_id: None,
+            sis_user_id: None,
+            enrollments: vec![],
+        }
+    }
+
+    /// The ids of the sections the user is enrolled in as a student.
+    pub fn section_ids(&self) -> Vec<i32> {
+        let mut ids = self
+            .enrollments
+            .iter()
+            .filter(|e| e.enrollment_type == "StudentEnrollment")
+            .filter_map(|e| e.course_section_id)
+            .collect::<Vec<_>>();
+        ids.sort_unstable();
+        ids.dedup();
+        ids
+    }
+
+    /// True if this is the "Test Student" Canvas creates for the
+    /// student view of a course.
+    ///
+    /// The reliable way to tell is the StudentViewEnrollment, see
+    /// `Canvas::get_test_student_ids`.  This is a fallback for when
+    /// that is not known, matching the names Canvas uses for it, and
+    /// only for users without an integration_id.
+    pub fn looks_like_test_student(&self) -> bool {
+        self.integration_id.is_none()
+            && self
+                .name
+                .as_ref()
+                .map(|name| TEST_STUDENT_NAMES.contains(&name.as_str()))
+                .unwrap_or(false)
+    }
+}
+
+const TEST_STUDENT_NAMES: &[&str] = &["Test Student", "Teststudent", "Testelev"];
+
+/// An enrollment of a user in a course.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Enrollment {
+    /// E.g. StudentEnrollment, TeacherEnrollment or TaEnrollment.
+    #[serde(rename = "type")]
+    pub enrollment_type: String,
+    pub enrollment_state: Option<String>,
+    pub user_id: Option<i32>,
+    pub course_section_id: Option<i32>,
+}
+
+impl Enrollment {
+    /// True if this enrollment gives the right to report results.
+    /// Only an active enrollment does, not e.g. an invitation not yet
+    /// accepted.
+    pub fn is_teacher(&self) -> bool {
+        (self.enrollment_type == "TeacherEnrollment" || self.enrollment_type == "TaEnrollment")
+            && self.enrollment_state.as_deref() == Some("active")
+    }
+}
+
+/// How course rooms are found in Canvas.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CourseLookup {
+    /// By sis course id, e.g. LT1016VT191.
+    #[default]
+    Sis,
+    /// By the numeric Canvas course id, as given by the LTI launch.
+    Id,
+}
+
+impl FromStr for CourseLookup {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "sis" => Ok(CourseLookup::Sis),
+            "id" => Ok(CourseLookup::Id),
+            s => Err(format_err!(
+                "Unknown course lookup {:?}, expected sis or id",
+                s
+            )),
+        }
+    }
 }
 
 pub struct Canvas {
     base_url: String,
     auth_key: String,
+    /// The numeric course id to use rather than the sis course id.
+    course_id: Option<String>,
     client: Client,
+    rate_limit: RateLimit,
+    /// The rate limit budget left for the token, as of the last request.
+    remaining: Mutex<Option<f64>>,
+    /// The queue to wait in before each request, and the course room
+    /// to wait as.
+    queue: Option<(Arc<FairQueue>, String)>,
+    /// The largest response body to read.
+    max_body: u64,
+}
+
+/// When to pause, to stay within the rate limit of Canvas.
+///
+/// Canvas gives each token a budget that is used up by requests and
+/// refilled over time, and throttles requests when it is empty.
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimit {
+    /// Pause before a request when less than this is left.
+    pub threshold: f64,
+    pub pause: Duration,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        RateLimit {
+            threshold: 100.,
+            pause: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Build the http client shared by all Canvas clients, sending
+/// `headers` on every request.
+pub fn http_client(max_idle_per_host: usize, headers: HeaderMap) -> Result<Client, Error> {
+    Ok(Client::builder()
+        .max_idle_per_host(max_idle_per_host)
+        .default_headers(headers)
+        .build()?)
 }
 
 impl Canvas {
-    pub fn new(hostname: &str, auth_key: &str) -> Result<Canvas, Error> {
-        Ok(Canvas {
+    /// Create a Canvas client for a specific user.
+    ///
+    /// The `client` is shared between requests, the `auth_key` is
+    /// sent with each request.
+    pub fn new(hostname: &str, auth_key: &str, client: Client, rate_limit: RateLimit) -> Canvas {
+        Canvas {
             base_url: format!("https://{}/api/v1", hostname),
             auth_key: auth_key.into(),
-            client: Client::builder().build()?,
-        })
+            course_id: None,
+            client,
+            rate_limit,
+            remaining: Mutex::new(None),
+            queue: None,
+            max_body: bounded::max_body_size(),
+        }
+    }
+
+    /// A client for a fake Canvas server at `server`, e.g.
+    /// `http://127.0.0.1:4711`.
+    #[cfg(test)]
+    pub fn mock_at(server: &str) -> Canvas {
+        Canvas {
+            base_url: format!("{}/api/v1", server),
+            ..Canvas::new(
+                "canvas.invalid",
+                "token",
+                Client::new(),
+                RateLimit::default(),
+            )
+        }
+    }
+
+    /// Read at most `bytes` of each response body.
+    #[cfg(test)]
+    pub fn with_max_body_size(mut self, bytes: u64) -> Canvas {
+        self.max_body = bytes;
+        self
     }
     pub fn get_auth_key(&self) -> &str {
         &self.auth_key
     }
 
+    /// Find the course room by its numeric Canvas id rather than by
+    /// the sis course id given to each call.
+    pub fn with_course_id(mut self, course_id: &str) -> Canvas {
+        self.course_id = Some(course_id.into());
+        self
+    }
+
+    /// Wait in `queue` as the course room `key` before each request.
+    pub fn queued(mut self, queue: Arc<FairQueue>, key: &str) -> Canvas {
+        self.queue = Some((queue, key.into()));
+        self
+    }
+
+    fn course_url(&self, sis_id: &str) -> String {
+        match &self.course_id {
+            Some(id) => format!("{}/courses/{}", self.base_url, id),
+            None => format!("{}/courses/sis_course_id:{}", self.base_url, sis_id),
+        }
+    }
+
     /// The sections of a course room is the real connection to ladok course rounds.
     ///
     /// sis_id will look like e.g. LT1016VT191.  Each element of the
     /// resulting section data may contain a ladok courseround oid in
-    /// the integration_id field.
+    /// the integration_id field.  Which sections a student is in is
+    /// given by `get_students_with_sections`.
     pub fn get_course_sections(&self, sis_id: &str) -> Result<Vec<CourseSection>, Error> {
-        Ok(self
-            .client
-            .get(&format!(
-                "{}/courses/sis_course_id:{}/sections",
-                self.base_url, sis_id
-            ))
-            .bearer_auth(&self.auth_key)
-            .send()?
-            .error_for_status()?
-            .json()?)
+        self.get_all(&format!(
+            "{}/sections?per_page=100",
+            self.course_url(sis_id)
+        ))
+    }
+    pub fn get_course(&self, sis_id: &str) -> Result<CourseRoom, Error> {
+        self.get_json(&self.course_url(sis_id))
     }
     pub fn get_assignments(&self, sis_id: &str) -> Result<Vec<Assignment>, Error> {
+        self.get_json(&format!("{}/assignments", self.course_url(sis_id)))
+    }
+
+    pub fn get_assignment_groups(&self, sis_id: &str) -> Result<Vec<AssignmentGroup>, Error> {
+        self.get_json(&format!("{}/assignment_groups", self.course_url(sis_id)))
+    }
+
+    /// Get a user in a course, e.g. for an integration_id missing on
+    /// a submission.
+    pub fn get_user(&self, sis_id: &str, user_id: i32) -> Result<User, Error> {
+        self.get_json(&format!("{}/users/{}", self.course_url(sis_id), user_id))
+    }
+
+    /// Get the enrollments of the authenticated user in a course.
+    pub fn get_my_enrollments(&self, sis_id: &str) -> Result<Vec<Enrollment>, Error> {
+        self.get_json(&format!(
+            "{}/enrollments?user_id=self",
+            self.course_url(sis_id)
+        ))
+    }
+
+    /// Get the user ids of the test students of a course.
+    pub fn get_test_student_ids(&self, sis_id: &str) -> Result<Vec<i32>, Error> {
         Ok(self
-            .client
-            .get(&format!(
-                "{}/courses/sis_course_id:{}/assignments",
-                self.base_url, sis_id
-            ))
-            .bearer_auth(&self.auth_key)
-            .send()?
-            .error_for_status()?
-            .json()?)
-    }
-
-    pub fn get_assignment_submissions(
+            .get_json::<Vec<Enrollment>>(&format!(
+                "{}/enrollments?type[]=StudentViewEnrollment",
+                self.course_url(sis_id)
+            ))?
+            .into_iter()
+            .filter(|e| e.enrollment_type == "StudentViewEnrollment")
+            .filter_map(|e| e.user_id)
+            .collect())
+    }
+
+    /// Get the submissions of several assignments at once, by
+    /// assignment id, with rubric assessments if `rubric` and comments
+    /// if `comments`.  Each page is sorted into buckets as it arrives,
+    /// and only the given assignments are asked for.
+    pub fn get_submissions_by_assignment(
         &self,
         sis_id: &str,
-        assignment: i32,
-    ) -> Result<Vec<Submission>, Error> {
-        let mut result = vec![];
-        let mut next_url = Some(format!(
-            "{}/courses/sis_course_id:{}/assignments/{}/submissions?student_ids[]=all&include[]=user&per_page=100",
-            self.base_url, sis_id, assignment
-        ));
-        while let Some(url) = next_url {
-            let mut resp = self
-                .client
-                .get(&url)
-                .bearer_auth(&self.auth_key)
-                .send()?
-                .error_for_status()?;
-            next_url = resp
-                .headers()
-                .get("link")
-                .and_then(|h| h.to_str().ok())
-                .and_then(get_next_url);
-            result.append(&mut resp.json()?);
-            dbg!(result.len());
+        assignments: &[i32],
+        rubric: bool,
+        comments: bool,
+    ) -> Result<HashMap<i32, Vec<Submission>>, Error> {
+        let mut buckets = HashMap::new();
+        if assignments.is_empty() {
+            return Ok(buckets);
+        }
+        let url = self.submissions_url(sis_id, assignments, rubric, comments);
+        self.get_submissions_from(&url, &mut buckets)?;
+        Ok(buckets)
+    }
+
+    /// The url of the first page of submissions of several
+    /// assignments, to start `get_submissions_from`.
+    pub fn submissions_url(
+        &self,
+        sis_id: &str,
+        assignments: &[i32],
+        rubric: bool,
+        comments: bool,
+    ) -> String {
+        let ids = assignments
+            .iter()
+            .map(|id| format!("&assignment_ids[]={}", id))
+            .collect::<String>();
+        let mut include = "include[]=user".to_string();
+        if rubric {
+            include.push_str("&include[]=rubric_assessment");
+        }
+        if comments {
+            include.push_str("&include[]=submission_comments");
         }
+        format!(
+            "{}/students/submissions?student_ids[]=all{}&{}&per_page=100",
+            self.course_url(sis_id),
+            ids,
+            include,
+        )
+    }
+
+    /// Get submissions from `bookmark` on into `buckets`, by assignment.
+    /// The bookmark is the url of a first page, or where an earlier
+    /// fetch was interrupted.  An interrupted fetch is resumed a few
+    /// times.  If it still fails, the error is an `Interrupted` with the
+    /// bookmark to go on from, and `buckets` keeps what was fetched.
+    pub fn get_submissions_from(
+        &self,
+        bookmark: &str,
+        buckets: &mut HashMap<i32, Vec<Submission>>,
+    ) -> Result<(), Error> {
+        crawl_resuming(
+            bookmark,
+            |url| self.get_page(url),
+            |page| bucket_by_assignment(buckets, page),
+        )
+    }
+
+    /// Get the students of a course with their enrollments, to map
+    /// each student to sections without a request per student.
+    pub fn get_students_with_sections(&self, sis_id: &str) -> Result<Vec<User>, Error> {
+        self.get_all(&format!(
+            "{}/users?enrollment_type[]=student&include[]=enrollments&per_page=100",
+            self.course_url(sis_id)
+        ))
+    }
+
+    /// Get all pages of a list.
+    fn get_all<T: DeserializeOwned>(&self, url: &str) -> Result<Vec<T>, Error> {
+        let mut result = vec![];
+        self.for_each_page(url, |mut page| {
+            result.append(&mut page);
+            debug!("Got {} items so far", result.len());
+        })?;
         Ok(result)
     }
+
+    /// Handle each page of a list as it arrives.
+    fn for_each_page<T: DeserializeOwned>(
+        &self,
+        url: &str,
+        handle: impl FnMut(Vec<T>),
+    ) -> Result<(), Error> {
+        crawl(url, |url| self.get_page(url), handle)
+    }
+
+    /// Get a page of a list, and the url of the next page, if any.
+    fn get_page<T: DeserializeOwned>(&self, url: &str) -> Result<(Vec<T>, Option<String>), Error> {
+        let _permit = self.turn();
+        let mut resp = self.get(url)?;
+        let next_url = resp
+            .headers()
+            .get("link")
+            .and_then(|h| h.to_str().ok())
+            .and_then(get_next_url);
+        Ok((bounded::json(&mut resp, self.max_body)?, next_url))
+    }
+
+    /// Wait for the rate limit budget, and for a turn in the queue, if
+    /// any.  The turn lasts until the permit is dropped, which should be
+    /// after the body of the response is read.
+    fn turn(&self) -> Option<Permit<'_>> {
+        self.wait_for_budget();
+        self.queue.as_ref().map(|(queue, key)| queue.acquire(key))
+    }
+
+    /// Send a request, in a turn taken by the caller.
+    fn get(&self, url: &str) -> Result<Response, Error> {
+        let response = timed(&format!("GET {}", pii_url(url)), || {
+            self.client.get(url).bearer_auth(&self.auth_key).send()
+        })
+        .map_err(|e| format_err!("{}", pii_url(&e.to_string())))?;
+        *self.remaining.lock().unwrap() = response
+            .headers()
+            .get("x-rate-limit-remaining")
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.parse().ok());
+        check_scope(response, self.max_body)
+    }
+
+    /// Pause if the rate limit budget is low.  Returns the pause, if any.
+    fn wait_for_budget(&self) -> Option<Duration> {
+        let remaining = (*self.remaining.lock().unwrap())?;
+        if remaining >= self.rate_limit.threshold {
+            return None;
+        }
+        info!(
+            "Canvas rate limit budget is down to {}, pausing {:?}",
+            remaining, self.rate_limit.pause,
+        );
+        thread::sleep(self.rate_limit.pause);
+        Some(self.rate_limit.pause)
+    }
+
+    fn get_json<T: DeserializeOwned>(&self, url: &str) -> Result<T, Error> {
+        let _permit = self.turn();
+        bounded::json(&mut self.get(url)?, self.max_body)
+    }
+}
+
+/// Times to resume an interrupted fetch of submissions.
+const RESUME_ATTEMPTS: u32 = 2;
+
+/// A list was only partly fetched.  The rest can be fetched by
+/// resuming from `bookmark`, the url of the page that failed.
+#[derive(Debug)]
+pub struct Interrupted {
+    pub bookmark: String,
+    pub cause: String,
+}
+
+impl fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Fetching {} failed: {}",
+            pii_url(&self.bookmark),
+            self.cause
+        )
+    }
+}
+
+impl Fail for Interrupted {}
+
+/// Fetch the pages of a list from `url` on, by `fetch` returning each
+/// page and the url of the next, and handle each page as it arrives.
+///
+/// A failure after the first page is an `Interrupted`, to resume from
+/// rather than start over.
+fn crawl<T>(
+    url: &str,
+    mut fetch: impl FnMut(&str) -> Result<(Vec<T>, Option<String>), Error>,
+    mut handle: impl FnMut(Vec<T>),
+) -> Result<(), Error> {
+    let mut next_url = Some(url.to_string());
+    let mut first = true;
+    while let Some(url) = next_url {
+        let (page, next) = match fetch(&url) {
+            Ok(fetched) => fetched,
+            Err(e) if first => return Err(e),
+            Err(e) => {
+                return Err(Interrupted {
+                    bookmark: url,
+                    cause: e.to_string(),
+                }
+                .into())
+            }
+        };
+        handle(page);
+        next_url = next;
+        first = false;
+    }
+    Ok(())
+}
+
+/// Crawl from `url` as `crawl` does, resuming an interrupted crawl
+/// from its bookmark up to `RESUME_ATTEMPTS` times.
+///
+/// Once resumed, a failure on the first page of the resumed crawl is
+/// also an `Interrupted`, since earlier pages were handled.
+fn crawl_resuming<T>(
+    url: &str,
+    mut fetch: impl FnMut(&str) -> Result<(Vec<T>, Option<String>), Error>,
+    mut handle: impl FnMut(Vec<T>),
+) -> Result<(), Error> {
+    let mut url = url.to_string();
+    let mut resumed = 0;
+    loop {
+        let e = match crawl(&url, &mut fetch, &mut handle) {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+        let interrupted = match e.downcast::<Interrupted>() {
+            Ok(interrupted) => interrupted,
+            Err(e) if resumed > 0 => Interrupted {
+                bookmark: url,
+                cause: e.to_string(),
+            },
+            Err(e) => return Err(e),
+        };
+        if resumed >= RESUME_ATTEMPTS {
+            return Err(interrupted.into());
+        }
+        warn!("{}, resuming", interrupted);
+        url = interrupted.bookmark;
+        resumed += 1;
+    }
+}
+
+/// Sort submissions into buckets by assignment.  Submissions without
+/// an assignment are dropped.
+pub fn bucket_by_assignment(buckets: &mut HashMap<i32, Vec<Submission>>, page: Vec<Submission>) {
+    for submission in page {
+        if let Some(id) = submission.assignment_id {
+            buckets.entry(id).or_default().push(submission);
+        }
+    }
+}
+
+/// The access token lacks a scope needed for a request.
+#[derive(Debug)]
+pub struct InsufficientScope {
+    pub url: String,
+}
+
+impl fmt::Display for InsufficientScope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Insufficient scope on the access token for {}",
+            pii_url(&self.url),
+        )
+    }
 }
 
+impl Fail for InsufficientScope {}
+
+/// Fail with InsufficientScope if Canvas denied the request for lack
+/// of a scope, as told by the WWW-Authenticate header or the body.
+fn check_scope(mut response: Response, max_body: u64) -> Result<Response, Error> {
+    let status = response.status();
+    if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+        let header = response
+            .headers()
+            .get("www-authenticate")
+            .and_then(|h| h.to_str().ok())
+            .unwrap_or("")
+            .to_string();
+        let body = bounded::text(&mut response, max_body).unwrap_or_default();
+        if is_insufficient_scope(&header, &body) {
+            return Err(InsufficientScope {
+                url: response.url().to_string(),
+            }
+            .into());
+        }
+    }
+    response
+        .error_for_status()
+        .map_err(|e| format_err!("{}", pii_url(&e.to_string())))
+}
+
+fn is_insufficient_scope(www_authenticate: &str, body: &str) -> bool {
+    www_authenticate.contains("insufficient_scope")
+        || body.to_lowercase().contains("insufficient scope")
+}
+
+/// Get the "next" url from a Link header.
+///
+/// The url is returned verbatim, including any url-encoded
+/// characters such as `%5B%5D`, as Canvas requires pagination links
+/// to be used as given.
 fn get_next_url(links: &str) -> Option<String> {
-    const END: &str = ">; rel=\"next\"";
-    for link in links.split(',') {
-        if link.starts_with('<') && link.ends_with(END) {
-            return Some(link[1..link.len() - END.len()].to_string());
+    let mut rest = links;
+    while let Some(start) = rest.find('<') {
+        let end = start + rest[start..].find('>')?;
+        let url = &rest[start + 1..end];
+        rest = &rest[end + 1..];
+        let params = &rest[..rest.find('<').unwrap_or(rest.len())];
+        let is_next = params
+            .split(&[';', ','][..])
+            .filter_map(|param| {
+                let (name, value) = param.split_at(param.find('=')?);
+                Some((name.trim(), value[1..].trim().trim_matches('"')))
+            })
+            .any(|(name, value)| {
+                name.eq_ignore_ascii_case("rel")
+                    && value.split_whitespace().any(|rel| rel == "next")
+            });
+        if is_next {
+            return Some(url.to_string());
         }
     }
     None
@@ -128,7 +727,6 @@ fn get_next_url(links: &str) -> Option<String> {
 fn test_get_next_url() {
     assert_eq!(
         get_next_url("<https://kth.test.instructure.com/api/v1/courses/7798/students/submissions?student_ids%5B%5D=all&page=first&per_page=100>; rel=\"current\",<https://kth.test.instructure.com/api/v1/courses/7798/students/submissions?student_ids%5B%5D=all&page=bookmark:WzY1OTU4MzZd&per_page=100>; rel=\"next\",<https://kth.test.instructure.com/api/v1/courses/7798/students/submissions?student_ids%5B%5D=all&page=first&per_page=100>; rel=\"first\""),
-        // TODO: Is %5B%5D ok here?  Or do we need do urldecode []?
         Some("https://kth.test.instructure.com/api/v1/courses/7798/students/submissions?student_ids%5B%5D=all&page=bookmark:WzY1OTU4MzZd&per_page=100".to_string()),
     )
 }
@@ -140,3 +738,263 @@ fn test_get_next_url_0() {
         None,
     )
 }
+
+#[test]
+fn test_get_next_url_unquoted_rel() {
+    assert_eq!(
+        get_next_url(
+            "<https://x/?page=2&per_page=10>; rel=next, <https://x/?page=1&per_page=10>; rel=first"
+        ),
+        Some("https://x/?page=2&per_page=10".to_string()),
+    )
+}
+
+#[test]
+fn test_get_next_url_last() {
+    assert_eq!(
+        get_next_url("<https://x/?page=1>; rel=\"first\", <https://x/?page=1>; rel=\"current\", <https://x/?page=2>; rel=\"next\""),
+        Some("https://x/?page=2".to_string()),
+    )
+}
+
+#[test]
+fn test_get_next_url_first() {
+    assert_eq!(
+        get_next_url("<https://x/?page=2&ids[]=1,2>;rel=\"next\",<https://x/?page=9>;rel=\"last\""),
+        Some("https://x/?page=2&ids[]=1,2".to_string()),
+    )
+}
+
+#[test]
+fn test_get_next_url_multiple_rels() {
+    assert_eq!(
+        get_next_url("<https://x/?page=1>; rel=\"first\", <https://x/?page=2>; title=\"two\"; rel=\"next last\""),
+        Some("https://x/?page=2".to_string()),
+    )
+}
+
+#[test]
+fn test_get_next_url_not_prefix() {
+    assert_eq!(
+        get_next_url("<https://x/?page=2>; rel=\"nextish\", <https://x/?page=1>; rel=\"prev\""),
+        None,
+    )
+}
+
+#[test]
+fn test_student_is_not_teacher() {
+    let enrollments: Vec<Enrollment> = serde_json::from_str(
+        r#"[{"type": "StudentEnrollment", "enrollment_state": "active", "user_id": 17}]"#,
+    )
+    .unwrap();
+    assert!(!enrollments.iter().any(Enrollment::is_teacher));
+}
+
+#[test]
+fn test_teacher_and_ta_are_teachers() {
+    let enrollments: Vec<Enrollment> = serde_json::from_str(
+        r#"[{"type": "TeacherEnrollment", "enrollment_state": "active"},
+            {"type": "TaEnrollment", "enrollment_state": "active"},
+            {"type": "TeacherEnrollment", "enrollment_state": "inactive"},
+            {"type": "TeacherEnrollment", "enrollment_state": "invited"},
+            {"type": "TaEnrollment"}]"#,
+    )
+    .unwrap();
+    let teacher = enrollments
+        .iter()
+        .map(Enrollment::is_teacher)
+        .collect::<Vec<_>>();
+    assert_eq!(teacher, vec![true, true, false, false, false]);
+}
+
+#[test]
+fn test_looks_like_test_student() {
+    let users: Vec<User> = serde_json::from_str(
+        r#"[{"id": 1, "name": "Test Student"},
+            {"id": 2, "name": "Testelev"},
+            {"id": 3, "name": "Test Student", "integration_id": "st-3"},
+            {"id": 4, "name": "Anna Student"}]"#,
+    )
+    .unwrap();
+    let test = users
+        .iter()
+        .map(User::looks_like_test_student)
+        .collect::<Vec<_>>();
+    assert_eq!(test, vec![true, true, false, false]);
+}
+
+#[test]
+fn test_pause_on_low_rate_budget() {
+    use std::time::Instant;
+    let canvas = Canvas::new(
+        "canvas.invalid",
+        "token",
+        Client::new(),
+        RateLimit {
+            threshold: 100.,
+            pause: Duration::from_millis(20),
+        },
+    );
+    assert_eq!(canvas.wait_for_budget(), None, "Unknown budget");
+    *canvas.remaining.lock().unwrap() = Some(650.);
+    assert_eq!(canvas.wait_for_budget(), None);
+    *canvas.remaining.lock().unwrap() = Some(42.5);
+    let start = Instant::now();
+    assert_eq!(canvas.wait_for_budget(), Some(Duration::from_millis(20)));
+    assert!(start.elapsed() >= Duration::from_millis(20));
+}
+
+#[test]
+fn test_user_sections_from_enrollments() {
+    let users: Vec<User> = serde_json::from_str(
+        r#"[{"id": 1, "name": "Anna", "integration_id": "st-1", "enrollments": [
+                {"type": "StudentEnrollment", "enrollment_state": "active",
+                 "user_id": 1, "course_section_id": 102},
+                {"type": "StudentEnrollment", "enrollment_state": "active",
+                 "user_id": 1, "course_section_id": 101},
+                {"type": "StudentEnrollment", "enrollment_state": "inactive",
+                 "user_id": 1, "course_section_id": 102},
+                {"type": "TaEnrollment", "enrollment_state": "active",
+                 "user_id": 1, "course_section_id": 103}]},
+            {"id": 2, "name": "Bo", "integration_id": "st-2"}]"#,
+    )
+    .unwrap();
+    assert_eq!(users[0].section_ids(), [101, 102]);
+    assert!(users[1].section_ids().is_empty());
+}
+
+#[test]
+fn test_course_url_schemes() {
+    let canvas = Canvas::new(
+        "canvas.example.org",
+        "token",
+        Client::new(),
+        RateLimit::default(),
+    );
+    assert_eq!(
+        canvas.course_url("LT1016VT191"),
+        "https://canvas.example.org/api/v1/courses/sis_course_id:LT1016VT191"
+    );
+    let canvas = canvas.with_course_id("7798");
+    assert_eq!(
+        canvas.course_url("LT1016VT191"),
+        "https://canvas.example.org/api/v1/courses/7798"
+    );
+    assert_eq!("id".parse::<CourseLookup>().unwrap(), CourseLookup::Id);
+    assert!("name".parse::<CourseLookup>().is_err());
+}
+
+#[test]
+fn test_insufficient_scope() {
+    assert!(is_insufficient_scope(
+        r#"Bearer realm="canvas-lms", error="insufficient_scope""#,
+        ""
+    ));
+    assert!(is_insufficient_scope(
+        "",
+        r#"{"errors":[{"message":"Insufficient scopes on access token."}]}"#
+    ));
+    assert!(!is_insufficient_scope(
+        "",
+        r#"{"status":"unauthorized","errors":[{"message":"user not authorized to perform that action"}]}"#
+    ));
+}
+
+#[test]
+fn test_buckets_match_filter() {
+    let submissions: Vec<Submission> = serde_json::from_str(
+        r#"[{"assignment_id": 17, "grade": "A", "user_id": 1},
+            {"assignment_id": 18, "grade": "B", "user_id": 1},
+            {"assignment_id": null, "grade": "C", "user_id": 2},
+            {"assignment_id": 17, "grade": "D", "user_id": 2},
+            {"assignment_id": 19, "grade": "E", "user_id": 3}]"#,
+    )
+    .unwrap();
+    let mut buckets = HashMap::new();
+    let (first, second) = submissions.split_at(2);
+    bucket_by_assignment(&mut buckets, first.to_vec());
+    bucket_by_assignment(&mut buckets, second.to_vec());
+    let grades = |submissions: &[Submission]| {
+        submissions
+            .iter()
+            .map(|s| (s.user_id, s.grade.clone()))
+            .collect::<Vec<_>>()
+    };
+    for id in &[17, 18, 19, 20] {
+        let filtered = submissions
+            .iter()
+            .filter(|s| s.assignment_id == Some(*id))
+            .cloned()
+            .collect::<Vec<_>>();
+        let bucket = buckets.get(id).map(Vec::as_slice).unwrap_or_default();
+        assert_eq!(grades(bucket), grades(&filtered));
+    }
+    assert_eq!(buckets.values().map(Vec::len).sum::<usize>(), 4);
+}
+
+#[test]
+fn test_crawl_resumes_from_bookmark() {
+    use std::cell::Cell;
+    let failures = Cell::new(1);
+    let fetch = |url: &str| {
+        let page = match url {
+            "p1" => (vec![1, 2], Some("p2".to_string())),
+            "p2" if failures.get() > 0 => {
+                failures.set(failures.get() - 1);
+                return Err(format_err!("Connection reset"));
+            }
+            "p2" => (vec![3], Some("p3".to_string())),
+            "p3" => (vec![4], None),
+            _ => return Err(format_err!("Unexpected url {}", url)),
+        };
+        Ok(page)
+    };
+    let mut items = vec![];
+    let e = crawl("p1", fetch, |page| items.extend(page)).unwrap_err();
+    let interrupted = e.downcast::<Interrupted>().unwrap();
+    assert_eq!(interrupted.bookmark, "p2");
+    assert_eq!(items, [1, 2]);
+
+    crawl(&interrupted.bookmark, fetch, |page| items.extend(page)).unwrap();
+    assert_eq!(items, [1, 2, 3, 4]);
+}
+
+#[test]
+fn test_crawl_resuming_gives_up_with_bookmark() {
+    use std::cell::Cell;
+    let failures = Cell::new(0);
+    let fetch = |url: &str| match url {
+        "p1" => Ok((vec![1, 2], Some("p2".to_string()))),
+        "p2" if failures.get() > 0 => {
+            failures.set(failures.get() - 1);
+            Err(format_err!("Connection reset"))
+        }
+        "p2" => Ok((vec![3], None)),
+        _ => Err(format_err!("Unexpected url {}", url)),
+    };
+
+    failures.set(RESUME_ATTEMPTS);
+    let mut items = vec![];
+    crawl_resuming("p1", fetch, |page| items.extend(page)).unwrap();
+    assert_eq!(items, [1, 2, 3], "Resumed without refetching p1");
+
+    failures.set(RESUME_ATTEMPTS + 1);
+    let mut items = vec![];
+    let e = crawl_resuming("p1", fetch, |page| items.extend(page)).unwrap_err();
+    let interrupted = e.downcast::<Interrupted>().unwrap();
+    assert_eq!(interrupted.bookmark, "p2");
+    assert_eq!(items, [1, 2]);
+
+    // The caller can go on from the bookmark.
+    crawl_resuming(&interrupted.bookmark, fetch, |page| items.extend(page)).unwrap();
+    assert_eq!(items, [1, 2, 3]);
+
+    let e = crawl_resuming::<i32>("p1", |_| Err(format_err!("Down")), |_| ()).unwrap_err();
+    assert!(e.downcast_ref::<Interrupted>().is_none(), "Nothing fetched");
+}
+
+#[test]
+fn test_crawl_first_page_failure_not_interrupted() {
+    let e = crawl::<i32>("p1", |_| Err(format_err!("Down")), |_| ()).unwrap_err();
+    assert!(e.downcast_ref::<Interrupted>().is_none());
+}
@@ -0,0 +1,170 @@
+//! Combining the submissions on several assignments into one grade.
+//!
+//! When more than one Canvas assignment maps to the same Ladok
+//! moment, each student gets a single grade for the moment, picked
+//! from their submissions by a `CombineRule`.
+use crate::canvas::Submission;
+use failure::{format_err, Error};
+use std::str::FromStr;
+
+/// How to pick one of several submissions for a moment.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum CombineRule {
+    /// The best grade.
+    Max,
+    /// The most recently graded.
+    #[default]
+    Latest,
+    /// The submission on the first of these assignments that belongs
+    /// to the moment, falling back to the latest.
+    Assignment(Vec<i32>),
+}
+
+impl FromStr for CombineRule {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "max" => Ok(CombineRule::Max),
+            "latest" => Ok(CombineRule::Latest),
+            s if s.starts_with("assignment:") => Ok(CombineRule::Assignment(
+                s["assignment:".len()..]
+                    .split(',')
+                    .map(|id| id.trim().parse())
+                    .collect::<Result<_, _>>()
+                    .map_err(|e| format_err!("Bad assignment id in {:?}: {}", s, e))?,
+            )),
+            s => Err(format_err!(
+                "Unknown combine rule {:?}, expected max, latest or assignment:<id>",
+                s
+            )),
+        }
+    }
+}
+
+/// Grades from best to worst.  Codes from different scales are never
+/// compared, so one list covers them all.
+const GRADE_ORDER: &[&str] = &["A", "B", "C", "D", "E", "VG", "G", "P", "FX", "F", "U"];
+
+fn grade_rank(grade: &str) -> usize {
+    let grade = grade.to_uppercase();
+    GRADE_ORDER
+        .iter()
+        .position(|g| *g == grade)
+        .unwrap_or(GRADE_ORDER.len())
+}
+
+/// Pick the submission to report for one student on one moment.
+///
+/// Only graded submissions compete; if none is graded, the first one
+/// is returned, to be reported as ungraded.
+pub fn combine<'a>(
+    rule: &CombineRule,
+    submissions: &[&'a Submission],
+) -> Result<Option<&'a Submission>, Error> {
+    if submissions.len() < 2 {
+        return Ok(submissions.first().cloned());
+    }
+    let graded = submissions
+        .iter()
+        .cloned()
+        .filter(|s| s.grade.is_some())
+        .collect::<Vec<_>>();
+    if graded.is_empty() {
+        return Ok(submissions.first().cloned());
+    }
+    match rule {
+        CombineRule::Max => Ok(graded
+            .into_iter()
+            .min_by_key(|s| s.grade.as_ref().map(|g| grade_rank(g)))),
+        CombineRule::Latest => latest(&graded),
+        CombineRule::Assignment(ids) => {
+            for id in ids {
+                if let Some(s) = submissions.iter().find(|s| s.assignment_id == Some(*id)) {
+                    return Ok(Some(s));
+                }
+            }
+            latest(&graded)
+        }
+    }
+}
+
+fn latest<'a>(graded: &[&'a Submission]) -> Result<Option<&'a Submission>, Error> {
+    let last = graded.iter().cloned().max_by_key(|s| s.graded_at);
+    if let Some(last) = last {
+        if let Some(other) = graded
+            .iter()
+            .find(|s| s.graded_at == last.graded_at && s.grade != last.grade)
+        {
+            return Err(format_err!(
+                "Conflicting grades {:?} and {:?} graded at the same time",
+                last.grade.as_ref().unwrap(),
+                other.grade.as_ref().unwrap(),
+            ));
+        }
+    }
+    Ok(last)
+}
+
+#[cfg(test)]
+fn submission(assignment_id: i32, grade: Option<&str>, graded_at: &str) -> Submission {
+    serde_json::from_value(serde_json::json!({
+        "assignment_id": assignment_id,
+        "grade": grade,
+        "graded_at": graded_at,
+        "grader_id": 17,
+        "user": null,
+    }))
+    .unwrap()
+}
+
+#[test]
+fn test_combine_rules() {
+    let a = submission(1, Some("C"), "2019-05-24T12:00:00+02:00");
+    let b = submission(2, Some("A"), "2019-05-20T12:00:00+02:00");
+    let c = submission(3, None, "2019-05-28T12:00:00+02:00");
+    let all = [&a, &b, &c];
+    let pick = |rule: &str| {
+        combine(&rule.parse().unwrap(), &all)
+            .unwrap()
+            .and_then(|s| s.assignment_id)
+    };
+    assert_eq!(pick("max"), Some(2));
+    assert_eq!(pick("latest"), Some(1));
+    assert_eq!(pick("assignment:2"), Some(2));
+    assert_eq!(
+        pick("assignment:7, 3"),
+        Some(3),
+        "Authoritative but ungraded"
+    );
+    assert_eq!(pick("assignment:7"), Some(1), "Falls back to latest");
+}
+
+#[test]
+fn test_combine_ungraded() {
+    let a = submission(1, None, "2019-05-24T12:00:00+02:00");
+    let b = submission(2, None, "2019-05-20T12:00:00+02:00");
+    let picked = combine(&CombineRule::Max, &[&a, &b]).unwrap().unwrap();
+    assert_eq!(picked.assignment_id, Some(1));
+    assert!(combine(&CombineRule::Max, &[]).unwrap().is_none());
+}
+
+#[test]
+fn test_combine_conflicting_grades() {
+    let a = submission(1, Some("P"), "2019-05-24T12:00:00+02:00");
+    let b = submission(2, Some("F"), "2019-05-24T12:00:00+02:00");
+    assert_eq!(
+        combine(&CombineRule::Latest, &[&a, &b])
+            .err()
+            .unwrap()
+            .to_string(),
+        "Conflicting grades \"F\" and \"P\" graded at the same time",
+    );
+    let picked = combine(&CombineRule::Max, &[&a, &b]).unwrap().unwrap();
+    assert_eq!(picked.grade.as_ref().unwrap(), "P");
+}
+
+#[test]
+fn test_bad_combine_rule() {
+    assert!("best".parse::<CombineRule>().is_err());
+    assert!("assignment:x".parse::<CombineRule>().is_err());
+}
@@ -0,0 +1,295 @@
+//! The order in which changes are written to Ladok.
+//!
+//! Ladok has no transactions spanning several requests, so if a
+//! request fails, the ones before it stay written.  The order is a
+//! trade-off between the number of requests and how partial a
+//! failed export can be:
+//!
+//! * `creates-first` (default): one request creating all new results,
+//!   then one updating all existing results.  Fewest requests, but a
+//!   failed update leaves the creates written.
+//! * `updates-first`: the same, but updates first, so corrections of
+//!   existing results are not held back by a failing create.
+//! * `per-student`: one request per student, in the order of the
+//!   export.  Many more requests, but a failure only affects the
+//!   student it concerns, and every other student is either fully
+//!   written or not at all.
+use crate::ladok::types::{
+    LarosateID, MomentUid, SkapaFlera, SkapaResultat, UppdateraFlera, UppdateraResultat,
+};
+use failure::{format_err, Error};
+use serde_json::{json, Value};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CommitOrder {
+    #[default]
+    CreatesFirst,
+    UpdatesFirst,
+    PerStudent,
+}
+
+impl FromStr for CommitOrder {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "creates-first" => Ok(CommitOrder::CreatesFirst),
+            "updates-first" => Ok(CommitOrder::UpdatesFirst),
+            "per-student" => Ok(CommitOrder::PerStudent),
+            s => Err(format_err!(
+                "Unknown commit order {:?}, expected creates-first, updates-first or per-student",
+                s
+            )),
+        }
+    }
+}
+
+/// A change for one student, in the order it was found.
+#[derive(Debug)]
+pub enum Change {
+    Create(SkapaResultat),
+    /// An update, and the update that would revert it.
+    Update(UppdateraResultat, UppdateraResultat),
+}
+
+impl Change {
+    /// The uids of the studieresultat and result the change is for.
+    pub fn uids(&self) -> Vec<String> {
+        match self {
+            Change::Create(data) => data
+                .StudieresultatUID
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+            Change::Update(data, _) => data
+                .Uid
+                .iter()
+                .map(ToString::to_string)
+                .chain(data.ResultatUID.iter().map(ToString::to_string))
+                .collect(),
+        }
+    }
+}
+
+/// One request to Ladok.
+#[derive(Debug)]
+pub enum Request {
+    Create(Vec<SkapaResultat>),
+    /// Updates, and the updates that would revert them.
+    Update(Vec<UppdateraResultat>, Vec<UppdateraResultat>),
+}
+
+/// Plan the requests needed to write `changes` in the given order.
+pub fn plan(order: CommitOrder, changes: Vec<Change>) -> Vec<Request> {
+    if order == CommitOrder::PerStudent {
+        return changes
+            .into_iter()
+            .map(|change| match change {
+                Change::Create(data) => Request::Create(vec![data]),
+                Change::Update(data, revert) => Request::Update(vec![data], vec![revert]),
+            })
+            .collect();
+    }
+    let mut creates = vec![];
+    let mut updates = vec![];
+    let mut reverts = vec![];
+    for change in changes {
+        match change {
+            Change::Create(data) => creates.push(data),
+            Change::Update(data, revert) => {
+                updates.push(data);
+                reverts.push(revert);
+            }
+        }
+    }
+    let create = Some(creates).filter(|c| !c.is_empty()).map(Request::Create);
+    let update = Some(updates)
+        .filter(|u| !u.is_empty())
+        .map(|u| Request::Update(u, reverts));
+    if order == CommitOrder::UpdatesFirst {
+        update.into_iter().chain(create).collect()
+    } else {
+        create.into_iter().chain(update).collect()
+    }
+}
+
+impl Request {
+    /// The uids of the studieresultat and results the request is for.
+    pub fn uids(&self) -> Vec<String> {
+        match self {
+            Request::Create(data) => data
+                .iter()
+                .flat_map(|d| d.StudieresultatUID.iter().map(ToString::to_string))
+                .collect(),
+            Request::Update(data, _) => data
+                .iter()
+                .flat_map(|d| {
+                    d.Uid
+                        .iter()
+                        .map(ToString::to_string)
+                        .chain(d.ResultatUID.iter().map(ToString::to_string))
+                })
+                .collect(),
+        }
+    }
+    /// The request as it would be sent to Ladok by `larosate`.
+    pub fn payload(&self, larosate: LarosateID) -> Value {
+        match self {
+            Request::Create(data) => json!({
+                "method": "POST",
+                "path": "/resultat/studieresultat/skapa",
+                "body": SkapaFlera {
+                    LarosateID: larosate,
+                    Resultat: data.clone(),
+                },
+            }),
+            Request::Update(data, _) => json!({
+                "method": "PUT",
+                "path": "/resultat/studieresultat/uppdatera",
+                "body": UppdateraFlera {
+                    LarosateID: larosate,
+                    Resultat: data.clone(),
+                },
+            }),
+        }
+    }
+}
+
+/// Write the requests for a moment to a json file in `dir` instead of
+/// sending them, for offline review.
+pub fn dump(
+    dir: &Path,
+    correlation_id: &str,
+    moment: &MomentUid,
+    window: Option<u32>,
+    larosate: LarosateID,
+    requests: &[Request],
+) -> Result<PathBuf, Error> {
+    let path = dir.join(format!(
+        "{}.json",
+        crate::audit::file_stem(correlation_id, moment, window)
+    ));
+    let file = File::create(&path).map_err(|e| format_err!("{}: {}", path.display(), e))?;
+    serde_json::to_writer_pretty(
+        file,
+        &requests
+            .iter()
+            .map(|r| r.payload(larosate))
+            .collect::<Vec<_>>(),
+    )?;
+    Ok(path)
+}
+
+/// Add the outcome of a request to a total, keeping the first error.
+pub fn tally(total: &mut Result<usize, String>, outcome: Result<usize, Error>) {
+    match (&mut *total, outcome) {
+        (Ok(total), Ok(n)) => *total += n,
+        (Ok(_), Err(e)) => *total = Err(e.to_string()),
+        (Err(_), _) => (),
+    }
+}
+
+#[cfg(test)]
+fn test_changes() -> Vec<Change> {
+    let create = |uid: &str| -> SkapaResultat {
+        serde_json::from_value(serde_json::json!({
+            "Uid": null,
+            "Betygsgrad": 131661,
+            "BetygsskalaID": 131657,
+            "Examinationsdatum": "2019-05-24",
+            "StudieresultatUID": uid,
+            "UtbildningsinstansUID": "m-1",
+        }))
+        .unwrap()
+    };
+    let update = |uid: &str| -> UppdateraResultat {
+        serde_json::from_value(serde_json::json!({
+            "Uid": uid,
+            "Betygsgrad": 131661,
+            "BetygsskalaID": 131657,
+            "Examinationsdatum": "2019-05-24",
+            "ResultatUID": "r-1",
+            "SenasteResultatandring": null,
+        }))
+        .unwrap()
+    };
+    vec![
+        Change::Create(create("sr-1")),
+        Change::Update(update("sr-2"), update("sr-2")),
+        Change::Create(create("sr-3")),
+    ]
+}
+
+/// Describe requests as e.g. "C2", a create of two results.
+#[cfg(test)]
+fn describe(requests: &[Request]) -> Vec<String> {
+    requests
+        .iter()
+        .map(|r| match r {
+            Request::Create(data) => format!("C{}", data.len()),
+            Request::Update(data, _) => format!("U{}", data.len()),
+        })
+        .collect()
+}
+
+#[test]
+fn test_commit_orders() {
+    let order = |order: &str| describe(&plan(order.parse().unwrap(), test_changes()));
+    assert_eq!(order("creates-first"), ["C2", "U1"]);
+    assert_eq!(order("updates-first"), ["U1", "C2"]);
+    assert_eq!(order("per-student"), ["C1", "U1", "C1"]);
+    assert!("interleaved".parse::<CommitOrder>().is_err());
+}
+
+#[test]
+fn test_commit_nothing() {
+    assert!(plan(CommitOrder::CreatesFirst, vec![]).is_empty());
+}
+
+#[test]
+fn test_tally_keeps_first_error() {
+    let mut total = Ok(0);
+    tally(&mut total, Ok(2));
+    tally(&mut total, Ok(1));
+    assert_eq!(total, Ok(3));
+    tally(&mut total, Err(format_err!("first")));
+    tally(&mut total, Err(format_err!("second")));
+    tally(&mut total, Ok(1));
+    assert_eq!(total, Err("first".to_string()));
+}
+
+#[test]
+fn test_dump_matches_requests() {
+    let requests = plan(CommitOrder::CreatesFirst, test_changes());
+    let dir = std::env::temp_dir();
+    let larosate = "27".parse().unwrap();
+    let path = dump(
+        &dir,
+        "test-dump",
+        &MomentUid::new("m-1"),
+        None,
+        larosate,
+        &requests,
+    )
+    .unwrap();
+    let dumped: Value = serde_json::from_reader(File::open(&path).unwrap()).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(dumped[0]["method"], "POST");
+    assert_eq!(dumped[0]["body"]["LarosateID"], 27);
+    assert_eq!(
+        dumped[0]["body"]["Resultat"],
+        serde_json::to_value(match &requests[0] {
+            Request::Create(data) => data,
+            _ => panic!("Expected a create first"),
+        })
+        .unwrap(),
+    );
+    assert_eq!(dumped[1]["path"], "/resultat/studieresultat/uppdatera");
+    assert_eq!(
+        dumped[1]["body"]["Resultat"][0]["Uid"], "sr-2",
+        "The update, not the revert",
+    );
+    assert_eq!(dumped.as_array().unwrap().len(), 2);
+}
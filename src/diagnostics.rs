@@ -0,0 +1,119 @@
+//! Diagnostics of how a course room maps to Ladok.
+//!
+//! Exports fail or report nothing when integration ids are missing or
+//! wrong, and do_report only tells about the first problem it finds.
+//! This collects all of it in one place, to fix the setup at once.
+use crate::canvas::{Assignment, CourseRoom, CourseSection};
+use crate::ladok::types::MomentUid;
+use crate::mapping::MomentMapping;
+use serde::Serialize;
+
+#[derive(Debug, Serialize)]
+pub struct Diagnostics {
+    pub sis_course_id: String,
+    /// The integration_id of the course room itself.
+    pub integration_id: Option<String>,
+    pub sections: Vec<SectionDiagnostics>,
+    pub assignments: Vec<AssignmentDiagnostics>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SectionDiagnostics {
+    pub name: Option<String>,
+    /// The Ladok round of the section.
+    pub integration_id: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AssignmentDiagnostics {
+    pub id: i32,
+    pub name: Option<String>,
+    pub integration_id: Option<String>,
+    /// The moment the assignment maps to, by integration_id or
+    /// otherwise, if any.
+    pub moment: Option<String>,
+    /// True if Ladok knows the moment, None if there is no moment or
+    /// Ladok did not let us check it.
+    pub valid_moment: Option<bool>,
+}
+
+impl Diagnostics {
+    /// True if no section has a Ladok round, so nothing can be reported.
+    pub fn lacks_rounds(&self) -> bool {
+        self.sections.iter().all(|s| s.integration_id.is_none())
+    }
+    /// The assignments that do not map to any moment.
+    pub fn unmapped(&self) -> impl Iterator<Item = &AssignmentDiagnostics> {
+        self.assignments.iter().filter(|a| a.moment.is_none())
+    }
+}
+
+/// Diagnose a course room, with `valid_moment` to check moments in Ladok.
+pub fn diagnose(
+    mapping: &MomentMapping,
+    sis_course_id: &str,
+    course: &CourseRoom,
+    sections: &[CourseSection],
+    assignments: &[Assignment],
+    mut valid_moment: impl FnMut(&MomentUid) -> Option<bool>,
+) -> Diagnostics {
+    Diagnostics {
+        sis_course_id: sis_course_id.into(),
+        integration_id: course.integration_id.clone(),
+        sections: sections
+            .iter()
+            .map(|section| SectionDiagnostics {
+                name: section.name.clone(),
+                integration_id: section.integration_id.clone(),
+            })
+            .collect(),
+        assignments: assignments
+            .iter()
+            .map(|assignment| {
+                let moment = mapping.moment_for(sis_course_id, assignment);
+                AssignmentDiagnostics {
+                    id: assignment.id,
+                    name: assignment.name.clone(),
+                    integration_id: assignment.integration_id.clone(),
+                    valid_moment: moment.as_ref().and_then(&mut valid_moment),
+                    moment: moment.map(|m| m.to_string()),
+                }
+            })
+            .collect(),
+    }
+}
+
+#[test]
+fn test_partially_configured_course() {
+    let course: CourseRoom = serde_json::from_str(r#"{"integration_id": null}"#).unwrap();
+    let sections: Vec<CourseSection> = serde_json::from_str(
+        r#"[{"id": 1, "name": "SF1625 HT19", "integration_id": "r-1"},
+            {"id": 2, "name": "Extra", "integration_id": null}]"#,
+    )
+    .unwrap();
+    let assignments: Vec<Assignment> = serde_json::from_str(
+        r#"[{"id": 1, "name": "Tenta", "integration_id": "m-1"},
+            {"id": 2, "name": "Lab", "integration_id": "m-typo"},
+            {"id": 3, "name": "Quiz", "integration_id": null}]"#,
+    )
+    .unwrap();
+    let diagnostics = diagnose(
+        &MomentMapping::default(),
+        "SF1625HT191",
+        &course,
+        &sections,
+        &assignments,
+        |moment| Some(moment.to_string() == "m-1"),
+    );
+    assert_eq!(diagnostics.integration_id, None);
+    assert!(!diagnostics.lacks_rounds());
+    assert_eq!(diagnostics.sections[1].integration_id, None);
+    let valid = diagnostics
+        .assignments
+        .iter()
+        .map(|a| (a.id, a.valid_moment))
+        .collect::<Vec<_>>();
+    assert_eq!(valid, vec![(1, Some(true)), (2, Some(false)), (3, None)]);
+    let unmapped = diagnostics.unmapped().map(|a| a.id).collect::<Vec<_>>();
+    assert_eq!(unmapped, vec![3]);
+}
@@ -0,0 +1,142 @@
+//! A bound on concurrent requests to Canvas and Ladok, shared fairly
+//! between course rooms.
+//!
+//! Requests beyond the bound wait in a queue per course room, and a
+//! freed slot goes to the next course room in turn rather than to the
+//! oldest request, so a course room with thousands of students does
+//! not hold up one with thirty.
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Condvar, Mutex};
+
+pub struct FairQueue {
+    limit: usize,
+    state: Mutex<State>,
+    granted: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    running: usize,
+    /// Requests waiting for a slot, per course room.
+    waiting: BTreeMap<String, usize>,
+    /// Slots given to a course room, not yet taken by its requests.
+    granted: BTreeMap<String, usize>,
+    /// The course rooms with waiting requests, in turn order.
+    turns: VecDeque<String>,
+}
+
+impl State {
+    /// Give free slots to waiting requests, one course room at a time.
+    fn dispatch(&mut self, limit: usize) {
+        while self.running < limit {
+            let key = match self.turns.pop_front() {
+                Some(key) => key,
+                None => return,
+            };
+            let waiting = self.waiting.get_mut(&key).expect("waiting in turn");
+            *waiting -= 1;
+            if *waiting == 0 {
+                self.waiting.remove(&key);
+            } else {
+                self.turns.push_back(key.clone());
+            }
+            *self.granted.entry(key).or_default() += 1;
+            self.running += 1;
+        }
+    }
+}
+
+/// A slot to run a request in, freed when dropped.
+pub struct Permit<'a> {
+    queue: &'a FairQueue,
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        let mut state = self.queue.state.lock().unwrap();
+        state.running -= 1;
+        state.dispatch(self.queue.limit);
+        self.queue.granted.notify_all();
+    }
+}
+
+impl FairQueue {
+    /// A queue running at most `limit` requests at once.
+    pub fn new(limit: usize) -> Self {
+        FairQueue {
+            limit: limit.max(1),
+            state: Mutex::new(State::default()),
+            granted: Condvar::new(),
+        }
+    }
+
+    /// Wait for a slot to run a request for the course room `key`.
+    pub fn acquire(&self, key: &str) -> Permit<'_> {
+        let mut state = self.state.lock().unwrap();
+        if state.running < self.limit && state.turns.is_empty() {
+            state.running += 1;
+            return Permit { queue: self };
+        }
+        let waiting = state.waiting.entry(key.to_string()).or_default();
+        *waiting += 1;
+        if *waiting == 1 {
+            state.turns.push_back(key.to_string());
+        }
+        state.dispatch(self.limit);
+        loop {
+            if let Some(granted) = state.granted.get_mut(key) {
+                *granted -= 1;
+                if *granted == 0 {
+                    state.granted.remove(key);
+                }
+                return Permit { queue: self };
+            }
+            state = self.granted.wait(state).unwrap();
+        }
+    }
+
+    /// The number of requests waiting for a slot.
+    pub fn depth(&self) -> usize {
+        self.state.lock().unwrap().waiting.values().sum()
+    }
+}
+
+#[test]
+fn test_small_course_not_starved() {
+    use std::sync::mpsc::channel;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    let queue = Arc::new(FairQueue::new(1));
+    let (done, finished) = channel();
+    let blocker = queue.acquire("big");
+    let mut threads = vec![];
+    for i in 0..10 {
+        let (queue, done) = (queue.clone(), done.clone());
+        threads.push(thread::spawn(move || {
+            let _permit = queue.acquire("big");
+            done.send(format!("big-{}", i)).unwrap();
+        }));
+    }
+    while queue.depth() < 10 {
+        thread::sleep(Duration::from_millis(1));
+    }
+    let (queue2, done2) = (queue.clone(), done.clone());
+    threads.push(thread::spawn(move || {
+        let _permit = queue2.acquire("small");
+        done2.send("small".to_string()).unwrap();
+    }));
+    while queue.depth() < 11 {
+        thread::sleep(Duration::from_millis(1));
+    }
+    drop(blocker);
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    let order = finished.try_iter().collect::<Vec<_>>();
+    assert_eq!(order.len(), 11);
+    let small = order.iter().position(|job| job == "small").unwrap();
+    assert!(small <= 1, "small job ran as number {}: {:?}", small, order);
+    assert_eq!(queue.depth(), 0);
+}
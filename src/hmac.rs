@@ -0,0 +1,37 @@
+//! Keyed hashes, for values that must neither be reversed nor forged
+//! without a secret of the service.
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+
+/// The HMAC-SHA256 of `data` with `key`, as lowercase hex.
+pub fn hmac_hex(key: &[u8], data: &[u8]) -> String {
+    let key = PKey::hmac(key).expect("any bytes are an hmac key");
+    let mut signer = Signer::new(MessageDigest::sha256(), &key).expect("sha256 is available");
+    signer.update(data).expect("hmac update");
+    let mac = signer.sign_to_vec().expect("hmac sign");
+    mac.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// True if `a` and `b` are equal, in a time that only depends on their
+/// lengths, for comparing secrets.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && openssl::memcmp::eq(a, b)
+}
+
+#[test]
+fn test_hmac_rfc4231() {
+    // Test case 2 of RFC 4231.
+    assert_eq!(
+        hmac_hex(b"Jefe", b"what do ya want for nothing?"),
+        "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+    );
+}
+
+#[test]
+fn test_constant_time_eq() {
+    assert!(constant_time_eq(b"s3cret", b"s3cret"));
+    assert!(!constant_time_eq(b"s3cret", b"s3creT"));
+    assert!(!constant_time_eq(b"s3cret", b"s3cret2"));
+    assert!(constant_time_eq(b"", b""));
+}
@@ -0,0 +1,101 @@
+//! Which hosts the service may talk to.
+//!
+//! The service holds a Ladok client certificate and Canvas secrets, so
+//! a misconfigured or tampered CANVAS_HOST or LADOK_API_BASEURL should
+//! stop it at startup rather than send them somewhere unexpected.
+use failure::{format_err, Error};
+use reqwest::Url;
+use std::str::FromStr;
+
+/// Comma-separated hostnames.  An entry starting with `.` allows any
+/// subdomain of it, e.g. `.ladok.se` allows `api.ladok.se`.  Without
+/// entries, any host is allowed.
+#[derive(Debug, Default)]
+pub struct HostAllowlist(Vec<String>);
+
+impl FromStr for HostAllowlist {
+    type Err = Error;
+    fn from_str(value: &str) -> Result<Self, Error> {
+        Ok(HostAllowlist(
+            value
+                .split(',')
+                .map(|entry| entry.trim().to_lowercase())
+                .filter(|entry| !entry.is_empty())
+                .collect(),
+        ))
+    }
+}
+
+impl HostAllowlist {
+    fn allows(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.').to_lowercase();
+        self.0.is_empty()
+            || self.0.iter().any(|entry| {
+                if entry.starts_with('.') {
+                    host.ends_with(entry.as_str())
+                } else {
+                    host == *entry
+                }
+            })
+    }
+    /// Check the host of `url`, configured as `what`.
+    pub fn check_url(&self, what: &str, url: &str) -> Result<(), Error> {
+        let url = Url::parse(url).map_err(|e| format_err!("{}: {}", what, e))?;
+        let host = url
+            .host_str()
+            .ok_or_else(|| format_err!("{}: {:?} has no host", what, url.as_str()))?;
+        if self.allows(host) {
+            Ok(())
+        } else {
+            Err(format_err!("{}: host {:?} is not allowed", what, host))
+        }
+    }
+    /// Check a bare hostname, as in CANVAS_HOST.
+    pub fn check_host(&self, what: &str, host: &str) -> Result<(), Error> {
+        self.check_url(what, &format!("https://{}/", host))
+    }
+}
+
+#[test]
+fn test_allowed_hosts() {
+    let allowlist: HostAllowlist = "canvas.kth.se, .ladok.se".parse().unwrap();
+    assert!(allowlist.check_host("CANVAS_HOST", "canvas.kth.se").is_ok());
+    assert!(allowlist.check_host("CANVAS_HOST", "Canvas.KTH.se").is_ok());
+    assert!(allowlist
+        .check_url("LADOK_API_BASEURL", "https://api.ladok.se/")
+        .is_ok());
+    assert!(allowlist
+        .check_url("LADOK_API_BASEURL", "https://api.test.ladok.se:443/x")
+        .is_ok());
+}
+
+#[test]
+fn test_disallowed_hosts() {
+    let allowlist: HostAllowlist = "canvas.kth.se, .ladok.se".parse().unwrap();
+    let rejected = |what, url| allowlist.check_url(what, url).is_err();
+    assert!(rejected("LADOK_API_BASEURL", "https://evil.example/"));
+    assert!(rejected(
+        "LADOK_API_BASEURL",
+        "https://ladok.se.evil.example/"
+    ));
+    assert!(rejected("LADOK_API_BASEURL", "https://evilladok.se/"));
+    assert!(rejected(
+        "LADOK_API_BASEURL",
+        "https://user@evil.example/.ladok.se"
+    ));
+    assert!(rejected("LADOK_API_BASEURL", "not a url"));
+    assert!(allowlist
+        .check_host("CANVAS_HOST", "canvas.kth.se.evil.example")
+        .is_err());
+    assert!(allowlist
+        .check_host("CANVAS_HOST", "evil.example/canvas.kth.se")
+        .is_err());
+}
+
+#[test]
+fn test_empty_allowlist_allows_any() {
+    let allowlist = HostAllowlist::default();
+    assert!(allowlist
+        .check_url("LADOK_API_BASEURL", "https://localhost:8443/")
+        .is_ok());
+}
@@ -0,0 +1,204 @@
+use chrono::{DateTime, Utc};
+use log::warn;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// A circuit breaker, to fail fast during a sustained Ladok outage.
+///
+/// After `threshold` consecutive failures within `window`, the
+/// circuit is opened and all requests fail immediately for
+/// `cooldown`.  After that, the circuit is half-open and a single
+/// request is let through to test if Ladok has recovered.
+///
+/// Requests can also be held for a known time, e.g. while Ladok is
+/// down for maintenance, regardless of the state of the circuit.
+pub struct CircuitBreaker {
+    threshold: u32,
+    window: Duration,
+    cooldown: Duration,
+    state: Mutex<State>,
+    held_until: Mutex<Option<DateTime<Utc>>>,
+}
+
+#[derive(Default)]
+struct State {
+    failures: u32,
+    first_failure: Option<Instant>,
+    open_until: Option<Instant>,
+    probing: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BreakerState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl fmt::Display for BreakerState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            BreakerState::Closed => "closed",
+            BreakerState::Open => "open",
+            BreakerState::HalfOpen => "half-open",
+        })
+    }
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, window: Duration, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            threshold: threshold.max(1),
+            window,
+            cooldown,
+            state: Mutex::new(State::default()),
+            held_until: Mutex::new(None),
+        }
+    }
+
+    /// Hold all requests until `until`.  A later hold extends it.
+    pub fn hold_until(&self, until: DateTime<Utc>) {
+        let mut held_until = self.held_until.lock().unwrap();
+        if held_until.is_none_or(|held| held < until) {
+            *held_until = Some(until);
+        }
+    }
+
+    /// The time requests are held until, if that is after `now`.
+    pub fn held_until(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let held_until = *self.held_until.lock().unwrap();
+        held_until.filter(|until| now < *until)
+    }
+
+    /// Check if a request may be sent now.
+    pub fn allow(&self) -> bool {
+        self.allow_at(Instant::now())
+    }
+    /// Record the outcome of a request that was allowed.
+    pub fn record(&self, success: bool) {
+        self.record_at(Instant::now(), success)
+    }
+    pub fn state(&self) -> BreakerState {
+        self.state_at(Instant::now())
+    }
+
+    fn allow_at(&self, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match state.open_until {
+            Some(until) if now < until => false,
+            Some(_) if state.probing => false,
+            Some(_) => {
+                state.probing = true;
+                true
+            }
+            None => true,
+        }
+    }
+
+    fn record_at(&self, now: Instant, success: bool) {
+        let mut state = self.state.lock().unwrap();
+        if success {
+            *state = State::default();
+        } else if state.open_until.is_some() {
+            // The probe failed, stay open for another cooldown.
+            state.open_until = Some(now + self.cooldown);
+            state.probing = false;
+        } else {
+            match state.first_failure {
+                Some(first) if now.duration_since(first) <= self.window => (),
+                _ => {
+                    state.first_failure = Some(now);
+                    state.failures = 0;
+                }
+            }
+            state.failures += 1;
+            if state.failures >= self.threshold {
+                warn!(
+                    "Ladok failed {} times, opening circuit for {:?}",
+                    state.failures, self.cooldown,
+                );
+                state.open_until = Some(now + self.cooldown);
+            }
+        }
+    }
+
+    fn state_at(&self, now: Instant) -> BreakerState {
+        match self.state.lock().unwrap().open_until {
+            Some(until) if now < until => BreakerState::Open,
+            Some(_) => BreakerState::HalfOpen,
+            None => BreakerState::Closed,
+        }
+    }
+}
+
+#[test]
+fn test_breaker_opens_and_closes() {
+    let breaker = CircuitBreaker::new(3, Duration::from_secs(60), Duration::from_secs(30));
+    let t0 = Instant::now();
+    for i in 0..3 {
+        assert!(breaker.allow_at(t0 + Duration::from_secs(i)));
+        breaker.record_at(t0 + Duration::from_secs(i), false);
+    }
+    let t1 = t0 + Duration::from_secs(10);
+    assert_eq!(breaker.state_at(t1), BreakerState::Open);
+    assert!(!breaker.allow_at(t1));
+
+    // After the cooldown, a single probe is let through.
+    let t2 = t0 + Duration::from_secs(40);
+    assert_eq!(breaker.state_at(t2), BreakerState::HalfOpen);
+    assert!(breaker.allow_at(t2));
+    assert!(!breaker.allow_at(t2));
+    breaker.record_at(t2, true);
+    assert_eq!(breaker.state_at(t2), BreakerState::Closed);
+    assert!(breaker.allow_at(t2));
+}
+
+#[test]
+fn test_breaker_failed_probe_reopens() {
+    let breaker = CircuitBreaker::new(1, Duration::from_secs(60), Duration::from_secs(30));
+    let t0 = Instant::now();
+    breaker.record_at(t0, false);
+    let t1 = t0 + Duration::from_secs(31);
+    assert!(breaker.allow_at(t1));
+    breaker.record_at(t1, false);
+    assert_eq!(
+        breaker.state_at(t1 + Duration::from_secs(29)),
+        BreakerState::Open
+    );
+}
+
+#[test]
+fn test_breaker_failures_outside_window() {
+    let breaker = CircuitBreaker::new(2, Duration::from_secs(60), Duration::from_secs(30));
+    let t0 = Instant::now();
+    breaker.record_at(t0, false);
+    breaker.record_at(t0 + Duration::from_secs(61), false);
+    assert_eq!(
+        breaker.state_at(t0 + Duration::from_secs(62)),
+        BreakerState::Closed
+    );
+    breaker.record_at(t0 + Duration::from_secs(62), false);
+    assert_eq!(
+        breaker.state_at(t0 + Duration::from_secs(63)),
+        BreakerState::Open
+    );
+}
+
+#[test]
+fn test_breaker_holds_requests() {
+    let breaker = CircuitBreaker::new(2, Duration::from_secs(60), Duration::from_secs(30));
+    let t0 = Utc::now();
+    assert_eq!(breaker.held_until(t0), None);
+    let until = t0 + chrono::Duration::minutes(10);
+    breaker.hold_until(until);
+    breaker.hold_until(t0 + chrono::Duration::minutes(5));
+    assert_eq!(breaker.held_until(t0), Some(until), "Not shortened");
+    breaker.record(true);
+    assert_eq!(
+        breaker.held_until(t0),
+        Some(until),
+        "Not ended by a success"
+    );
+    assert_eq!(breaker.held_until(until), None);
+}
@@ -1,80 +1,390 @@
-use failure::{format_err, Error};
-use reqwest::{Client, Identity, RequestBuilder};
+use crate::bounded;
+use crate::fair::{FairQueue, Permit};
+use crate::logging::{pii_is_plain, pii_url};
+use crate::timing::timed;
+use chrono::{DateTime, Local, Utc};
+use failure::{format_err, Error, Fail};
+use log::{info, warn};
+use reqwest::header::HeaderMap;
+use reqwest::{Client, Identity, RequestBuilder, Response, StatusCode};
 use serde::de::DeserializeOwned;
-use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
 
+mod breaker;
+pub use breaker::{BreakerState, CircuitBreaker};
+mod scales;
+pub use scales::ScaleCache;
+mod targets;
+pub use targets::{Rules, Target, Targets};
+mod throttle;
+pub use throttle::Throttle;
+
+#[allow(dead_code)] // Mirrors the Ladok schema, not every field is used.
 pub mod types;
 use types::*;
 
+/// Ladok refused our client certificate, so this is a problem with
+/// the configuration of the service rather than with the request.
+#[derive(Debug)]
+pub struct NotAuthorized {
+    pub status: u16,
+    pub url: String,
+}
+
+impl fmt::Display for NotAuthorized {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Ladok refused the client certificate: {} on {}",
+            self.status,
+            pii_url(&self.url),
+        )
+    }
+}
+
+impl Fail for NotAuthorized {}
+
+/// Ladok accepted our client certificate but denied the request, e.g.
+/// since the certificate lacks authority to report on a moment of
+/// another department.
+#[derive(Debug)]
+pub struct Forbidden {
+    pub url: String,
+}
+
+impl fmt::Display for Forbidden {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Ladok denied the request on {}", pii_url(&self.url))
+    }
+}
+
+impl Fail for Forbidden {}
+
+/// Ladok is down for scheduled maintenance.
+#[derive(Debug)]
+pub struct Maintenance {
+    /// When Ladok is expected back, as given by Ladok, if at all.
+    pub until: Option<DateTime<Utc>>,
+}
+
+impl fmt::Display for Maintenance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.until {
+            Some(until) => write!(
+                f,
+                "Ladok is currently under maintenance, please try again after {}",
+                until.with_timezone(&Local).format("%Y-%m-%d %H:%M"),
+            ),
+            None => f.write_str("Ladok is currently under maintenance, please try again later"),
+        }
+    }
+}
+
+impl Fail for Maintenance {}
+
+/// True if Ladok is down for maintenance, so retrying is useless.
+pub fn is_maintenance(e: &Error) -> bool {
+    e.downcast_ref::<Maintenance>().is_some()
+}
+
+/// How long to hold requests to Ladok during maintenance, when Ladok
+/// does not tell how long it lasts.
+const MAINTENANCE_HOLD_SECONDS: i64 = 60;
+
+/// A maintenance response is a 503 with a Retry-After header, or
+/// with a body telling about the maintenance.
+fn maintenance(
+    status: StatusCode,
+    retry_after: Option<&str>,
+    body: &str,
+    now: DateTime<Utc>,
+) -> Option<Maintenance> {
+    if status != StatusCode::SERVICE_UNAVAILABLE {
+        return None;
+    }
+    let body = body.to_lowercase();
+    if retry_after.is_some() || body.contains("maintenance") || body.contains("underhåll") {
+        Some(Maintenance {
+            until: retry_after.and_then(|value| parse_retry_after(value, now)),
+        })
+    } else {
+        None
+    }
+}
+
+/// Parse a Retry-After header, either a number of seconds or an http
+/// date, into the time to retry after.
+fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let value = value.trim();
+    match value.parse::<u32>() {
+        Ok(seconds) => Some(now + chrono::Duration::seconds(seconds.into())),
+        Err(_) => DateTime::parse_from_rfc2822(value)
+            .ok()
+            .map(|date| date.with_timezone(&Utc)),
+    }
+}
+
+/// True if Ladok accepted the client certificate but denied the
+/// request.
+pub fn is_forbidden(e: &Error) -> bool {
+    e.downcast_ref::<Forbidden>().is_some()
+}
+
+/// Build the http client for Ladok, with the client `identity`,
+/// sending `headers` on every request.
+///
+/// The TLS backend requires TLS 1.2 or later.  With
+/// `http2_prior_knowledge`, HTTP/2 is used without negotiating it.
+pub fn http_client(
+    identity: Option<Identity>,
+    max_idle_per_host: usize,
+    http2_prior_knowledge: bool,
+    headers: HeaderMap,
+) -> Result<Client, Error> {
+    let mut builder = Client::builder()
+        .max_idle_per_host(max_idle_per_host)
+        .default_headers(headers);
+    if let Some(identity) = identity {
+        builder = builder.identity(identity);
+    }
+    if http2_prior_knowledge {
+        builder = builder.h2_prior_knowledge();
+    }
+    Ok(builder.build()?)
+}
+
+/// Check a configured minimum TLS version.
+///
+/// The TLS backend always requires at least TLS 1.2, and does not
+/// let us require anything else, so only "1.2" is accepted.
+pub fn check_min_tls(version: &str) -> Result<(), Error> {
+    match version {
+        "1.2" => Ok(()),
+        "1.0" | "1.1" => Err(format_err!(
+            "TLS {} is not allowed, the minimum is 1.2",
+            version
+        )),
+        _ => Err(format_err!(
+            "Unsupported minimum TLS version {:?}, only 1.2 is supported",
+            version
+        )),
+    }
+}
+
+/// The outcome of writing a batch of results.
+#[derive(Debug, Default)]
+pub struct Written {
+    /// The results Ladok accepted, as now stored.
+    pub resultat: Vec<Resultat>,
+    /// The results Ladok did not accept.
+    pub fel: Vec<ResultatFel>,
+}
+
+impl From<ResultatLista> for Written {
+    fn from(lista: ResultatLista) -> Self {
+        Written {
+            resultat: lista.Resultat,
+            fel: lista.Fel,
+        }
+    }
+}
+
 pub struct Ladok {
     server: String,
     client: Client,
-    betygskalor_cache: BTreeMap<BetygsskalaID, Betygskala>,
+    breaker: Arc<CircuitBreaker>,
+    throttle: Arc<Throttle>,
+    scales: Arc<ScaleCache>,
+    /// The queue to wait in before each request, and the course room
+    /// to wait as.
+    queue: Option<(Arc<FairQueue>, String)>,
+    /// The largest response body to read.
+    max_body: u64,
+    /// Our lärosäte, that results are written as.
+    larosate: LarosateID,
 }
 
 impl Ladok {
-    pub fn new(server: &str, client_identity: Identity) -> Result<Ladok, Error> {
-        Ok(Ladok {
+    /// Create a Ladok client.
+    ///
+    /// The `client` is expected to be built with the client identity
+    /// for the Ladok server, and is shared between requests, as are
+    /// the `breaker`, the `throttle` for writes and the cache of
+    /// grading `scales`.  Results are written as the lärosäte
+    /// `larosate`.
+    pub fn new(
+        server: &str,
+        client: Client,
+        breaker: Arc<CircuitBreaker>,
+        throttle: Arc<Throttle>,
+        scales: Arc<ScaleCache>,
+        larosate: LarosateID,
+    ) -> Ladok {
+        Ladok {
             server: server.to_string(),
-            client: Client::builder().identity(client_identity).build()?,
-            betygskalor_cache: BTreeMap::new(),
-        })
+            client,
+            breaker,
+            throttle,
+            scales,
+            queue: None,
+            max_body: bounded::max_body_size(),
+            larosate,
+        }
+    }
+
+    /// The lärosäte results are written as.
+    pub fn larosate(&self) -> LarosateID {
+        self.larosate
+    }
+
+    /// Wait in `queue` as the course room `key` before each request.
+    pub fn queued(mut self, queue: Arc<FairQueue>, key: &str) -> Ladok {
+        self.queue = Some((queue, key.into()));
+        self
+    }
+
+    /// Read at most `bytes` of each response body.
+    #[cfg(test)]
+    pub fn with_max_body_size(mut self, bytes: u64) -> Ladok {
+        self.max_body = bytes;
+        self
+    }
+
+    /// Write results as another lärosäte.
+    #[cfg(test)]
+    pub fn with_larosate(mut self, larosate: LarosateID) -> Ladok {
+        self.larosate = larosate;
+        self
     }
 
     fn get_betygskala(&self, id: BetygsskalaID) -> Result<Betygskala, Error> {
-        do_json_or_err(self.client.get(&format!(
+        self.do_json_or_err(self.client.get(&format!(
             "{}/resultat/grunddata/betygsskala/{}",
             self.server, id
         )))
     }
 
+    /// Check that Ladok responds, by loading a grading scale.
+    pub fn ping(&mut self, betygskala: BetygsskalaID) -> Result<(), Error> {
+        let loaded = self.get_betygskala(betygskala)?;
+        self.scales.insert(loaded);
+        Ok(())
+    }
+
+    /// Load grading scales into the shared cache, so the first exports
+    /// using them need not wait for it.  Failures are only logged.
+    pub fn preload_betygskalor(&self, ids: &[BetygsskalaID]) {
+        for &id in ids {
+            match self.cached_betygskala(id) {
+                Ok(scale) => info!("Preloaded grading scale {} ({})", id, scale.Kod),
+                Err(e) => warn!("Failed to preload grading scale {}: {}", id, e),
+            }
+        }
+    }
+
+    /// Load a grading scale into the cache, unless already there.
+    pub fn load_betygskala(&self, id: BetygsskalaID) -> Result<(), Error> {
+        self.cached_betygskala(id).map(|_| ())
+    }
+
+    fn cached_betygskala(&self, id: BetygsskalaID) -> Result<Arc<Betygskala>, Error> {
+        self.scales.get_or_load(id, || self.get_betygskala(id))
+    }
+
+    /// Get the code of a grade, e.g. to show an existing result.
+    pub fn grade_code(
+        &mut self,
+        betygskala: BetygsskalaID,
+        id: BetygsgradID,
+    ) -> Result<String, Error> {
+        let betygskala = self.cached_betygskala(betygskala)?;
+        betygskala
+            .get_by_id(id)
+            .map(|grade| grade.Kod.clone())
+            .ok_or_else(|| format_err!("Grade {} not in {}", id, betygskala.Kod))
+    }
+
+    /// Get a grade by its code in a scale.
+    ///
+    /// If `allowed` is given, only those codes are accepted, even if
+    /// other grades in the scale match.
     pub fn get_grade(
         &mut self,
         betygskala: BetygsskalaID,
         grade: &str,
+        allowed: Option<&[String]>,
     ) -> Result<Betygsgrad, Error> {
-        let betygskala = if let Some(betygskala) = self.betygskalor_cache.get(&betygskala) {
-            betygskala
-        } else {
-            let loaded = self.get_betygskala(betygskala)?;
-            self.betygskalor_cache.insert(betygskala, loaded);
-            &self.betygskalor_cache[&betygskala]
-        };
-        betygskala
-            .get(&grade)
-            .cloned()
-            .ok_or_else(|| format_err!("Grade {:?} not in {}", grade, betygskala.Kod))
+        let betygskala = self.cached_betygskala(betygskala)?;
+        let found = betygskala
+            .get(grade)
+            .ok_or_else(|| format_err!("Grade {:?} not in {}", grade, betygskala.Kod))?;
+        if let Some(allowed) = allowed {
+            if !allowed.contains(&found.Kod) {
+                return Err(format_err!(
+                    "Grade {:?} is in {} but not allowed for this moment",
+                    grade,
+                    betygskala.Kod,
+                ));
+            }
+        }
+        Ok(found.clone())
+    }
+
+    /// Get a moment (or other utbildningsinstans), e.g. for its
+    /// period of validity.
+    pub fn get_utbildningsinstans(&self, uid: &MomentUid) -> Result<Utbildningsinstans, Error> {
+        self.do_json_or_err(self.client.get(&format!(
+            "{}/utbildningsinformation/utbildningsinstans/{}",
+            self.server, uid
+        )))
+    }
+
+    /// Get one page of up to `limit` students, for handling a moment a
+    /// window of students at a time.
+    pub fn sok_studieresultat_page(
+        &self,
+        tillfallen: &[Tillfalle],
+        moment: &MomentUid,
+        page: u32,
+        limit: u32,
+    ) -> Result<SokresultatStudieresultatResultat, Error> {
+        let url = format!(
+            "{}/resultat/studieresultat/rapportera/utbildningsinstans/{}/sok",
+            self.server, moment,
+        );
+        let mut data = StudieresultatForRapporteringSokVarden::new(tillfallen, moment);
+        data.Page = page;
+        data.Limit = limit;
+        self.do_json_or_err(self.client.put(&url).json(&data))
+    }
+
+    /// Find a student by personnummer.
+    pub fn get_student_by_personnummer(&self, personnummer: &str) -> Result<Student, Error> {
+        self.do_json_or_err(self.client.get(&format!(
+            "{}/studentinformation/student/personnummer/{}",
+            self.server, personnummer
+        )))
     }
 
     pub fn sok_studieresultat(
         &self,
-        kurstillf: &[String],
-        moment: &str,
+        tillfallen: &[Tillfalle],
+        moment: &MomentUid,
     ) -> Result<SokresultatStudieresultatResultat, Error> {
         let url = format!(
             "{}/resultat/studieresultat/rapportera/utbildningsinstans/{}/sok",
             self.server, moment,
         );
-        let mut data = StudieresultatForRapporteringSokVarden {
-            KurstillfallenUID: kurstillf.to_vec(),
-            Page: 1,
-            Filtrering: vec!["OBEHANDLADE".into(), "UTKAST".into()],
-            UtbildningsinstansUID: Some(moment.to_string()),
-            OrderBy: vec![
-                "EFTERNAMN_ASC".into(),
-                "FORNAMN_ASC".into(),
-                "PERSONNUMMER_ASC".into(),
-            ],
-            Limit: 100,
-        };
+        let mut data = StudieresultatForRapporteringSokVarden::new(tillfallen, moment);
         let mut resultat: SokresultatStudieresultatResultat =
-            do_json_or_err(self.client.put(&url).json(&data))?;
+            self.do_json_or_err(self.client.put(&url).json(&data))?;
 
         while resultat.Resultat.len() < resultat.TotaltAntalPoster {
             data.Page += 1;
             let r2: SokresultatStudieresultatResultat =
-                do_json_or_err(self.client.put(&url).json(&data))?;
-            resultat.Resultat.extend(r2.Resultat.into_iter());
+                self.do_json_or_err(self.client.put(&url).json(&data))?;
+            resultat.Resultat.extend(r2.Resultat);
         }
         println!(
             "Got {} of {} results, after fething {} page(s) of up to {} students.",
@@ -86,49 +396,359 @@ impl Ladok {
         Ok(resultat)
     }
 
-    pub fn skapa_studieresultat(&self, data: Vec<SkapaResultat>) -> Result<Vec<Resultat>, Error> {
+    pub fn skapa_studieresultat(&self, data: Vec<SkapaResultat>) -> Result<Written, Error> {
         let url = format!("{}/resultat/studieresultat/skapa", self.server);
-        Ok(
-            do_json_or_err::<ResultatLista>(self.client.post(&url).json(&SkapaFlera {
-                LarosateID: LarosateID::KTH,
+        self.throttle.acquire();
+        Ok(self
+            .do_json_or_err::<ResultatLista>(self.client.post(&url).json(&SkapaFlera {
+                LarosateID: self.larosate,
                 Resultat: data,
             }))?
-            .Resultat,
-        )
+            .into())
     }
 
-    pub fn uppdatera_studieresultat(
+    /// Create results, unless they already exist.
+    ///
+    /// Searches for the current results first, so that retrying a
+    /// create that failed (or seemed to fail) does not create any
+    /// result twice.
+    pub fn skapa_saknade_studieresultat(
         &self,
-        data: Vec<UppdateraResultat>,
-    ) -> Result<Vec<Resultat>, Error> {
+        tillfallen: &[Tillfalle],
+        moment: &MomentUid,
+        data: Vec<SkapaResultat>,
+    ) -> Result<Written, Error> {
+        let count = data.len();
+        let data = self
+            .sok_studieresultat(tillfallen, moment)?
+            .still_missing(data);
+        if data.len() < count {
+            info!(
+                "{} of {} results to create already exist",
+                count - data.len(),
+                count,
+            );
+        }
+        if data.is_empty() {
+            Ok(Written::default())
+        } else {
+            self.skapa_studieresultat(data)
+        }
+    }
+
+    pub fn uppdatera_studieresultat(&self, data: Vec<UppdateraResultat>) -> Result<Written, Error> {
         let url = format!("{}/resultat/studieresultat/uppdatera", self.server);
-        Ok(
-            do_json_or_err::<ResultatLista>(self.client.put(&url).json(&UppdateraFlera {
-                LarosateID: LarosateID::KTH,
+        self.throttle.acquire();
+        Ok(self
+            .do_json_or_err::<ResultatLista>(self.client.put(&url).json(&UppdateraFlera {
+                LarosateID: self.larosate,
                 Resultat: data,
             }))?
-            .Resultat,
-        )
+            .into())
+    }
+
+    /// Mark results as ready (klarmarkera), the step before attestering.
+    pub fn klarmarkera_studieresultat(&self, data: &[Resultat]) -> Result<Vec<Resultat>, Error> {
+        let url = format!("{}/resultat/studieresultat/klarmarkera", self.server);
+        self.throttle.acquire();
+        Ok(self
+            .do_json_or_err::<ResultatLista>(self.client.put(&url).json(&KlarmarkeraFlera {
+                LarosateID: self.larosate,
+                Resultat: data.iter().filter_map(ResultatReferens::of).collect(),
+            }))?
+            .Resultat)
+    }
+
+    /// Klarmarkera and attest results, skipping the steps already done.
+    ///
+    /// Returns the results that were attested.
+    pub fn klarmarkera_och_attestera(&self, data: Vec<Resultat>) -> Result<Vec<Resultat>, Error> {
+        let (utkast, mut ready): (Vec<_>, Vec<_>) = data
+            .into_iter()
+            .filter(|r| r.ProcessStatus != Some(ProcessStatus::Attesterad))
+            .partition(|r| r.ProcessStatus != Some(ProcessStatus::Klarmarkerad));
+        if !utkast.is_empty() {
+            ready.extend(self.klarmarkera_studieresultat(&utkast)?);
+        }
+        if ready.is_empty() {
+            Ok(vec![])
+        } else {
+            self.attestera_studieresultat(&ready)
+        }
+    }
+
+    /// Attest klarmarkerade results.
+    ///
+    /// Note that attestering is irreversible, and that the client
+    /// certificate must belong to someone authorized to attest.
+    pub fn attestera_studieresultat(&self, data: &[Resultat]) -> Result<Vec<Resultat>, Error> {
+        let url = format!("{}/resultat/studieresultat/attestera", self.server);
+        self.throttle.acquire();
+        Ok(self
+            .do_json_or_err::<ResultatLista>(self.client.put(&url).json(&AttesteraFlera {
+                LarosateID: self.larosate,
+                Resultat: data.iter().filter_map(ResultatReferens::of).collect(),
+            }))?
+            .Resultat)
+    }
+
+    /// Delete a result that is not yet attested.
+    pub fn ta_bort_resultat(&self, uid: &ResultatUid) -> Result<(), Error> {
+        let url = format!("{}/resultat/studieresultat/resultat/{}", self.server, uid);
+        self.throttle.acquire();
+        let _permit = self.turn();
+        self.do_or_err(self.client.delete(&url))?;
+        Ok(())
+    }
+
+    fn do_json_or_err<T>(&self, request: RequestBuilder) -> Result<T, Error>
+    where
+        T: DeserializeOwned,
+    {
+        let _permit = self.turn();
+        bounded::json(&mut self.do_or_err(request)?, self.max_body)
+    }
+
+    /// Wait for a turn in the queue, if any.  The turn lasts until the
+    /// permit is dropped, which should be after the body of the
+    /// response is read.
+    fn turn(&self) -> Option<Permit<'_>> {
+        self.queue.as_ref().map(|(queue, key)| queue.acquire(key))
+    }
+
+    /// Send a request, in a turn taken by the caller.
+    fn do_or_err(&self, request: RequestBuilder) -> Result<Response, Error> {
+        if let Some(until) = self.breaker.held_until(Utc::now()) {
+            return Err(Maintenance { until: Some(until) }.into());
+        }
+        if !self.breaker.allow() {
+            return Err(format_err!("Ladok temporarily unavailable"));
+        }
+        let request = request.header("accept", "application/json").build()?;
+        let what = format!("{} {}", request.method(), pii_url(request.url().as_str()));
+        let response = timed(&what, || self.client.execute(request));
+        self.breaker.record(match &response {
+            Ok(response) => !response.status().is_server_error(),
+            Err(_) => false,
+        });
+        let mut response = response.map_err(|e| format_err!("{}", pii_url(&e.to_string())))?;
+        let status = response.status();
+        if status == StatusCode::UNAUTHORIZED {
+            return Err(NotAuthorized {
+                status: status.as_u16(),
+                url: response.url().to_string(),
+            }
+            .into());
+        }
+        if status == StatusCode::FORBIDDEN {
+            return Err(Forbidden {
+                url: response.url().to_string(),
+            }
+            .into());
+        }
+        if let Err(e) = response.error_for_status_ref() {
+            let text =
+                bounded::text(&mut response, self.max_body).unwrap_or_else(|_| "(no data)".into());
+            let retry_after = response
+                .headers()
+                .get("retry-after")
+                .and_then(|h| h.to_str().ok());
+            let now = Utc::now();
+            if let Some(maintenance) = maintenance(status, retry_after, &text, now) {
+                let hold = chrono::Duration::seconds(MAINTENANCE_HOLD_SECONDS);
+                self.breaker
+                    .hold_until(maintenance.until.unwrap_or(now + hold));
+                return Err(maintenance.into());
+            }
+            // The response may name students.
+            let body = if pii_is_plain() {
+                text
+            } else {
+                "(response hidden by LOG_PII)".into()
+            };
+            Err(format_err!(
+                "Got {:?} on {:?}:\n{}\n",
+                e.status(),
+                e.url().map(|url| pii_url(url.as_str())),
+                body,
+            ))
+        } else {
+            Ok(response)
+        }
     }
 }
 
-fn do_json_or_err<T>(request: RequestBuilder) -> Result<T, Error>
-where
-    T: DeserializeOwned,
-{
-    let mut response = request.header("accept", "application/json").send()?;
-    if let Err(e) = response.error_for_status_ref() {
-        Err(format_err!(
-            "Got {:?} on {:?}:\n{}\n",
-            e.status(),
-            e.url(),
-            response
-                .text()
-                .as_ref()
-                .map(AsRef::as_ref)
-                .unwrap_or("(no data)"),
-        ))
-    } else {
-        Ok(response.json()?)
+#[test]
+fn test_min_tls() {
+    assert!(check_min_tls("1.2").is_ok());
+    assert!(check_min_tls("1.1").is_err());
+    assert!(check_min_tls("1.3").is_err());
+}
+
+#[cfg(test)]
+fn test_client() -> Ladok {
+    use std::time::Duration;
+    let breaker = CircuitBreaker::new(5, Duration::from_secs(60), Duration::from_secs(30));
+    Ladok::new(
+        "https://ladok.invalid",
+        Client::new(),
+        Arc::new(breaker),
+        Arc::new(Throttle::new(0., 1)),
+        Arc::new(ScaleCache::default()),
+        LarosateID::KTH,
+    )
+}
+
+#[cfg(test)]
+fn test_scale() -> Betygskala {
+    serde_json::from_str(
+        r#"{"ID": 131657, "Kod": "AF", "Betygsgrad": [
+            {"ID": 131661, "Kod": "A", "GiltigSomSlutbetyg": true},
+            {"ID": 131662, "Kod": "B", "GiltigSomSlutbetyg": true},
+            {"ID": 131667, "Kod": "FX", "GiltigSomSlutbetyg": false},
+            {"ID": 131668, "Kod": "F", "GiltigSomSlutbetyg": true}]}"#,
+    )
+    .unwrap()
+}
+
+/// A client for a fake Ladok server, that answers each request with
+/// the status and json body given by `respond` for the request text.
+#[cfg(test)]
+pub fn mock_client(respond: fn(&str) -> (u16, String)) -> Ladok {
+    mock_at(&crate::mock_server::serve(respond))
+}
+
+/// A client for a fake Ladok server at `server`, e.g.
+/// `http://127.0.0.1:4711`.
+#[cfg(test)]
+pub fn mock_at(server: &str) -> Ladok {
+    use std::time::Duration;
+    let breaker = CircuitBreaker::new(5, Duration::from_secs(60), Duration::from_secs(30));
+    Ladok::new(
+        server,
+        Client::new(),
+        Arc::new(breaker),
+        Arc::new(Throttle::new(0., 1)),
+        Arc::new(ScaleCache::default()),
+        LarosateID::KTH,
+    )
+}
+
+/// A client that knows the AF scale without asking Ladok.
+#[cfg(test)]
+pub fn test_client_with_scale() -> Ladok {
+    let ladok = test_client();
+    let scale = test_scale();
+    ladok.scales.insert(scale);
+    ladok
+}
+
+#[test]
+fn test_grade_disallowed_for_moment() {
+    let mut ladok = test_client();
+    let scale = test_scale();
+    let id = scale.ID;
+    ladok.scales.insert(scale);
+    let allowed = vec!["A".to_string(), "B".to_string(), "F".to_string()];
+    assert_eq!(ladok.get_grade(id, "B", Some(&allowed)).unwrap().Kod, "B");
+    assert_eq!(
+        ladok
+            .get_grade(id, "FX", Some(&allowed))
+            .err()
+            .unwrap()
+            .to_string(),
+        "Grade \"FX\" is in AF but not allowed for this moment",
+    );
+    assert_eq!(ladok.get_grade(id, "FX", None).unwrap().Kod, "FX");
+    assert!(ladok.get_grade(id, "P", None).is_err());
+}
+
+#[test]
+fn test_preloaded_scale_shared_without_fetch() {
+    use std::time::Duration;
+    let scales = Arc::new(ScaleCache::default());
+    scales.insert(test_scale());
+    let client = || {
+        let breaker = CircuitBreaker::new(5, Duration::from_secs(60), Duration::from_secs(30));
+        // Any request to this server fails, so grades must come from the cache.
+        Ladok::new(
+            "https://ladok.invalid",
+            Client::new(),
+            Arc::new(breaker),
+            Arc::new(Throttle::new(0., 1)),
+            scales.clone(),
+            LarosateID::KTH,
+        )
+    };
+    let id = test_scale().ID;
+    assert_eq!(client().get_grade(id, "A", None).unwrap().Kod, "A");
+    assert_eq!(client().get_grade(id, "F", None).unwrap().Kod, "F");
+    let other = serde_json::from_str("131658").unwrap();
+    assert!(client().get_grade(other, "A", None).is_err());
+}
+
+#[test]
+fn test_cleared_scale_refetched() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static FETCHED: AtomicUsize = AtomicUsize::new(0);
+    fn respond(_request: &str) -> (u16, String) {
+        FETCHED.fetch_add(1, Ordering::SeqCst);
+        (
+            200,
+            r#"{"ID": 131657, "Kod": "AF", "Betygsgrad": [
+                {"ID": 131661, "Kod": "A", "GiltigSomSlutbetyg": true}]}"#
+                .into(),
+        )
     }
+    let mut ladok = mock_client(respond);
+    let id = test_scale().ID;
+    assert_eq!(ladok.get_grade(id, "A", None).unwrap().Kod, "A");
+    assert_eq!(ladok.get_grade(id, "A", None).unwrap().Kod, "A");
+    assert_eq!(FETCHED.load(Ordering::SeqCst), 1);
+    assert_eq!(ladok.scales.clear(), 1);
+    assert_eq!(ladok.get_grade(id, "A", None).unwrap().Kod, "A");
+    assert_eq!(FETCHED.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn test_maintenance_response() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static REQUESTS: AtomicUsize = AtomicUsize::new(0);
+    let mut ladok = mock_client(|_| {
+        REQUESTS.fetch_add(1, Ordering::SeqCst);
+        (
+            503,
+            r#"{"Meddelande": "Ladok har planerat underhåll"}"#.into(),
+        )
+    });
+    let err = ladok.ping(test_scale().ID).unwrap_err();
+    assert!(is_maintenance(&err));
+    assert_eq!(
+        err.to_string(),
+        "Ladok is currently under maintenance, please try again later"
+    );
+    let err = ladok
+        .get_student_by_personnummer("199001011234")
+        .unwrap_err();
+    assert!(is_maintenance(&err), "{}", err);
+    assert_eq!(
+        REQUESTS.load(Ordering::SeqCst),
+        1,
+        "Held during maintenance"
+    );
+
+    let now = Utc::now();
+    let date = "Tue, 15 Oct 2019 06:00:00 GMT";
+    let until = maintenance(StatusCode::SERVICE_UNAVAILABLE, Some(date), "", now);
+    let expected = DateTime::parse_from_rfc3339("2019-10-15T06:00:00Z").unwrap();
+    assert_eq!(until.unwrap().until, Some(expected.with_timezone(&Utc)));
+    let until = maintenance(StatusCode::SERVICE_UNAVAILABLE, Some("120"), "", now);
+    assert_eq!(
+        until.unwrap().until,
+        Some(now + chrono::Duration::seconds(120))
+    );
+    let until = maintenance(StatusCode::SERVICE_UNAVAILABLE, Some("soon"), "", now);
+    assert_eq!(until.unwrap().until, None);
+    assert!(maintenance(StatusCode::SERVICE_UNAVAILABLE, None, "Overloaded", now).is_none());
+    assert!(maintenance(StatusCode::BAD_GATEWAY, Some("120"), "maintenance", now).is_none());
 }
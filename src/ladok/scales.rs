@@ -0,0 +1,52 @@
+use super::types::{Betygskala, BetygsskalaID};
+use failure::Error;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, RwLock};
+
+/// The grading scales loaded from Ladok, shared by all requests.
+///
+/// Scales rarely change, so they are kept for the life of the process.
+/// Loads are done one at a time, so that concurrent exports needing
+/// the same scale do not all fetch it.
+#[derive(Default)]
+pub struct ScaleCache {
+    scales: RwLock<BTreeMap<BetygsskalaID, Arc<Betygskala>>>,
+    loading: Mutex<()>,
+}
+
+impl ScaleCache {
+    pub fn get(&self, id: BetygsskalaID) -> Option<Arc<Betygskala>> {
+        self.scales.read().unwrap().get(&id).cloned()
+    }
+
+    pub fn insert(&self, scale: Betygskala) -> Arc<Betygskala> {
+        let scale = Arc::new(scale);
+        self.scales.write().unwrap().insert(scale.ID, scale.clone());
+        scale
+    }
+
+    /// Forget all scales, e.g. after a scale is changed in Ladok.
+    /// Returns the number of scales forgotten.
+    pub fn clear(&self) -> usize {
+        let mut scales = self.scales.write().unwrap();
+        let count = scales.len();
+        scales.clear();
+        count
+    }
+
+    /// Get a scale, calling `load` if it is not cached yet.
+    pub fn get_or_load(
+        &self,
+        id: BetygsskalaID,
+        load: impl FnOnce() -> Result<Betygskala, Error>,
+    ) -> Result<Arc<Betygskala>, Error> {
+        if let Some(scale) = self.get(id) {
+            return Ok(scale);
+        }
+        let _loading = self.loading.lock().unwrap();
+        if let Some(scale) = self.get(id) {
+            return Ok(scale);
+        }
+        Ok(self.insert(load()?))
+    }
+}
@@ -0,0 +1,164 @@
+use super::types::LarosateID;
+use super::{CircuitBreaker, Ladok, ScaleCache, Throttle};
+use failure::{format_err, Error};
+use reqwest::Client;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// A Ladok environment to report to.
+pub struct Target {
+    pub base_url: String,
+    /// Built with the client identity for this environment.
+    pub http: Client,
+    pub breaker: Arc<CircuitBreaker>,
+    /// Paces writes to this environment, shared by all exports.
+    pub throttle: Arc<Throttle>,
+    pub scales: Arc<ScaleCache>,
+    /// The lärosäte results are written as.
+    pub larosate: LarosateID,
+}
+
+impl Target {
+    pub fn client(&self) -> Ladok {
+        Ladok::new(
+            &self.base_url,
+            self.http.clone(),
+            self.breaker.clone(),
+            self.throttle.clone(),
+            self.scales.clone(),
+            self.larosate,
+        )
+    }
+}
+
+/// Which course rooms to report to which named target.
+///
+/// Written as comma-separated `pattern=target` pairs, where a pattern
+/// is a sis_course_id, or a prefix of one followed by `*`.  The first
+/// matching rule is used.
+#[derive(Debug, Default)]
+pub struct Rules(Vec<(String, String)>);
+
+impl FromStr for Rules {
+    type Err = Error;
+    fn from_str(value: &str) -> Result<Self, Error> {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|rule| !rule.is_empty())
+            .map(|rule| {
+                let mut parts = rule.splitn(2, '=');
+                match (parts.next(), parts.next()) {
+                    (Some(pattern), Some(target)) if !pattern.trim().is_empty() => {
+                        Ok((pattern.trim().to_string(), target.trim().to_string()))
+                    }
+                    _ => Err(format_err!("Expected pattern=target, got {:?}", rule)),
+                }
+            })
+            .collect::<Result<_, _>>()
+            .map(Rules)
+    }
+}
+
+impl Rules {
+    /// The name of the target for a course room, if any rule matches.
+    fn target_for(&self, sis_course_id: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(pattern, _)| match pattern.strip_suffix('*') {
+                Some(prefix) => sis_course_id.starts_with(prefix),
+                None => sis_course_id == pattern,
+            })
+            .map(|(_, target)| target.as_ref())
+    }
+}
+
+/// The Ladok environments of a deployment.  Course rooms not matched
+/// by any rule are reported to the default target.
+pub struct Targets {
+    default: Target,
+    named: BTreeMap<String, Target>,
+    rules: Rules,
+}
+
+impl Targets {
+    /// Check that every rule names a known target.
+    pub fn new(
+        default: Target,
+        named: BTreeMap<String, Target>,
+        rules: Rules,
+    ) -> Result<Self, Error> {
+        if let Some((pattern, target)) = rules.0.iter().find(|(_, t)| !named.contains_key(t)) {
+            return Err(format_err!(
+                "Rule {:?} refers to unknown Ladok target {:?}",
+                pattern,
+                target,
+            ));
+        }
+        Ok(Targets {
+            default,
+            named,
+            rules,
+        })
+    }
+    pub fn default_target(&self) -> &Target {
+        &self.default
+    }
+    /// The target to report a course room to.
+    pub fn for_course(&self, sis_course_id: &str) -> &Target {
+        self.rules
+            .target_for(sis_course_id)
+            .and_then(|name| self.named.get(name))
+            .unwrap_or(&self.default)
+    }
+    /// All targets, the default first.
+    pub fn all(&self) -> impl Iterator<Item = (&str, &Target)> {
+        std::iter::once(("default", &self.default)).chain(
+            self.named
+                .iter()
+                .map(|(name, target)| (name.as_ref(), target)),
+        )
+    }
+}
+
+#[cfg(test)]
+fn test_target(base_url: &str) -> Target {
+    use std::time::Duration;
+    Target {
+        base_url: base_url.into(),
+        http: Client::new(),
+        breaker: Arc::new(CircuitBreaker::new(
+            5,
+            Duration::from_secs(60),
+            Duration::from_secs(30),
+        )),
+        throttle: Arc::new(Throttle::new(0., 1)),
+        scales: Arc::new(ScaleCache::default()),
+        larosate: LarosateID::KTH,
+    }
+}
+
+#[test]
+fn test_course_pattern_selects_target() {
+    let targets = Targets::new(
+        test_target("https://api.ladok.se"),
+        vec![("test".to_string(), test_target("https://api.test.ladok.se"))]
+            .into_iter()
+            .collect(),
+        "SF1625*=test, DD1337HT191=test".parse().unwrap(),
+    )
+    .unwrap();
+    let base_url = |sis| targets.for_course(sis).base_url.as_str();
+    assert_eq!(base_url("SF1625HT191"), "https://api.test.ladok.se");
+    assert_eq!(base_url("DD1337HT191"), "https://api.test.ladok.se");
+    assert_eq!(base_url("DD1337VT201"), "https://api.ladok.se");
+    assert_eq!(base_url("SF1624HT191"), "https://api.ladok.se");
+}
+
+#[test]
+fn test_rule_to_unknown_target_rejected() {
+    let rules = "SF1625*=pilot".parse().unwrap();
+    assert!(Targets::new(test_target("https://api.ladok.se"), BTreeMap::new(), rules).is_err());
+    assert!("SF1625*".parse::<Rules>().is_err());
+}
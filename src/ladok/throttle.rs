@@ -0,0 +1,83 @@
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A token bucket, pacing writes to Ladok to at most `rate` requests
+/// per second, with bursts of up to `burst` requests.
+///
+/// A rate of zero means no limit.
+pub struct Throttle {
+    rate: f64,
+    burst: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl Throttle {
+    pub fn new(rate: f64, burst: u32) -> Self {
+        let burst = f64::from(burst.max(1));
+        Throttle {
+            rate,
+            burst,
+            state: Mutex::new((burst, Instant::now())),
+        }
+    }
+
+    /// Wait until a request may be sent.
+    pub fn acquire(&self) {
+        let wait = self.reserve_at(Instant::now());
+        if wait > Duration::from_secs(0) {
+            thread::sleep(wait);
+        }
+    }
+
+    /// Take a token, returning how long to wait before using it.
+    ///
+    /// The bucket may go below zero, so concurrent callers queue up
+    /// behind each other rather than all waking at once.
+    fn reserve_at(&self, now: Instant) -> Duration {
+        if self.rate <= 0. {
+            return Duration::from_secs(0);
+        }
+        let mut state = self.state.lock().unwrap();
+        let (tokens, last) = *state;
+        let elapsed = now.saturating_duration_since(last).as_secs_f64();
+        let tokens = (tokens + elapsed * self.rate).min(self.burst) - 1.;
+        *state = (tokens, now.max(last));
+        if tokens >= 0. {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-tokens / self.rate)
+        }
+    }
+}
+
+#[test]
+fn test_throttle_spaces_requests() {
+    let throttle = Throttle::new(10., 1);
+    let t0 = Instant::now();
+    let waits = (0..3)
+        .map(|_| throttle.reserve_at(t0).as_millis())
+        .collect::<Vec<_>>();
+    assert_eq!(waits, [0, 100, 200]);
+    // After a pause, the bucket is refilled, but only up to the burst.
+    let t1 = t0 + Duration::from_secs(5);
+    assert_eq!(throttle.reserve_at(t1).as_millis(), 0);
+    assert_eq!(throttle.reserve_at(t1).as_millis(), 100);
+}
+
+#[test]
+fn test_throttle_acquire_waits() {
+    let throttle = Throttle::new(100., 1);
+    let start = Instant::now();
+    for _ in 0..4 {
+        throttle.acquire();
+    }
+    assert!(start.elapsed() >= Duration::from_millis(30));
+}
+
+#[test]
+fn test_unlimited_throttle() {
+    let throttle = Throttle::new(0., 1);
+    let t0 = Instant::now();
+    assert!((0..10).all(|_| throttle.reserve_at(t0) == Duration::from_secs(0)));
+}
@@ -1,15 +1,177 @@
 use chrono::{NaiveDate, NaiveDateTime};
 use serde::{de::Visitor, Deserialize, Deserializer, Serialize};
+use std::collections::BTreeMap;
 use std::convert::TryInto;
 use std::fmt;
 use std::num::NonZeroU32;
+use std::str::FromStr;
+
+/// Declare a newtype for one kind of Ladok uid.
+///
+/// Ladok identifies most things by uids, that are all strings, so
+/// having a distinct type for each kind lets the compiler catch uids
+/// passed in the wrong place.
+macro_rules! uid_type {
+    ($(#[$meta:meta])* $name:ident) => {
+        $(#[$meta])*
+        #[derive(Clone, Debug, Deserialize, Serialize, PartialOrd, Ord, PartialEq, Eq)]
+        #[serde(transparent)]
+        pub struct $name(String);
+
+        impl $name {
+            pub fn new(uid: impl Into<String>) -> Self {
+                $name(uid.into())
+            }
+        }
+
+        impl AsRef<str> for $name {
+            fn as_ref(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+                self.0.fmt(f)
+            }
+        }
+    };
+}
+
+uid_type!(
+    /// A student, as in the integration_id of a Canvas user.
+    StudentUid
+);
+uid_type!(
+    /// A moment (utbildningsinstans) of a course, as in the
+    /// integration_id of a Canvas assignment.
+    MomentUid
+);
+uid_type!(
+    /// A course round (kurstillfälle), as in the integration_id of a
+    /// Canvas section.
+    RoundUid
+);
+uid_type!(
+    /// A single result, i.e. a grade on a moment for a student.
+    ResultatUid
+);
+uid_type!(
+    /// The studieresultat of a student on a course, which holds the
+    /// results of all moments.
+    StudieresultatUid
+);
+
+/// When a result was last changed, for optimistic locking.  Kept as
+/// the text Ladok sent, since Ladok compares it to its own, and a
+/// timestamp parsed and formatted again may differ in precision or
+/// offset.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct Resultatandring(String);
+
+/// The round a Canvas section is linked to.
+///
+/// Course moments are reported on a kurstillfälle, whose uid is the
+/// integration_id of the section as is.  Some programme moments are
+/// reported on an utbildningstillfälle instead, marked by prefixing
+/// the uid in the integration_id with `utbildningstillfalle:`.
+#[derive(Clone, Debug, PartialOrd, Ord, PartialEq, Eq)]
+pub enum Tillfalle {
+    Kurs(RoundUid),
+    Utbildning(RoundUid),
+}
+
+impl Tillfalle {
+    pub fn from_integration_id(id: &str) -> Self {
+        match id.strip_prefix("utbildningstillfalle:") {
+            Some(uid) => Tillfalle::Utbildning(RoundUid::new(uid)),
+            None => Tillfalle::Kurs(RoundUid::new(id)),
+        }
+    }
+
+    pub fn uid(&self) -> &RoundUid {
+        match self {
+            Tillfalle::Kurs(uid) | Tillfalle::Utbildning(uid) => uid,
+        }
+    }
+}
+
+impl fmt::Display for Tillfalle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            Tillfalle::Kurs(uid) => uid.fmt(f),
+            Tillfalle::Utbildning(uid) => write!(f, "utbildningstillfalle:{}", uid),
+        }
+    }
+}
 
 #[derive(Clone, Debug, Deserialize)]
 #[allow(non_snake_case)]
 pub struct Betygsgrad {
-    GiltigSomSlutbetyg: bool,
+    GiltigSomSlutbetyg: Option<bool>,
     pub ID: BetygsgradID,
     pub Kod: String,
+    #[serde(default)]
+    pub Benamning: Benamning,
+}
+
+impl Betygsgrad {
+    /// True unless Ladok says the grade is not valid as a final grade,
+    /// like FX.
+    pub fn valid_as_final(&self) -> bool {
+        self.GiltigSomSlutbetyg != Some(false)
+    }
+    /// The code and, if known, the benämning of this grade, for display.
+    pub fn describe(&self) -> String {
+        match self.Benamning.text() {
+            Some(text) if text != self.Kod => format!("{} – {}", self.Kod, text),
+            _ => self.Kod.clone(),
+        }
+    }
+}
+
+/// A text in several languages.
+///
+/// Ladok gives these either as an object keyed by language code, or
+/// as a list of objects with Sprakkod and Text.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(from = "BenamningRepr")]
+pub struct Benamning(BTreeMap<String, String>);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum BenamningRepr {
+    Map(BTreeMap<String, String>),
+    List(Vec<SprakText>),
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct SprakText {
+    Sprakkod: String,
+    Text: String,
+}
+
+impl From<BenamningRepr> for Benamning {
+    fn from(repr: BenamningRepr) -> Self {
+        Benamning(match repr {
+            BenamningRepr::Map(map) => map,
+            BenamningRepr::List(list) => list.into_iter().map(|t| (t.Sprakkod, t.Text)).collect(),
+        })
+    }
+}
+
+impl Benamning {
+    pub fn get(&self, lang: &str) -> Option<&str> {
+        self.0.get(lang).map(AsRef::as_ref)
+    }
+    /// The Swedish text, or else the English, or else any.
+    pub fn text(&self) -> Option<&str> {
+        self.get("sv")
+            .or_else(|| self.get("en"))
+            .or_else(|| self.0.values().next().map(AsRef::as_ref))
+    }
 }
 
 #[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialOrd, Ord, PartialEq, Eq)]
@@ -24,8 +186,9 @@ impl fmt::Display for BetygsgradID {
 #[derive(Debug, Deserialize)]
 #[allow(non_snake_case)]
 pub struct Betygskala {
+    #[serde(default)]
     Betygsgrad: Vec<Betygsgrad>,
-    ID: BetygsskalaID,
+    pub ID: BetygsskalaID,
     pub Kod: String,
 }
 
@@ -38,6 +201,15 @@ impl fmt::Display for BetygsskalaID {
     }
 }
 
+impl FromStr for BetygsskalaID {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        s.parse()
+            .map(BetygsskalaID)
+            .map_err(|e| format!("Bad betygsskala id {:?}: {}", s, e))
+    }
+}
+
 impl<'de> Deserialize<'de> for BetygsskalaID {
     /// A custom deserializer, since the value sometimes appear as a quoted string i Ladok json.
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
@@ -89,6 +261,9 @@ impl Betygskala {
     pub fn get(&self, kod: &str) -> Option<&Betygsgrad> {
         self.Betygsgrad.iter().find(|b| b.Kod == kod)
     }
+    pub fn get_by_id(&self, id: BetygsgradID) -> Option<&Betygsgrad> {
+        self.Betygsgrad.iter().find(|b| b.ID == id)
+    }
 }
 
 /// https://www.test.ladok.se/restdoc/schemas/schemas.ladok.se-resultat.html#element_StudieresultatForRapporteringSokVarden
@@ -98,89 +273,236 @@ pub struct StudieresultatForRapporteringSokVarden {
     // ' dap:Base ' super type was not found in this schema. Some elements and attributes may be missing.
     pub Filtrering: Vec<String>, // rr:StudieresultatTillstandVidRapporteringEnum [0..*]
     // <rr:GruppUID> xs:string </rr:GruppUID> [0..1] (not used)
-    pub KurstillfallenUID: Vec<String>,
+    pub KurstillfallenUID: Vec<RoundUid>,
     pub Limit: u32,
     /// very important to have order by otherwise you get really
     /// strange results with missing data and duplicate students
     pub OrderBy: Vec<String>, // rr:StudieresultatOrderByEnum [0..*]
     pub Page: u32,
     // <rr:StudenterUID> xs:string </rr:StudenterUID> [0..*] (not used)
-    pub UtbildningsinstansUID: Option<String>,
+    pub UtbildningsinstansUID: Option<MomentUid>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub UtbildningstillfallenUID: Vec<RoundUid>,
+}
+
+impl StudieresultatForRapporteringSokVarden {
+    /// Search for the first page of results to report on `moment`, for
+    /// students on any of `tillfallen`.
+    pub fn new(tillfallen: &[Tillfalle], moment: &MomentUid) -> Self {
+        let uids = |kurs: bool| {
+            tillfallen
+                .iter()
+                .filter(|t| matches!(t, Tillfalle::Kurs(_)) == kurs)
+                .map(|t| t.uid().clone())
+                .collect()
+        };
+        StudieresultatForRapporteringSokVarden {
+            KurstillfallenUID: uids(true),
+            Page: 1,
+            Filtrering: vec!["OBEHANDLADE".into(), "UTKAST".into()],
+            UtbildningsinstansUID: Some(moment.clone()),
+            UtbildningstillfallenUID: uids(false),
+            OrderBy: vec![
+                "EFTERNAMN_ASC".into(),
+                "FORNAMN_ASC".into(),
+                "PERSONNUMMER_ASC".into(),
+            ],
+            Limit: 100,
+        }
+    }
 }
 
 /// https://www.test.ladok.se/restdoc/schemas/schemas.ladok.se.html#type_Student
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 #[allow(non_snake_case)]
 pub struct Student {
-    Uid: String,
+    Uid: StudentUid,
+    /// Twelve digits, without a separator.  Not serialized, to keep it
+    /// out of dumps.
+    #[serde(default, skip_serializing)]
+    Personnummer: Option<String>,
+}
+
+impl Student {
+    pub fn uid(&self) -> &StudentUid {
+        &self.Uid
+    }
+
+    /// True if this is the student with the personnummer `digits`,
+    /// as given by `personnummer_digits`.  Ten digits match without
+    /// the century.
+    pub fn has_personnummer(&self, digits: &str) -> bool {
+        self.Personnummer
+            .as_deref()
+            .and_then(personnummer_digits)
+            .is_some_and(|own| own == digits || (digits.len() == 10 && own.ends_with(digits)))
+    }
+}
+
+/// The digits of a personnummer, with or without the century and the
+/// separator, or None if `value` does not look like one.
+pub fn personnummer_digits(value: &str) -> Option<String> {
+    let digits = value.trim().replace(['-', '+'], "");
+    if (digits.len() == 10 || digits.len() == 12) && digits.chars().all(|c| c.is_ascii_digit()) {
+        Some(digits)
+    } else {
+        None
+    }
 }
 
-#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
 pub struct LarosateID(NonZeroU32);
 
 impl LarosateID {
-    pub const KTH: LarosateID = LarosateID(unsafe { NonZeroU32::new_unchecked(29) });
+    pub const KTH: LarosateID = LarosateID(NonZeroU32::new(29).unwrap());
+}
+
+impl FromStr for LarosateID {
+    type Err = std::num::ParseIntError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.trim().parse().map(LarosateID)
+    }
 }
 
 /// https://www.test.ladok.se/restdoc/schemas/schemas.ladok.se-resultat.html#type_Studieresultat
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 #[allow(non_snake_case)]
 pub struct Studieresultat {
     LarosateID: Option<LarosateID>,
     SenastSparad: Option<NaiveDateTime>,
     SenastAndradAv: Option<String>,
-    pub Uid: Option<String>,
+    pub Uid: Option<StudieresultatUid>,
     AktuellKursinstans: Option<String>,
-    AktuelltKurstillfalle: Option<String>,
-    // Anonymiseringskod: Option<String>, (ignorerar vi)
+    AktuelltKurstillfalle: Option<RoundUid>,
+    // Anonymiseringskod: Option<String>, (se Rapporteringskontext)
     // Avbrott ignorerar vi tills vidare
     KursUID: Option<String>,
     Rapporteringskontext: Option<Rapporteringskontext>,
+    #[serde(default)]
     ResultatPaUtbildningar: Vec<ResultatPaUtbildning>,
     SenastRegistrerad: Option<NaiveDateTime>,
     Student: Option<Student>,
 }
 
 impl Studieresultat {
-    pub fn get_arbetsunderlag(&self, kurstillf: &str) -> Option<&Resultat> {
+    /// True if the student has registered on the round.
+    pub fn is_registered(&self) -> bool {
+        self.SenastRegistrerad.is_some()
+    }
+    /// True unless the result belongs to another lärosäte than ours.
+    pub fn is_ours(&self, larosate: LarosateID) -> bool {
+        self.LarosateID.is_none_or(|id| id == larosate)
+    }
+
+    pub fn get_arbetsunderlag(&self, moment: &MomentUid) -> Option<&Resultat> {
         for rpu in &self.ResultatPaUtbildningar {
             if let Some(au) = rpu.Arbetsunderlag.as_ref() {
-                if au.UtbildningsinstansUID.as_ref().map(AsRef::as_ref) == Some(kurstillf) {
+                if au.UtbildningsinstansUID.as_ref() == Some(moment) {
                     return Some(au);
                 }
             }
         }
         None
     }
+    /// The latest attested result on a moment, if any.
+    pub fn get_senast_attesterat(&self, moment: &MomentUid) -> Option<&Resultat> {
+        self.ResultatPaUtbildningar
+            .iter()
+            .filter_map(|rpu| rpu.SenastAttesteradeResultat.as_ref())
+            .find(|r| r.UtbildningsinstansUID.as_ref() == Some(moment))
+    }
+    /// The kursinstans of the studieresultat, which is the moment of
+    /// the final course grade.
+    pub fn kursinstans(&self) -> Option<&str> {
+        self.AktuellKursinstans.as_deref()
+    }
+    /// The round the studieresultat is on.
+    pub fn round(&self) -> Option<&RoundUid> {
+        self.AktuelltKurstillfalle.as_ref()
+    }
     pub fn get_betygsskala(&self) -> Option<BetygsskalaID> {
         self.Rapporteringskontext
             .as_ref()
             .and_then(|rk| rk.BetygsskalaID)
     }
+    /// True if the result is reported anonymously, by anonymiseringskod.
+    pub fn is_anonymized(&self) -> bool {
+        self.Rapporteringskontext
+            .as_ref()
+            .and_then(|rk| rk.Anonymiseringskod.as_deref())
+            .is_some_and(|kod| !kod.trim().is_empty())
+    }
 }
 
 /// https://www.test.ladok.se/restdoc/schemas/schemas.ladok.se-resultat.html#element_SokresultatStudieresultatResultat
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 #[allow(non_snake_case)]
 pub struct SokresultatStudieresultatResultat {
+    #[serde(default)]
     pub Resultat: Vec<Studieresultat>,
     pub TotaltAntalPoster: usize,
 }
 
 /// https://www.test.ladok.se/restdoc/schemas/schemas.ladok.se-resultat.html#element_Rapporteringskontext
-#[derive(Debug, Deserialize)]
+///
+/// Only the scale and anonymiseringskod are used here, so the other
+/// fields may be absent without failing the whole search.
+#[derive(Debug, Deserialize, Serialize)]
 #[allow(non_snake_case)]
 pub struct Rapporteringskontext {
     Anonymiseringskod: Option<String>,
     BetygsskalaID: Option<BetygsskalaID>,
-    KravPaHanvisningTillBeslutshandling: bool,
-    KravPaProjekttitel: bool,
-    UtbildningUID: String,
-    UtbildningsinstansUID: String,
+    KravPaHanvisningTillBeslutshandling: Option<bool>,
+    KravPaProjekttitel: Option<bool>,
+    UtbildningUID: Option<String>,
+    UtbildningsinstansUID: Option<String>,
+}
+
+/// Where in the reporting process a result is.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(from = "u32", into = "u32")]
+pub enum ProcessStatus {
+    Utkast,
+    Klarmarkerad,
+    Attesterad,
+    Other(u32),
+}
+
+impl From<u32> for ProcessStatus {
+    fn from(status: u32) -> Self {
+        match status {
+            1 => ProcessStatus::Utkast,
+            2 => ProcessStatus::Klarmarkerad,
+            3 => ProcessStatus::Attesterad,
+            other => ProcessStatus::Other(other),
+        }
+    }
+}
+
+impl From<ProcessStatus> for u32 {
+    fn from(status: ProcessStatus) -> Self {
+        match status {
+            ProcessStatus::Utkast => 1,
+            ProcessStatus::Klarmarkerad => 2,
+            ProcessStatus::Attesterad => 3,
+            ProcessStatus::Other(other) => other,
+        }
+    }
+}
+
+impl fmt::Display for ProcessStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProcessStatus::Utkast => f.write_str("utkast"),
+            ProcessStatus::Klarmarkerad => f.write_str("klarmarkerad"),
+            ProcessStatus::Attesterad => f.write_str("attesterad"),
+            ProcessStatus::Other(status) => write!(f, "process status {}", status),
+        }
+    }
 }
 
 /// https://www.test.ladok.se/restdoc/schemas/schemas.ladok.se-resultat.html#element_ResultatPaUtbildning
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 #[allow(non_snake_case)]
 pub struct ResultatPaUtbildning {
     // ' dap:Base ' super type was not found in this schema. Some elements and attributes may be missing.
@@ -194,30 +516,113 @@ pub struct ResultatPaUtbildning {
 }
 
 impl SokresultatStudieresultatResultat {
-    pub fn find_student(&self, uid: &str) -> Option<&Studieresultat> {
+    pub fn find_student(&self, uid: &StudentUid) -> Option<&Studieresultat> {
+        self.find_student_on(uid, &[])
+    }
+
+    /// The uid of the student with a personnummer, for a Canvas user
+    /// known only by it.
+    pub fn find_personnummer(&self, personnummer: &str) -> Option<&StudentUid> {
+        let digits = personnummer_digits(personnummer)?;
         self.Resultat
             .iter()
-            .find(|r| r.Student.as_ref().map(|s| s.Uid == uid).unwrap_or(false))
+            .filter_map(|r| r.Student.as_ref())
+            .find(|s| s.has_personnummer(&digits))
+            .map(Student::uid)
+    }
+
+    /// Keep only the results that have no draft in these search
+    /// results, e.g. to retry a create without creating duplicates.
+    pub fn still_missing(&self, data: Vec<SkapaResultat>) -> Vec<SkapaResultat> {
+        data.into_iter()
+            .filter(|skapa| {
+                let found = self
+                    .Resultat
+                    .iter()
+                    .find(|r| r.Uid.is_some() && r.Uid == skapa.StudieresultatUID);
+                match (found, &skapa.UtbildningsinstansUID) {
+                    (Some(found), Some(moment)) => found.get_arbetsunderlag(moment).is_none(),
+                    _ => true,
+                }
+            })
+            .collect()
+    }
+
+    /// Find a student, preferring a studieresultat on one of `rounds`
+    /// if the student is on several.
+    pub fn find_student_on(
+        &self,
+        uid: &StudentUid,
+        rounds: &[RoundUid],
+    ) -> Option<&Studieresultat> {
+        let mut found = self
+            .Resultat
+            .iter()
+            .filter(|r| r.Student.as_ref().map(|s| &s.Uid == uid).unwrap_or(false));
+        let first = found.next()?;
+        Some(
+            std::iter::once(first)
+                .chain(found)
+                .find(|r| {
+                    r.AktuelltKurstillfalle
+                        .as_ref()
+                        .map(|round| rounds.contains(round))
+                        .unwrap_or(false)
+                })
+                .unwrap_or(first),
+        )
+    }
+}
+
+/// https://www.test.ladok.se/restdoc/schemas/schemas.ladok.se-utbildningsinformation.html#type_Utbildningsinstans
+#[derive(Debug, Deserialize)]
+#[allow(non_snake_case)]
+pub struct Utbildningsinstans {
+    pub Uid: Option<MomentUid>,
+    pub Giltighetsperiod: Option<Giltighetsperiod>,
+}
+
+/// A period of dates, where either end may be open.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[allow(non_snake_case)]
+pub struct Giltighetsperiod {
+    pub Startdatum: Option<NaiveDate>,
+    pub Slutdatum: Option<NaiveDate>,
+}
+
+impl Giltighetsperiod {
+    pub fn contains(&self, date: NaiveDate) -> bool {
+        self.clamp(date) == date
+    }
+    /// The date closest to `date` within the period.
+    pub fn clamp(&self, date: NaiveDate) -> NaiveDate {
+        let date = self.Startdatum.map(|s| date.max(s)).unwrap_or(date);
+        self.Slutdatum.map(|e| date.min(e)).unwrap_or(date)
     }
 }
 
 /// https://www.test.ladok.se/restdoc/schemas/schemas.ladok.se-resultat.html#type_SkapaResultat
-#[derive(Debug, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 #[allow(non_snake_case)]
 pub struct SkapaResultat {
-    pub Uid: Option<String>,
+    /// The uid of the new result.  It does not exist until Ladok
+    /// creates the result, so this should be None.  Note that this is
+    /// not the same as the uid of the Studieresultat, which goes in
+    /// StudieresultatUID.
+    pub Uid: Option<ResultatUid>,
     pub Betygsgrad: Option<BetygsgradID>,
     pub BetygsskalaID: BetygsskalaID,
     pub Examinationsdatum: Option<NaiveDate>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub Noteringar: Vec<Notering>,
     /*
     <rr:ExamineradOmfattning> xs:decimal </rr:ExamineradOmfattning> [0..1]
     <rr:HanvisningTillBeslutshandling> ... </rr:HanvisningTillBeslutshandling> [0..1]
-    <rr:Noteringar> rr:Notering </rr:Noteringar> [0..*]
     <rr:Projekttitel> ... </rr:Projekttitel> [0..1]
     <rr:AktivitetstillfalleUID> xs:string </rr:AktivitetstillfalleUID> [0..1]
      */
-    pub StudieresultatUID: Option<String>,
-    pub UtbildningsinstansUID: Option<String>,
+    pub StudieresultatUID: Option<StudieresultatUid>,
+    pub UtbildningsinstansUID: Option<MomentUid>,
 }
 
 /// https://www.test.ladok.se/restdoc/schemas/schemas.ladok.se-resultat.html#element_SkapaFlera
@@ -237,36 +642,160 @@ pub struct UppdateraFlera {
 }
 
 /// https://www.test.ladok.se/restdoc/schemas/schemas.ladok.se-resultat.html#type_UppdateraResultat
-#[derive(Debug, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 #[allow(non_snake_case)]
 pub struct UppdateraResultat {
     // <!-- ' base:BaseEntitet ' super type was not found in this schema. Some elements and attributes may be missing. -->
-    pub Uid: Option<String>,
+    pub Uid: Option<StudieresultatUid>,
 
     pub Betygsgrad: Option<BetygsgradID>,
     pub BetygsskalaID: BetygsskalaID,
     pub Examinationsdatum: Option<NaiveDate>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub Noteringar: Vec<Notering>,
     //<rr:ExamineradOmfattning> xs:decimal </rr:ExamineradOmfattning> [0..1]
     //<rr:HanvisningTillBeslutshandling> ... </rr:HanvisningTillBeslutshandling> [0..1]
-    //<rr:Noteringar> rr:Notering </rr:Noteringar> [0..*]
     //<rr:Projekttitel> ... </rr:Projekttitel> [0..1]
     //<rr:AktivitetstillfalleUID> xs:string </rr:AktivitetstillfalleUID> [0..1]
-    pub ResultatUID: Option<String>,
-    pub SenasteResultatandring: Option<NaiveDateTime>,
+    pub ResultatUID: Option<ResultatUid>,
+    pub SenasteResultatandring: Option<Resultatandring>,
+}
+
+/// A note on a result.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[allow(non_snake_case)]
+pub struct Notering {
+    pub Text: String,
+}
+
+impl Notering {
+    const KOMPLETTERING: &'static str = "Komplettering";
+    /// The longest text Ladok takes in a note.
+    pub const MAX_CHARS: usize = 250;
+
+    /// A note with the text of a Canvas comment, cut to the length
+    /// Ladok takes, the "…" included.  None if there is no text.
+    pub fn from_comment(comment: &str) -> Option<Self> {
+        let text = comment.split_whitespace().collect::<Vec<_>>().join(" ");
+        if text.is_empty() {
+            return None;
+        }
+        let text = if text.chars().count() > Notering::MAX_CHARS {
+            let end = text
+                .char_indices()
+                .nth(Notering::MAX_CHARS - 1)
+                .map_or(text.len(), |(end, _)| end);
+            format!("{}…", text[..end].trim_end().trim_end_matches('…'))
+        } else {
+            text
+        };
+        Some(Notering { Text: text })
+    }
+
+    /// The note that the student may complement a failed result,
+    /// rather than retake the moment.
+    pub fn komplettering() -> Self {
+        Notering {
+            Text: Notering::KOMPLETTERING.into(),
+        }
+    }
+    /// True if `noteringar` says the result may be complemented.
+    pub fn has_komplettering(noteringar: &[Notering]) -> bool {
+        noteringar.iter().any(|n| n.Text == Notering::KOMPLETTERING)
+    }
+    /// The notes of a result that has `existing` notes in Ladok, with
+    /// the komplettering note added or removed, and `comment` added
+    /// unless already there.  Other notes are kept as they are.
+    pub fn merged(
+        existing: &[Notering],
+        komplettering: bool,
+        comment: Option<Notering>,
+    ) -> Vec<Notering> {
+        let mut noteringar = existing
+            .iter()
+            .filter(|n| komplettering || n.Text != Notering::KOMPLETTERING)
+            .cloned()
+            .collect::<Vec<_>>();
+        if komplettering && !Notering::has_komplettering(&noteringar) {
+            noteringar.push(Notering::komplettering());
+        }
+        if let Some(comment) = comment {
+            if !noteringar.contains(&comment) {
+                noteringar.push(comment);
+            }
+        }
+        noteringar
+    }
+}
+
+/// A reference to a result, for klarmarkering or attestering.
+#[derive(Debug, Serialize)]
+#[allow(non_snake_case)]
+pub struct ResultatReferens {
+    pub ResultatUID: ResultatUid,
+    /// For optimistic locking, as read from Ladok.
+    pub SenasteResultatandring: Option<Resultatandring>,
+}
+
+impl ResultatReferens {
+    pub fn of(resultat: &Resultat) -> Option<ResultatReferens> {
+        Some(ResultatReferens {
+            ResultatUID: resultat.Uid.clone()?,
+            SenasteResultatandring: resultat.SenasteResultatandring.clone(),
+        })
+    }
+}
+
+/// https://www.test.ladok.se/restdoc/schemas/schemas.ladok.se-resultat.html#element_KlarmarkeraFlera
+#[derive(Debug, Serialize)]
+#[allow(non_snake_case)]
+pub struct KlarmarkeraFlera {
+    pub LarosateID: LarosateID,
+    pub Resultat: Vec<ResultatReferens>,
+}
+
+/// https://www.test.ladok.se/restdoc/schemas/schemas.ladok.se-resultat.html#element_AttesteraFlera
+#[derive(Debug, Serialize)]
+#[allow(non_snake_case)]
+pub struct AttesteraFlera {
+    pub LarosateID: LarosateID,
+    pub Resultat: Vec<ResultatReferens>,
 }
 
 /// https://www.test.ladok.se/restdoc/schemas/schemas.ladok.se-resultat.html#type_ResultatLista
 #[derive(Debug, Deserialize)]
 #[allow(non_snake_case)]
 pub struct ResultatLista {
+    #[serde(default)]
     pub Resultat: Vec<Resultat>,
+    /// The results of a batch that Ladok did not accept.
+    #[serde(default)]
+    pub Fel: Vec<ResultatFel>,
+}
+
+/// A result in a batch that Ladok did not accept, and why.
+#[derive(Debug, Deserialize)]
+#[allow(non_snake_case)]
+pub struct ResultatFel {
+    pub StudieresultatUID: Option<StudieresultatUid>,
+    pub ResultatUID: Option<ResultatUid>,
+    pub Meddelande: Option<String>,
+}
+
+impl ResultatFel {
+    /// The uids identifying the failed result.
+    pub fn uids(&self) -> Vec<String> {
+        let studieresultat = self.StudieresultatUID.as_ref().map(ToString::to_string);
+        let resultat = self.ResultatUID.as_ref().map(ToString::to_string);
+        studieresultat.into_iter().chain(resultat).collect()
+    }
 }
 
 /// https://www.test.ladok.se/restdoc/schemas/schemas.ladok.se-resultat.html#element_Resultat
 #[derive(Debug, Deserialize, Serialize)]
 #[allow(non_snake_case)]
 pub struct Resultat {
-    pub Uid: Option<String>,
+    pub Uid: Option<ResultatUid>,
     AktivitetstillfalleUID: Option<String>,
     // <at:Beslut> ... </at:Beslut> [0..1]
     pub Betygsgrad: Option<BetygsgradID>,
@@ -278,10 +807,300 @@ pub struct Resultat {
     //<rr:HanvisningTillBeslutshandling> ... </rr:HanvisningTillBeslutshandling> [0..1]
     //<rr:Klarmarkering> rr:Klarmarkera </rr:Klarmarkering> [0..1]
     //<rr:KurstillfalleUID> xs:string </rr:KurstillfalleUID> [0..1]
-    //<rr:Noteringar> rr:Notering </rr:Noteringar> [0..*]
-    //<rr:ProcessStatus> xs:int </rr:ProcessStatus> [0..1]
+    #[serde(default)]
+    pub Noteringar: Vec<Notering>,
+    pub ProcessStatus: Option<ProcessStatus>,
     //<rr:Projekttitel> ... </rr:Projekttitel> [0..1]
-    pub SenasteResultatandring: Option<NaiveDateTime>,
-    StudieresultatUID: Option<String>,
-    UtbildningsinstansUID: Option<String>,
+    pub SenasteResultatandring: Option<Resultatandring>,
+    StudieresultatUID: Option<StudieresultatUid>,
+    UtbildningsinstansUID: Option<MomentUid>,
+}
+
+#[test]
+fn test_attestera_request() {
+    let resultat: Vec<Resultat> = serde_json::from_str(
+        r#"[{"Uid": "r-1", "SenasteResultatandring": "2019-05-24T13:14:15.123"},
+            {"Uid": null}]"#,
+    )
+    .unwrap();
+    let data = AttesteraFlera {
+        LarosateID: LarosateID::KTH,
+        Resultat: resultat.iter().filter_map(ResultatReferens::of).collect(),
+    };
+    assert_eq!(
+        serde_json::to_value(&data).unwrap(),
+        serde_json::json!({
+            "LarosateID": 29,
+            "Resultat": [{
+                "ResultatUID": "r-1",
+                "SenasteResultatandring": "2019-05-24T13:14:15.123",
+            }],
+        }),
+    );
+}
+
+#[test]
+fn test_resultatandring_echoed_as_received() {
+    for sent in &[
+        "2019-05-24T13:14:15.120",
+        "2019-05-24T13:14:15.123456789",
+        "2019-05-24T13:14:15",
+        "2019-05-24T13:14:15.1+02:00",
+    ] {
+        let json = format!(r#"{{"Uid": "r-1", "SenasteResultatandring": "{}"}}"#, sent);
+        let resultat: Resultat = serde_json::from_str(&json).unwrap();
+        let referens = ResultatReferens::of(&resultat).unwrap();
+        assert_eq!(
+            serde_json::to_value(&referens).unwrap()["SenasteResultatandring"],
+            *sent,
+        );
+    }
+}
+
+#[test]
+fn test_betygsgrad_with_benamning() {
+    let grades: Vec<Betygsgrad> = serde_json::from_str(
+        r#"[{"ID": 131662, "Kod": "B", "GiltigSomSlutbetyg": true,
+             "Benamning": {"en": "Very good", "sv": "Mycket bra"}},
+            {"ID": 2302, "Kod": "G", "GiltigSomSlutbetyg": true,
+             "Benamning": [{"Sprakkod": "en", "Text": "Pass"}]},
+            {"ID": 131661, "Kod": "A", "GiltigSomSlutbetyg": true}]"#,
+    )
+    .unwrap();
+    assert_eq!(grades[0].Benamning.get("en"), Some("Very good"));
+    assert_eq!(grades[0].describe(), "B – Mycket bra");
+    assert_eq!(grades[1].describe(), "G – Pass");
+    assert_eq!(grades[2].describe(), "A");
+}
+
+#[test]
+fn test_find_student_on_round() {
+    let resultat: SokresultatStudieresultatResultat = serde_json::from_str(
+        r#"{"TotaltAntalPoster": 3, "Resultat": [
+            {"Uid": "sr-1", "Student": {"Uid": "st-1"}, "AktuelltKurstillfalle": "kt-vt19",
+             "ResultatPaUtbildningar": []},
+            {"Uid": "sr-2", "Student": {"Uid": "st-1"}, "AktuelltKurstillfalle": "kt-ht19",
+             "ResultatPaUtbildningar": []},
+            {"Uid": "sr-3", "Student": {"Uid": "st-2"}, "AktuelltKurstillfalle": "kt-vt19",
+             "ResultatPaUtbildningar": []}]}"#,
+    )
+    .unwrap();
+    let student = StudentUid::new("st-1");
+    let uid = |r: Option<&Studieresultat>| r.and_then(|r| r.Uid.clone());
+    assert_eq!(
+        uid(resultat.find_student_on(&student, &[RoundUid::new("kt-ht19")])),
+        Some(StudieresultatUid::new("sr-2")),
+    );
+    assert_eq!(
+        uid(resultat.find_student_on(&student, &[RoundUid::new("kt-vt20")])),
+        Some(StudieresultatUid::new("sr-1")),
+        "Falls back to the first",
+    );
+    assert_eq!(
+        uid(resultat.find_student(&StudentUid::new("st-2"))),
+        Some(StudieresultatUid::new("sr-3")),
+    );
+    assert!(resultat.find_student(&StudentUid::new("st-3")).is_none());
+}
+
+#[test]
+fn test_find_student_by_personnummer() {
+    let resultat: SokresultatStudieresultatResultat = serde_json::from_str(
+        r#"{"TotaltAntalPoster": 2, "Resultat": [
+            {"Uid": "sr-1", "Student": {"Uid": "st-1", "Personnummer": "199001011234"},
+             "ResultatPaUtbildningar": []},
+            {"Uid": "sr-2", "Student": {"Uid": "st-2"}, "ResultatPaUtbildningar": []}]}"#,
+    )
+    .unwrap();
+    let st1 = Some(StudentUid::new("st-1"));
+    assert_eq!(resultat.find_personnummer("199001011234").cloned(), st1);
+    assert_eq!(resultat.find_personnummer("19900101-1234").cloned(), st1);
+    assert_eq!(resultat.find_personnummer("900101-1234").cloned(), st1);
+    assert_eq!(resultat.find_personnummer("900101-1235"), None);
+    assert_eq!(resultat.find_personnummer("u1abcdef"), None);
+    assert_eq!(
+        serde_json::to_value(&resultat.Resultat[0].Student).unwrap(),
+        serde_json::json!({"Uid": "st-1"}),
+        "The personnummer is not serialized",
+    );
+}
+
+#[test]
+fn test_retried_create_skips_drafts() {
+    let resultat: SokresultatStudieresultatResultat = serde_json::from_str(
+        r#"{"TotaltAntalPoster": 2, "Resultat": [
+            {"Uid": "sr-1", "Student": {"Uid": "st-1"}, "ResultatPaUtbildningar": [
+                {"Arbetsunderlag": {"Uid": "r-1", "UtbildningsinstansUID": "m-1"}}]},
+            {"Uid": "sr-2", "Student": {"Uid": "st-2"}, "ResultatPaUtbildningar": []}]}"#,
+    )
+    .unwrap();
+    let create = |uid: &str| -> SkapaResultat {
+        serde_json::from_value(serde_json::json!({
+            "Uid": null,
+            "Betygsgrad": 131661,
+            "BetygsskalaID": 131657,
+            "Examinationsdatum": "2019-05-24",
+            "StudieresultatUID": uid,
+            "UtbildningsinstansUID": "m-1",
+        }))
+        .unwrap()
+    };
+    let missing = resultat.still_missing(vec![create("sr-1"), create("sr-2"), create("sr-3")]);
+    assert_eq!(
+        missing
+            .into_iter()
+            .filter_map(|s| s.StudieresultatUID)
+            .collect::<Vec<_>>(),
+        [
+            StudieresultatUid::new("sr-2"),
+            StudieresultatUid::new("sr-3")
+        ],
+    );
+}
+
+#[test]
+fn test_process_status() {
+    let resultat: Vec<Resultat> = serde_json::from_str(
+        r#"[{"Uid": "r-1", "ProcessStatus": 1},
+            {"Uid": "r-2", "ProcessStatus": 2},
+            {"Uid": "r-3", "ProcessStatus": 3},
+            {"Uid": "r-4", "ProcessStatus": 7},
+            {"Uid": "r-5"}]"#,
+    )
+    .unwrap();
+    let status = resultat.iter().map(|r| r.ProcessStatus).collect::<Vec<_>>();
+    assert_eq!(
+        status,
+        [
+            Some(ProcessStatus::Utkast),
+            Some(ProcessStatus::Klarmarkerad),
+            Some(ProcessStatus::Attesterad),
+            Some(ProcessStatus::Other(7)),
+            None,
+        ],
+    );
+    assert_eq!(ProcessStatus::Klarmarkerad.to_string(), "klarmarkerad");
+}
+
+#[test]
+fn test_utbildningstillfalle_search() {
+    let tillfallen = [
+        Tillfalle::from_integration_id("utbildningstillfalle:ut-1"),
+        Tillfalle::from_integration_id("kt-1"),
+    ];
+    assert_eq!(tillfallen[0], Tillfalle::Utbildning(RoundUid::new("ut-1")));
+    assert_eq!(tillfallen[0].to_string(), "utbildningstillfalle:ut-1");
+    let data = StudieresultatForRapporteringSokVarden::new(&tillfallen, &MomentUid::new("m-1"));
+    let body = serde_json::to_value(&data).unwrap();
+    assert_eq!(body["KurstillfallenUID"], serde_json::json!(["kt-1"]));
+    assert_eq!(
+        body["UtbildningstillfallenUID"],
+        serde_json::json!(["ut-1"])
+    );
+    assert_eq!(body["UtbildningsinstansUID"], "m-1");
+
+    let data =
+        StudieresultatForRapporteringSokVarden::new(&tillfallen[1..], &MomentUid::new("m-1"));
+    let body = serde_json::to_value(&data).unwrap();
+    assert!(body.get("UtbildningstillfallenUID").is_none());
+}
+
+#[test]
+fn test_tolerates_unknown_and_absent_fields() {
+    let resultat: SokresultatStudieresultatResultat = serde_json::from_str(
+        r#"{"TotaltAntalPoster": 2, "NyttFalt": {"x": 1}, "Resultat": [
+            {"Uid": "sr-1", "Student": {"Uid": "st-1", "Fornamn": "Anna"},
+             "Rapporteringskontext": {"BetygsskalaID": 131657, "Ny": true},
+             "ResultatPaUtbildningar": [
+                 {"Arbetsunderlag": {"Uid": "r-1", "Betygsgrad": 131662,
+                                     "UtbildningsinstansUID": "m-1",
+                                     "Okand": [1, 2]}}]},
+            {"Uid": "sr-2", "Student": {"Uid": "st-2"}}]}"#,
+    )
+    .unwrap();
+    let one = resultat.find_student(&StudentUid::new("st-1")).unwrap();
+    assert_eq!(one.get_betygsskala(), "131657".parse().ok());
+    let draft = one.get_arbetsunderlag(&MomentUid::new("m-1")).unwrap();
+    assert_eq!(draft.Uid, Some(ResultatUid::new("r-1")));
+    let two = resultat.find_student(&StudentUid::new("st-2")).unwrap();
+    assert!(two.get_arbetsunderlag(&MomentUid::new("m-1")).is_none());
+
+    let scale: Betygskala = serde_json::from_str(
+        r#"{"ID": 131657, "Kod": "AF", "Giltig": true,
+            "Betygsgrad": [{"ID": 131661, "Kod": "A"}]}"#,
+    )
+    .unwrap();
+    assert_eq!(
+        scale.get("A").map(|g| g.ID),
+        "131661".parse().ok().map(BetygsgradID)
+    );
+    let lista: ResultatLista = serde_json::from_str("{}").unwrap();
+    assert!(lista.Resultat.is_empty());
+}
+
+#[test]
+fn test_komplettering_serialization() {
+    let create = |noteringar| {
+        let mut create: SkapaResultat = serde_json::from_value(serde_json::json!({
+            "Uid": null,
+            "Betygsgrad": 131661,
+            "BetygsskalaID": 131657,
+            "StudieresultatUID": "sr-1",
+        }))
+        .unwrap();
+        create.Noteringar = noteringar;
+        create
+    };
+    let json = serde_json::to_value(create(vec![Notering::komplettering()])).unwrap();
+    assert_eq!(
+        json["Noteringar"],
+        serde_json::json!([{"Text": "Komplettering"}])
+    );
+    let json = serde_json::to_value(create(vec![])).unwrap();
+    assert!(json.get("Noteringar").is_none());
+
+    let resultat: Resultat =
+        serde_json::from_str(r#"{"Uid": "r-1", "Noteringar": [{"Text": "Komplettering"}]}"#)
+            .unwrap();
+    assert!(Notering::has_komplettering(&resultat.Noteringar));
+}
+
+#[test]
+fn test_notering_from_comment() {
+    let note = Notering::from_comment("  Godkänd efter\nmuntlig   komplettering ").unwrap();
+    let mut update: UppdateraResultat = serde_json::from_value(serde_json::json!({
+        "Uid": "sr-1",
+        "Betygsgrad": 131661,
+        "BetygsskalaID": 131657,
+        "ResultatUID": "r-1",
+        "SenasteResultatandring": "2019-05-24T13:14:15.123",
+    }))
+    .unwrap();
+    update.Noteringar = vec![note];
+    assert_eq!(
+        serde_json::to_value(&update).unwrap()["Noteringar"],
+        serde_json::json!([{"Text": "Godkänd efter muntlig komplettering"}]),
+    );
+
+    let long = Notering::from_comment(&"å".repeat(300)).unwrap();
+    assert!(long.Text.chars().count() <= Notering::MAX_CHARS);
+    assert!(long.Text.ends_with("å…"));
+    let fits = "å".repeat(Notering::MAX_CHARS);
+    assert_eq!(Notering::from_comment(&fits).unwrap().Text, fits);
+    assert!(Notering::from_comment(" \n ").is_none());
+}
+
+#[test]
+fn test_notering_merged() {
+    let note = |text: &str| Notering { Text: text.into() };
+    let existing = [note("Komplettering"), note("Från examinator")];
+    assert_eq!(
+        Notering::merged(&existing, false, Some(note("Muntlig"))),
+        [note("Från examinator"), note("Muntlig")],
+    );
+    assert_eq!(Notering::merged(&existing, true, None), existing);
+    assert_eq!(
+        Notering::merged(&existing[1..], true, Some(note("Från examinator"))),
+        [note("Från examinator"), note("Komplettering")],
+    );
 }
@@ -0,0 +1,217 @@
+//! Log output, either for humans or as json lines for a log pipeline.
+use chrono::{SecondsFormat, Utc};
+use failure::{format_err, Error};
+use log::Record;
+use serde_json::json;
+use std::fmt::Display;
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::OnceLock;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    #[default]
+    Human,
+    /// One json object per line.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "human" => Ok(LogFormat::Human),
+            "json" => Ok(LogFormat::Json),
+            s => Err(format_err!(
+                "Unknown log format {:?}, expected human or json",
+                s
+            )),
+        }
+    }
+}
+
+/// How student identifiers appear in logs and error messages.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Pii {
+    #[default]
+    Plain,
+    /// A keyed hash, to tell students apart without identifying them.
+    Hashed,
+    Off,
+}
+
+impl FromStr for Pii {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "plain" => Ok(Pii::Plain),
+            "hashed" => Ok(Pii::Hashed),
+            "off" => Ok(Pii::Off),
+            s => Err(format_err!(
+                "Unknown LOG_PII {:?}, expected plain, hashed or off",
+                s
+            )),
+        }
+    }
+}
+
+static PII: AtomicU8 = AtomicU8::new(Pii::Plain as u8);
+static PII_KEY: OnceLock<Vec<u8>> = OnceLock::new();
+
+/// Set how student identifiers appear.  Hashing requires a secret
+/// `key`, so the hashes cannot be reversed by hashing every student.
+pub fn set_pii(pii: Pii, key: Option<&str>) -> Result<(), Error> {
+    if pii == Pii::Hashed {
+        let key = key
+            .filter(|key| !key.is_empty())
+            .ok_or_else(|| format_err!("LOG_PII=hashed requires LOG_PII_KEY"))?;
+        let _ = PII_KEY.set(key.as_bytes().to_vec());
+    }
+    PII.store(pii as u8, Ordering::Relaxed);
+    Ok(())
+}
+
+fn current() -> Pii {
+    match PII.load(Ordering::Relaxed) {
+        x if x == Pii::Hashed as u8 => Pii::Hashed,
+        x if x == Pii::Off as u8 => Pii::Off,
+        _ => Pii::Plain,
+    }
+}
+
+fn key() -> &'static [u8] {
+    PII_KEY.get().map(Vec::as_slice).unwrap_or_default()
+}
+
+/// A student identifier, as it should appear in logs and error messages.
+pub fn pii(value: impl Display) -> String {
+    redact(current(), key(), &value.to_string())
+}
+
+/// A url, or a text with urls such as an error message, with the
+/// student identifiers in its path and query as LOG_PII says.
+pub fn pii_url(text: &str) -> String {
+    redact_urls(current(), key(), text)
+}
+
+/// True if student identifiers may be logged as is.
+pub fn pii_is_plain() -> bool {
+    PII.load(Ordering::Relaxed) == Pii::Plain as u8
+}
+
+/// The hash is stable for a key, which is enough to follow a student
+/// through the logs of a deployment.
+fn redact(pii: Pii, key: &[u8], value: &str) -> String {
+    match pii {
+        Pii::Plain => value.to_string(),
+        Pii::Hashed => format!("#{}", &crate::hmac::hmac_hex(key, value.as_bytes())[..12]),
+        Pii::Off => "(student)".to_string(),
+    }
+}
+
+/// The parts of Canvas and Ladok urls followed by a student identifier.
+const STUDENT_MARKERS: &[&str] = &["/users/", "/personnummer/", "user_id="];
+
+fn redact_urls(pii: Pii, key: &[u8], text: &str) -> String {
+    if pii == Pii::Plain {
+        return text.to_string();
+    }
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some((at, marker)) = STUDENT_MARKERS
+        .iter()
+        .filter_map(|marker| rest.find(marker).map(|at| (at, marker)))
+        .min()
+    {
+        let start = at + marker.len();
+        let end = rest[start..]
+            .find(|c: char| !(c.is_alphanumeric() || c == '-' || c == ':'))
+            .map_or(rest.len(), |end| start + end);
+        result.push_str(&rest[..start]);
+        if end > start {
+            result.push_str(&redact(pii, key, &rest[start..end]));
+        }
+        rest = &rest[end..];
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Initialize the logger, filtered by `RUST_LOG` as usual.
+pub fn init(format: LogFormat) {
+    let mut builder = env_logger::Builder::from_default_env();
+    if format == LogFormat::Json {
+        builder.format(|buf, record| writeln!(buf, "{}", json_line(record)));
+    }
+    builder.init();
+}
+
+/// A record as a line of json.
+///
+/// The log records carry no structured fields, so a correlation id is
+/// only part of the message, as in the human format.
+fn json_line(record: &Record) -> String {
+    json!({
+        "timestamp": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        "level": record.level().to_string(),
+        "target": record.target(),
+        "message": record.args().to_string(),
+    })
+    .to_string()
+}
+
+#[test]
+fn test_json_lines() {
+    use serde_json::Value;
+    let line = json_line(
+        &Record::builder()
+            .level(log::Level::Warn)
+            .target("report_results_ladok_rs")
+            .args(format_args!("Export {} failed:\n\"quoted\"", "17f-0"))
+            .build(),
+    );
+    assert!(!line.contains('\n'));
+    let value: Value = serde_json::from_str(&line).unwrap();
+    assert_eq!(value["level"], "WARN");
+    assert_eq!(value["target"], "report_results_ladok_rs");
+    assert_eq!(value["message"], "Export 17f-0 failed:\n\"quoted\"");
+    assert!(value["timestamp"].as_str().unwrap().ends_with('Z'));
+    assert!("xml".parse::<LogFormat>().is_err());
+}
+
+#[test]
+fn test_pii_modes() {
+    let uid = "2a7c5a57-5b4a-11e7-a1d0-f2c1c4d35f6a";
+    let key = b"secret";
+    assert_eq!(redact(Pii::Plain, key, uid), uid);
+    let hashed = redact(Pii::Hashed, key, uid);
+    assert_eq!(hashed.len(), 13);
+    assert!(hashed.starts_with('#'));
+    assert!(!hashed.contains("2a7c5a57"));
+    assert_eq!(hashed, redact(Pii::Hashed, key, uid), "Stable");
+    assert_ne!(hashed, redact(Pii::Hashed, key, "other"));
+    assert_ne!(hashed, redact(Pii::Hashed, b"other", uid), "Keyed");
+    assert_eq!(redact(Pii::Off, key, uid), "(student)");
+    assert_eq!("hashed".parse::<Pii>().unwrap(), Pii::Hashed);
+    assert!("secret".parse::<Pii>().is_err());
+    assert!(set_pii(Pii::Hashed, None).is_err());
+    assert!(set_pii(Pii::Hashed, Some("")).is_err());
+}
+
+#[test]
+fn test_pii_in_urls() {
+    let url = "GET https://canvas.example.org/api/v1/courses/17/users/4711?x=1 \
+               https://ladok/studentinformation/student/personnummer/199001011234 \
+               https://canvas.example.org/api/v1/courses/17/enrollments?user_id=self";
+    assert_eq!(redact_urls(Pii::Plain, b"k", url), url);
+    let off = redact_urls(Pii::Off, b"k", url);
+    assert!(off.contains("/courses/17/users/(student)?x=1"));
+    assert!(off.contains("/personnummer/(student) "));
+    assert!(off.contains("enrollments?user_id=(student)"));
+    let hashed = redact_urls(Pii::Hashed, b"k", url);
+    assert!(!hashed.contains("4711"));
+    assert!(!hashed.contains("199001011234"));
+    assert!(hashed.contains(&format!("/users/{}?", redact(Pii::Hashed, b"k", "4711"))));
+}
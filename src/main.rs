@@ -1,59 +1,189 @@
-use chrono::{Duration, Utc};
+use chrono::{DateTime, Duration, Local, NaiveDate, TimeZone, Utc};
 use dotenv::dotenv;
-use failure::{format_err, Error};
+use failure::{format_err, Error, Fail};
 use log::{error, info, warn};
 use reqwest::{Client, Identity};
-use serde::{Deserialize, Serialize};
-use std::collections::{BTreeMap, BTreeSet};
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::env::var;
+use std::fmt;
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration as StdDuration;
 use warp::filters::path::Tail;
 use warp::filters::BoxedFilter;
-use warp::http::{header, Response, StatusCode};
+use warp::http::{header, HeaderMap, Response, StatusCode};
 use warp::reject::custom;
 use warp::{body, get2 as get, path, post2 as post, query, Filter, Rejection, Reply};
 
+mod artifact;
+mod audit;
+mod batch;
+mod bounded;
+mod branding;
 mod canvas;
+mod combine;
+mod commit;
+mod diagnostics;
+mod fair;
+mod hmac;
+mod hosts;
 mod ladok;
-use canvas::{Canvas, Submission, User};
-use ladok::types::{SkapaResultat, SokresultatStudieresultatResultat, UppdateraResultat};
-use ladok::Ladok;
-use templates::RenderRucte;
+mod logging;
+mod mapping;
+#[cfg(test)]
+mod mock_server;
+mod notify;
+mod numeric;
+mod outbound;
+mod ready;
+mod replay;
+mod rubric;
+mod schema;
+mod timing;
+mod undo;
+use artifact::Artifacts;
+use audit::AuditRow;
+use branding::Branding;
+use canvas::{
+    Assignment, AssignmentGroup, Canvas, CourseLookup, CourseRoom, CourseSection, Enrollment,
+    RateLimit, Submission, User,
+};
+use combine::CombineRule;
+use commit::{Change, CommitOrder, Request};
+use fair::FairQueue;
+use generated::templates::{self, RenderRucte};
+use hosts::HostAllowlist;
+use ladok::types::{
+    personnummer_digits, Betygsgrad, BetygsgradID, BetygsskalaID, Giltighetsperiod, LarosateID,
+    MomentUid, Notering, ProcessStatus, Resultat, ResultatFel, RoundUid, SkapaResultat,
+    SokresultatStudieresultatResultat, StudentUid, Studieresultat, Tillfalle, UppdateraResultat,
+};
+use ladok::{CircuitBreaker, Ladok, Rules, ScaleCache, Target, Targets, Throttle};
+use logging::{pii, pii_is_plain, pii_url, LogFormat, Pii};
+use mapping::MomentMapping;
+use notify::Notifier;
+use numeric::{NumericGrades, NumericScale};
+use outbound::ExtraHeaders;
+use ready::Readiness;
+use replay::UsedCodes;
+use rubric::RubricGrades;
+use timing::Phase;
+use undo::{UndoLog, UndoRecord};
 
 fn main() -> Result<(), Error> {
     let _ = dotenv();
-    env_logger::init();
+    logging::init(var_or("LOG_FORMAT", LogFormat::default())?);
+    logging::set_pii(
+        var_or("LOG_PII", Pii::default())?,
+        var("LOG_PII_KEY").ok().as_deref(),
+    )?;
+    if std::env::args().any(|arg| arg == "--check-config") {
+        let ping = std::env::args().any(|arg| arg == "--ping");
+        std::process::exit(if ServerContext::check_config(ping) {
+            0
+        } else {
+            1
+        });
+    }
     let context = Arc::new(ServerContext::from_env()?);
-    let ctx: BoxedFilter<(Arc<ServerContext>,)> = warp::any()
-        .and_then(move || Ok::<_, Error>(context.clone()).map_err(custom))
-        .boxed();
-    let routes = warp::any()
-        .and(path("api"))
-        .and(path(env!("CARGO_PKG_NAME")))
-        .and(
-            path("_about")
+    timing::set_slow_threshold(StdDuration::from_millis(var_or("SLOW_CALL_MS", 5000)?));
+    bounded::set_max_body_size(var_or("MAX_RESPONSE_BYTES", 64 * 1024 * 1024)?);
+    let preload = preload_betygskalor()?;
+    for (_, target) in context.ladok.all() {
+        target.client().preload_betygskalor(&preload);
+    }
+    {
+        let ctx = context.clone();
+        let scale = ready_betygsskala(var("READY_BETYGSSKALA").ok().as_deref(), &preload)?;
+        let interval = StdDuration::from_secs(var_or("READY_PROBE_INTERVAL", 10)?);
+        std::thread::spawn(move || {
+            ctx.readiness.probe_until_ready(interval, || {
+                ctx.ladok.all().try_for_each(|(name, target)| {
+                    target
+                        .client()
+                        .ping(scale)
+                        .map_err(|e| format_err!("{}: {}", name, e))
+                })
+            })
+        });
+    }
+    let ctx = {
+        let context = context.clone();
+        warp::any()
+            .and_then(move || Ok::<_, Error>(context.clone()).map_err(custom))
+            .boxed()
+    };
+    let routes = path_prefix(&context.route_prefix).and(
+        path("_about")
+            .and(get())
+            .and(ctx.clone())
+            .map(about)
+            .or(path("_monitor").and(get()).and(ctx.clone()).map(monitor))
+            .or(path("_ready").and(get()).and(ctx.clone()).map(ready))
+            .or(path("_schema").and(get()).and(ctx.clone()).map(api_schema))
+            .or(path("s").and(path::tail()).and_then(static_file))
+            .or(path("export")
+                .and(post())
+                .and(ctx.clone())
+                .and(body::form())
+                .map(export_step_1))
+            .or(path("export2")
                 .and(get())
                 .and(ctx.clone())
-                .map(about)
-                .or(path("_monitor").and(get()).map(monitor))
-                .or(path("s").and(path::tail()).and_then(static_file))
-                .or(path("export")
-                    .and(post())
-                    .and(ctx.clone())
-                    .and(body::form())
-                    .map(export_step_1))
-                .or(path("export2")
-                    .and(get())
-                    .and(ctx.clone())
-                    .and(query())
-                    .map(export_step_2))
-                .or(path("export3")
-                    .and(post())
-                    .and(ctx.clone())
-                    .and(body::form())
-                    .map(export_step_3)),
-        );
+                .and(query())
+                .map(export_step_2))
+            .or(path("export3")
+                .and(post())
+                .and(ctx.clone())
+                .and(body::form())
+                .map(export_step_3))
+            .or(path("undo")
+                .and(post())
+                .and(ctx.clone())
+                .and(body::form())
+                .map(undo_export))
+            .or(path("_ladok_results")
+                .and(get())
+                .and(ctx.clone())
+                .and(warp::header::optional("authorization"))
+                .and(query())
+                .map(ladok_results))
+            .or(path("_cache")
+                .and(path("clear"))
+                .and(post())
+                .and(ctx.clone())
+                .and(warp::header::optional("authorization"))
+                .map(clear_cache))
+            .or(path("_diagnostics")
+                .and(get())
+                .and(ctx.clone())
+                .and(warp::header::optional("authorization"))
+                .and(query())
+                .map(course_diagnostics))
+            .or(path("_grade_mapping")
+                .and(post())
+                .and(ctx.clone())
+                .and(warp::header::optional("authorization"))
+                .and(body::json())
+                .map(grade_mapping))
+            .or(path("_student")
+                .and(post())
+                .and(ctx.clone())
+                .and(warp::header::optional("authorization"))
+                .and(body::json())
+                .map(report_student))
+            .or(path("_batch")
+                .and(post())
+                .and(ctx.clone())
+                .and(warp::header::optional("authorization"))
+                .and(query())
+                .and(body::content_length_limit(1024 * 1024))
+                .and(body::concat())
+                .map(batch::report_courses)),
+    );
 
     let addr = var("LISTEN")
         .as_ref()
@@ -64,6 +194,19 @@ fn main() -> Result<(), Error> {
     Ok(())
 }
 
+/// A filter for the segments of a path prefix, e.g. "api/report-results-ladok-rs".
+fn path_prefix(prefix: &str) -> BoxedFilter<()> {
+    prefix
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .fold(warp::any().boxed(), |filter, segment| {
+            // A path filter needs a static str, the prefix is only
+            // read once at startup.
+            let segment: &'static str = Box::leak(segment.to_string().into_boxed_str());
+            filter.and(path(segment)).boxed()
+        })
+}
+
 /// Handler for static files.
 /// Create a response from the file data with a correct content type
 /// and a far expires header (or a 404 if the file does not exist).
@@ -86,24 +229,192 @@ struct ServerContext {
     canvas_host: String, // hostname
     canvas_client_id: String,
     canvas_client_secret: String,
-    ladok_base_url: String,
-    ladok_key_data: Vec<u8>,
-    ladok_key_pass: String,
     proxy_base: String,
+    /// The path of the api, without leading or trailing slash.
+    route_prefix: String,
+    /// Shared by all Canvas requests, to make use of connection pooling.
+    canvas_http: Client,
+    canvas_rate_limit: RateLimit,
+    /// How to find course rooms, unless given in the request.
+    canvas_course_lookup: CourseLookup,
+    /// The scopes to ask for when authorizing, if limited.
+    canvas_scopes: Option<String>,
+    /// The scopes to ask for if a token lacks a needed scope.  None
+    /// for all scopes the developer key allows.
+    canvas_upgrade_scopes: Option<String>,
+    /// The Ladok environments to report to.
+    ladok: Targets,
+    /// Token required for administrative requests, if enabled.
+    service_token: Option<String>,
+    /// Token for support and audit staff, only allowed on requests
+    /// that do not write to Ladok.
+    audit_token: Option<String>,
+    /// Canvas access token used for batch reports.
+    canvas_service_token: Option<String>,
+    /// Exports of more students than this must be confirmed.
+    max_students: Option<usize>,
+    /// Max number of course rooms reported at once in a batch.
+    batch_concurrency: usize,
+    /// Bounds the requests to Canvas and Ladok running at once, shared
+    /// fairly between course rooms.
+    upstream_queue: Arc<FairQueue>,
+    report: ReportConfig,
+    /// For undoing recent exports.
+    undo_log: UndoLog,
+    readiness: Readiness,
+    branding: Branding,
+    notifier: Notifier,
+    /// Where to keep the result of each export, if anywhere.
+    artifacts: Option<Artifacts>,
+    /// Recently used OAuth codes, to detect replays.
+    used_codes: UsedCodes,
 }
 
 impl ServerContext {
     fn from_env() -> Result<ServerContext, Error> {
+        let max_idle = var_or("HTTP_MAX_IDLE_PER_HOST", 16)?;
+        ladok::check_min_tls(&var_or("LADOK_TLS_MIN_VERSION", "1.2".to_string())?)?;
+        let allowed_hosts = var_or("ALLOWED_HOSTS", HostAllowlist::default())?;
+        let canvas_host = var2("CANVAS_HOST")?;
+        allowed_hosts.check_host("CANVAS_HOST", &canvas_host)?;
+        let contact = var("USER_AGENT_CONTACT").ok();
+        let canvas_headers = outbound::headers(
+            contact.as_deref(),
+            &var_or("CANVAS_EXTRA_HEADERS", ExtraHeaders::default())?,
+        )?;
+        let ladok_headers = outbound::headers(
+            contact.as_deref(),
+            &var_or("LADOK_EXTRA_HEADERS", ExtraHeaders::default())?,
+        )?;
+        let notify_webhook = var("NOTIFY_WEBHOOK_URL").ok();
+        if let Some(url) = &notify_webhook {
+            allowed_hosts.check_url("NOTIFY_WEBHOOK_URL", url)?;
+        }
+        let notify_headers = outbound::headers(
+            contact.as_deref(),
+            &var_or("NOTIFY_EXTRA_HEADERS", ExtraHeaders::default())?,
+        )?;
+        let named = var("LADOK_TARGETS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(|name| {
+                let suffix = format!("_{}", name.to_uppercase());
+                Ok((
+                    name.to_string(),
+                    ladok_target(&suffix, max_idle, &allowed_hosts, &ladok_headers)?,
+                ))
+            })
+            .collect::<Result<_, Error>>()?;
         Ok(ServerContext {
-            canvas_host: var2("CANVAS_HOST")?,
+            canvas_host,
             canvas_client_id: var2("CANVAS_CLIENT_ID")?,
             canvas_client_secret: var2("CANVAS_CLIENT_SECRET")?,
-            ladok_base_url: var2("LADOK_API_BASEURL")?,
-            ladok_key_data: base64::decode(&var2("LADOK_API_PFX_BASE64")?)?,
-            ladok_key_pass: var2("LADOK_API_PFX_PASSPHRASE")?,
             proxy_base: var2("PROXY_BASE")?,
+            route_prefix: var("ROUTE_PREFIX")
+                .unwrap_or_else(|_| concat!("api/", env!("CARGO_PKG_NAME")).into())
+                .trim_matches('/')
+                .into(),
+            canvas_http: canvas::http_client(max_idle, canvas_headers)?,
+            canvas_rate_limit: RateLimit {
+                threshold: var_or(
+                    "CANVAS_RATE_LIMIT_THRESHOLD",
+                    RateLimit::default().threshold,
+                )?,
+                pause: StdDuration::from_millis(var_or("CANVAS_RATE_LIMIT_PAUSE_MS", 1000)?),
+            },
+            canvas_course_lookup: var_or("CANVAS_COURSE_LOOKUP", CourseLookup::default())?,
+            canvas_scopes: var("CANVAS_SCOPES").ok(),
+            canvas_upgrade_scopes: var("CANVAS_UPGRADE_SCOPES").ok(),
+            ladok: Targets::new(
+                ladok_target("", max_idle, &allowed_hosts, &ladok_headers)?,
+                named,
+                var_or("LADOK_TARGET_RULES", Rules::default())?,
+            )?,
+            service_token: var("SERVICE_TOKEN").ok(),
+            audit_token: var("AUDIT_TOKEN").ok(),
+            canvas_service_token: var("CANVAS_SERVICE_TOKEN").ok(),
+            max_students: match var("LADOK_MAX_STUDENTS") {
+                Ok(max) => Some(
+                    max.parse()
+                        .map_err(|e| format_err!("LADOK_MAX_STUDENTS: {}", e))?,
+                ),
+                Err(_) => None,
+            },
+            batch_concurrency: var_or("BATCH_CONCURRENCY", 4)?,
+            upstream_queue: Arc::new(FairQueue::new(var_or("UPSTREAM_CONCURRENCY", 16)?)),
+            report: ReportConfig::from_env()?,
+            undo_log: UndoLog::new(var_or("UNDO_LOG_SIZE", 100)?),
+            readiness: Readiness::default(),
+            branding: Branding::from_env(),
+            notifier: Notifier::new(notify_webhook, notify_headers)?,
+            artifacts: match var("EXPORT_ARTIFACT_DIR") {
+                Ok(dir) => Some(Artifacts::new(
+                    dir,
+                    Duration::days(var_or("EXPORT_ARTIFACT_RETENTION_DAYS", 30)?),
+                )),
+                Err(_) => None,
+            },
+            used_codes: UsedCodes::new(StdDuration::from_secs(var_or("OAUTH_CODE_TTL", 600)?)),
         })
     }
+    /// Notify about a finished export and keep its result, best effort.
+    fn finished(&self, sis_course_id: &str, result: &Result<ExportResults, Error>) {
+        let correlation_id = result.as_ref().ok().map(|r| r.correlation_id.as_str());
+        self.notifier.notify(
+            correlation_id,
+            &batch::CourseOutcome::new(sis_course_id, result.as_ref()),
+        );
+        if let Some(artifacts) = &self.artifacts {
+            if let Err(e) = keep_artifact(artifacts, sis_course_id, result, Utc::now()) {
+                warn!("Failed to keep the result of an export: {}", e);
+            }
+        }
+    }
+    /// Check the configuration, and if `ping` is true, that Canvas and
+    /// Ladok can be reached.  Print a report and return true if all is ok.
+    fn check_config(ping: bool) -> bool {
+        let mut ok = true;
+        let mut report = |what: &str, result: Result<String, Error>| match result {
+            Ok(msg) => println!("ok    {}: {}", what, msg),
+            Err(e) => {
+                println!("FAIL  {}: {}", what, e);
+                ok = false;
+            }
+        };
+        report(
+            "Ladok identity",
+            var2("LADOK_API_PFX_BASE64")
+                .and_then(|pfx| ladok_identity(&pfx, &var2("LADOK_API_PFX_PASSPHRASE")?))
+                .map(|_| "loaded".into()),
+        );
+        report(
+            "Readiness scale",
+            preload_betygskalor()
+                .and_then(|preload| {
+                    ready_betygsskala(var("READY_BETYGSSKALA").ok().as_deref(), &preload)
+                })
+                .map(|id| id.to_string()),
+        );
+        match ServerContext::from_env() {
+            Ok(ctx) => {
+                report("Configuration", Ok("complete".into()));
+                if ping {
+                    for (name, target) in ctx.ladok.all() {
+                        report(
+                            &format!("Ladok ({})", name),
+                            http_ping(&target.http, &target.base_url),
+                        );
+                    }
+                    let canvas_url = format!("https://{}/api/v1/", ctx.canvas_host);
+                    report("Canvas", http_ping(&ctx.canvas_http, &canvas_url));
+                }
+            }
+            Err(e) => report("Configuration", Err(e)),
+        }
+        ok
+    }
     fn auth_canvas_client(&self, code: &str) -> Result<Canvas, Error> {
         #[derive(Serialize)]
         struct OathRequest<'a> {
@@ -112,8 +423,9 @@ impl ServerContext {
             client_secret: &'a str,
             redirect_uri: &'a str,
             code: &'a str,
-        };
+        }
         #[derive(Debug, Deserialize)]
+        #[allow(dead_code)] // Only used for logging.
         struct CanvasUser {
             id: u32,
             name: String,
@@ -126,8 +438,8 @@ impl ServerContext {
             user: CanvasUser,
             // ignoring token_type, refresh_token and expires_in for now.
         }
-        let oauth = Client::builder()
-            .build()?
+        let oauth = self
+            .canvas_http
             .post(&format!("https://{}/login/oauth2/token", self.canvas_host))
             .json(&OathRequest {
                 grant_type: "authorization_code",
@@ -141,397 +453,6260 @@ impl ServerContext {
             .error_for_status()?
             .json::<OathResponse>()?;
         info!("Got access token for {:?}", oauth.user);
-        Canvas::new(&self.canvas_host, &oauth.access_token)
-    }
-    fn canvas_by_access_token(&self, access_token: &str) -> Result<Canvas, Error> {
-        Canvas::new(&self.canvas_host, access_token)
-    }
-    fn get_oath_url(&self, next_url: &str) -> String {
-        format!(
-            "https://{}/login/oauth2/auth?{}",
-            self.canvas_host,
-            serde_urlencoded::to_string(&[
-                ("client_id", self.canvas_client_id.as_str()),
-                ("response_type", "code"),
-                ("redirect_uri", &next_url),
-            ])
-            .unwrap(),
+        Ok(self.canvas_by_access_token(&oauth.access_token))
+    }
+    fn canvas_by_access_token(&self, access_token: &str) -> Canvas {
+        Canvas::new(
+            &self.canvas_host,
+            access_token,
+            self.canvas_http.clone(),
+            self.canvas_rate_limit,
         )
     }
+    /// A Canvas client for exporting a course room, waiting its turn
+    /// for each request.
+    fn canvas_for_export(&self, canvas: Canvas, sis_course_id: &str) -> Canvas {
+        canvas.queued(self.upstream_queue.clone(), sis_course_id)
+    }
+    /// A Canvas client for a course room, found by numeric id if so
+    /// configured and the id is known.  A malformed id is an error,
+    /// since it would go into the path of Canvas urls.
+    fn canvas_for_course(
+        &self,
+        canvas: Canvas,
+        canvas_course_id: Option<&str>,
+    ) -> Result<Canvas, Error> {
+        check_canvas_course_id(canvas_course_id)?;
+        Ok(match (self.canvas_course_lookup, canvas_course_id) {
+            (CourseLookup::Id, Some(id)) => canvas.with_course_id(id),
+            _ => canvas,
+        })
+    }
+    /// Check that a course room found by numeric id has the posted
+    /// sis course id, since that selects the Ladok environment, the
+    /// moments and the undo record of the export.
+    fn check_course_room(&self, canvas: &Canvas, sis_course_id: &str) -> Result<(), Error> {
+        if self.canvas_course_lookup != CourseLookup::Id {
+            return Ok(());
+        }
+        let course = canvas.get_course(sis_course_id)?;
+        check_sis_course_id(&course, sis_course_id)
+    }
+    fn get_oath_url(&self, next_url: &str, scope: Option<&str>) -> String {
+        oauth_url(&self.canvas_host, &self.canvas_client_id, next_url, scope)
+    }
     fn main_url(&self) -> String {
-        format!("{}/api/{}/export", self.proxy_base, env!("CARGO_PKG_NAME"))
+        format!("{}/{}/export", self.proxy_base, self.route_prefix)
     }
-    fn ladok_client(&self) -> Result<Ladok, Error> {
-        Ladok::new(
-            &self.ladok_base_url,
-            Identity::from_pkcs12_der(&self.ladok_key_data, &self.ladok_key_pass)?,
-        )
+    /// Check the authorization header of an administrative request
+    /// for `what`.  Gives the error response if it lacks `needed`.
+    fn refuse(
+        &self,
+        authorization: Option<&str>,
+        needed: TokenScope,
+        what: &str,
+    ) -> Option<Response<Vec<u8>>> {
+        let scope = token_scope(
+            self.service_token.as_deref(),
+            self.audit_token.as_deref(),
+            authorization,
+        );
+        check_scope(scope, needed).err().map(|status| {
+            warn!("{} requested without a valid {:?} token", what, needed);
+            let message = if status == StatusCode::FORBIDDEN {
+                "The audit token may not write to Ladok"
+            } else {
+                "Service token required"
+            };
+            batch::json_response(status, &message)
+        })
+    }
+    /// A Ladok client for the environment of a course room, or the
+    /// default environment if None.
+    fn ladok_client(&self, sis_course_id: Option<&str>) -> Ladok {
+        let (target, key) = match sis_course_id {
+            Some(sis_course_id) => (self.ladok.for_course(sis_course_id), sis_course_id),
+            None => (self.ladok.default_target(), ""),
+        };
+        target.client().queued(self.upstream_queue.clone(), key)
     }
 }
 
-fn var2(name: &str) -> Result<String, Error> {
-    var(name).map_err(|e| format_err!("{}: {}", name, e))
-}
-
-fn about(ctx: Arc<ServerContext>) -> impl Reply {
-    Response::builder()
-        .html(|o| templates::about(o, &ctx.canvas_host, &ctx.ladok_base_url))
-        .unwrap()
+/// A Ladok environment, configured by the variables with `suffix`
+/// added to their names.
+fn ladok_target(
+    suffix: &str,
+    max_idle: usize,
+    allowed_hosts: &HostAllowlist,
+    headers: &HeaderMap,
+) -> Result<Target, Error> {
+    let base_url_var = format!("LADOK_API_BASEURL{}", suffix);
+    let base_url = var2(&base_url_var)?;
+    allowed_hosts.check_url(&base_url_var, &base_url)?;
+    let identity = ladok_identity(
+        &var2(&format!("LADOK_API_PFX_BASE64{}", suffix))?,
+        &var2(&format!("LADOK_API_PFX_PASSPHRASE{}", suffix))?,
+    )?;
+    Ok(Target {
+        base_url,
+        http: ladok::http_client(
+            Some(identity),
+            max_idle,
+            var_or("LADOK_HTTP2_PRIOR_KNOWLEDGE", false)?,
+            headers.clone(),
+        )?,
+        breaker: Arc::new(CircuitBreaker::new(
+            var_or("LADOK_BREAKER_THRESHOLD", 5)?,
+            StdDuration::from_secs(var_or("LADOK_BREAKER_WINDOW", 60)?),
+            StdDuration::from_secs(var_or("LADOK_BREAKER_COOLDOWN", 30)?),
+        )),
+        throttle: Arc::new(Throttle::new(
+            var_or("LADOK_WRITES_PER_SECOND", 0.)?,
+            var_or("LADOK_WRITE_BURST", 1)?,
+        )),
+        scales: Arc::new(ScaleCache::default()),
+        larosate: var_or("LADOK_LAROSATE_ID", LarosateID::KTH)?,
+    })
 }
 
-fn monitor() -> impl Reply {
-    format!(
-        "APPLICATION_STATUS: {} {}-{}",
-        "OK",
-        env!("CARGO_PKG_NAME"),
-        env!("CARGO_PKG_VERSION"),
-    )
+/// Load the Ladok client identity from a base64-encoded pkcs12 file.
+fn ladok_identity(pfx_base64: &str, passphrase: &str) -> Result<Identity, Error> {
+    let data = base64::decode(pfx_base64)
+        .map_err(|e| format_err!("LADOK_API_PFX_BASE64 is not valid base64: {}", e))?;
+    Identity::from_pkcs12_der(&data, passphrase).map_err(|e| {
+        format_err!(
+            "Failed to load Ladok identity (check LADOK_API_PFX_PASSPHRASE): {}",
+            e
+        )
+    })
 }
 
-fn export_step_1(ctx: Arc<ServerContext>, b: ExportPostData) -> impl Reply {
-    // const correlationId = req.id;
-    eprintln!("Export request posted: {:?}", b);
-    let sis_course_id = b.lis_course_offering_sourcedid;
-    let canvas_course_id = b.custom_canvas_course_id;
-    let next_url = format!(
-        "{}2?{}",
-        ctx.main_url(),
-        serde_urlencoded::to_string(QueryArgs {
-            canvasCourseId: Some(canvas_course_id),
-            error: None,
-            code: b.code,
-            sisCourseId: sis_course_id,
-        })
-        .unwrap(),
-    );
-    info!(
-        "Tell auth to redirect back to {} using canvas client id {}",
-        next_url, ctx.canvas_client_id,
-    );
-
-    let basic_url = ctx.get_oath_url(&next_url);
-    Response::builder()
-        .status(StatusCode::FOUND)
-        .header(header::LOCATION, basic_url.clone())
-        .body(format!("Please refer to {}", basic_url).into_bytes())
+/// Check that a server responds, with anything but a server error.
+fn http_ping(client: &Client, url: &str) -> Result<String, Error> {
+    let response = client.get(url).send()?;
+    if response.status().is_server_error() {
+        Err(format_err!("{} responded {}", url, response.status()))
+    } else {
+        Ok(format!("{} responded {}", url, response.status()))
+    }
 }
 
-#[derive(Debug, Deserialize)]
-struct ExportPostData {
-    lis_course_offering_sourcedid: String,
-    custom_canvas_course_id: String,
-    code: Option<String>,
+/// Configuration of how results are reported.
+#[derive(Debug)]
+struct ReportConfig {
+    /// Moments for assignments lacking an integration_id.
+    moment_mapping: MomentMapping,
+    /// For moments that only allow a subset of the grades in their
+    /// scale, the allowed grade codes.  Ladok does not give the subset
+    /// in the rapporteringskontext nor the utbildningsinstans, so it
+    /// is configured, from `MOMENT_GRADES_FILE`.
+    moment_grades: BTreeMap<String, Vec<String>>,
+    /// Moments of final grades, besides the kursinstans itself.
+    final_moments: BTreeSet<String>,
+    /// Moments that permit a failed result to be complemented.
+    komplettering_moments: BTreeSet<String>,
+    /// Marks a Canvas grade as open for komplettering, e.g. `F+komp`.
+    komplettering_suffix: String,
+    /// Marks a Canvas submission comment to be sent as a note on the
+    /// result in Ladok, if enabled.
+    note_prefix: Option<String>,
+    /// Grades from rubric totals, for rubric-graded assignments.
+    rubric_grades: RubricGrades,
+    /// Ladok grade codes for numeric Canvas grading schemes.
+    numeric_grades: NumericGrades,
+    /// Times to retry looking up a student missing an integration_id.
+    uid_retries: u32,
+    /// Report grades on submissions Canvas flags as late.
+    report_late: bool,
+    /// Hold submissions more than this many days late for review.
+    late_review_days: Option<f64>,
+    /// Report grades on submissions Canvas flags as missing.
+    report_missing: bool,
+    /// Report grades that are not yet posted to the student.
+    report_unposted: bool,
+    /// Skip grades given before the submission they grade.
+    check_timestamps: bool,
+    /// Report the old grade of submissions resubmitted since grading.
+    report_resubmitted: bool,
+    /// Skip students that have not registered on the round.
+    skip_unregistered: bool,
+    /// Skip students whose results belong to another lärosäte, rather
+    /// than writing them as ours.
+    skip_foreign: bool,
+    /// Use the due date of the assignment as examination date for
+    /// submissions lacking graded_at.
+    due_date_fallback: bool,
+    /// Use the current date as examination date for submissions
+    /// lacking graded_at, and lacking a due date if that is used.
+    today_fallback: bool,
+    /// What to do with examination dates outside the moment's period.
+    exam_date_window: ExamDateWindow,
+    /// How many days into the future an examination date may be.
+    exam_date_max_future_days: i64,
+    /// How to pick the grade when several assignments map to one moment.
+    combine: CombineRule,
+    /// The order of writes to Ladok.
+    commit_order: CommitOrder,
+    /// Write would-be Ladok requests to files here instead of sending them.
+    dump_dir: Option<PathBuf>,
+    /// Klarmarkera and attest the written results.  This is irreversible!
+    attest: bool,
+    /// Let teachers choose to report and attest in one export, after
+    /// confirming it.
+    attest_mode: bool,
+    /// Fetch and write results this many students at a time, rather
+    /// than all students of a moment at once.
+    window_size: Option<u32>,
+    /// Write nothing unless all students can be reported.
+    all_or_nothing: bool,
 }
 
-fn export_step_2(ctx: Arc<ServerContext>, query: QueryArgs) -> impl Reply {
-    let canvas = match ctx.auth_canvas_client(query.code.as_ref().unwrap()) {
-        Ok(client) => client,
-        Err(e) => {
-            warn!("The access token cannot be retrieved from Canvas: {}", e);
-            return access_denied();
+impl ReportConfig {
+    fn from_env() -> Result<ReportConfig, Error> {
+        ReportConfig {
+            moment_mapping: {
+                let mapping = match var("MOMENT_MAPPING_FILE") {
+                    Ok(path) => MomentMapping::load(&path)?,
+                    Err(_) => MomentMapping::default(),
+                };
+                match var("MOMENT_NAME_PATTERN") {
+                    Ok(pattern) => mapping
+                        .with_name_pattern(&pattern, var("MOMENT_CODES_FILE").ok().as_deref())?,
+                    Err(_) => mapping,
+                }
+            },
+            moment_grades: match var("MOMENT_GRADES_FILE") {
+                Ok(path) => serde_json::from_reader(
+                    std::fs::File::open(&path).map_err(|e| format_err!("{}: {}", path, e))?,
+                )
+                .map_err(|e| format_err!("{}: {}", path, e))?,
+                Err(_) => BTreeMap::new(),
+            },
+            final_moments: var("LADOK_FINAL_MOMENTS")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|moment| !moment.is_empty())
+                .map(String::from)
+                .collect(),
+            komplettering_moments: var("LADOK_KOMPLETTERING_MOMENTS")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|moment| !moment.is_empty())
+                .map(String::from)
+                .collect(),
+            komplettering_suffix: var_or("KOMPLETTERING_SUFFIX", "+komp".to_string())?,
+            note_prefix: var("LADOK_NOTE_PREFIX").ok().filter(|p| !p.is_empty()),
+            rubric_grades: match var("RUBRIC_GRADES_FILE") {
+                Ok(path) => RubricGrades::load(&path)?,
+                Err(_) => RubricGrades::default(),
+            },
+            numeric_grades: match var("NUMERIC_GRADES_FILE") {
+                Ok(path) => NumericGrades::load(&path)?,
+                Err(_) => NumericGrades::default(),
+            },
+            uid_retries: var_or("UID_LOOKUP_RETRIES", 2)?,
+            report_late: var_or("REPORT_LATE", true)?,
+            late_review_days: var("LATE_REVIEW_DAYS")
+                .ok()
+                .map(|s| s.parse())
+                .transpose()
+                .map_err(|e| format_err!("LATE_REVIEW_DAYS: {}", e))?,
+            report_missing: var_or("REPORT_MISSING", false)?,
+            report_unposted: var_or("REPORT_UNPOSTED", false)?,
+            check_timestamps: var_or("CHECK_GRADE_TIMESTAMPS", false)?,
+            report_resubmitted: var_or("REPORT_RESUBMITTED", false)?,
+            skip_unregistered: var_or("SKIP_UNREGISTERED", false)?,
+            skip_foreign: var_or("SKIP_FOREIGN_LAROSATE", true)?,
+            due_date_fallback: var_or("EXAM_DATE_FROM_DUE_DATE", false)?,
+            today_fallback: var_or("EXAM_DATE_TODAY", false)?,
+            exam_date_window: var_or("EXAM_DATE_WINDOW", ExamDateWindow::default())?,
+            exam_date_max_future_days: var_or("EXAM_DATE_MAX_FUTURE_DAYS", 0)?,
+            combine: var_or("MOMENT_COMBINE", CombineRule::default())?,
+            commit_order: var_or("COMMIT_ORDER", CommitOrder::default())?,
+            dump_dir: var("LADOK_DUMP_DIR").ok().map(PathBuf::from),
+            attest: var_or("LADOK_ATTEST", false)?,
+            attest_mode: var_or("LADOK_ATTEST_MODE", false)?,
+            window_size: Some(var_or("LADOK_WINDOW_SIZE", 0)?).filter(|size| *size > 0),
+            all_or_nothing: var_or("LADOK_ALL_OR_NOTHING", false)?,
         }
-    };
+        .checked()
+    }
 
-    let modules = match canvas.get_assignments(&query.sisCourseId) {
-        Ok(m) => m,
-        Err(e) => {
-            warn!(
-                "Failed to get assignments for {:?}: {}",
-                query.sisCourseId, e,
-            );
-            return bad_request("Failed to get assignments for course room");
+    fn checked(self) -> Result<ReportConfig, Error> {
+        if self.all_or_nothing && self.window_size.is_some() {
+            return Err(format_err!(
+                "LADOK_ALL_OR_NOTHING cannot be combined with LADOK_WINDOW_SIZE"
+            ));
         }
-    };
-    let modules = modules
-        .into_iter()
-        .filter_map(|a| {
-            if let Some(i_id) = a.integration_id {
-                Some((a.name.unwrap_or_else(|| "(unknown)".into()), a.id, i_id))
-            } else {
-                None
-            }
-        })
-        .collect::<Vec<_>>();
-
-    if query.canvasCourseId.is_none() {
-        warn!("/export2 accessed with missing parameters. Ignoring the request...");
-        return bad_request("The URL you are accessing needs extra parameters, please check it. If you came here by a link, inform us about this error.");
+        Ok(self)
     }
+}
 
-    if let Some(error) = query.error {
-        if error == "access_denied" {
-            warn!("/export2 accessed without giving permission. Ignoring the request...");
-            return access_denied();
+impl Default for ReportConfig {
+    fn default() -> Self {
+        ReportConfig {
+            moment_mapping: MomentMapping::default(),
+            moment_grades: BTreeMap::new(),
+            final_moments: BTreeSet::new(),
+            komplettering_moments: BTreeSet::new(),
+            komplettering_suffix: "+komp".into(),
+            note_prefix: None,
+            rubric_grades: RubricGrades::default(),
+            numeric_grades: NumericGrades::default(),
+            uid_retries: 2,
+            report_late: true,
+            late_review_days: None,
+            report_missing: false,
+            report_unposted: false,
+            check_timestamps: false,
+            report_resubmitted: false,
+            skip_unregistered: false,
+            skip_foreign: true,
+            due_date_fallback: false,
+            today_fallback: false,
+            exam_date_window: ExamDateWindow::default(),
+            exam_date_max_future_days: 0,
+            combine: CombineRule::default(),
+            commit_order: CommitOrder::default(),
+            dump_dir: None,
+            attest: false,
+            attest_mode: false,
+            window_size: None,
+            all_or_nothing: false,
         }
-        error!("/export2 accessed with an unexpected 'error' parameter which value is: {:?}. Ignoring the request...", error);
-        return bad_request("An error ocurred. Please try it later.");
     }
+}
 
-    if query.code.is_none() {
-        warn!("/export2 accessed without authorization code. Ignoring the request...");
-        return bad_request("Access denied. You need to authorize this app to use it");
+fn var2(name: &str) -> Result<String, Error> {
+    var(name).map_err(|e| format_err!("{}: {}", name, e))
+}
+
+/// Get an optional variable from the environment, or `default` if unset.
+fn var_or<T>(name: &str, default: T) -> Result<T, Error>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    match var(name) {
+        Ok(value) => value.parse().map_err(|e| format_err!("{}: {}", name, e)),
+        Err(_) => Ok(default),
     }
+}
 
+fn about(ctx: Arc<ServerContext>) -> impl Reply {
     Response::builder()
         .html(|o| {
-            templates::collecting(
+            templates::about(
                 o,
-                canvas.get_auth_key(),
-                &query.canvasCourseId.unwrap(),
-                &query.sisCourseId,
-                &modules,
+                &ctx.branding,
+                &ctx.canvas_host,
+                &ctx.ladok.default_target().base_url,
             )
         })
         .unwrap()
 }
 
-fn bad_request(message: &str) -> Response<Vec<u8>> {
-    Response::builder()
-        .status(StatusCode::BAD_REQUEST)
-        .html(|o| templates::error(o, StatusCode::BAD_REQUEST, message))
-        .unwrap()
+fn monitor(ctx: Arc<ServerContext>) -> impl Reply {
+    format!(
+        "APPLICATION_STATUS: {} {}-{}\nLADOK_CIRCUIT: {}\nUPSTREAM_QUEUE_DEPTH: {}\n",
+        "OK",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        ctx.ladok.default_target().breaker.state(),
+        ctx.upstream_queue.depth(),
+    )
 }
 
-fn access_denied() -> Response<Vec<u8>> {
-    let status = StatusCode::UNAUTHORIZED;
-    let msg = "You should launch this application from a Canvas course";
-    Response::builder()
-        .status(status)
-        .html(|o| templates::error(o, status, msg))
-        .unwrap()
+/// Readiness probe, ok only when every Ladok environment can be
+/// reached.
+fn ready(ctx: Arc<ServerContext>) -> impl Reply {
+    let breakers = ctx
+        .ladok
+        .all()
+        .map(|(_, target)| &*target.breaker)
+        .collect::<Vec<_>>();
+    ctx.readiness.response(&breakers)
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-#[allow(non_snake_case)]
-struct QueryArgs {
-    canvasCourseId: Option<String>,
-    error: Option<String>,
-    code: Option<String>,
-    sisCourseId: String,
+/// The grading scales to load at startup, from
+/// `LADOK_PRELOAD_BETYGSSKALOR`.
+fn preload_betygskalor() -> Result<Vec<BetygsskalaID>, Error> {
+    match var("LADOK_PRELOAD_BETYGSSKALOR") {
+        Ok(ids) => ids
+            .split(',')
+            .map(|id| id.trim().parse())
+            .collect::<Result<_, _>>()
+            .map_err(|e| format_err!("LADOK_PRELOAD_BETYGSSKALOR: {}", e)),
+        Err(_) => Ok(vec![]),
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-#[allow(non_snake_case)]
-struct Step3Args {
-    canvas_token: String,
-    canvas_course_id: Option<String>,
-    sis_course_id: String,
+/// The grading scale to load from Ladok to tell that it responds,
+/// `READY_BETYGSSKALA` or else the first scale to preload.  There is
+/// no default, as scale ids differ between Ladok installations.
+fn ready_betygsskala(
+    configured: Option<&str>,
+    preload: &[BetygsskalaID],
+) -> Result<BetygsskalaID, Error> {
+    match configured {
+        Some(id) => id
+            .trim()
+            .parse()
+            .map_err(|e| format_err!("READY_BETYGSSKALA: {}", e)),
+        None => preload.first().copied().ok_or_else(|| {
+            format_err!("READY_BETYGSSKALA or LADOK_PRELOAD_BETYGSSKALOR must be set")
+        }),
+    }
 }
 
-fn export_step_3(ctx: Arc<ServerContext>, query: Step3Args) -> impl Reply {
-    info!(
-        "Should export for {:?} / {:?}",
-        query.sis_course_id, query.canvas_course_id,
-    );
+/// The OpenAPI document for this api.
+fn api_schema(ctx: Arc<ServerContext>) -> impl Reply {
+    batch::json_response(
+        StatusCode::OK,
+        &schema::openapi(&format!("/{}", ctx.route_prefix)),
+    )
+}
 
-    let canvas = match ctx.canvas_by_access_token(&query.canvas_token) {
-        Ok(client) => client,
-        Err(e) => {
-            warn!("The access token cannot be retrieved from Canvas: {}", e);
-            return access_denied();
+/// True if `authorization` carries the service token, if there is one.
+/// Compared in constant time, so the token cannot be guessed by timing.
+fn is_service_token(service_token: Option<&str>, authorization: Option<&str>) -> bool {
+    match (service_token, authorization) {
+        (Some(token), Some(auth)) => {
+            hmac::constant_time_eq(auth.as_bytes(), format!("Bearer {}", token).as_bytes())
         }
-    };
-
-    let result = (|| {
-        let mut ladok = ctx.ladok_client()?;
+        _ => false,
+    }
+}
 
-        do_report(&canvas, &mut ladok, &query.sis_course_id)
-    })()
-    .unwrap();
+/// What the token of an administrative request allows.  A scope
+/// allows everything the scopes before it do.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum TokenScope {
+    /// Reading Canvas and Ladok, e.g. for diagnostics.
+    Audit,
+    /// Also reporting results to Ladok.
+    Service,
+}
 
-    Response::builder()
-        .html(|o| templates::done(o, result))
-        .unwrap()
+/// The scope of the token in `authorization`, if any.
+fn token_scope(
+    service_token: Option<&str>,
+    audit_token: Option<&str>,
+    authorization: Option<&str>,
+) -> Option<TokenScope> {
+    if is_service_token(service_token, authorization) {
+        Some(TokenScope::Service)
+    } else if is_service_token(audit_token, authorization) {
+        Some(TokenScope::Audit)
+    } else {
+        None
+    }
 }
 
-fn do_report(
-    canvas: &Canvas,
-    ladok: &mut Ladok,
-    sis_courseroom: &str,
-) -> Result<ExportResults, Error> {
-    let kurstillf = canvas
-        .get_course_sections(sis_courseroom)?
-        .into_iter()
-        .filter_map(|s| s.integration_id)
-        .collect::<BTreeSet<_>>()
-        .into_iter()
-        .collect::<Vec<String>>();
-    if kurstillf.is_empty() {
-        return Err(format_err!(
-            "Canvas room {} is lacking integration id",
-            sis_courseroom
-        ));
+/// Ok if `scope` allows what needs `needed`.  A known token without
+/// the scope is forbidden, no token is unauthorized.
+fn check_scope(scope: Option<TokenScope>, needed: TokenScope) -> Result<(), StatusCode> {
+    match scope {
+        Some(scope) if scope >= needed => Ok(()),
+        Some(_) => Err(StatusCode::FORBIDDEN),
+        None => Err(StatusCode::UNAUTHORIZED),
     }
+}
 
-    let mut retval = ExportResults::new();
+#[derive(Debug, Deserialize)]
+struct LadokResultsArgs {
+    /// The integration_id of a section.
+    round: String,
+    moment: String,
+    /// Selects the Ladok environment, if there are several.
+    sis_course_id: Option<String>,
+}
 
-    for assignment in canvas
-        .get_assignments(sis_courseroom)?
-        .into_iter()
-        .filter(|a| a.integration_id.is_some())
+/// Handler for support staff, the Ladok results of a moment as json.
+fn ladok_results(
+    ctx: Arc<ServerContext>,
+    authorization: Option<String>,
+    args: LadokResultsArgs,
+) -> Response<Vec<u8>> {
+    if let Some(response) = ctx.refuse(authorization.as_deref(), TokenScope::Audit, "Ladok results")
     {
-        let moment_id = assignment.integration_id.as_ref().unwrap();
-        eprintln!(
-            "Should report on moment {} on course {:?}",
-            moment_id, kurstillf
-        );
-        let submissions = canvas.get_assignment_submissions(sis_courseroom, assignment.id)?;
+        return response;
+    }
+    let tillfalle = Tillfalle::from_integration_id(&args.round);
+    let moment = MomentUid::new(args.moment);
+    info!("Ladok results of {} on {} requested", moment, tillfalle);
+    let ladok = ctx.ladok_client(args.sis_course_id.as_ref().map(AsRef::as_ref));
+    match ladok.sok_studieresultat(&[tillfalle], &moment) {
+        Ok(resultat) => batch::json_response(StatusCode::OK, &resultat),
+        Err(e) => {
+            warn!("Failed to get Ladok results of {}: {}", moment, e);
+            batch::json_response(StatusCode::BAD_GATEWAY, &e.to_string())
+        }
+    }
+}
 
-        let resultat = ladok.sok_studieresultat(&kurstillf, &moment_id)?;
+/// Handler to forget the cached grading scales, e.g. after a scale is
+/// changed in Ladok.
+fn clear_cache(ctx: Arc<ServerContext>, authorization: Option<String>) -> Response<Vec<u8>> {
+    if let Some(response) = ctx.refuse(authorization.as_deref(), TokenScope::Service, "Cache clear")
+    {
+        return response;
+    }
+    let cleared = ctx
+        .ladok
+        .all()
+        .map(|(_, target)| target.scales.clear())
+        .sum::<usize>();
+    info!("Cleared {} cached grading scales", cleared);
+    batch::json_response(StatusCode::OK, &serde_json::json!({ "cleared": cleared }))
+}
 
-        let mut create_queue = vec![];
-        let mut update_queue = vec![];
+#[derive(Debug, Deserialize)]
+struct GradeMappingArgs {
+    /// The integration_id of a section.
+    round: String,
+    moment: String,
+    /// A personnummer or a Ladok student uid.
+    student: String,
+    /// A grade as set in Canvas, letter or numeric.
+    grade: Option<String>,
+    /// A rubric-graded assignment and the rubric total on it, instead
+    /// of a grade.
+    assignment: Option<i32>,
+    rubric_total: Option<f64>,
+    /// The course room, for its numeric grading scheme.  Selects the
+    /// Ladok environment, if there are several.
+    sis_course_id: Option<String>,
+    /// The Canvas token of a teacher in the course room, instead of a
+    /// service or audit token.
+    canvas_token: Option<String>,
+}
 
-        for submission in submissions
-            .iter()
-            .filter(|s| s.assignment_id == Some(assignment.id))
-        {
-            if let Some(canvas_user) = &submission.user {
-                if let Some(student) = &canvas_user.integration_id {
-                    match prepare_ladok_change(ladok, &student, &resultat, moment_id, submission) {
-                        Ok(ChangeToLadok::Update(data, grade)) => {
-                            update_queue.push(data);
-                            retval.add(canvas_user, &format!(" Updated ({}) ", grade));
-                        }
-                        Ok(ChangeToLadok::Create(data, grade)) => {
-                            create_queue.push(data);
-                            retval.add(canvas_user, &format!(" Created ({}) ", grade));
-                        }
-                        Ok(ChangeToLadok::NoChange(grade)) => {
-                            retval.add(canvas_user, &format!(" No change ({}) ", grade));
-                        }
-                        Ok(ChangeToLadok::NoGrade) => {
-                            retval.add(canvas_user, " No grade ");
-                        }
-                        Err(e) => {
-                            eprintln!("Error {}", e);
-                            retval.add(canvas_user, &format!(" Error ({})", e));
-                        }
-                    }
-                } else {
-                    retval.add(canvas_user, " No integration_id ");
-                }
-            }
-        }
-        info!(
-            "There are {} results to create and {} to update",
-            create_queue.len(),
-            update_queue.len(),
-        );
-        if !create_queue.is_empty() {
-            retval.created = ladok
-                .skapa_studieresultat(create_queue)
-                .map(|result| result.len())
-                .map_err(|e| e.to_string())
-        }
-        if !update_queue.is_empty() {
-            retval.updated = ladok
-                .uppdatera_studieresultat(update_queue)
-                .map(|result| result.len())
-                .map_err(|e| e.to_string());
-        }
-    }
-    info!("Ok.  Done.");
-    Ok(retval)
+/// How a Canvas grade maps to Ladok for a student.
+#[derive(Debug, Serialize)]
+struct GradeMapping {
+    moment: MomentUid,
+    grade: String,
+    betygsskala: BetygsskalaID,
+    betygsgrad: BetygsgradID,
+    /// The code and benämning of the grade in Ladok.
+    ladok_grade: String,
+    valid_as_final: bool,
+    /// True if the moment is the final grade of the course.
+    is_final: bool,
+    komplettering: bool,
 }
 
-#[derive(Debug)]
-pub struct ExportResults {
-    students: BTreeMap<i32, String>,
-    created: Result<usize, String>,
-    updated: Result<usize, String>,
+/// How the Canvas grade `grade` of `student` maps to the scale of the
+/// student on `moment`, checked as when exporting, without writing.
+fn map_grade(
+    ladok: &mut Ladok,
+    config: &ReportConfig,
+    moment: &MomentData,
+    student: &StudentUid,
+    grade: &str,
+) -> Result<GradeMapping, Error> {
+    let (code, komplettering) = split_komplettering(config, grade);
+    check_komplettering(config, &moment.id, komplettering)?;
+    let code = moment.grade_code(code)?;
+    let one = moment
+        .resultat
+        .find_student(student)
+        .ok_or_else(|| format_err!("Student {} not in Ladok result-list", pii(student)))?;
+    let (betygsskala, found) = ladok_grade(ladok, config, moment, one, student, &code)?;
+    Ok(GradeMapping {
+        moment: moment.id.clone(),
+        grade: grade.to_string(),
+        betygsskala,
+        betygsgrad: found.ID,
+        ladok_grade: found.describe(),
+        valid_as_final: found.valid_as_final(),
+        is_final: moment.is_final(config),
+        komplettering,
+    })
 }
 
-impl ExportResults {
-    fn new() -> Self {
-        ExportResults {
-            students: BTreeMap::new(),
-            created: Ok(0),
-            updated: Ok(0),
+/// The Canvas grade to map, from the rubric total if one is given for
+/// a rubric-graded assignment, as when exporting.
+fn canvas_grade(config: &ReportConfig, args: &GradeMappingArgs) -> Result<String, Error> {
+    if let (Some(assignment), Some(total)) = (args.assignment, args.rubric_total) {
+        if config.rubric_grades.is_rubric_graded(assignment) {
+            return config
+                .rubric_grades
+                .grade(assignment, total)
+                .map(String::from)
+                .ok_or_else(|| {
+                    format_err!("No grade for rubric total {} on {}", total, assignment)
+                });
         }
     }
-    fn add(&mut self, student: &User, status: &str) {
-        self.students
-            .entry(student.id)
-            .or_insert_with(|| {
-                format!(
-                    "{}: ",
-                    student.name.as_ref().map(AsRef::as_ref).unwrap_or("-")
-                )
-            })
-            .push_str(status);
+    args.grade
+        .clone()
+        .ok_or_else(|| format_err!("Give a grade, or a rubric total of a rubric-graded assignment"))
+}
+
+/// The Canvas client to check a grade mapping with, if any.  Support
+/// staff give the service or audit token, and teachers their Canvas
+/// token and a course room they teach.  Gives the status and message
+/// to refuse with otherwise.
+fn grade_mapping_canvas(
+    ctx: &ServerContext,
+    authorization: Option<&str>,
+    args: &GradeMappingArgs,
+) -> Result<Option<Canvas>, (StatusCode, &'static str)> {
+    let token = match &args.canvas_token {
+        Some(token) => token,
+        None => {
+            let scope = token_scope(
+                ctx.service_token.as_deref(),
+                ctx.audit_token.as_deref(),
+                authorization,
+            );
+            if check_scope(scope, TokenScope::Audit).is_err() {
+                warn!("Grade mapping requested without a valid token");
+                return Err((StatusCode::UNAUTHORIZED, "Service token required"));
+            }
+            let service = ctx.canvas_service_token.as_deref();
+            return Ok(service.map(|token| ctx.canvas_by_access_token(token)));
+        }
+    };
+    let sis_course_id = args.sis_course_id.as_deref().ok_or((
+        StatusCode::BAD_REQUEST,
+        "sis_course_id is required with canvas_token",
+    ))?;
+    let canvas = ctx.canvas_by_access_token(token);
+    match canvas.get_my_enrollments(sis_course_id) {
+        Ok(ref enrollments) if enrollments.iter().any(Enrollment::is_teacher) => Ok(Some(canvas)),
+        _ => {
+            warn!(
+                "Grade mapping in {} requested by a non-teacher",
+                sis_course_id
+            );
+            Err((StatusCode::FORBIDDEN, "Not a teacher in the course room"))
+        }
     }
 }
 
-fn prepare_ladok_change(
-    ladok: &mut Ladok,
+/// Handler for teachers and support staff, how a Canvas grade of one
+/// student would be reported on a moment, before exporting the course
+/// room.
+fn grade_mapping(
+    ctx: Arc<ServerContext>,
+    authorization: Option<String>,
+    args: GradeMappingArgs,
+) -> Response<Vec<u8>> {
+    let canvas = match grade_mapping_canvas(&ctx, authorization.as_deref(), &args) {
+        Ok(canvas) => canvas,
+        Err((status, message)) => return batch::json_response(status, &message),
+    };
+    let grade = match canvas_grade(&ctx.report, &args) {
+        Ok(grade) => grade,
+        Err(e) => return batch::json_response(StatusCode::UNPROCESSABLE_ENTITY, &e.to_string()),
+    };
+    let numeric = match (&canvas, args.sis_course_id.as_deref()) {
+        (Some(canvas), Some(sis)) if !ctx.report.numeric_grades.is_empty() => {
+            match canvas.get_course(sis) {
+                Ok(course) => course
+                    .grading_standard_id
+                    .and_then(|id| ctx.report.numeric_grades.for_scheme(id))
+                    .cloned(),
+                Err(e) => {
+                    warn!("Failed to get course room {} from Canvas: {}", sis, e);
+                    return batch::json_response(StatusCode::BAD_GATEWAY, &e.to_string());
+                }
+            }
+        }
+        _ => None,
+    };
+    // A teacher may only look at the students of a round of the course
+    // room, so the round is checked before asking Ladok anything.
+    let round_students = match (&canvas, &args.canvas_token, args.sis_course_id.as_deref()) {
+        (Some(canvas), Some(_), Some(sis)) => match students_of_round(canvas, sis, &args.round) {
+            Ok(Some(students)) => Some(students),
+            Ok(None) => {
+                warn!("Grade mapping in {} for a round not in it", sis);
+                return batch::json_response(
+                    StatusCode::NOT_FOUND,
+                    &"Round not found in the course room",
+                );
+            }
+            Err(e) => {
+                warn!("Failed to get students of {} from Canvas: {}", sis, e);
+                return batch::json_response(StatusCode::BAD_GATEWAY, &e.to_string());
+            }
+        },
+        _ => None,
+    };
+    let mut ladok = ctx.ladok_client(args.sis_course_id.as_deref());
+    let student = match &round_students {
+        Some(_) => None,
+        None => match student_uid(&ladok, &args.student) {
+            Ok(student) => Some(student),
+            Err(e) => {
+                warn!(
+                    "Failed to find student {}: {}",
+                    pii(&args.student),
+                    pii_url(&e.to_string()),
+                );
+                return batch::json_response(StatusCode::NOT_FOUND, &"Student not found in Ladok");
+            }
+        },
+    };
+    let tillfalle = Tillfalle::from_integration_id(&args.round);
+    let moment_id = MomentUid::new(args.moment);
+    let resultat = match ladok.sok_studieresultat(&[tillfalle], &moment_id) {
+        Ok(resultat) => resultat,
+        Err(e) => {
+            warn!("Failed to get Ladok results of {}: {}", moment_id, e);
+            return batch::json_response(StatusCode::BAD_GATEWAY, &e.to_string());
+        }
+    };
+    let student = match (student, &round_students) {
+        (Some(student), _) => student,
+        (None, students) => {
+            let students = students.as_deref().unwrap_or_default();
+            match student_of_round(students, &args.student, &resultat) {
+                Some(student) => student,
+                // Whether the student is unknown or elsewhere is not told.
+                None => {
+                    return batch::json_response(
+                        StatusCode::NOT_FOUND,
+                        &"Student not found in the course room",
+                    )
+                }
+            }
+        }
+    };
+    let moment = MomentData {
+        id: moment_id,
+        resultat,
+        window: None,
+        numeric,
+        exam_date: None,
+    };
+    match map_grade(&mut ladok, &ctx.report, &moment, &student, &grade) {
+        Ok(mapping) => batch::json_response(StatusCode::OK, &mapping),
+        Err(e) => batch::json_response(StatusCode::UNPROCESSABLE_ENTITY, &e.to_string()),
+    }
+}
+
+/// The students of the section of a course room that is the round
+/// with the integration_id `round`, or None if no section is.
+fn students_of_round(
+    canvas: &Canvas,
+    sis_course_id: &str,
+    round: &str,
+) -> Result<Option<Vec<User>>, Error> {
+    let sections = canvas.get_course_sections(sis_course_id)?;
+    let section = match section_of_round(&sections, round) {
+        Some(section) => section,
+        None => return Ok(None),
+    };
+    let students = canvas.get_students_with_sections(sis_course_id)?;
+    Ok(Some(
+        students
+            .into_iter()
+            .filter(|user| user.section_ids().contains(&section))
+            .collect(),
+    ))
+}
+
+/// The id of the section that is the round `round`, if any.
+fn section_of_round(sections: &[CourseSection], round: &str) -> Option<i32> {
+    sections
+        .iter()
+        .find(|section| section.integration_id.as_deref() == Some(round.trim()))
+        .map(|section| section.id)
+}
+
+/// The Ladok uid of `student`, a personnummer or Ladok uid, if it is
+/// one of the `students` of a round, as found in the `resultat` of the
+/// round.
+fn student_of_round(
+    students: &[User],
     student: &str,
     resultat: &SokresultatStudieresultatResultat,
-    moment_id: &str,
-    submission: &Submission,
-) -> Result<ChangeToLadok, Error> {
-    let grade = match &submission.grade {
-        Some(ref grade) => grade.to_uppercase(),
-        None => return Ok(ChangeToLadok::NoGrade),
+) -> Option<StudentUid> {
+    let student = student.trim();
+    let wanted = match personnummer_digits(student) {
+        Some(_) => resultat.find_personnummer(student)?.to_string(),
+        None => student.to_string(),
     };
+    students
+        .iter()
+        .filter_map(|user| ladok_uid(user, resultat))
+        .find(|uid| *uid == wanted)
+        .map(|uid| StudentUid::new(uid.as_str()))
+}
 
-    let one = resultat
-        .find_student(&student)
-        .ok_or_else(|| format_err!("Student {} not in Ladok result-list", student))?;
+#[derive(Debug, Deserialize)]
+struct StudentArgs {
+    sis_course_id: String,
+    /// A personnummer or a Ladok student uid.
+    student: String,
+}
 
-    let betygskala = one
-        .get_betygsskala()
-        .ok_or_else(|| format_err!("Missing Betygskala for student {}", student))?;
+/// The outcome of reporting a single student.
+#[derive(Debug, Serialize)]
+struct StudentOutcome {
+    correlation_id: String,
+    /// The outcome per moment.
+    moments: Vec<(String, String)>,
+    outcome: batch::CourseOutcome,
+}
 
-    let grade = ladok.get_grade(betygskala, &grade)?;
+/// Handler for support staff, to report a single student in a course
+/// room, e.g. when the student misses a grade.
+fn report_student(
+    ctx: Arc<ServerContext>,
+    authorization: Option<String>,
+    args: StudentArgs,
+) -> Response<Vec<u8>> {
+    if let Some(response) = ctx.refuse(
+        authorization.as_deref(),
+        TokenScope::Service,
+        "Student report",
+    ) {
+        return response;
+    }
+    let canvas_token = match &ctx.canvas_service_token {
+        Some(token) => token,
+        None => {
+            return batch::json_response(
+                StatusCode::SERVICE_UNAVAILABLE,
+                &"No canvas service token configured",
+            )
+        }
+    };
+    let mut ladok = ctx.ladok_client(Some(&args.sis_course_id));
+    let student = match student_uid(&ladok, &args.student) {
+        Ok(student) => student,
+        Err(e) => {
+            warn!(
+                "Failed to find student {}: {}",
+                pii(&args.student),
+                pii_url(&e.to_string()),
+            );
+            return batch::json_response(StatusCode::NOT_FOUND, &"Student not found in Ladok");
+        }
+    };
+    info!(
+        "Report of student {} in {} requested",
+        pii(&student),
+        args.sis_course_id
+    );
+    let canvas = ctx.canvas_for_export(
+        ctx.canvas_by_access_token(canvas_token),
+        &args.sis_course_id,
+    );
+    let result = do_report(
+        &canvas,
+        &mut ladok,
+        &ctx.report,
+        &args.sis_course_id,
+        &RunOptions {
+            only_student: Some(&student),
+            ..RunOptions::default()
+        },
+    );
+    ctx.finished(&args.sis_course_id, &result);
+    match result {
+        Ok(mut result) => {
+            let undo = std::mem::take(&mut result.undo);
+            ctx.undo_log.push(&result.correlation_id, undo);
+            let outcome = StudentOutcome {
+                correlation_id: result.correlation_id.clone(),
+                moments: result.moment_outcomes.values().flatten().cloned().collect(),
+                outcome: batch::CourseOutcome::new(&args.sis_course_id, Ok(&result)),
+            };
+            let mut response = batch::json_response(StatusCode::OK, &outcome);
+            response.headers_mut().extend(count_headers(&result));
+            response
+        }
+        Err(e) if e.downcast_ref::<NotInCourseRoom>().is_some() => {
+            batch::json_response(StatusCode::NOT_FOUND, &e.to_string())
+        }
+        Err(e) => {
+            warn!("Report of student in {} failed: {}", args.sis_course_id, e);
+            batch::json_response(StatusCode::BAD_GATEWAY, &e.to_string())
+        }
+    }
+}
 
-    let exam_date = submission
-        .graded_at
-        .ok_or_else(|| format_err!("Submission missing graded_at for student {}", student))?
-        .naive_local()
-        .date();
+#[derive(Debug, Deserialize)]
+struct DiagnosticsArgs {
+    sis_course_id: String,
+    /// "json" for json rather than html.
+    format: Option<String>,
+}
 
-    Ok(if let Some(underlag) = one.get_arbetsunderlag(moment_id) {
-        if underlag.Betygsgrad != Some(grade.ID) || underlag.Examinationsdatum != Some(exam_date) {
-            eprintln!(
-                "Updating grade from {:?} to {:?} for {:?}",
-                underlag.Betygsgrad, grade, student
-            );
-            ChangeToLadok::Update(
-                UppdateraResultat {
-                    Uid: one.Uid.clone(),
-                    Betygsgrad: Some(grade.ID),
-                    BetygsskalaID: betygskala,
-                    Examinationsdatum: Some(exam_date),
-                    ResultatUID: underlag.Uid.clone(),
-                    SenasteResultatandring: underlag.SenasteResultatandring,
-                },
-                grade.Kod.clone(),
+/// Handler for support staff, how a course room maps to Ladok.
+fn course_diagnostics(
+    ctx: Arc<ServerContext>,
+    authorization: Option<String>,
+    args: DiagnosticsArgs,
+) -> Response<Vec<u8>> {
+    if let Some(response) = ctx.refuse(authorization.as_deref(), TokenScope::Audit, "Diagnostics") {
+        return response;
+    }
+    let canvas_token = match &ctx.canvas_service_token {
+        Some(token) => token,
+        None => {
+            return batch::json_response(
+                StatusCode::SERVICE_UNAVAILABLE,
+                &"No canvas service token configured",
             )
-        } else {
-            eprintln!("Grade {:?} up to date for {:?}", grade, student);
-            ChangeToLadok::NoChange(grade.Kod.clone())
         }
+    };
+    info!("Diagnostics of {} requested", args.sis_course_id);
+    let canvas = ctx.canvas_by_access_token(canvas_token);
+    let ladok = ctx.ladok_client(Some(&args.sis_course_id));
+    let sis = &args.sis_course_id;
+    let fetched = canvas.get_course(sis).and_then(|course| {
+        Ok((
+            course,
+            canvas.get_course_sections(sis)?,
+            canvas.get_assignments(sis)?,
+        ))
+    });
+    let (course, sections, assignments) = match fetched {
+        Ok(fetched) => fetched,
+        Err(e) => {
+            warn!("Failed to get course room {} from Canvas: {}", sis, e);
+            return batch::json_response(StatusCode::BAD_GATEWAY, &e.to_string());
+        }
+    };
+    let mut checked = BTreeMap::new();
+    let diagnostics = diagnostics::diagnose(
+        &ctx.report.moment_mapping,
+        sis,
+        &course,
+        &sections,
+        &assignments,
+        |moment| {
+            *checked.entry(moment.clone()).or_insert_with(|| {
+                match ladok.get_utbildningsinstans(moment) {
+                    Ok(_) => Some(true),
+                    Err(ref e) if ladok::is_forbidden(e) || ladok::is_maintenance(e) => None,
+                    Err(e) => {
+                        info!("Moment {} not found in Ladok: {}", moment, e);
+                        Some(false)
+                    }
+                }
+            })
+        },
+    );
+    if args.format.as_deref() == Some("json") {
+        batch::json_response(StatusCode::OK, &diagnostics)
     } else {
-        ChangeToLadok::Create(
-            SkapaResultat {
-                Uid: one.Uid.clone(),
-                Betygsgrad: Some(grade.ID),
-                BetygsskalaID: betygskala,
-                Examinationsdatum: Some(exam_date),
-                StudieresultatUID: one.Uid.clone(),
-                UtbildningsinstansUID: Some(moment_id.to_string()),
-            },
-            grade.Kod.clone(),
-        )
-    })
+        Response::builder()
+            .html(|o| templates::diagnostics(o, &ctx.branding, &diagnostics))
+            .unwrap()
+    }
 }
 
-enum ChangeToLadok {
-    Update(UppdateraResultat, String),
-    Create(SkapaResultat, String),
-    NoChange(String),
-    NoGrade,
+/// The Ladok uid of a student given by personnummer or uid.
+fn student_uid(ladok: &Ladok, student: &str) -> Result<StudentUid, Error> {
+    let student = student.trim();
+    match personnummer_digits(student) {
+        Some(digits) => Ok(ladok.get_student_by_personnummer(&digits)?.uid().clone()),
+        None => Ok(StudentUid::new(student)),
+    }
+}
+
+/// Check that a course room has the sis course id `expected`.
+fn check_sis_course_id(course: &CourseRoom, expected: &str) -> Result<(), Error> {
+    if course.sis_course_id.as_deref() == Some(expected) {
+        Ok(())
+    } else {
+        Err(format_err!(
+            "The course room is not {:?}, but {:?}",
+            expected,
+            course
+                .sis_course_id
+                .as_deref()
+                .unwrap_or("without a sis course id"),
+        ))
+    }
+}
+
+/// Check that a Canvas course id, if given, is numeric.
+fn check_canvas_course_id(id: Option<&str>) -> Result<(), Error> {
+    match id {
+        Some(id) if id.is_empty() || !id.bytes().all(|b| b.is_ascii_digit()) => {
+            Err(format_err!("Bad Canvas course id {:?}", id))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn export_step_1(ctx: Arc<ServerContext>, b: ExportPostData) -> impl Reply {
+    // const correlationId = req.id;
+    eprintln!("Export request posted: {:?}", b);
+    let sis_course_id = match normalize_sis_course_id(&b.lis_course_offering_sourcedid) {
+        Ok(id) => id,
+        Err(e) => {
+            warn!(
+                "Bad sis course id {:?}: {}",
+                b.lis_course_offering_sourcedid, e
+            );
+            return bad_request(
+                &ctx.branding,
+                &format!(
+                    "This course room has a malformed sis id ({}), so it cannot be exported.",
+                    e
+                ),
+            );
+        }
+    };
+    if let Err(e) = check_canvas_course_id(Some(&b.custom_canvas_course_id)) {
+        warn!("Export posted with {}", e);
+        return bad_request(&ctx.branding, &e.to_string());
+    }
+    info!("Export of sis course {}", sis_course_id);
+    let next_url = export2_url(
+        &ctx.main_url(),
+        &sis_course_id,
+        Some(b.custom_canvas_course_id),
+        b.code,
+        false,
+    );
+    info!(
+        "Tell auth to redirect back to {} using canvas client id {}",
+        next_url, ctx.canvas_client_id,
+    );
+
+    redirect(&ctx.get_oath_url(&next_url, ctx.canvas_scopes.as_deref()))
+}
+
+/// Authorize again with the broader scopes, when the access token
+/// lacks a scope, and come back to the same course room.
+///
+/// This is done once per export; if the token still lacks a scope
+/// after that, Canvas will not grant it, so tell the user instead of
+/// asking again and again.
+fn upgrade_scope(
+    ctx: &ServerContext,
+    sis_course_id: &str,
+    canvas_course_id: Option<String>,
+    scope_upgraded: bool,
+) -> Response<Vec<u8>> {
+    if scope_upgraded {
+        warn!("Broader scopes for {} were not granted", sis_course_id);
+        return scope_denied(&ctx.branding);
+    }
+    let next_url = export2_url(&ctx.main_url(), sis_course_id, canvas_course_id, None, true);
+    info!("Asking for broader scopes for {}", sis_course_id);
+    redirect(&ctx.get_oath_url(&next_url, ctx.canvas_upgrade_scopes.as_deref()))
+}
+
+/// The url of the second export step, for the Canvas authorization
+/// to redirect back to.
+fn export2_url(
+    main_url: &str,
+    sis_course_id: &str,
+    canvas_course_id: Option<String>,
+    code: Option<String>,
+    scope_upgraded: bool,
+) -> String {
+    format!(
+        "{}2?{}",
+        main_url,
+        serde_urlencoded::to_string(QueryArgs {
+            canvasCourseId: canvas_course_id,
+            error: None,
+            code,
+            sisCourseId: sis_course_id.into(),
+            scopeUpgraded: Some(true).filter(|_| scope_upgraded),
+        })
+        .unwrap(),
+    )
+}
+
+/// The url to authorize in Canvas, optionally for specific scopes.
+fn oauth_url(canvas_host: &str, client_id: &str, next_url: &str, scope: Option<&str>) -> String {
+    let mut params = vec![
+        ("client_id", client_id),
+        ("response_type", "code"),
+        ("redirect_uri", next_url),
+    ];
+    if let Some(scope) = scope {
+        params.push(("scope", scope));
+    }
+    format!(
+        "https://{}/login/oauth2/auth?{}",
+        canvas_host,
+        serde_urlencoded::to_string(params).unwrap(),
+    )
+}
+
+fn redirect(url: &str) -> Response<Vec<u8>> {
+    Response::builder()
+        .status(StatusCode::FOUND)
+        .header(header::LOCATION, url)
+        .body(format!("Please refer to {}", url).into_bytes())
+        .unwrap()
+}
+
+/// Trim a sis course id, and check that it is safe to use in Canvas
+/// urls, e.g. LT1016VT191.
+fn normalize_sis_course_id(id: &str) -> Result<String, String> {
+    let id = id.trim();
+    if id.is_empty() {
+        Err("it is empty".into())
+    } else if id.len() > 64 {
+        Err("it is too long".into())
+    } else if let Some(c) = id
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || "-_.".contains(*c)))
+    {
+        Err(format!("{:?} is not allowed", c))
+    } else {
+        Ok(id.to_string())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportPostData {
+    lis_course_offering_sourcedid: String,
+    custom_canvas_course_id: String,
+    code: Option<String>,
+}
+
+fn export_step_2(ctx: Arc<ServerContext>, query: QueryArgs) -> impl Reply {
+    if let Some(code) = &query.code {
+        if !ctx.used_codes.first_use(code) {
+            warn!("/export2 accessed with an already used authorization code");
+            return code_replayed(&ctx.branding);
+        }
+    }
+    if let Err(e) = check_canvas_course_id(query.canvasCourseId.as_deref()) {
+        warn!("/export2 accessed with {}", e);
+        return bad_request(&ctx.branding, &e.to_string());
+    }
+    let canvas = match ctx.auth_canvas_client(query.code.as_ref().unwrap()) {
+        Ok(client) => ctx.canvas_for_course(client, query.canvasCourseId.as_deref()),
+        Err(e) => {
+            warn!("The access token cannot be retrieved from Canvas: {}", e);
+            return access_denied(&ctx.branding);
+        }
+    };
+    let canvas = match canvas {
+        Ok(canvas) => canvas,
+        Err(e) => return bad_request(&ctx.branding, &e.to_string()),
+    };
+
+    let modules = match canvas.get_assignments(&query.sisCourseId) {
+        Ok(m) => m,
+        Err(e) => {
+            warn!(
+                "Failed to get assignments for {:?}: {}",
+                query.sisCourseId, e,
+            );
+            if e.downcast_ref::<canvas::InsufficientScope>().is_some() {
+                let upgraded = query.scopeUpgraded.unwrap_or_default();
+                return upgrade_scope(&ctx, &query.sisCourseId, query.canvasCourseId, upgraded);
+            }
+            return bad_request(&ctx.branding, "Failed to get assignments for course room");
+        }
+    };
+    if let Err(e) = ctx.check_course_room(&canvas, &query.sisCourseId) {
+        warn!("/export2 accessed for the wrong course room: {}", e);
+        return bad_request(&ctx.branding, &e.to_string());
+    }
+    let modules = modules
+        .iter()
+        .filter_map(|a| {
+            let moment = ctx
+                .report
+                .moment_mapping
+                .moment_for(&query.sisCourseId, a)?;
+            let name = a.name.clone().unwrap_or_else(|| "(unknown)".into());
+            Some((name, a.id, moment.to_string()))
+        })
+        .collect::<Vec<_>>();
+
+    if query.canvasCourseId.is_none() {
+        warn!("/export2 accessed with missing parameters. Ignoring the request...");
+        return bad_request(&ctx.branding, "The URL you are accessing needs extra parameters, please check it. If you came here by a link, inform us about this error.");
+    }
+
+    if let Some(error) = query.error {
+        if error == "access_denied" {
+            warn!("/export2 accessed without giving permission. Ignoring the request...");
+            return access_denied(&ctx.branding);
+        }
+        error!("/export2 accessed with an unexpected 'error' parameter which value is: {:?}. Ignoring the request...", error);
+        return bad_request(&ctx.branding, "An error ocurred. Please try it later.");
+    }
+
+    if query.code.is_none() {
+        warn!("/export2 accessed without authorization code. Ignoring the request...");
+        return bad_request(
+            &ctx.branding,
+            "Access denied. You need to authorize this app to use it",
+        );
+    }
+
+    Response::builder()
+        .html(|o| {
+            templates::collecting(
+                o,
+                &ctx.branding,
+                canvas.get_auth_key(),
+                &query.canvasCourseId.unwrap(),
+                &query.sisCourseId,
+                ctx.report.attest_mode,
+                query.scopeUpgraded.unwrap_or_default(),
+                &modules,
+            )
+        })
+        .unwrap()
+}
+
+fn bad_request(branding: &Branding, message: &str) -> Response<Vec<u8>> {
+    Response::builder()
+        .status(StatusCode::BAD_REQUEST)
+        .html(|o| templates::error(o, branding, StatusCode::BAD_REQUEST, message))
+        .unwrap()
+}
+
+fn access_denied(branding: &Branding) -> Response<Vec<u8>> {
+    let status = StatusCode::UNAUTHORIZED;
+    let msg = "You should launch this application from a Canvas course";
+    Response::builder()
+        .status(status)
+        .html(|o| templates::error(o, branding, status, msg))
+        .unwrap()
+}
+
+/// The authorization code was used before, e.g. by reloading the page.
+fn code_replayed(branding: &Branding) -> Response<Vec<u8>> {
+    let status = StatusCode::BAD_REQUEST;
+    let msg = "This authorization has already been used, please relaunch the export from Canvas";
+    Response::builder()
+        .status(status)
+        .html(|o| templates::error(o, branding, status, msg))
+        .unwrap()
+}
+
+/// Canvas did not grant the broader scopes, even when asked again.
+fn scope_denied(branding: &Branding) -> Response<Vec<u8>> {
+    let status = StatusCode::FORBIDDEN;
+    let msg = "Canvas did not grant the access needed to export, please contact the administrator";
+    Response::builder()
+        .status(status)
+        .html(|o| templates::error(o, branding, status, msg))
+        .unwrap()
+}
+
+fn ladok_maintenance(branding: &Branding, maintenance: &ladok::Maintenance) -> Response<Vec<u8>> {
+    let status = StatusCode::SERVICE_UNAVAILABLE;
+    Response::builder()
+        .status(status)
+        .html(|o| templates::error(o, branding, status, &maintenance.to_string()))
+        .unwrap()
 }
 
-include!(concat!(env!("OUT_DIR"), "/templates.rs"));
+fn server_error(branding: &Branding, message: &str) -> Response<Vec<u8>> {
+    let status = StatusCode::INTERNAL_SERVER_ERROR;
+    Response::builder()
+        .status(status)
+        .html(|o| templates::error(o, branding, status, message))
+        .unwrap()
+}
+
+/// The service cannot talk to Ladok as configured, which the user
+/// cannot do anything about.  The details are only logged.
+fn server_misconfigured(branding: &Branding) -> Response<Vec<u8>> {
+    server_error(
+        branding,
+        "Server misconfiguration, please contact the administrator",
+    )
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[allow(non_snake_case)]
+struct QueryArgs {
+    canvasCourseId: Option<String>,
+    error: Option<String>,
+    code: Option<String>,
+    sisCourseId: String,
+    /// Set when coming back from authorizing with broader scopes.
+    scopeUpgraded: Option<bool>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[allow(non_snake_case)]
+struct Step3Args {
+    canvas_token: String,
+    canvas_course_id: Option<String>,
+    sis_course_id: String,
+    /// Only list students whose results are changed.
+    #[serde(default)]
+    changes_only: bool,
+    /// Only report assignments in this assignment group, by id or name.
+    assignment_group: Option<String>,
+    /// Given when the user has confirmed a large export.
+    confirm_token: Option<String>,
+    /// The examination date of all students, as yyyy-mm-dd, if given.
+    exam_date: Option<String>,
+    /// Also klarmarkera and attest the written results.
+    #[serde(default)]
+    attest: bool,
+    /// Given when the user has confirmed attesting the results.
+    attest_token: Option<String>,
+    /// Set when the token was authorized with broader scopes.
+    #[serde(default)]
+    scope_upgraded: bool,
+}
+
+fn export_step_3(ctx: Arc<ServerContext>, query: Step3Args) -> impl Reply {
+    info!(
+        "Should export for {:?} / {:?}",
+        query.sis_course_id, query.canvas_course_id,
+    );
+
+    let canvas = match ctx.canvas_for_course(
+        ctx.canvas_by_access_token(&query.canvas_token),
+        query.canvas_course_id.as_deref(),
+    ) {
+        Ok(canvas) => ctx.canvas_for_export(canvas, &query.sis_course_id),
+        Err(e) => {
+            warn!("Export requested with {}", e);
+            return bad_request(&ctx.branding, &e.to_string());
+        }
+    };
+    match canvas.get_my_enrollments(&query.sis_course_id) {
+        Ok(ref enrollments) if enrollments.iter().any(Enrollment::is_teacher) => (),
+        Ok(_) => {
+            warn!(
+                "User is not a teacher in {:?}. Ignoring the request...",
+                query.sis_course_id,
+            );
+            return access_denied(&ctx.branding);
+        }
+        Err(e) => {
+            warn!(
+                "Failed to get enrollments in {:?}: {}",
+                query.sis_course_id, e,
+            );
+            if e.downcast_ref::<canvas::InsufficientScope>().is_some() {
+                return upgrade_scope(
+                    &ctx,
+                    &query.sis_course_id,
+                    query.canvas_course_id,
+                    query.scope_upgraded,
+                );
+            }
+            return access_denied(&ctx.branding);
+        }
+    }
+    if let Err(e) = ctx.check_course_room(&canvas, &query.sis_course_id) {
+        warn!("Export requested for the wrong course room: {}", e);
+        return bad_request(&ctx.branding, &e.to_string());
+    }
+    let assignment_group = query
+        .assignment_group
+        .as_deref()
+        .filter(|group| !group.trim().is_empty());
+    let exam_date = match parse_exam_date(query.exam_date.as_deref()) {
+        Ok(date) => date,
+        Err(e) => return bad_request(&ctx.branding, &e.to_string()),
+    };
+    if let Some(date) = exam_date {
+        // The period of each moment is only known during the export.
+        if let Err(e) = check_exam_date_not_future(&ctx.report, date, today()) {
+            return bad_request(&ctx.branding, &e.to_string());
+        }
+    }
+    if query.attest && !ctx.report.attest_mode {
+        return bad_request(&ctx.branding, "Attesting is not enabled for this service.");
+    }
+    let grades = if ctx.max_students.is_some() || query.attest {
+        match reportable_grades(&canvas, &ctx.report, &query.sis_course_id, assignment_group) {
+            Ok(grades) => grades,
+            Err(e) => {
+                warn!(
+                    "Failed to count students in {:?}: {}",
+                    query.sis_course_id, e
+                );
+                return bad_request(&ctx.branding, "Failed to get students of the course room");
+            }
+        }
+    } else {
+        vec![]
+    };
+    let students = count_students(&grades);
+    if let Some(max) = ctx.max_students {
+        let token = confirm_token(&ctx.canvas_client_secret, &query.sis_course_id, &grades);
+        if needs_confirmation(max, students, &token, query.confirm_token.as_deref()) {
+            info!(
+                "{} students in {:?} is more than {}, asking for confirmation",
+                students, query.sis_course_id, max,
+            );
+            return Response::builder()
+                .html(|o| {
+                    templates::confirm(
+                        o,
+                        &ctx.branding,
+                        &query.canvas_token,
+                        query.canvas_course_id.as_deref(),
+                        &query.sis_course_id,
+                        query.changes_only,
+                        assignment_group,
+                        exam_date,
+                        query.attest,
+                        students,
+                        &token,
+                    )
+                })
+                .unwrap();
+        }
+    }
+    if query.attest {
+        let token = attest_token(
+            &ctx.canvas_client_secret,
+            &query.sis_course_id,
+            exam_date,
+            &grades,
+        );
+        if query.attest_token.as_deref() != Some(token.as_str()) {
+            info!(
+                "Asking to confirm attesting {} students in {:?}",
+                students, query.sis_course_id,
+            );
+            return Response::builder()
+                .html(|o| {
+                    templates::confirm_attest(
+                        o,
+                        &ctx.branding,
+                        &query.canvas_token,
+                        query.canvas_course_id.as_deref(),
+                        &query.sis_course_id,
+                        query.changes_only,
+                        assignment_group,
+                        exam_date,
+                        query.confirm_token.as_deref(),
+                        students,
+                        &grades,
+                        &token,
+                    )
+                })
+                .unwrap();
+        }
+    }
+    let mut ladok = ctx.ladok_client(Some(&query.sis_course_id));
+    let result = do_report(
+        &canvas,
+        &mut ladok,
+        &ctx.report,
+        &query.sis_course_id,
+        &RunOptions {
+            changes_only: query.changes_only,
+            assignment_group,
+            exam_date,
+            attest: query.attest,
+            ..RunOptions::default()
+        },
+    );
+    ctx.finished(&query.sis_course_id, &result);
+    let mut result = match result {
+        Ok(result) => result,
+        Err(e) => {
+            error!("Export of {:?} failed: {}", query.sis_course_id, e);
+            return if e.downcast_ref::<ladok::NotAuthorized>().is_some() {
+                server_misconfigured(&ctx.branding)
+            } else if let Some(e) = e.downcast_ref::<BadExamDate>() {
+                bad_request(&ctx.branding, &e.to_string())
+            } else if let Some(maintenance) = e.downcast_ref::<ladok::Maintenance>() {
+                ladok_maintenance(&ctx.branding, maintenance)
+            } else {
+                server_error(&ctx.branding, "The export failed. Please try again later.")
+            };
+        }
+    };
+    let undo = std::mem::take(&mut result.undo);
+    let can_undo = !undo.is_empty() && !undo.attested;
+    ctx.undo_log.push(&result.correlation_id, undo);
+
+    let counts = count_headers(&result);
+    let mut response = Response::builder()
+        .html(|o| {
+            templates::done(
+                o,
+                &ctx.branding,
+                &query.canvas_token,
+                query.canvas_course_id.as_deref(),
+                &query.sis_course_id,
+                can_undo,
+                result,
+            )
+        })
+        .unwrap();
+    response.headers_mut().extend(counts);
+    response
+}
+
+/// The counts of an export as headers, for monitoring without parsing
+/// the body.  A count that failed is left out.
+fn count_headers(result: &ExportResults) -> HeaderMap {
+    let counts = [
+        ("x-ladok-created", result.created.as_ref().ok()),
+        ("x-ladok-updated", result.updated.as_ref().ok()),
+        ("x-ladok-errors", Some(&result.errors)),
+    ];
+    let mut headers = HeaderMap::new();
+    for (name, count) in counts.iter() {
+        if let Some(count) = count {
+            headers.insert(*name, (**count).into());
+        }
+    }
+    headers
+}
+
+/// The token confirming a large export of exactly the `grades` that
+/// were counted.  Like `attest_token`, it cannot be made up, and any
+/// change to the grades in Canvas asks again.
+fn confirm_token(key: &str, sis_course_id: &str, grades: &[Reportable]) -> String {
+    grades_token(key, format!("confirm\n{}\n", sis_course_id), grades)
+}
+
+/// The token confirming attesting exactly the `grades` that were
+/// previewed.  It is keyed by a secret of the service, so it cannot be
+/// made up, and any change to the grades in Canvas asks again.
+fn attest_token(
+    key: &str,
+    sis_course_id: &str,
+    exam_date: Option<NaiveDate>,
+    grades: &[Reportable],
+) -> String {
+    let previewed = format!("attest\n{}\n{:?}\n", sis_course_id, exam_date);
+    grades_token(key, previewed, grades)
+}
+
+/// The hmac of what was `previewed`, followed by the `grades`.
+fn grades_token(key: &str, mut previewed: String, grades: &[Reportable]) -> String {
+    for grade in grades {
+        previewed.push_str(&format!(
+            "{}\t{}\t{}\n",
+            grade.user, grade.assignment_id, grade.grade
+        ));
+    }
+    hmac::hmac_hex(key.as_bytes(), previewed.as_bytes())
+}
+
+/// True if an export of `students` needs to be confirmed first.
+fn needs_confirmation(max: usize, students: usize, token: &str, confirmed: Option<&str>) -> bool {
+    students > max && confirmed != Some(token)
+}
+
+/// A grade an export would report, as previewed before it is
+/// confirmed.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Reportable {
+    pub student: String,
+    pub user: i32,
+    pub assignment: String,
+    pub assignment_id: i32,
+    pub grade: String,
+}
+
+/// The grades to report in a course room, for the safety valve and
+/// to preview before attesting.
+fn reportable_grades(
+    canvas: &Canvas,
+    config: &ReportConfig,
+    sis_courseroom: &str,
+    assignment_group: Option<&str>,
+) -> Result<Vec<Reportable>, Error> {
+    let mapped = get_assignments(canvas, sis_courseroom, assignment_group)?
+        .into_iter()
+        .filter(|a| {
+            config
+                .moment_mapping
+                .moment_for(sis_courseroom, a)
+                .is_some()
+        })
+        .collect::<Vec<_>>();
+    let (rubric, plain): (Vec<i32>, Vec<i32>) = mapped
+        .iter()
+        .map(|a| a.id)
+        .partition(|id| config.rubric_grades.is_rubric_graded(*id));
+    let mut by_assignment =
+        canvas.get_submissions_by_assignment(sis_courseroom, &plain, false, false)?;
+    by_assignment.extend(canvas.get_submissions_by_assignment(
+        sis_courseroom,
+        &rubric,
+        true,
+        false,
+    )?);
+    let mut grades = vec![];
+    for assignment in &mapped {
+        let submissions = by_assignment.remove(&assignment.id).unwrap_or_default();
+        grades.extend(reportable_of(config, assignment, submissions));
+    }
+    grades.sort();
+    Ok(grades)
+}
+
+/// The grades of `submissions` to an `assignment` that an export would
+/// report, leaving out those it skips, e.g. as late or unposted.
+fn reportable_of(
+    config: &ReportConfig,
+    assignment: &Assignment,
+    mut submissions: Vec<Submission>,
+) -> Vec<Reportable> {
+    if config.rubric_grades.is_rubric_graded(assignment.id) {
+        config.rubric_grades.apply(assignment.id, &mut submissions);
+    }
+    let mut grades = vec![];
+    for submission in &mut submissions {
+        submission.anonymous_unreleased = assignment.withholds_anonymous_grade(submission);
+        if skipped(config, submission).is_some() {
+            continue;
+        }
+        if let (Some(grade), Some(user)) = (submission.given_grade(), &submission.user) {
+            if user.integration_id.is_some() {
+                grades.push(Reportable {
+                    student: user.name.clone().unwrap_or_else(|| user.id.to_string()),
+                    user: user.id,
+                    assignment: assignment.name.clone().unwrap_or_default(),
+                    assignment_id: assignment.id,
+                    grade: grade.to_string(),
+                });
+            }
+        }
+    }
+    grades
+}
+
+/// The number of students with a grade to report.
+fn count_students(grades: &[Reportable]) -> usize {
+    grades.iter().map(|g| g.user).collect::<BTreeSet<_>>().len()
+}
+
+#[derive(Debug, Deserialize)]
+struct UndoArgs {
+    canvas_token: String,
+    canvas_course_id: Option<String>,
+    sis_course_id: String,
+    correlation_id: String,
+}
+
+fn undo_export(ctx: Arc<ServerContext>, query: UndoArgs) -> impl Reply {
+    info!(
+        "Should undo export {:?} for {:?}",
+        query.correlation_id, query.sis_course_id,
+    );
+    let canvas = match ctx.canvas_for_course(
+        ctx.canvas_by_access_token(&query.canvas_token),
+        query.canvas_course_id.as_deref(),
+    ) {
+        Ok(canvas) => canvas,
+        Err(e) => return bad_request(&ctx.branding, &e.to_string()),
+    };
+    match canvas.get_my_enrollments(&query.sis_course_id) {
+        Ok(ref enrollments) if enrollments.iter().any(Enrollment::is_teacher) => (),
+        _ => return access_denied(&ctx.branding),
+    }
+    if let Err(e) = ctx.check_course_room(&canvas, &query.sis_course_id) {
+        warn!("Undo requested for the wrong course room: {}", e);
+        return bad_request(&ctx.branding, &e.to_string());
+    }
+    let mut record = match ctx
+        .undo_log
+        .take(&query.correlation_id, &query.sis_course_id)
+    {
+        Some(record) => record,
+        None => return bad_request(&ctx.branding, "This export cannot be undone (anymore)."),
+    };
+    let result = record
+        .undo(&ctx.ladok_client(Some(&query.sis_course_id)))
+        .map_err(|e| e.to_string());
+    let retry = !record.attested;
+    if result.is_err() && retry {
+        // Keep what is left, so the undo can be tried again.
+        ctx.undo_log.push(&query.correlation_id, record);
+    }
+    Response::builder()
+        .html(|o| templates::undone(o, &ctx.branding, result, retry))
+        .unwrap()
+}
+
+/// Options for a single export, as chosen by the user.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RunOptions<'a> {
+    /// Only list students whose results are changed.
+    pub changes_only: bool,
+    /// Only report assignments in this assignment group, by id or name.
+    pub assignment_group: Option<&'a str>,
+    /// Only report this student.
+    pub only_student: Option<&'a StudentUid>,
+    /// The examination date of all students, rather than when each
+    /// was graded.
+    pub exam_date: Option<NaiveDate>,
+    /// Write, klarmarkera and attest each moment, stopping at the
+    /// first step that fails.
+    pub attest: bool,
+    /// Only consider what is graded or changed since then.
+    pub since: Option<DateTime<Utc>>,
+}
+
+fn do_report(
+    canvas: &Canvas,
+    ladok: &mut Ladok,
+    config: &ReportConfig,
+    sis_courseroom: &str,
+    options: &RunOptions,
+) -> Result<ExportResults, Error> {
+    let RunOptions {
+        changes_only,
+        assignment_group,
+        only_student,
+        exam_date,
+        attest: attest_mode,
+        since,
+    } = *options;
+    let mut canvas_time = Phase::default();
+    let mut ladok_read_time = Phase::default();
+    let mut ladok_write_time = Phase::default();
+    let sections = canvas_time.time(|| canvas.get_course_sections(sis_courseroom))?;
+    let students = canvas_time.time(|| canvas.get_students_with_sections(sis_courseroom))?;
+    let (tillfallen, student_rounds) = rounds_of_sections(&sections, &students);
+    let round_names = round_names(&sections);
+    if tillfallen.is_empty() {
+        return Err(format_err!(
+            "Canvas room {} is lacking integration id",
+            sis_courseroom
+        ));
+    }
+
+    let test_students = canvas_time
+        .time(|| canvas.get_test_student_ids(sis_courseroom))
+        .unwrap_or_else(|e| {
+            warn!("Failed to get test students of {}: {}", sis_courseroom, e);
+            vec![]
+        });
+
+    let mut retval = ExportResults::new();
+    retval.changes_only = changes_only;
+    retval.undo = UndoRecord::new(sis_courseroom);
+
+    let assignments =
+        canvas_time.time(|| get_assignments(canvas, sis_courseroom, assignment_group))?;
+    let moments = group_by_moment(config, sis_courseroom, &assignments);
+    if moments.is_empty() {
+        info!("No assignments in {} map to Ladok moments", sis_courseroom);
+        retval.notice = Some(NO_MOMENTS.into());
+        return Ok(retval);
+    }
+    let numeric = if config.numeric_grades.is_empty() {
+        None
+    } else {
+        canvas_time
+            .time(|| canvas.get_course(sis_courseroom))?
+            .grading_standard_id
+            .and_then(|id| config.numeric_grades.for_scheme(id))
+    };
+    let (rubric, plain): (Vec<i32>, Vec<i32>) = moments
+        .values()
+        .flatten()
+        .map(|a| a.id)
+        .partition(|id| config.rubric_grades.is_rubric_graded(*id));
+    let comments = config.note_prefix.is_some();
+    let mut by_assignment = canvas_time
+        .time(|| canvas.get_submissions_by_assignment(sis_courseroom, &plain, false, comments))?;
+    by_assignment.extend(
+        canvas_time.time(|| {
+            canvas.get_submissions_by_assignment(sis_courseroom, &rubric, true, comments)
+        })?,
+    );
+    canvas_time.time(|| {
+        fill_missing_users(
+            &mut by_assignment,
+            config.uid_retries,
+            UID_RETRY_PAUSE,
+            |user_id| canvas.get_user(sis_courseroom, user_id),
+            &mut retval,
+        )
+    });
+    let mut fetched = Vec::with_capacity(moments.len());
+    for (moment_id, assignments) in &moments {
+        eprintln!(
+            "Should report on moment {} on course {:?}",
+            moment_id, tillfallen
+        );
+        let mut submissions = vec![];
+        let mut fallback_dates = BTreeMap::new();
+        for assignment in assignments {
+            let mut fetched = by_assignment.remove(&assignment.id).unwrap_or_default();
+            if config.rubric_grades.is_rubric_graded(assignment.id) {
+                config.rubric_grades.apply(assignment.id, &mut fetched);
+            }
+            for submission in &mut fetched {
+                submission.anonymous_unreleased = assignment.withholds_anonymous_grade(submission);
+            }
+            submissions.extend(fetched);
+            if config.due_date_fallback {
+                if let Some(date) = due_date_fallback(assignment, today()) {
+                    fallback_dates.insert(assignment.id, date);
+                }
+            }
+        }
+        if let Some(since) = since {
+            keep_changed_since(assignments, &mut submissions, since);
+        }
+
+        if since.is_some() && submissions.is_empty() {
+            info!("Nothing in {} is changed since {:?}", moment_id, since);
+            continue;
+        }
+        let resultat = ladok_read_time
+            .time(|| search_moment(ladok, config, &tillfallen, moment_id, 1, &mut retval));
+        let resultat = match resultat {
+            Ok(Some(resultat)) => resultat,
+            Ok(None) => continue,
+            Err(ref e) if ladok::is_forbidden(e) => {
+                retval.moment = Some(moment_id.clone());
+                let by_user = group_by_user(
+                    &submissions,
+                    &test_students,
+                    only_student,
+                    None,
+                    &mut retval,
+                );
+                let users = by_user
+                    .values()
+                    .filter_map(|s| s[0].user.as_ref())
+                    .collect::<Vec<_>>();
+                retval.not_authorized(moment_id, &users);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        let moment = MomentData {
+            id: moment_id.clone(),
+            resultat,
+            window: ladok_read_time.time(|| moment_window(ladok, config, moment_id, exam_date))?,
+            numeric: numeric.cloned(),
+            exam_date,
+        };
+        if let Some(date) = exam_date {
+            check_run_exam_date(config, &moment, date, today())?;
+        }
+        fetched.push((moment, submissions, fallback_dates));
+    }
+
+    let fetched = ladok_read_time
+        .time(|| with_scales(ladok, fetched, &test_students, only_student, &mut retval));
+    for (moment, submissions, _) in &fetched {
+        let unmappable =
+            ladok_read_time.time(|| unmappable_grades(ladok, config, moment, submissions))?;
+        retval.unmappable_grades.extend(unmappable);
+    }
+    if !retval.unmappable_grades.is_empty() {
+        warn!(
+            "Not exporting {}, some grades cannot be reported: {:?}",
+            sis_courseroom, retval.unmappable_grades,
+        );
+        return Ok(retval);
+    }
+
+    let mut held_back = vec![];
+    for (mut moment, submissions, fallback_dates) in fetched {
+        let moment_id = &moment.id.clone();
+        let mut changes = vec![];
+        let mut audit = vec![];
+        let mut written = vec![];
+        let mut authorized = true;
+
+        retval.moment = Some(moment_id.clone());
+        let by_user = group_by_user(
+            &submissions,
+            &test_students,
+            only_student,
+            Some(&moment.resultat),
+            &mut retval,
+        );
+
+        let mut pending = by_user.keys().cloned().collect::<BTreeSet<_>>();
+        let mut page = 1;
+        loop {
+            let last = match config.window_size {
+                Some(size) => {
+                    moment.resultat.Resultat.is_empty()
+                        || (page * size) as usize >= moment.resultat.TotaltAntalPoster
+                }
+                None => true,
+            };
+            let mut window = take_window(&mut pending, last, |id| {
+                by_user[&id][0]
+                    .user
+                    .as_ref()
+                    .and_then(|user| user.integration_id.as_ref())
+                    .map(|student| {
+                        let rounds = student_rounds.get(&id).map(Vec::as_slice).unwrap_or(&[]);
+                        moment
+                            .resultat
+                            .find_student_on(&StudentUid::new(student.as_str()), rounds)
+                            .is_some()
+                    })
+                    .unwrap_or(true)
+            });
+            if page > 1 {
+                ladok_read_time.time(|| {
+                    check_window(ladok, config, &moment, &mut window, &by_user, &mut retval)
+                });
+            }
+            let mut changed_users = vec![];
+            let mut owners = BTreeMap::new();
+            let mut reported = vec![];
+            for user_submissions in window.iter().map(|id| &by_user[id]) {
+                let submission = match combine::combine(&config.combine, user_submissions) {
+                    Ok(Some(submission)) => submission,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        retval.errors += 1;
+                        if let Some(canvas_user) = &user_submissions[0].user {
+                            retval.add(canvas_user, &format!(" Error ({})", e));
+                        }
+                        continue;
+                    }
+                };
+                if let Some(canvas_user) = &submission.user {
+                    if let Some(student) = ladok_uid(canvas_user, &moment.resultat) {
+                        let student = CanvasStudent {
+                            uid: StudentUid::new(student.as_str()),
+                            rounds: student_rounds
+                                .get(&canvas_user.id)
+                                .map(Vec::as_slice)
+                                .unwrap_or(&[]),
+                        };
+                        let fallback_date = exam_date_fallback(
+                            config,
+                            submission
+                                .assignment_id
+                                .and_then(|id| fallback_dates.get(&id).cloned()),
+                            today(),
+                        );
+                        match ladok_read_time.time(|| {
+                            prepare_ladok_change(
+                                ladok,
+                                config,
+                                &student,
+                                &moment,
+                                fallback_date,
+                                submission,
+                            )
+                        }) {
+                            Ok(ChangeToLadok::Update(data, grade, revert)) => {
+                                let change = Change::Update(data, revert);
+                                owners.extend(
+                                    change.uids().into_iter().map(|uid| (uid, canvas_user.id)),
+                                );
+                                reported.push(Reported {
+                                    student: canvas_user.id,
+                                    uids: change.uids(),
+                                    round: reported_round(&moment, &student, &round_names),
+                                    grade: change_grade(ladok, &change),
+                                });
+                                changes.push(change);
+                                changed_users.push(canvas_user);
+                                retval.add(canvas_user, &format!(" Updated ({}) ", grade));
+                            }
+                            Ok(ChangeToLadok::Create(data, grade)) => {
+                                let change = Change::Create(data);
+                                owners.extend(
+                                    change.uids().into_iter().map(|uid| (uid, canvas_user.id)),
+                                );
+                                reported.push(Reported {
+                                    student: canvas_user.id,
+                                    uids: change.uids(),
+                                    round: reported_round(&moment, &student, &round_names),
+                                    grade: change_grade(ladok, &change),
+                                });
+                                changes.push(change);
+                                changed_users.push(canvas_user);
+                                retval.add(canvas_user, &format!(" Created ({}) ", grade));
+                            }
+                            Ok(ChangeToLadok::NoChange(grade)) => {
+                                retval.unchanged(canvas_user, &grade);
+                            }
+                            Ok(ChangeToLadok::NoGrade) => {
+                                retval.add(canvas_user, " No grade ");
+                            }
+                            Ok(ChangeToLadok::Missing) => {
+                                retval.skipped_missing += 1;
+                                retval.add(canvas_user, " Missing, skipped ");
+                            }
+                            Ok(ChangeToLadok::Late) => {
+                                retval.add(canvas_user, " Late, skipped ");
+                            }
+                            Ok(ChangeToLadok::HeldForReview) => {
+                                retval.add(canvas_user, " Late, held for review ");
+                            }
+                            Ok(ChangeToLadok::NotPosted) => {
+                                retval.add(canvas_user, " Grade not yet posted, skipped ");
+                            }
+                            Ok(ChangeToLadok::AnonymousUnreleased) => {
+                                retval.add(
+                                    canvas_user,
+                                    " Grades not yet released (anonymous grading), skipped ",
+                                );
+                            }
+                            Ok(ChangeToLadok::NeedsRegrade) => {
+                                retval.needs_regrade += 1;
+                                retval.add(canvas_user, " Resubmitted, needs regrade, skipped ");
+                            }
+                            Ok(ChangeToLadok::SuspectTimestamp) => {
+                                retval.add(canvas_user, " Suspect grade timestamp, skipped ");
+                            }
+                            Ok(ChangeToLadok::NotRegistered) => {
+                                retval.add(canvas_user, " Not registered on round, skipped ");
+                            }
+                            Ok(ChangeToLadok::OtherLarosate) => {
+                                retval
+                                    .add(canvas_user, " Belongs to another institution, skipped ");
+                            }
+                            Ok(ChangeToLadok::NeedsCorrection(locked, grade)) => {
+                                retval.needs_correction += 1;
+                                retval.add(
+                                    canvas_user,
+                                    &format!(
+                                        " Requires grade correction in Ladok ({} in Ladok, {} in Canvas) ",
+                                        locked, grade,
+                                    ),
+                                );
+                            }
+                            Err(e) => {
+                                eprintln!("Error {}", e);
+                                retval.errors += 1;
+                                retval.add(canvas_user, &format!(" Error ({})", e));
+                            }
+                        }
+                        if config.dump_dir.is_some() {
+                            audit.push(AuditRow::new(
+                                ladok,
+                                student_label(canvas_user),
+                                moment
+                                    .resultat
+                                    .find_student_on(&student.uid, student.rounds),
+                                moment_id,
+                                submission.grade.as_deref(),
+                            ));
+                        }
+                    } else {
+                        retval.no_integration_id += 1;
+                        retval.add(canvas_user, " No integration_id ");
+                    }
+                }
+            }
+            info!(
+                "There are {} results to create and {} to update",
+                changes
+                    .iter()
+                    .filter(|c| matches!(c, Change::Create(_)))
+                    .count(),
+                changes
+                    .iter()
+                    .filter(|c| matches!(c, Change::Update(..)))
+                    .count(),
+            );
+            if let Some(dir) = &config.dump_dir {
+                let window = config.window_size.map(|_| page);
+                let requests = commit::plan(config.commit_order, changes.split_off(0));
+                let path = commit::dump(
+                    dir,
+                    &retval.correlation_id,
+                    moment_id,
+                    window,
+                    ladok.larosate(),
+                    &requests,
+                )?;
+                info!("Wrote Ladok requests to {}", path.display());
+                retval.dumped.push(path.display().to_string());
+                let path = audit::write_csv(
+                    dir,
+                    &retval.correlation_id,
+                    moment_id,
+                    window,
+                    &audit.split_off(0),
+                )?;
+                retval.dumped.push(path.display().to_string());
+                retval.record(moment_id, reported);
+            } else if config.all_or_nothing {
+                held_back.push(PendingWrite {
+                    moment: moment_id.clone(),
+                    changes: changes.split_off(0),
+                    users: changed_users.into_iter().cloned().collect(),
+                    owners,
+                    reported,
+                });
+            } else {
+                let count = changes.len();
+                let denied = ladok_write_time.time(|| {
+                    write_changes(
+                        ladok,
+                        config,
+                        &tillfallen,
+                        moment_id,
+                        changes.split_off(0),
+                        &mut retval,
+                        &mut written,
+                    )
+                });
+                if attest_mode {
+                    retval.attest_stopped = write_stop(moment_id, &retval);
+                }
+                retval.attribute_failures(&owners, &changed_users);
+                retval.record_written(moment_id, reported);
+                if let Err(denied) = denied {
+                    // Neither the students not written, nor those of
+                    // later windows, can be reported on the moment.
+                    let mut denied = owners_of(&owners, &denied, &changed_users);
+                    denied.extend(pending.iter().filter_map(|id| by_user[id][0].user.as_ref()));
+                    retval.not_authorized(moment_id, &denied);
+                    authorized = false;
+                    break;
+                }
+                if retval.attest_stopped.is_some() {
+                    break;
+                }
+                if config.window_size.is_some() {
+                    retval.windows.push(count);
+                }
+            }
+            if last {
+                break;
+            }
+            page += 1;
+            match ladok_read_time
+                .time(|| search_moment(ladok, config, &tillfallen, moment_id, page, &mut retval))
+            {
+                Ok(Some(resultat)) => moment.resultat = resultat,
+                Ok(None) => {
+                    let reason = "Anonymous marking, report manually in Ladok";
+                    fail_students(&pending, &by_user, reason, &mut retval);
+                    break;
+                }
+                Err(e) => {
+                    warn!("Failed to get window {} of {}: {}", page, moment_id, e);
+                    let reason = format!("Ladok results unavailable, try again: {}", e);
+                    fail_students(&pending, &by_user, &reason, &mut retval);
+                    break;
+                }
+            }
+        }
+        if let Some(dir) = &config.dump_dir {
+            if let Some(counts) = retval.grade_counts.get(moment_id.as_ref()) {
+                let path =
+                    audit::write_grade_counts(dir, &retval.correlation_id, moment_id, counts)?;
+                retval.dumped.push(path.display().to_string());
+            }
+            continue;
+        }
+        if retval.attest_stopped.is_some() {
+            break;
+        }
+        // Attested results leave the search, so attest only after all
+        // windows are fetched, not to shift the later windows.
+        if attest_mode && authorized {
+            let attested =
+                ladok_write_time.time(|| attest_in_steps(ladok, moment_id, written, &mut retval));
+            if let Err(stop) = attested {
+                retval.attest_stopped = Some(stop);
+                break;
+            }
+        } else if config.attest && authorized {
+            ladok_write_time.time(|| attest(ladok, written, &mut retval));
+        }
+    }
+    if config.all_or_nothing {
+        ladok_write_time.time(|| {
+            write_all_or_nothing(
+                ladok,
+                config,
+                &tillfallen,
+                held_back,
+                attest_mode,
+                &mut retval,
+            )
+        });
+    }
+    if only_student.is_some() && !retval.only_student_found {
+        return Err(NotInCourseRoom.into());
+    }
+    info!(
+        "Ok.  Done.  Spent {:?} in Canvas, {:?} reading and {:?} writing Ladok.",
+        canvas_time.total(),
+        ladok_read_time.total(),
+        ladok_write_time.total(),
+    );
+    Ok(retval)
+}
+
+/// Search the results of a moment, or a window of them.
+///
+/// Returns None, after telling why in `retval`, if the moment is
+/// anonymized, since anonymous results must not be matched to students
+/// by name or uid.  Every window is checked, as the anonymized results
+/// may not be among the first.  If Ladok does not allow reporting on
+/// the moment, the error is left for the caller, to list the students.
+fn search_moment(
+    ladok: &Ladok,
+    config: &ReportConfig,
+    tillfallen: &[Tillfalle],
+    moment_id: &MomentUid,
+    page: u32,
+    retval: &mut ExportResults,
+) -> Result<Option<SokresultatStudieresultatResultat>, Error> {
+    let resultat = match config.window_size {
+        Some(size) => ladok.sok_studieresultat_page(tillfallen, moment_id, page, size),
+        None => ladok.sok_studieresultat(tillfallen, moment_id),
+    };
+    match resultat {
+        Ok(ref resultat) if resultat.Resultat.iter().any(Studieresultat::is_anonymized) => {
+            warn!("Moment {} is anonymized, not reporting it", moment_id);
+            retval.anonymized.push(moment_id.clone());
+            Ok(None)
+        }
+        resultat => resultat.map(Some),
+    }
+}
+
+/// Changes held back until all moments are checked.
+struct PendingWrite {
+    moment: MomentUid,
+    changes: Vec<Change>,
+    /// The students of the changes.
+    users: Vec<User>,
+    /// The Canvas user id for the uids of each change.
+    owners: BTreeMap<String, i32>,
+    reported: Vec<Reported>,
+}
+
+/// A change queued for a student, to record once it is written.
+struct Reported {
+    /// The Canvas user id of the student.
+    student: i32,
+    /// The uids of the change, as given by `Change::uids`.
+    uids: Vec<String>,
+    /// The round of the result, if known.
+    round: Option<String>,
+    /// The Ladok grade of the result.
+    grade: Option<String>,
+}
+
+/// The Ladok grade a change reports, for the grade counts, with
+/// komplettering told apart.
+fn change_grade(ladok: &mut Ladok, change: &Change) -> Option<String> {
+    let (scale, grade, noteringar) = match change {
+        Change::Create(data) => (data.BetygsskalaID, data.Betygsgrad?, &data.Noteringar),
+        Change::Update(data, _) => (data.BetygsskalaID, data.Betygsgrad?, &data.Noteringar),
+    };
+    let code = ladok.grade_code(scale, grade).ok()?;
+    Some(if Notering::has_komplettering(noteringar) {
+        format!("{} (komplettering)", code)
+    } else {
+        code
+    })
+}
+
+/// Write the changes of all moments, but only if no student failed.
+fn write_all_or_nothing(
+    ladok: &Ladok,
+    config: &ReportConfig,
+    tillfallen: &[Tillfalle],
+    pending: Vec<PendingWrite>,
+    attest_mode: bool,
+    retval: &mut ExportResults,
+) {
+    if retval.errors > 0 {
+        warn!(
+            "Not writing to Ladok, {} students could not be reported",
+            retval.errors
+        );
+        retval.notice = Some(format!(
+            "Nothing was written to Ladok, since {} student(s) could not be reported.",
+            retval.errors
+        ));
+        return;
+    }
+    for pending in pending {
+        let mut written = vec![];
+        let denied = write_changes(
+            ladok,
+            config,
+            tillfallen,
+            &pending.moment,
+            pending.changes,
+            retval,
+            &mut written,
+        );
+        let users = pending.users.iter().collect::<Vec<_>>();
+        if attest_mode {
+            retval.attest_stopped = write_stop(&pending.moment, retval);
+        }
+        retval.attribute_failures(&pending.owners, &users);
+        retval.record_written(&pending.moment, pending.reported);
+        if let Err(denied) = denied {
+            let denied = owners_of(&pending.owners, &denied, &users);
+            retval.not_authorized(&pending.moment, &denied);
+        } else if attest_mode {
+            if retval.attest_stopped.is_none() {
+                if let Err(stop) = attest_in_steps(ladok, &pending.moment, written, retval) {
+                    retval.attest_stopped = Some(stop);
+                }
+            }
+            if retval.attest_stopped.is_some() {
+                return;
+            }
+        } else if config.attest {
+            attest(ladok, written, retval);
+        }
+    }
+}
+
+/// Klarmarkera and attest the written results of a moment.
+fn attest(ladok: &Ladok, written: Vec<Resultat>, retval: &mut ExportResults) {
+    if written.is_empty() {
+        return;
+    }
+    retval.undo.attested = true;
+    retval.attested = Some(
+        ladok
+            .klarmarkera_och_attestera(written)
+            .map(|result| result.len())
+            .map_err(|e| e.to_string()),
+    );
+}
+
+/// A step of reporting and attesting a moment, in order.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+enum AttestStep {
+    Write,
+    Klarmarkera,
+    Attestera,
+}
+
+/// Where reporting and attesting stopped, and why.
+#[derive(Debug, Serialize)]
+pub struct AttestStop {
+    moment: MomentUid,
+    step: AttestStep,
+    error: String,
+}
+
+impl AttestStop {
+    /// The state the results of the moment are left in.
+    fn state(&self) -> &'static str {
+        match self.step {
+            AttestStep::Write => {
+                "Alla resultat kunde inte skrivas till Ladok.  Inget på momentet har klarmarkerats, \
+                 och de resultat som skrevs är utkast."
+            }
+            AttestStep::Klarmarkera => {
+                "Resultaten är skrivna som utkast i Ladok, men inte klarmarkerade."
+            }
+            AttestStep::Attestera => "Resultaten är klarmarkerade i Ladok, men inte attesterade.",
+        }
+    }
+}
+
+/// Why writing a moment should stop a report and attest run, if it
+/// should.  Call before the failures are attributed to students.
+fn write_stop(moment: &MomentUid, retval: &ExportResults) -> Option<AttestStop> {
+    let error = match (&retval.created, &retval.updated) {
+        (Err(e), _) | (_, Err(e)) => e.clone(),
+        _ if !retval.ladok_failures.is_empty() => format!(
+            "Ladok did not accept {} result(s)",
+            retval.ladok_failures.len()
+        ),
+        _ => return None,
+    };
+    Some(AttestStop {
+        moment: moment.clone(),
+        step: AttestStep::Write,
+        error,
+    })
+}
+
+/// Klarmarkera and then attest the written results of a moment, one
+/// step at a time, so a failure tells what state they are left in.
+fn attest_in_steps(
+    ladok: &Ladok,
+    moment: &MomentUid,
+    written: Vec<Resultat>,
+    retval: &mut ExportResults,
+) -> Result<(), AttestStop> {
+    let stop = |step, e: Error| AttestStop {
+        moment: moment.clone(),
+        step,
+        error: e.to_string(),
+    };
+    let (utkast, mut ready): (Vec<_>, Vec<_>) = written
+        .into_iter()
+        .filter(|r| r.ProcessStatus != Some(ProcessStatus::Attesterad))
+        .partition(|r| r.ProcessStatus != Some(ProcessStatus::Klarmarkerad));
+    if !utkast.is_empty() {
+        let klarmarkerade = ladok
+            .klarmarkera_studieresultat(&utkast)
+            .map_err(|e| stop(AttestStep::Klarmarkera, e))?;
+        ready.extend(klarmarkerade);
+    }
+    if ready.is_empty() {
+        return Ok(());
+    }
+    retval.undo.attested = true;
+    let attested = ladok
+        .attestera_studieresultat(&ready)
+        .map_err(|e| stop(AttestStep::Attestera, e))?;
+    let total = retval.attested.get_or_insert(Ok(0));
+    commit::tally(total, Ok(attested.len()));
+    Ok(())
+}
+
+/// Write changes to Ladok, collecting the written results.
+///
+/// If Ladok does not allow reporting on the moment, the remaining
+/// changes are not tried, and the uids of the changes not written are
+/// returned.  Other errors are tallied in `retval`.
+fn write_changes(
+    ladok: &Ladok,
+    config: &ReportConfig,
+    tillfallen: &[Tillfalle],
+    moment_id: &MomentUid,
+    changes: Vec<Change>,
+    retval: &mut ExportResults,
+    written: &mut Vec<Resultat>,
+) -> Result<(), Vec<String>> {
+    let undo = &mut retval.undo;
+    let failures = &mut retval.ladok_failures;
+    let written_uids = &mut retval.written_uids;
+    let mut requests = commit::plan(config.commit_order, changes).into_iter();
+    while let Some(request) = requests.next() {
+        let uids = request.uids();
+        let (total, outcome) = match request {
+            Request::Create(data) => (
+                &mut retval.created,
+                ladok
+                    .skapa_studieresultat(data.clone())
+                    .or_else(|e| {
+                        if ladok::is_forbidden(&e)
+                            || ladok::is_maintenance(&e)
+                            || e.downcast_ref::<ladok::NotAuthorized>().is_some()
+                        {
+                            return Err(e);
+                        }
+                        warn!("Failed to create results, retrying: {}", e);
+                        ladok.skapa_saknade_studieresultat(tillfallen, moment_id, data)
+                    })
+                    .inspect(|result| undo.add_created(&result.resultat)),
+            ),
+            Request::Update(data, reverts) => (
+                &mut retval.updated,
+                ladok
+                    .uppdatera_studieresultat(data)
+                    .inspect(|result| undo.add_updated(reverts, &result.resultat)),
+            ),
+        };
+        match outcome {
+            Err(ref e) if ladok::is_forbidden(e) => {
+                return Err(uids
+                    .into_iter()
+                    .chain(requests.flat_map(|r| r.uids()))
+                    .collect());
+            }
+            outcome => commit::tally(
+                total,
+                outcome.map(|result| {
+                    let n = result.resultat.len();
+                    let failed = result.fel.iter().flat_map(|f| f.uids()).collect::<Vec<_>>();
+                    written_uids.extend(uids.into_iter().filter(|uid| !failed.contains(uid)));
+                    written.extend(result.resultat);
+                    failures.extend(result.fel);
+                    n
+                }),
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// The users owning any of `uids`, as found in `owners`.
+fn owners_of<'a>(
+    owners: &BTreeMap<String, i32>,
+    uids: &[String],
+    users: &[&'a User],
+) -> Vec<&'a User> {
+    users
+        .iter()
+        .filter(|user| uids.iter().any(|uid| owners.get(uid) == Some(&user.id)))
+        .cloned()
+        .collect()
+}
+
+/// The pause between retries of a failed user lookup.
+const UID_RETRY_PAUSE: StdDuration = StdDuration::from_millis(500);
+
+/// Look up a Canvas user by `lookup`, retried up to `retries` times if
+/// it fails.
+fn lookup_user(
+    user_id: i32,
+    retries: u32,
+    pause: StdDuration,
+    mut lookup: impl FnMut() -> Result<User, Error>,
+) -> Result<User, String> {
+    let mut attempt = 0;
+    loop {
+        match lookup() {
+            Ok(found) => return Ok(found),
+            Err(e) if attempt < retries => {
+                warn!("Failed to look up user {}, retrying: {}", pii(user_id), e);
+                attempt += 1;
+                std::thread::sleep(pause);
+            }
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+}
+
+/// Fill in the user of submissions that come without one, by looking
+/// up each such user once.  A user that cannot be looked up is counted
+/// as an error, so an all-or-nothing export writes nothing, and its
+/// submissions are dropped.
+fn fill_missing_users(
+    by_assignment: &mut HashMap<i32, Vec<Submission>>,
+    retries: u32,
+    pause: StdDuration,
+    mut lookup: impl FnMut(i32) -> Result<User, Error>,
+    retval: &mut ExportResults,
+) {
+    let missing = by_assignment
+        .values()
+        .flatten()
+        .filter(|s| s.user.is_none())
+        .filter_map(|s| s.user_id)
+        .collect::<BTreeSet<_>>();
+    let mut found = BTreeMap::new();
+    for user_id in missing {
+        match lookup_user(user_id, retries, pause, || lookup(user_id)) {
+            Ok(user) => {
+                found.insert(user_id, user);
+            }
+            Err(e) => {
+                retval.uid_lookup_failed += 1;
+                retval.errors += 1;
+                let user = User::unknown(user_id);
+                retval.add(
+                    &user,
+                    &format!(" Could not look up student, try again ({}) ", e),
+                );
+            }
+        }
+    }
+    for submissions in by_assignment.values_mut() {
+        submissions.retain_mut(|s| match (s.user.is_none(), s.user_id) {
+            (true, Some(user_id)) => match found.get(&user_id) {
+                Some(user) => {
+                    s.user = Some(user.clone());
+                    true
+                }
+                None => false,
+            },
+            _ => true,
+        });
+    }
+}
+
+/// The Ladok uid of a Canvas user, as its integration_id or, lacking
+/// that, by the personnummer in its sis_user_id if the student is in
+/// the result list.
+fn ladok_uid(user: &User, resultat: &SokresultatStudieresultatResultat) -> Option<String> {
+    user.integration_id.clone().or_else(|| {
+        user.sis_user_id
+            .as_deref()
+            .and_then(|personnummer| resultat.find_personnummer(personnummer))
+            .map(|uid| uid.to_string())
+    })
+}
+
+/// Keep only the submissions to a moment of students with a submission
+/// graded at or after `since`, unless an assignment of the moment is
+/// itself changed since then.  All submissions of such a student are
+/// kept, since the grade of a moment may be combined from several
+/// assignments.  Submissions without a grading time count as changed,
+/// rather than risk missing a grade.  A grade considered again is only
+/// written if it differs from Ladok.
+fn keep_changed_since(
+    assignments: &[&Assignment],
+    submissions: &mut Vec<Submission>,
+    since: DateTime<Utc>,
+) {
+    if assignments
+        .iter()
+        .any(|a| a.updated_at.is_some_and(|updated| updated >= since))
+    {
+        return;
+    }
+    let changed = submissions
+        .iter()
+        .filter(|s| s.graded_at.is_none_or(|graded| graded >= since))
+        .map(|s| s.user_id)
+        .collect::<BTreeSet<_>>();
+    submissions.retain(|s| changed.contains(&s.user_id));
+}
+
+/// Group the submissions of real students by user, only those of
+/// `only_student` if given.  The Ladok uid of each user is resolved
+/// as for reporting, by personnummer in `resultat` if the user lacks
+/// an integration_id.
+fn group_by_user<'a>(
+    submissions: &'a [Submission],
+    test_students: &[i32],
+    only_student: Option<&StudentUid>,
+    resultat: Option<&SokresultatStudieresultatResultat>,
+    retval: &mut ExportResults,
+) -> BTreeMap<i32, Vec<&'a Submission>> {
+    let mut by_user = BTreeMap::<i32, Vec<&Submission>>::new();
+    for submission in submissions {
+        if let Some(canvas_user) = &submission.user {
+            if let Some(student) = only_student {
+                let uid = match resultat {
+                    Some(resultat) => ladok_uid(canvas_user, resultat),
+                    None => canvas_user.integration_id.clone(),
+                };
+                if uid.as_deref() != Some(student.as_ref()) {
+                    continue;
+                }
+                retval.only_student_found = true;
+            }
+            if test_students.contains(&canvas_user.id) || canvas_user.looks_like_test_student() {
+                retval.exclude_test_student(canvas_user);
+            } else {
+                by_user.entry(canvas_user.id).or_default().push(submission);
+            }
+        } else if only_student.is_none() {
+            retval.skip_no_user(submission);
+        }
+    }
+    by_user
+}
+
+/// The distinct rounds of the sections, and the rounds of each of the
+/// `students`, by the sections they are enrolled in.
+///
+/// Cross-listed sections may share a round, which should still only be
+/// searched once, and listed once for each student.
+fn rounds_of_sections(
+    sections: &[CourseSection],
+    students: &[User],
+) -> (Vec<Tillfalle>, BTreeMap<i32, Vec<RoundUid>>) {
+    let mut tillfallen = BTreeSet::new();
+    let mut section_rounds = BTreeMap::new();
+    for section in sections {
+        if let Some(round) = &section.integration_id {
+            let tillfalle = Tillfalle::from_integration_id(round);
+            section_rounds.insert(section.id, tillfalle.uid().clone());
+            tillfallen.insert(tillfalle);
+        }
+    }
+    let mut student_rounds = BTreeMap::<i32, Vec<RoundUid>>::new();
+    for student in students {
+        for section in student.section_ids() {
+            if let Some(round) = section_rounds.get(&section) {
+                let rounds = student_rounds.entry(student.id).or_default();
+                if !rounds.contains(round) {
+                    rounds.push(round.clone());
+                }
+            }
+        }
+    }
+    (tillfallen.into_iter().collect(), student_rounds)
+}
+
+/// The name of the first section on each round, for display.
+fn round_names(sections: &[CourseSection]) -> BTreeMap<RoundUid, String> {
+    let mut names = BTreeMap::new();
+    for section in sections {
+        if let (Some(round), Some(name)) = (&section.integration_id, &section.name) {
+            let round = Tillfalle::from_integration_id(round).uid().clone();
+            names.entry(round).or_insert_with(|| name.clone());
+        }
+    }
+    names
+}
+
+/// The round a student is reported on, with its name if known.
+fn reported_round(
+    moment: &MomentData,
+    student: &CanvasStudent,
+    names: &BTreeMap<RoundUid, String>,
+) -> Option<String> {
+    let round = moment
+        .resultat
+        .find_student_on(&student.uid, student.rounds)?
+        .round()?;
+    Some(match names.get(round) {
+        Some(name) => format!("{} ({})", name, round),
+        None => round.to_string(),
+    })
+}
+
+const NO_MOMENTS: &str = "No Canvas assignments are linked to Ladok moments for this course. \
+     Set the integration id of the assignments to the uid of their Ladok moments.";
+
+/// Get the assignments of a course room, only those in an assignment
+/// group if one is given.
+fn get_assignments(
+    canvas: &Canvas,
+    sis_courseroom: &str,
+    assignment_group: Option<&str>,
+) -> Result<Vec<Assignment>, Error> {
+    let assignments = canvas.get_assignments(sis_courseroom)?;
+    match assignment_group {
+        Some(group) => {
+            let groups = canvas.get_assignment_groups(sis_courseroom)?;
+            in_assignment_group(assignments, group, &groups)
+        }
+        None => Ok(assignments),
+    }
+}
+
+/// Keep the assignments in the group given by id or name.
+fn in_assignment_group(
+    mut assignments: Vec<Assignment>,
+    group: &str,
+    groups: &[AssignmentGroup],
+) -> Result<Vec<Assignment>, Error> {
+    let group = group.trim();
+    let id = groups
+        .iter()
+        .find(|g| g.id.to_string() == group)
+        .or_else(|| groups.iter().find(|g| g.name.eq_ignore_ascii_case(group)))
+        .map(|g| g.id)
+        .ok_or_else(|| format_err!("No assignment group {:?} in the course room", group))?;
+    assignments.retain(|a| a.assignment_group_id == Some(id));
+    Ok(assignments)
+}
+
+/// Group the assignments that map to a Ladok moment by moment.
+fn group_by_moment<'a>(
+    config: &ReportConfig,
+    sis_courseroom: &str,
+    assignments: &'a [Assignment],
+) -> BTreeMap<MomentUid, Vec<&'a Assignment>> {
+    let mut moments = BTreeMap::<MomentUid, Vec<&Assignment>>::new();
+    for assignment in assignments {
+        if let Some(moment_id) = config.moment_mapping.moment_for(sis_courseroom, assignment) {
+            moments.entry(moment_id).or_default().push(assignment);
+        }
+    }
+    moments
+}
+
+/// The result of an export, kept as an artifact.
+#[derive(Serialize)]
+struct ExportArtifact<'a> {
+    sis_course_id: &'a str,
+    error: Option<String>,
+    result: Option<&'a ExportResults>,
+}
+
+/// Write the result of an export as an artifact, and prune old ones.
+fn keep_artifact(
+    artifacts: &Artifacts,
+    sis_course_id: &str,
+    result: &Result<ExportResults, Error>,
+    now: chrono::DateTime<Utc>,
+) -> Result<PathBuf, Error> {
+    let correlation_id = match result {
+        Ok(result) => result.correlation_id.clone(),
+        Err(_) => new_correlation_id(),
+    };
+    let path = artifacts.write(
+        &correlation_id,
+        now,
+        &ExportArtifact {
+            sis_course_id,
+            error: result.as_ref().err().map(ToString::to_string),
+            result: result.as_ref().ok(),
+        },
+    )?;
+    artifacts.prune(now)?;
+    Ok(path)
+}
+
+/// The outcome per student, identified as LOG_PII says.
+fn serialize_students<S: Serializer>(
+    students: &BTreeMap<i32, String>,
+    s: S,
+) -> Result<S::Ok, S::Error> {
+    s.collect_seq(students.iter().map(|(id, text)| {
+        if pii_is_plain() {
+            return text.clone();
+        }
+        let after = |label: String| text.find(&label).map(|i| &text[i + label.len()..]);
+        let outcome = after(format!("({}): ", id))
+            .or_else(|| after(format!("Canvas user {}: ", id)))
+            .unwrap_or_default();
+        format!("{}: {}", pii(id), outcome)
+    }))
+}
+
+/// Values by Canvas user, as pairs with the user as LOG_PII says.
+fn serialize_by_student<S: Serializer, T: Serialize>(
+    map: &BTreeMap<i32, T>,
+    s: S,
+) -> Result<S::Ok, S::Error> {
+    s.collect_seq(map.iter().map(|(id, value)| (pii(id), value)))
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExportResults {
+    /// Identifies this export, e.g. for undo.
+    correlation_id: String,
+    #[serde(serialize_with = "serialize_students")]
+    students: BTreeMap<i32, String>,
+    /// Submissions without an associated user (test students, deleted users).
+    skipped_no_user: usize,
+    /// Submissions skipped since they are flagged as missing.
+    skipped_missing: usize,
+    /// Results that can only be changed by a correction in Ladok.
+    needs_correction: usize,
+    /// Submissions skipped since they are resubmitted after grading.
+    needs_regrade: usize,
+    /// Results already up to date in Ladok.
+    unchanged: usize,
+    /// Students that could not be reported.
+    errors: usize,
+    /// Students without a Ladok uid in Canvas, to fix in the setup.
+    no_integration_id: usize,
+    /// Students whose Ladok uid could not be looked up, e.g. since
+    /// Canvas failed, and that may work on another try.
+    uid_lookup_failed: usize,
+    /// Results Ladok did not accept, not yet attributed to students.
+    #[serde(skip)]
+    ladok_failures: Vec<ResultatFel>,
+    /// The uids of the changes Ladok accepted, as given by
+    /// `Change::uids`.
+    #[serde(skip)]
+    written_uids: BTreeSet<String>,
+    /// Only list students whose results are changed.
+    changes_only: bool,
+    created: Result<usize, String>,
+    updated: Result<usize, String>,
+    /// Files with the requests, if dumped rather than sent to Ladok.
+    dumped: Vec<String>,
+    /// None unless attestering is enabled.
+    attested: Option<Result<usize, String>>,
+    /// Where reporting and attesting stopped, if it failed.
+    attest_stopped: Option<AttestStop>,
+    /// Information for the user, e.g. why nothing was exported.
+    notice: Option<String>,
+    /// Grades that cannot be reported, found before writing anything.
+    unmappable_grades: Vec<String>,
+    /// The number of changes written per window, if windowed.
+    windows: Vec<usize>,
+    /// Moments Ladok does not allow us to report on.
+    not_authorized: Vec<MomentUid>,
+    /// Moments with anonymous marking, to report manually in Ladok.
+    anonymized: Vec<MomentUid>,
+    /// The moment being reported.
+    #[serde(skip)]
+    moment: Option<MomentUid>,
+    /// Whether the single student to report, if any, had submissions.
+    #[serde(skip)]
+    only_student_found: bool,
+    /// The outcome for each student on each moment.
+    #[serde(serialize_with = "serialize_by_student")]
+    moment_outcomes: BTreeMap<i32, Vec<(String, String)>>,
+    /// The Ladok rounds each student's results were written on.
+    #[serde(serialize_with = "serialize_by_student")]
+    rounds: BTreeMap<i32, Vec<String>>,
+    /// The number of each grade reported, per moment.
+    grade_counts: BTreeMap<String, BTreeMap<String, usize>>,
+    #[serde(skip)]
+    undo: UndoRecord,
+}
+
+impl ExportResults {
+    fn new() -> Self {
+        ExportResults {
+            correlation_id: new_correlation_id(),
+            students: BTreeMap::new(),
+            skipped_no_user: 0,
+            skipped_missing: 0,
+            needs_correction: 0,
+            needs_regrade: 0,
+            unchanged: 0,
+            errors: 0,
+            no_integration_id: 0,
+            uid_lookup_failed: 0,
+            ladok_failures: vec![],
+            written_uids: BTreeSet::new(),
+            changes_only: false,
+            created: Ok(0),
+            updated: Ok(0),
+            dumped: vec![],
+            attested: None,
+            attest_stopped: None,
+            notice: None,
+            unmappable_grades: vec![],
+            windows: vec![],
+            not_authorized: vec![],
+            anonymized: vec![],
+            moment: None,
+            only_student_found: false,
+            moment_outcomes: BTreeMap::new(),
+            rounds: BTreeMap::new(),
+            grade_counts: BTreeMap::new(),
+            undo: UndoRecord::default(),
+        }
+    }
+    fn add(&mut self, student: &User, status: &str) {
+        self.students
+            .entry(student.id)
+            .or_insert_with(|| format!("{}: ", student_label(student)))
+            .push_str(status);
+        if let Some(moment) = &self.moment {
+            self.moment_outcomes
+                .entry(student.id)
+                .or_default()
+                .push((moment.to_string(), status.trim().to_string()));
+        }
+    }
+    /// Count a grade reported on a moment.
+    fn count_grade(&mut self, moment: &MomentUid, grade: String) {
+        *self
+            .grade_counts
+            .entry(moment.to_string())
+            .or_default()
+            .entry(grade)
+            .or_default() += 1;
+    }
+    /// Tell the students of the results Ladok did not accept.
+    ///
+    /// `owners` maps the uids of each change to the Canvas user id of
+    /// its student.
+    fn attribute_failures(&mut self, owners: &BTreeMap<String, i32>, users: &[&User]) {
+        for fel in std::mem::take(&mut self.ladok_failures) {
+            self.errors += 1;
+            let message = fel.Meddelande.as_deref().unwrap_or("no reason given");
+            let user = fel
+                .uids()
+                .iter()
+                .find_map(|uid| owners.get(uid))
+                .and_then(|id| users.iter().find(|user| user.id == *id));
+            match user {
+                Some(user) => self.add(user, &format!(" Failed in Ladok ({}) ", message)),
+                None => warn!("Ladok did not accept a result: {}", message),
+            }
+        }
+    }
+    /// Record changes on a moment as reported, e.g. when dumped.
+    fn record(&mut self, moment: &MomentUid, reported: Vec<Reported>) {
+        for reported in reported {
+            if let Some(round) = reported.round {
+                self.reported_on(reported.student, round);
+            }
+            if let Some(grade) = reported.grade {
+                self.count_grade(moment, grade);
+            }
+        }
+    }
+    /// Record the changes on a moment Ladok accepted as reported.
+    fn record_written(&mut self, moment: &MomentUid, reported: Vec<Reported>) {
+        let written = reported
+            .into_iter()
+            .filter(|r| r.uids.iter().all(|uid| self.written_uids.contains(uid)))
+            .collect();
+        self.record(moment, written);
+    }
+    /// A result of the student is written on `round`.
+    fn reported_on(&mut self, student: i32, round: String) {
+        let rounds = self.rounds.entry(student).or_default();
+        if !rounds.contains(&round) {
+            rounds.push(round);
+        }
+    }
+    /// Ladok denied reporting on a moment, for these students.
+    fn not_authorized(&mut self, moment: &MomentUid, students: &[&User]) {
+        warn!("Not authorized to report on moment {}", moment);
+        self.not_authorized.push(moment.clone());
+        for student in students {
+            self.add(student, " Not authorized to report this moment ");
+        }
+    }
+    fn unchanged(&mut self, student: &User, grade: &str) {
+        self.unchanged += 1;
+        if !self.changes_only {
+            self.add(student, &format!(" No change ({}) ", grade));
+        }
+    }
+    fn exclude_test_student(&mut self, student: &User) {
+        self.students
+            .entry(student.id)
+            .or_insert_with(|| format!("{}: Excluded: test student", student_label(student)));
+    }
+    fn skip_no_user(&mut self, submission: &Submission) {
+        warn!(
+            "Skipping submission for user_id {}: no associated user",
+            submission.user_id.map(pii).unwrap_or_else(|| "-".into()),
+        );
+        self.skipped_no_user += 1;
+    }
+}
+
+/// A student as teachers can recognize them, by name and Canvas id.
+fn student_label(student: &User) -> String {
+    match &student.name {
+        Some(name) => format!("{} ({})", name, student.id),
+        None => format!("Canvas user {}", student.id),
+    }
+}
+
+/// Create an id for an export, unique for the life of the process.
+fn new_correlation_id() -> String {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    format!(
+        "{:x}-{:x}",
+        Utc::now().timestamp_millis(),
+        COUNTER.fetch_add(1, Ordering::SeqCst),
+    )
+}
+
+/// Load the grading scales of each moment, keeping the moments whose
+/// scales are available.  The students of a moment without its scale
+/// are counted as errors, while other moments are still reported.
+fn with_scales<T>(
+    ladok: &Ladok,
+    fetched: Vec<(MomentData, Vec<Submission>, T)>,
+    test_students: &[i32],
+    only_student: Option<&StudentUid>,
+    retval: &mut ExportResults,
+) -> Vec<(MomentData, Vec<Submission>, T)> {
+    let mut available = Vec::with_capacity(fetched.len());
+    for (moment, submissions, extra) in fetched {
+        let failed = moment
+            .resultat
+            .Resultat
+            .iter()
+            .filter_map(|r| r.get_betygsskala())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .find_map(|scale| ladok.load_betygskala(scale).err());
+        match failed {
+            None => available.push((moment, submissions, extra)),
+            Some(e) => {
+                warn!("Grading scale of {} unavailable: {}", moment.id, e);
+                retval.moment = Some(moment.id.clone());
+                let by_user = group_by_user(
+                    &submissions,
+                    test_students,
+                    only_student,
+                    Some(&moment.resultat),
+                    retval,
+                );
+                for user_submissions in by_user.values() {
+                    if let Some(user) = &user_submissions[0].user {
+                        retval.errors += 1;
+                        retval.add(user, " Grading scale unavailable in Ladok, try again ");
+                    }
+                }
+            }
+        }
+    }
+    available
+}
+
+/// The Canvas grades on a moment that cannot be reported on its scale.
+/// Grades of submissions that are skipped anyway are not checked.
+///
+/// This is checked for all moments before writing anything, so a
+/// mistake in the grade mapping is found before a partial export.
+/// Failing to fetch a scale from Ladok is an error rather than an
+/// unmappable grade, since it says nothing about the mapping.
+fn unmappable_grades(
+    ladok: &mut Ladok,
+    config: &ReportConfig,
+    moment: &MomentData,
+    submissions: &[Submission],
+) -> Result<Vec<String>, Error> {
+    let scales = moment
+        .resultat
+        .Resultat
+        .iter()
+        .filter_map(|r| r.get_betygsskala())
+        .collect::<BTreeSet<_>>();
+    for scale in &scales {
+        ladok.load_betygskala(*scale)?;
+    }
+    let mut grades = BTreeSet::new();
+    let mut unmapped = BTreeSet::new();
+    let reported = submissions
+        .iter()
+        .filter(|submission| skipped(config, submission).is_none());
+    for grade in reported.filter_map(Submission::given_grade) {
+        let (grade, komplettering) = split_komplettering(config, grade);
+        if let Err(e) = check_komplettering(config, &moment.id, komplettering) {
+            unmapped.insert(e.to_string());
+        }
+        match moment.grade_code(grade) {
+            Ok(grade) => {
+                grades.insert(grade);
+            }
+            Err(e) => {
+                unmapped.insert(format!("Moment {}: {}", moment.id, e));
+            }
+        }
+    }
+    let mut result = unmapped.into_iter().collect::<Vec<_>>();
+    let allowed = config
+        .moment_grades
+        .get(moment.id.as_ref())
+        .map(Vec::as_slice);
+    let is_final = moment.is_final(config);
+    for scale in scales {
+        for grade in &grades {
+            let checked = ladok
+                .get_grade(scale, grade, allowed)
+                .and_then(|found| check_final_grade(&found, is_final));
+            if let Err(e) = checked {
+                result.push(format!("Moment {}: {}", moment.id, e));
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// The grade without the komplettering suffix, and whether it had it.
+fn split_komplettering<'a>(config: &ReportConfig, grade: &'a str) -> (&'a str, bool) {
+    let suffix = config.komplettering_suffix.as_str();
+    match grade.trim().strip_suffix(suffix) {
+        Some(grade) if !suffix.is_empty() => (grade.trim(), true),
+        _ => (grade, false),
+    }
+}
+
+/// Check that komplettering, if given, is permitted on the moment.
+fn check_komplettering(
+    config: &ReportConfig,
+    moment: &MomentUid,
+    komplettering: bool,
+) -> Result<(), Error> {
+    if komplettering && !config.komplettering_moments.contains(moment.as_ref()) {
+        Err(format_err!(
+            "Moment {} does not permit komplettering",
+            moment
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Check that a grade on a final grade moment is valid as a final grade.
+fn check_final_grade(grade: &Betygsgrad, is_final: bool) -> Result<(), Error> {
+    if is_final && !grade.valid_as_final() {
+        Err(format_err!(
+            "Grade {:?} is not valid as a final grade",
+            grade.Kod
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// The Ladok grade for the code of a Canvas grade, on the scale of the
+/// studieresultat `one` of `student`, if it may be reported.
+fn ladok_grade(
+    ladok: &mut Ladok,
+    config: &ReportConfig,
+    moment: &MomentData,
+    one: &Studieresultat,
+    student: &StudentUid,
+    grade: &str,
+) -> Result<(BetygsskalaID, Betygsgrad), Error> {
+    let betygskala = one
+        .get_betygsskala()
+        .ok_or_else(|| format_err!("Missing Betygskala for student {}", pii(student)))?;
+    let allowed = config
+        .moment_grades
+        .get(moment.id.as_ref())
+        .map(Vec::as_slice);
+    let grade = ladok.get_grade(betygskala, grade, allowed)?;
+    check_final_grade(&grade, moment.is_final(config))?;
+    Ok((betygskala, grade))
+}
+
+/// What is known about a moment to report on.
+struct MomentData {
+    id: MomentUid,
+    resultat: SokresultatStudieresultatResultat,
+    /// The period in which the moment may be examined, if known.
+    window: Option<Giltighetsperiod>,
+    /// The mapping of numeric grades, if the course has a numeric
+    /// grading scheme.
+    numeric: Option<NumericScale>,
+    /// The examination date of all students, if given for the run.
+    exam_date: Option<NaiveDate>,
+}
+
+impl MomentData {
+    /// A moment with results but nothing else known, for tests.
+    #[cfg(test)]
+    fn test(id: &str, resultat: SokresultatStudieresultatResultat) -> Self {
+        MomentData {
+            id: MomentUid::new(id),
+            resultat,
+            window: None,
+            numeric: None,
+            exam_date: None,
+        }
+    }
+    /// True if the moment is the final course grade, either since it
+    /// is the kursinstans of the results or by configuration.
+    fn is_final(&self, config: &ReportConfig) -> bool {
+        config.final_moments.contains(self.id.as_ref())
+            || self
+                .resultat
+                .Resultat
+                .iter()
+                .any(|r| r.kursinstans() == Some(self.id.as_ref()))
+    }
+    /// The Ladok grade code for a Canvas grade.
+    fn grade_code(&self, grade: &str) -> Result<String, Error> {
+        match &self.numeric {
+            Some(numeric) => numeric.code(grade).map(str::to_uppercase),
+            None => Ok(grade.to_uppercase()),
+        }
+    }
+}
+
+/// What to do with an examination date outside the period of the moment.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum ExamDateWindow {
+    /// Don't check the date.
+    Off,
+    /// Move the date to the nearest date in the period.
+    Clamp,
+    /// Report an error for the student.
+    #[default]
+    Error,
+}
+
+impl FromStr for ExamDateWindow {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "off" => Ok(ExamDateWindow::Off),
+            "clamp" => Ok(ExamDateWindow::Clamp),
+            "error" => Ok(ExamDateWindow::Error),
+            s => Err(format_err!(
+                "Unknown exam date window {:?}, expected off, clamp or error",
+                s
+            )),
+        }
+    }
+}
+
+impl ExamDateWindow {
+    fn apply(self, window: Option<&Giltighetsperiod>, date: NaiveDate) -> Result<NaiveDate, Error> {
+        match window {
+            Some(window) if !window.contains(date) => match self {
+                ExamDateWindow::Off => Ok(date),
+                ExamDateWindow::Clamp => Ok(window.clamp(date)),
+                ExamDateWindow::Error => Err(format_err!(
+                    "Examination date {} is outside the period of the moment",
+                    date
+                )),
+            },
+            _ => Ok(date),
+        }
+    }
+}
+
+/// A student to report a result for.
+struct CanvasStudent<'a> {
+    uid: StudentUid,
+    /// The rounds of the Canvas sections the student is in.
+    rounds: &'a [RoundUid],
+}
+
+fn prepare_ladok_change(
+    ladok: &mut Ladok,
+    config: &ReportConfig,
+    student: &CanvasStudent,
+    moment: &MomentData,
+    fallback_date: Option<FallbackDate>,
+    submission: &Submission,
+) -> Result<ChangeToLadok, Error> {
+    let grade = match submission.given_grade() {
+        Some(grade) => grade,
+        None => return Ok(ChangeToLadok::NoGrade),
+    };
+    if let Some(skip) = skipped(config, submission) {
+        return Ok(skip);
+    }
+    let (grade, komplettering) = split_komplettering(config, grade);
+    check_komplettering(config, &moment.id, komplettering)?;
+    let grade = moment.grade_code(grade)?;
+    let comment = config
+        .note_prefix
+        .as_deref()
+        .and_then(|prefix| submission.comment_with_prefix(prefix))
+        .and_then(Notering::from_comment);
+
+    let moment_id = &moment.id;
+    let one = moment
+        .resultat
+        .find_student_on(&student.uid, student.rounds)
+        .ok_or_else(|| format_err!("Student {} not in Ladok result-list", pii(&student.uid)))?;
+    if config.skip_foreign && !one.is_ours(ladok.larosate()) {
+        return Ok(ChangeToLadok::OtherLarosate);
+    }
+    if config.skip_unregistered && !one.is_registered() {
+        return Ok(ChangeToLadok::NotRegistered);
+    }
+
+    let (betygskala, grade) = ladok_grade(ladok, config, moment, one, &student.uid, &grade)?;
+
+    let exam_date = match moment.exam_date {
+        Some(date) => date,
+        None => {
+            let exam_date = exam_date(submission, fallback_date).ok_or_else(|| {
+                format_err!(
+                    "Submission missing graded_at for student {}",
+                    pii(&student.uid)
+                )
+            })?;
+            config
+                .exam_date_window
+                .apply(moment.window.as_ref(), exam_date)?
+        }
+    };
+    check_exam_date_not_future(config, exam_date, today())?;
+
+    if let Some(locked) = needs_correction(one, moment_id) {
+        if locked.Betygsgrad == Some(grade.ID) {
+            let status = locked.ProcessStatus.unwrap_or(ProcessStatus::Attesterad);
+            return Ok(ChangeToLadok::NoChange(format!(
+                "{}; {} i Ladok",
+                grade.describe(),
+                status
+            )));
+        }
+        let locked = match locked.Betygsgrad {
+            Some(id) => ladok.grade_code(betygskala, id)?,
+            None => "-".into(),
+        };
+        return Ok(ChangeToLadok::NeedsCorrection(locked, grade.describe()));
+    }
+
+    let describe = if komplettering {
+        format!("{} (komplettering)", grade.describe())
+    } else {
+        grade.describe()
+    };
+    Ok(if let Some(underlag) = one.get_arbetsunderlag(moment_id) {
+        let described = match underlag.ProcessStatus {
+            Some(status) => format!("{}; {} i Ladok", describe, status),
+            None => describe,
+        };
+        let noteringar = Notering::merged(&underlag.Noteringar, komplettering, comment);
+        // A date of today would move the result to a new day on each
+        // run, so keep the date it already has.
+        let from_today = moment.exam_date.is_none()
+            && submission.graded_at.is_none()
+            && matches!(fallback_date, Some(FallbackDate::Today(_)));
+        let exam_date = match underlag.Examinationsdatum {
+            Some(date) if from_today => date,
+            _ => exam_date,
+        };
+        if underlag.Betygsgrad != Some(grade.ID)
+            || underlag.Examinationsdatum != Some(exam_date)
+            || noteringar != underlag.Noteringar
+        {
+            eprintln!(
+                "Updating grade from {:?} to {:?} for {}",
+                underlag.Betygsgrad,
+                grade,
+                pii(&student.uid),
+            );
+            ChangeToLadok::Update(
+                UppdateraResultat {
+                    Uid: one.Uid.clone(),
+                    Betygsgrad: Some(grade.ID),
+                    BetygsskalaID: betygskala,
+                    Examinationsdatum: Some(exam_date),
+                    Noteringar: noteringar,
+                    ResultatUID: underlag.Uid.clone(),
+                    SenasteResultatandring: underlag.SenasteResultatandring.clone(),
+                },
+                described,
+                UppdateraResultat {
+                    Uid: one.Uid.clone(),
+                    Betygsgrad: underlag.Betygsgrad,
+                    BetygsskalaID: betygskala,
+                    Examinationsdatum: underlag.Examinationsdatum,
+                    Noteringar: underlag.Noteringar.clone(),
+                    ResultatUID: underlag.Uid.clone(),
+                    SenasteResultatandring: None,
+                },
+            )
+        } else {
+            eprintln!("Grade {:?} up to date for {}", grade, pii(&student.uid));
+            ChangeToLadok::NoChange(described)
+        }
+    } else {
+        ChangeToLadok::Create(
+            SkapaResultat {
+                // The result does not exist yet, one.Uid is the studieresultat.
+                Uid: None,
+                Betygsgrad: Some(grade.ID),
+                BetygsskalaID: betygskala,
+                Examinationsdatum: Some(exam_date),
+                Noteringar: Notering::merged(&[], komplettering, comment),
+                StudieresultatUID: one.Uid.clone(),
+                UtbildningsinstansUID: Some(moment_id.clone()),
+            },
+            describe,
+        )
+    })
+}
+
+/// The examination date of a submission is the date it was graded,
+/// or the fallback date, if any.
+fn exam_date(submission: &Submission, fallback: Option<FallbackDate>) -> Option<NaiveDate> {
+    submission
+        .graded_at
+        .map(|graded_at| local_date(&graded_at))
+        .or(fallback.map(FallbackDate::date))
+}
+
+/// Today, in the timezone of the server, as set by `TZ`.
+fn today() -> NaiveDate {
+    Local::now().date_naive()
+}
+
+/// The date of a point in time in the timezone of the server, so all
+/// dates compared to `today()` are in the same timezone.
+fn local_date<Tz: TimeZone>(time: &DateTime<Tz>) -> NaiveDate {
+    time.with_timezone(&Local).date_naive()
+}
+
+/// Parse the examination date from the form, if one is given.
+fn parse_exam_date(date: Option<&str>) -> Result<Option<NaiveDate>, Error> {
+    match date.map(str::trim).filter(|date| !date.is_empty()) {
+        Some(date) => NaiveDate::parse_from_str(date, "%Y-%m-%d")
+            .map(Some)
+            .map_err(|e| format_err!("Bad examination date {:?}: {}", date, e)),
+        None => Ok(None),
+    }
+}
+
+/// The single student to report has no submissions in the course room.
+#[derive(Debug)]
+struct NotInCourseRoom;
+
+impl fmt::Display for NotInCourseRoom {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Student not found in the course room")
+    }
+}
+
+impl Fail for NotInCourseRoom {}
+
+/// An examination date given for a run that cannot be used, so the
+/// user can be told to pick another.
+#[derive(Debug)]
+struct BadExamDate(String);
+
+impl fmt::Display for BadExamDate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Fail for BadExamDate {}
+
+/// The period of a moment, when the window policy or an examination
+/// date for the run needs it.  Failing to get it is an error, as the
+/// dates would otherwise go unchecked.
+fn moment_window(
+    ladok: &Ladok,
+    config: &ReportConfig,
+    moment_id: &MomentUid,
+    exam_date: Option<NaiveDate>,
+) -> Result<Option<Giltighetsperiod>, Error> {
+    if config.exam_date_window == ExamDateWindow::Off && exam_date.is_none() {
+        return Ok(None);
+    }
+    let instans = ladok
+        .get_utbildningsinstans(moment_id)
+        .map_err(|e| format_err!("Failed to get period of {}: {}", moment_id, e))?;
+    Ok(instans.Giltighetsperiod)
+}
+
+/// Check an examination date given for a run.  It is never clamped,
+/// since it is what the user asked for.
+fn check_run_exam_date(
+    config: &ReportConfig,
+    moment: &MomentData,
+    date: NaiveDate,
+    today: NaiveDate,
+) -> Result<(), Error> {
+    ExamDateWindow::Error
+        .apply(moment.window.as_ref(), date)
+        .map_err(|e| format_err!("{} {}", e, moment.id))
+        .and_then(|_| check_exam_date_not_future(config, date, today))
+        .map_err(|e| BadExamDate(e.to_string()).into())
+}
+
+/// Reject an examination date too far in the future, most likely a
+/// mistake in Canvas.
+fn check_exam_date_not_future(
+    config: &ReportConfig,
+    exam_date: NaiveDate,
+    today: NaiveDate,
+) -> Result<(), Error> {
+    let latest = today + Duration::days(config.exam_date_max_future_days);
+    if exam_date > latest {
+        Err(format_err!(
+            "Examination date {} is in the future, the latest allowed is {}",
+            exam_date,
+            latest
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// The examination date to use when graded_at is missing: the due
+/// date, if found and enabled, or else today, if enabled.
+fn exam_date_fallback(
+    config: &ReportConfig,
+    due_date: Option<NaiveDate>,
+    today: NaiveDate,
+) -> Option<FallbackDate> {
+    match due_date {
+        Some(due) => Some(FallbackDate::Due(due)),
+        None if config.today_fallback => Some(FallbackDate::Today(today)),
+        None => None,
+    }
+}
+
+/// The examination date to use when a submission lacks graded_at.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FallbackDate {
+    /// The due date of the assignment.
+    Due(NaiveDate),
+    /// The date of the run, which changes from run to run, so it is
+    /// only used for new results.
+    Today(NaiveDate),
+}
+
+impl FallbackDate {
+    fn date(self) -> NaiveDate {
+        match self {
+            FallbackDate::Due(date) | FallbackDate::Today(date) => date,
+        }
+    }
+}
+
+/// The due date of an assignment, to use as examination date when
+/// graded_at is missing.  Only a due date in the past is accepted.
+fn due_date_fallback(assignment: &Assignment, today: NaiveDate) -> Option<NaiveDate> {
+    let due = local_date(&assignment.due_at?);
+    if due <= today {
+        Some(due)
+    } else {
+        warn!(
+            "Due date {} of assignment {} is in the future, not using it as exam date",
+            due, assignment.id,
+        );
+        None
+    }
+}
+
+/// Take the pending students that are in the current window of Ladok
+/// results, or all remaining students if it is the last window.
+fn take_window(
+    pending: &mut BTreeSet<i32>,
+    last: bool,
+    in_window: impl Fn(i32) -> bool,
+) -> Vec<i32> {
+    let window = pending
+        .iter()
+        .cloned()
+        .filter(|id| last || in_window(*id))
+        .collect::<Vec<_>>();
+    for id in &window {
+        pending.remove(id);
+    }
+    window
+}
+
+/// Check the grades of a window after the first against the scales of
+/// its results, as is done for the first window before anything is
+/// written.  If any grade cannot be reported, the students of the
+/// window are listed with an error instead, since the earlier windows
+/// may already be written.
+fn check_window(
+    ladok: &mut Ladok,
+    config: &ReportConfig,
+    moment: &MomentData,
+    window: &mut Vec<i32>,
+    by_user: &BTreeMap<i32, Vec<&Submission>>,
+    retval: &mut ExportResults,
+) {
+    let submissions = window
+        .iter()
+        .flat_map(|id| by_user[id].iter().map(|s| (*s).clone()))
+        .collect::<Vec<_>>();
+    let unmappable = match unmappable_grades(ladok, config, moment, &submissions) {
+        Ok(unmappable) => unmappable,
+        Err(e) => {
+            warn!("Grading scale of {} unavailable: {}", moment.id, e);
+            let reason = "Grading scale unavailable in Ladok, try again";
+            fail_students(window.iter(), by_user, reason, retval);
+            window.clear();
+            return;
+        }
+    };
+    if !unmappable.is_empty() {
+        warn!("Not reporting a window of {}: {:?}", moment.id, unmappable);
+        fail_students(window.iter(), by_user, &unmappable.join("; "), retval);
+        window.clear();
+    }
+}
+
+/// List students of a moment with an error, e.g. when their window of
+/// results could not be fetched from Ladok.
+fn fail_students<'a>(
+    ids: impl IntoIterator<Item = &'a i32>,
+    by_user: &BTreeMap<i32, Vec<&Submission>>,
+    reason: &str,
+    retval: &mut ExportResults,
+) {
+    for id in ids {
+        if let Some(user) = by_user.get(id).and_then(|s| s[0].user.as_ref()) {
+            retval.errors += 1;
+            retval.add(user, &format!(" Error ({}) ", reason));
+        }
+    }
+}
+
+/// The result that blocks a plain update or create on a moment, if
+/// any: a klarmarkerad draft, or an attested result with no draft.
+fn needs_correction<'a>(one: &'a Studieresultat, moment: &MomentUid) -> Option<&'a Resultat> {
+    match one.get_arbetsunderlag(moment) {
+        Some(underlag) if underlag.ProcessStatus == Some(ProcessStatus::Klarmarkerad) => {
+            Some(underlag)
+        }
+        Some(_) => None,
+        None => one.get_senast_attesterat(moment),
+    }
+}
+
+/// Why the grade of a submission is not reported, judging by the
+/// submission alone, if it is not.
+fn skipped(config: &ReportConfig, submission: &Submission) -> Option<ChangeToLadok> {
+    check_anonymous(submission)
+        .or_else(|| check_posted(config, submission))
+        .or_else(|| check_resubmitted(config, submission))
+        .or_else(|| check_late_and_missing(config, submission))
+        .or_else(|| check_late_review(config, submission))
+        .or_else(|| check_timestamps(config, submission))
+}
+
+/// Check if the grade of a submission is withheld by anonymous
+/// grading.  Unlike other unposted grades, this cannot be overridden.
+fn check_anonymous(submission: &Submission) -> Option<ChangeToLadok> {
+    if submission.anonymous_unreleased {
+        Some(ChangeToLadok::AnonymousUnreleased)
+    } else {
+        None
+    }
+}
+
+/// Check if the grade of a submission is posted to the student.
+fn check_posted(config: &ReportConfig, submission: &Submission) -> Option<ChangeToLadok> {
+    if submission.posted_at.is_none() && !config.report_unposted {
+        Some(ChangeToLadok::NotPosted)
+    } else {
+        None
+    }
+}
+
+/// Check that a submission was graded after it was submitted.  A
+/// grade from before the submission suggests clock skew or an import
+/// gone wrong.  Stale grades of resubmitted submissions are left to
+/// `check_resubmitted`.
+fn check_timestamps(config: &ReportConfig, submission: &Submission) -> Option<ChangeToLadok> {
+    if !config.check_timestamps || submission.needs_regrade() {
+        return None;
+    }
+    match (submission.graded_at, submission.submitted_at) {
+        (Some(graded), Some(submitted)) if graded < submitted => {
+            Some(ChangeToLadok::SuspectTimestamp)
+        }
+        _ => None,
+    }
+}
+
+/// Check if the grade of a submission is stale, since the student has
+/// resubmitted after grading.
+fn check_resubmitted(config: &ReportConfig, submission: &Submission) -> Option<ChangeToLadok> {
+    if submission.needs_regrade() && !config.report_resubmitted {
+        Some(ChangeToLadok::NeedsRegrade)
+    } else {
+        None
+    }
+}
+
+/// Check the late and missing flags of a submission against the policy.
+fn check_late_and_missing(config: &ReportConfig, submission: &Submission) -> Option<ChangeToLadok> {
+    if submission.missing == Some(true) && !config.report_missing {
+        Some(ChangeToLadok::Missing)
+    } else if submission.late == Some(true) && !config.report_late {
+        Some(ChangeToLadok::Late)
+    } else {
+        None
+    }
+}
+
+/// Check how late a submission is against the review threshold.
+fn check_late_review(config: &ReportConfig, submission: &Submission) -> Option<ChangeToLadok> {
+    let days = config.late_review_days?;
+    if submission.seconds_late? > days * 24. * 3600. {
+        Some(ChangeToLadok::HeldForReview)
+    } else {
+        None
+    }
+}
+
+#[derive(Debug)]
+enum ChangeToLadok {
+    /// The update, the new grade, and the update to revert it.
+    Update(UppdateraResultat, String, UppdateraResultat),
+    Create(SkapaResultat, String),
+    NoChange(String),
+    NoGrade,
+    /// Skipped since Canvas flags the submission as missing.
+    Missing,
+    /// Skipped since Canvas flags the submission as late.
+    Late,
+    /// Skipped since the submission is too late to report without a
+    /// manual review.
+    HeldForReview,
+    /// Skipped since the student has not registered on the round.
+    NotRegistered,
+    /// Skipped since the result belongs to another lärosäte.
+    OtherLarosate,
+    /// Skipped since the grade is not yet posted to the student.
+    NotPosted,
+    /// Skipped since anonymous grading still withholds the grade.
+    AnonymousUnreleased,
+    /// Skipped since the student has resubmitted after grading.
+    NeedsRegrade,
+    /// Skipped since the grade is older than the submission.
+    SuspectTimestamp,
+    /// Skipped since the result is klarmarkerad or attested in Ladok
+    /// with another grade, and can only be changed by a correction.
+    /// The grade in Ladok and the grade from Canvas.
+    NeedsCorrection(String, String),
+}
+
+#[test]
+fn test_skip_submission_without_user() {
+    let submissions: Vec<Submission> = serde_json::from_str(
+        r#"[{"assignment_id": 17, "grade": "P", "user_id": null, "user": null,
+             "graded_at": null, "grader_id": 4711},
+            {"assignment_id": 17, "grade": "P", "user_id": 1,
+             "user": {"id": 1, "name": "Anna", "integration_id": "st-1"}}]"#,
+    )
+    .unwrap();
+    let mut result = ExportResults::new();
+    let by_user = group_by_user(&submissions, &[], None, None, &mut result);
+    assert_eq!(by_user.keys().collect::<Vec<_>>(), [&1]);
+    assert_eq!(result.skipped_no_user, 1);
+    assert!(result.students.is_empty());
+
+    let mut page = Vec::new();
+    templates::done(
+        &mut page,
+        &Branding::default(),
+        "t",
+        None,
+        "LT1016VT191",
+        false,
+        result,
+    )
+    .unwrap();
+    let page = String::from_utf8(page).unwrap();
+    assert!(page.contains("Hoppade över 1 inlämning(ar) utan kopplad användare."));
+}
+
+#[test]
+fn test_changes_only_counts_unchanged() {
+    let user: User = serde_json::from_str(r#"{"id": 4711, "name": "Anna"}"#).unwrap();
+    let other: User = serde_json::from_str(r#"{"id": 4712, "name": "Bo"}"#).unwrap();
+    let mut result = ExportResults::new();
+    result.changes_only = true;
+    result.unchanged(&user, "P");
+    result.add(&other, " Created (P) ");
+    assert_eq!(result.unchanged, 1);
+    assert_eq!(
+        result.students.into_iter().collect::<Vec<_>>(),
+        [(4712, "Bo (4712):  Created (P) ".to_string())],
+    );
+}
+
+#[test]
+fn test_test_student_excluded_once() {
+    let user: User = serde_json::from_str(r#"{"id": 17, "name": "Test Student"}"#).unwrap();
+    let mut result = ExportResults::new();
+    result.exclude_test_student(&user);
+    result.exclude_test_student(&user);
+    assert_eq!(
+        result.students.into_iter().collect::<Vec<_>>(),
+        [(17, "Test Student (17): Excluded: test student".to_string())],
+    );
+}
+
+#[test]
+fn test_student_without_name() {
+    let user: User = serde_json::from_str(r#"{"id": 4711, "name": null}"#).unwrap();
+    let mut result = ExportResults::new();
+    result.add(&user, " Error (Grade \"X\" not in AF)");
+    assert_eq!(
+        result.students[&4711],
+        "Canvas user 4711:  Error (Grade \"X\" not in AF)",
+    );
+}
+
+#[test]
+fn test_cross_listed_sections_share_round() {
+    let sections: Vec<CourseSection> = serde_json::from_str(
+        r#"[{"id": 101, "name": "LT1016 VT19", "integration_id": "kt-1"},
+            {"id": 102, "name": "LT1016 VT19 (cross-listed)", "integration_id": "kt-1"},
+            {"id": 103, "name": "LT1017 VT19", "integration_id": "kt-2"},
+            {"id": 104, "name": "Without round", "integration_id": null}]"#,
+    )
+    .unwrap();
+    let student = |id, sections: &[i32]| {
+        let mut user = User::unknown(id);
+        for section in sections {
+            user.enrollments.push(
+                serde_json::from_value(serde_json::json!({
+                    "type": "StudentEnrollment",
+                    "enrollment_state": "active",
+                    "user_id": id,
+                    "course_section_id": section,
+                }))
+                .unwrap(),
+            );
+        }
+        user
+    };
+    let students = [
+        student(1, &[101]),
+        student(2, &[101, 102]),
+        student(3, &[102, 103]),
+        student(4, &[104]),
+    ];
+    let (tillfallen, student_rounds) = rounds_of_sections(&sections, &students);
+    let kt1 = RoundUid::new("kt-1");
+    let kt2 = RoundUid::new("kt-2");
+    assert_eq!(
+        tillfallen,
+        [Tillfalle::Kurs(kt1.clone()), Tillfalle::Kurs(kt2.clone())]
+    );
+    assert_eq!(student_rounds[&1], student_rounds[&2]);
+    assert_eq!(student_rounds[&2], vec![kt1.clone()]);
+    assert_eq!(student_rounds[&3], [kt1, kt2]);
+    assert!(!student_rounds.contains_key(&4));
+}
+
+#[test]
+fn test_no_mapped_assignments() {
+    let assignments: Vec<Assignment> = serde_json::from_str(
+        r#"[{"id": 17, "name": "Lab 1", "integration_id": null},
+            {"id": 18, "name": "Tentamen"}]"#,
+    )
+    .unwrap();
+    let config = ReportConfig::default();
+    assert!(group_by_moment(&config, "LT1016VT191", &assignments).is_empty());
+
+    let mut result = ExportResults::new();
+    result.notice = Some(NO_MOMENTS.into());
+    let mut page = Vec::new();
+    templates::done(
+        &mut page,
+        &Branding::default(),
+        "token",
+        None,
+        "LT1016VT191",
+        false,
+        result,
+    )
+    .unwrap();
+    let page = String::from_utf8(page).unwrap();
+    assert!(page.contains("No Canvas assignments are linked to Ladok moments"));
+}
+
+#[test]
+fn test_routes_under_custom_prefix() {
+    let routes = |prefix: &str| path_prefix(prefix).and(path("_monitor")).map(|| "ok");
+    let request = |path: &str| warp::test::request().path(path);
+    let default = routes(concat!("api/", env!("CARGO_PKG_NAME")));
+    assert!(request("/api/report-results-ladok-rs/_monitor").matches(&default));
+
+    let custom = routes("/lms/ladok-export/");
+    assert!(request("/lms/ladok-export/_monitor").matches(&custom));
+    assert_eq!(
+        request("/lms/ladok-export/_monitor").reply(&custom).body(),
+        "ok"
+    );
+    assert!(!request("/api/report-results-ladok-rs/_monitor").matches(&custom));
+    assert!(!request("/lms/_monitor").matches(&custom));
+}
+
+#[test]
+fn test_service_token_required() {
+    assert!(is_service_token(Some("s3cret"), Some("Bearer s3cret")));
+    assert!(!is_service_token(Some("s3cret"), Some("Bearer other")));
+    assert!(!is_service_token(Some("s3cret"), Some("s3cret")));
+    assert!(!is_service_token(Some("s3cret"), None));
+    assert!(
+        !is_service_token(None, Some("Bearer ")),
+        "Without a configured token, nothing is authorized"
+    );
+    assert!(!is_service_token(None, None));
+}
+
+#[test]
+fn test_audit_token_only_reads() {
+    let scope = |auth| token_scope(Some("s3cret"), Some("look"), Some(auth));
+    assert_eq!(scope("Bearer s3cret"), Some(TokenScope::Service));
+    assert_eq!(scope("Bearer look"), Some(TokenScope::Audit));
+    assert_eq!(scope("Bearer other"), None);
+
+    // E.g. _student reports results, _diagnostics only reads.
+    let audit = scope("Bearer look");
+    assert_eq!(
+        check_scope(audit, TokenScope::Service),
+        Err(StatusCode::FORBIDDEN)
+    );
+    assert_eq!(check_scope(audit, TokenScope::Audit), Ok(()));
+    let service = scope("Bearer s3cret");
+    assert_eq!(check_scope(service, TokenScope::Service), Ok(()));
+    assert_eq!(check_scope(service, TokenScope::Audit), Ok(()));
+    assert_eq!(
+        check_scope(None, TokenScope::Audit),
+        Err(StatusCode::UNAUTHORIZED)
+    );
+    assert_eq!(
+        token_scope(Some("s3cret"), None, Some("Bearer ")),
+        None,
+        "Without an audit token, nothing is audit",
+    );
+}
+
+#[test]
+fn test_large_export_needs_confirmation() {
+    let grade = |user| Reportable {
+        student: format!("Student {}", user),
+        user,
+        assignment: "Tenta".into(),
+        assignment_id: 17,
+        grade: "A".into(),
+    };
+    let grades = (1..=250).map(grade).collect::<Vec<_>>();
+    let token = confirm_token("secret", "LT1016VT191", &grades);
+    assert!(!needs_confirmation(300, 250, &token, None));
+    assert!(needs_confirmation(200, 250, &token, None));
+    assert!(!needs_confirmation(200, 250, &token, Some(&token)));
+    let fewer = confirm_token("secret", "LT1016VT191", &grades[..210]);
+    assert!(
+        needs_confirmation(200, 250, &token, Some(&fewer)),
+        "Confirmed fewer students"
+    );
+    assert!(
+        needs_confirmation(200, 250, &token, Some("LT1016VT191/250")),
+        "Made up token"
+    );
+    assert_ne!(token, confirm_token("other", "LT1016VT191", &grades));
+}
+
+#[test]
+fn test_reportable_grades_leave_out_skipped() {
+    let assignment: Assignment = serde_json::from_str(r#"{"id": 17, "name": "Tenta"}"#).unwrap();
+    let submission = |id: i32, late, posted: bool| {
+        let mut submission = submission_with_flags(late, false);
+        submission.user = Some(
+            serde_json::from_value(serde_json::json!({
+                "id": id, "name": format!("Student {}", id), "integration_id": format!("st-{}", id),
+            }))
+            .unwrap(),
+        );
+        if !posted {
+            submission.posted_at = None;
+        }
+        submission
+    };
+    let submissions = vec![
+        submission(1, false, true),
+        submission(2, true, true),
+        submission(3, false, false),
+    ];
+    let config = ReportConfig {
+        report_late: false,
+        ..ReportConfig::default()
+    };
+    let grades = reportable_of(&config, &assignment, submissions);
+    assert_eq!(grades.iter().map(|g| g.user).collect::<Vec<_>>(), [1]);
+    assert_eq!(grades[0].grade, "P");
+}
+
+#[test]
+fn test_attest_token_bound_to_preview() {
+    let grade = |user, grade: &str| Reportable {
+        student: format!("Student {}", user),
+        user,
+        assignment: "Tenta".into(),
+        assignment_id: 17,
+        grade: grade.into(),
+    };
+    let grades = [grade(1, "A"), grade(2, "B")];
+    let token = attest_token("secret", "LT1016VT191", None, &grades);
+    assert_eq!(token, attest_token("secret", "LT1016VT191", None, &grades));
+    assert_ne!(token, attest_token("other", "LT1016VT191", None, &grades));
+    let changed = [grade(1, "A"), grade(2, "C")];
+    assert_ne!(token, attest_token("secret", "LT1016VT191", None, &changed));
+    assert_ne!(
+        token,
+        attest_token("secret", "LT1016VT191", None, &grades[..1])
+    );
+    let date = NaiveDate::from_ymd_opt(2019, 5, 24);
+    assert_ne!(token, attest_token("secret", "LT1016VT191", date, &grades));
+    assert_eq!(
+        count_students(&[grade(1, "A"), grade(1, "B"), grade(2, "C")]),
+        2
+    );
+}
+
+#[test]
+fn test_ladok_identity_bad_base64() {
+    let err = ladok_identity("not base64!", "secret").err().unwrap();
+    assert!(err
+        .to_string()
+        .starts_with("LADOK_API_PFX_BASE64 is not valid base64"));
+}
+
+#[test]
+fn test_ladok_identity_bad_pfx() {
+    let err = ladok_identity(&base64::encode(b"not a pfx file"), "secret")
+        .err()
+        .unwrap();
+    assert!(err.to_string().starts_with("Failed to load Ladok identity"));
+}
+
+#[test]
+fn test_ladok_identity_bad_passphrase() {
+    let pfx = base64::encode(&include_bytes!("ladok/test-identity.pfx")[..]);
+    let err = ladok_identity(&pfx, "wrong").err().unwrap();
+    assert!(
+        err.to_string()
+            .starts_with("Failed to load Ladok identity (check LADOK_API_PFX_PASSPHRASE): "),
+        "Unexpected message: {}",
+        err,
+    );
+}
+
+#[test]
+fn test_ladok_client_with_identity() {
+    let pfx = base64::encode(&include_bytes!("ladok/test-identity.pfx")[..]);
+    for http2 in &[false, true] {
+        let identity = ladok_identity(&pfx, "test").unwrap();
+        assert!(ladok::http_client(Some(identity), 4, *http2, HeaderMap::new()).is_ok());
+    }
+}
+
+#[cfg(test)]
+fn submission_with_flags(late: bool, missing: bool) -> Submission {
+    let mut submission: Submission = serde_json::from_str(
+        r#"{"assignment_id": 17, "grade": "P", "user_id": 4711, "user": null,
+            "graded_at": "2019-05-24T12:00:00+02:00", "grader_id": 17,
+            "posted_at": "2019-05-24T12:05:00+02:00"}"#,
+    )
+    .unwrap();
+    submission.late = Some(late);
+    submission.missing = Some(missing);
+    submission
+}
+
+#[test]
+fn test_unposted_grade_skipped() {
+    let mut submission = submission_with_flags(false, false);
+    let mut config = ReportConfig::default();
+    assert!(check_posted(&config, &submission).is_none());
+    submission.posted_at = None;
+    let check = check_posted(&config, &submission);
+    assert!(matches!(check, Some(ChangeToLadok::NotPosted)));
+    config.report_unposted = true;
+    assert!(check_posted(&config, &submission).is_none());
+}
+
+#[test]
+fn test_unavailable_scale_skips_only_its_moment() {
+    fn respond(request: &str) -> (u16, String) {
+        if request.starts_with("GET /resultat/grunddata/betygsskala/131657 ") {
+            (
+                200,
+                r#"{"ID": 131657, "Kod": "AF", "Betygsgrad": [
+                    {"ID": 131661, "Kod": "A", "GiltigSomSlutbetyg": true}]}"#
+                    .into(),
+            )
+        } else {
+            (500, r#"{"Meddelande": "Internal error"}"#.into())
+        }
+    }
+    let ladok = ladok::mock_client(respond);
+    let moment = |id: &str, scale: &str| {
+        let resultat = serde_json::from_value(serde_json::json!({
+            "TotaltAntalPoster": 1,
+            "Resultat": [{
+                "Uid": "sr-1",
+                "Student": {"Uid": "st-1"},
+                "Rapporteringskontext": {"BetygsskalaID": scale, "UtbildningsinstansUID": id},
+                "ResultatPaUtbildningar": [],
+            }],
+        }))
+        .unwrap();
+        MomentData::test(id, resultat)
+    };
+    let submissions: Vec<Submission> = serde_json::from_str(
+        r#"[{"assignment_id": 17, "grade": "A", "user_id": 1,
+             "user": {"id": 1, "name": "Anna", "integration_id": "st-1"}}]"#,
+    )
+    .unwrap();
+    let fetched = vec![
+        (moment("m-1", "131657"), submissions.clone(), ()),
+        (moment("m-2", "131658"), submissions.clone(), ()),
+    ];
+    let mut retval = ExportResults::new();
+    let kept = with_scales(&ladok, fetched, &[], None, &mut retval);
+
+    assert_eq!(
+        kept.iter()
+            .map(|(m, ..)| m.id.to_string())
+            .collect::<Vec<_>>(),
+        ["m-1"],
+    );
+    assert_eq!(retval.errors, 1);
+    assert_eq!(
+        retval.moment_outcomes[&1],
+        [(
+            "m-2".to_string(),
+            "Grading scale unavailable in Ladok, try again".to_string()
+        )],
+    );
+    let mut ladok = ladok;
+    let (m1, submissions, ()) = &kept[0];
+    let config = ReportConfig::default();
+    assert!(unmappable_grades(&mut ladok, &config, m1, submissions)
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn test_grade_mapping_of_student() {
+    let mut ladok = ladok::test_client_with_scale();
+    let mut config = ReportConfig::default();
+    let moment = MomentData::test("m-1", test_sokresultat(Some("2019-01-14T12:00:00")));
+    let student = StudentUid::new("st-1");
+    let mapping = map_grade(&mut ladok, &config, &moment, &student, "b").unwrap();
+    assert_eq!(
+        serde_json::to_value(&mapping).unwrap(),
+        serde_json::json!({
+            "moment": "m-1",
+            "grade": "b",
+            "betygsskala": 131657,
+            "betygsgrad": 131662,
+            "ladok_grade": "B",
+            "valid_as_final": true,
+            "is_final": false,
+            "komplettering": false,
+        }),
+    );
+
+    let error = |ladok: &mut Ladok, config: &ReportConfig, grade| {
+        map_grade(ladok, config, &moment, &student, grade)
+            .unwrap_err()
+            .to_string()
+    };
+    assert_eq!(error(&mut ladok, &config, "P"), "Grade \"P\" not in AF");
+    config.final_moments.insert("m-1".into());
+    assert_eq!(
+        error(&mut ladok, &config, "FX"),
+        "Grade \"FX\" is not valid as a final grade"
+    );
+    let other = StudentUid::new("st-2");
+    assert!(map_grade(&mut ladok, &config, &moment, &other, "B").is_err());
+
+    let table = serde_json::from_str(r#"{"5": "A", "4": "B", "0": "F"}"#).unwrap();
+    let numeric = MomentData {
+        numeric: Some(NumericScale::new(17, table).unwrap()),
+        ..MomentData::test("m-1", test_sokresultat(Some("2019-01-14T12:00:00")))
+    };
+    let mapping = map_grade(&mut ladok, &config, &numeric, &student, "4").unwrap();
+    assert_eq!(mapping.grade, "4");
+    assert_eq!(mapping.ladok_grade, "B");
+}
+
+#[test]
+fn test_grade_mapping_from_rubric() {
+    let mut config = ReportConfig {
+        rubric_grades: RubricGrades::test(
+            r#"{"17": [{"min": 90, "grade": "A"}, {"min": 0, "grade": "F"}]}"#,
+        ),
+        ..ReportConfig::default()
+    };
+    let mut args: GradeMappingArgs = serde_json::from_str(
+        r#"{"round": "kt-1", "moment": "m-1", "student": "199001011234",
+            "grade": "B", "assignment": 17, "rubric_total": 92.5}"#,
+    )
+    .unwrap();
+    assert_eq!(canvas_grade(&config, &args).unwrap(), "A");
+    args.rubric_total = Some(-1.);
+    assert!(canvas_grade(&config, &args).is_err());
+    args.rubric_total = None;
+    assert_eq!(
+        canvas_grade(&config, &args).unwrap(),
+        "B",
+        "No rubric total"
+    );
+    config.rubric_grades = RubricGrades::default();
+    args.rubric_total = Some(92.5);
+    assert_eq!(
+        canvas_grade(&config, &args).unwrap(),
+        "B",
+        "Not rubric graded"
+    );
+    args.grade = None;
+    assert!(canvas_grade(&config, &args).is_err());
+}
+
+#[test]
+fn test_grade_mapping_only_for_students_of_round() {
+    let sections: Vec<CourseSection> = serde_json::from_str(
+        r#"[{"id": 1, "name": "A", "integration_id": "kt-1"},
+            {"id": 2, "name": "B", "integration_id": "kt-2"}]"#,
+    )
+    .unwrap();
+    assert_eq!(section_of_round(&sections, "kt-2"), Some(2));
+    assert_eq!(section_of_round(&sections, "kt-other"), None);
+
+    let students: Vec<User> = serde_json::from_str(
+        r#"[{"id": 17, "integration_id": "st-1"},
+            {"id": 18, "sis_user_id": "19900101-1234"}]"#,
+    )
+    .unwrap();
+    let resultat = serde_json::from_value(serde_json::json!({
+        "TotaltAntalPoster": 3,
+        "Resultat": [
+            {"Uid": "sr-1", "Student": {"Uid": "st-1", "Personnummer": "199202021234"},
+             "ResultatPaUtbildningar": []},
+            {"Uid": "sr-2", "Student": {"Uid": "st-2", "Personnummer": "199001011234"},
+             "ResultatPaUtbildningar": []},
+            {"Uid": "sr-3", "Student": {"Uid": "st-3", "Personnummer": "199303031234"},
+             "ResultatPaUtbildningar": []}],
+    }))
+    .unwrap();
+    let found = |student| student_of_round(&students, student, &resultat).map(|s| s.to_string());
+    assert_eq!(found("st-1").as_deref(), Some("st-1"));
+    assert_eq!(found("19920202-1234").as_deref(), Some("st-1"));
+    assert_eq!(found("199001011234").as_deref(), Some("st-2"));
+    // Registered on the round in Ladok, but not a student of the section.
+    assert_eq!(found("st-3"), None);
+    assert_eq!(found("19930303-1234"), None);
+    assert_eq!(found("19990909-9999"), None);
+}
+
+#[test]
+fn test_comment_sent_as_note() {
+    let mut ladok = ladok::test_client_with_scale();
+    let student = CanvasStudent {
+        uid: StudentUid::new("st-1"),
+        rounds: &[],
+    };
+    let moment = MomentData::test("m-1", test_sokresultat(Some("2019-01-14T12:00:00")));
+    let mut submission = submission_with_flags(false, false);
+    submission.grade = Some("B".into());
+    submission.submission_comments = serde_json::from_str(
+        r#"[{"author_id": 17, "comment": "Ladok: Godkänd efter muntlig tentamen"},
+            {"author_id": 4711, "comment": "Ladok: Jag vill ha A"},
+            {"author_id": 17, "comment": "Bra jobbat!"}]"#,
+    )
+    .unwrap();
+    let mut notes = |config: &ReportConfig| match prepare_ladok_change(
+        &mut ladok,
+        config,
+        &student,
+        &moment,
+        None,
+        &submission,
+    ) {
+        Ok(ChangeToLadok::Create(skapa, _)) => skapa.Noteringar,
+        other => panic!("Unexpected {:?}", other.map(|_| ())),
+    };
+    let mut config = ReportConfig::default();
+    assert_eq!(notes(&config), []);
+    config.note_prefix = Some("Ladok:".into());
+    assert_eq!(
+        notes(&config),
+        [Notering {
+            Text: "Godkänd efter muntlig tentamen".into()
+        }],
+    );
+}
+
+#[test]
+fn test_comment_note_updates_result() {
+    let mut ladok = ladok::test_client_with_scale();
+    let student = CanvasStudent {
+        uid: StudentUid::new("st-1"),
+        rounds: &[],
+    };
+    let resultat = serde_json::from_value(serde_json::json!({
+        "TotaltAntalPoster": 1,
+        "Resultat": [{
+            "Uid": "sr-1",
+            "Student": {"Uid": "st-1"},
+            "Rapporteringskontext": {
+                "BetygsskalaID": "131657",
+                "KravPaHanvisningTillBeslutshandling": false,
+                "KravPaProjekttitel": false,
+                "UtbildningUID": "u-1",
+                "UtbildningsinstansUID": "m-1"},
+            "ResultatPaUtbildningar": [{
+                "Arbetsunderlag": {
+                    "Uid": "r-1",
+                    "Betygsgrad": 131662,
+                    "Examinationsdatum": "2019-05-24",
+                    "UtbildningsinstansUID": "m-1",
+                    "Noteringar": [{"Text": "Från examinator"}],
+                    "SenasteResultatandring": "2019-05-24T13:14:15.123",
+                },
+            }],
+            "SenastRegistrerad": "2019-01-14T12:00:00",
+        }],
+    }))
+    .unwrap();
+    let moment = MomentData {
+        exam_date: NaiveDate::from_ymd_opt(2019, 5, 24),
+        ..MomentData::test("m-1", resultat)
+    };
+    let mut submission = submission_with_flags(false, false);
+    submission.grade = Some("B".into());
+    submission.submission_comments =
+        serde_json::from_str(r#"[{"author_id": 17, "comment": "Ladok: Muntlig"}]"#).unwrap();
+    let mut config = ReportConfig::default();
+    let change = prepare_ladok_change(&mut ladok, &config, &student, &moment, None, &submission);
+    assert!(matches!(change, Ok(ChangeToLadok::NoChange(..))));
+
+    config.note_prefix = Some("Ladok:".into());
+    let change = prepare_ladok_change(&mut ladok, &config, &student, &moment, None, &submission);
+    let note = |text: &str| Notering { Text: text.into() };
+    match change {
+        Ok(ChangeToLadok::Update(update, _, _)) => assert_eq!(
+            update.Noteringar,
+            [note("Från examinator"), note("Muntlig")],
+            "The note is added to those already in Ladok"
+        ),
+        other => panic!("Unexpected {:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn test_only_graded_since() {
+    use chrono::TimeZone;
+    let assignment = |json| -> Assignment { serde_json::from_str(json).unwrap() };
+    let submissions: Vec<Submission> = serde_json::from_str(
+        r#"[{"assignment_id": 17, "grade": "A", "user_id": 1,
+             "graded_at": "2019-05-20T10:00:00+02:00"},
+            {"assignment_id": 17, "grade": "B", "user_id": 2,
+             "graded_at": "2019-05-24T08:00:00Z"},
+            {"assignment_id": 17, "grade": "C", "user_id": 3,
+             "graded_at": "2019-05-24T13:00:00Z"},
+            {"assignment_id": 17, "grade": null, "user_id": 4}]"#,
+    )
+    .unwrap();
+    let since = Utc.with_ymd_and_hms(2019, 5, 24, 8, 0, 0).unwrap();
+    let users = |kept: &[Submission]| kept.iter().map(|s| s.user_id).collect::<Vec<_>>();
+
+    let stable = assignment(r#"{"id": 17, "updated_at": "2019-05-01T12:00:00Z"}"#);
+    let mut kept = submissions.clone();
+    keep_changed_since(&[&stable], &mut kept, since);
+    assert_eq!(
+        users(&kept),
+        [Some(2), Some(3), Some(4)],
+        "Graded at the boundary or later, or not known when"
+    );
+
+    let changed = assignment(r#"{"id": 17, "updated_at": "2019-05-24T09:00:00Z"}"#);
+    let mut kept = submissions.clone();
+    keep_changed_since(&[&stable, &changed], &mut kept, since);
+    assert_eq!(kept.len(), 4, "All of a moment with a changed assignment");
+}
+
+#[test]
+fn test_graded_since_keeps_whole_moment_of_student() {
+    use chrono::TimeZone;
+    let assignment = |json| -> Assignment { serde_json::from_str(json).unwrap() };
+    let first = assignment(r#"{"id": 17, "updated_at": "2019-05-01T12:00:00Z"}"#);
+    let second = assignment(r#"{"id": 18, "updated_at": "2019-05-01T12:00:00Z"}"#);
+    let mut kept: Vec<Submission> = serde_json::from_str(
+        r#"[{"assignment_id": 17, "grade": "A", "user_id": 1,
+             "graded_at": "2019-05-20T10:00:00Z"},
+            {"assignment_id": 18, "grade": "E", "user_id": 1,
+             "graded_at": "2019-05-24T13:00:00Z"},
+            {"assignment_id": 17, "grade": "B", "user_id": 2,
+             "graded_at": "2019-05-20T10:00:00Z"},
+            {"assignment_id": 18, "grade": "C", "user_id": 2,
+             "graded_at": "2019-05-20T10:00:00Z"}]"#,
+    )
+    .unwrap();
+    let since = Utc.with_ymd_and_hms(2019, 5, 24, 8, 0, 0).unwrap();
+    keep_changed_since(&[&first, &second], &mut kept, since);
+    let grades = kept.iter().map(|s| s.grade.as_deref()).collect::<Vec<_>>();
+    assert_eq!(
+        grades,
+        [Some("A"), Some("E")],
+        "The unchanged grade is still combined with the new one"
+    );
+}
+
+#[test]
+fn test_muted_anonymous_assignment_skipped() {
+    let assignment = |json| -> Assignment { serde_json::from_str(json).unwrap() };
+    let anonymous = assignment(r#"{"id": 17, "anonymous_grading": true, "muted": true}"#);
+    let mut submission = submission_with_flags(false, false);
+    submission.anonymous_unreleased = anonymous.withholds_anonymous_grade(&submission);
+    let config = ReportConfig {
+        report_unposted: true,
+        ..ReportConfig::default()
+    };
+    let mut ladok = ladok::test_client_with_scale();
+    let student = CanvasStudent {
+        uid: StudentUid::new("st-1"),
+        rounds: &[],
+    };
+    let moment = MomentData::test("m-1", test_sokresultat(None));
+    let change = prepare_ladok_change(&mut ladok, &config, &student, &moment, None, &submission);
+    assert!(matches!(change, Ok(ChangeToLadok::AnonymousUnreleased)));
+
+    let unmuted = assignment(r#"{"id": 17, "anonymous_grading": true, "muted": false}"#);
+    assert!(!unmuted.withholds_anonymous_grade(&submission));
+    submission.posted_at = None;
+    assert!(unmuted.withholds_anonymous_grade(&submission));
+    let plain = assignment(r#"{"id": 17, "muted": true}"#);
+    assert!(!plain.withholds_anonymous_grade(&submission));
+}
+
+#[test]
+fn test_resubmitted_needs_regrade() {
+    let mut submission = submission_with_flags(false, false);
+    let mut config = ReportConfig::default();
+    assert!(check_resubmitted(&config, &submission).is_none());
+    submission.grade_matches_current_submission = Some(false);
+    submission.workflow_state = Some("submitted".into());
+    let check = check_resubmitted(&config, &submission);
+    assert!(matches!(check, Some(ChangeToLadok::NeedsRegrade)));
+    config.report_resubmitted = true;
+    assert!(check_resubmitted(&config, &submission).is_none());
+}
+
+#[test]
+fn test_missing_skipped_by_default() {
+    let config = ReportConfig::default();
+    let check = check_late_and_missing(&config, &submission_with_flags(false, true));
+    assert!(matches!(check, Some(ChangeToLadok::Missing)));
+}
+
+#[test]
+fn test_missing_reported_when_enabled() {
+    let config = ReportConfig {
+        report_missing: true,
+        ..ReportConfig::default()
+    };
+    assert!(check_late_and_missing(&config, &submission_with_flags(false, true)).is_none());
+}
+
+#[test]
+fn test_late_reported_by_default() {
+    let config = ReportConfig::default();
+    assert!(check_late_and_missing(&config, &submission_with_flags(true, false)).is_none());
+}
+
+#[test]
+fn test_late_skipped_when_disabled() {
+    let config = ReportConfig {
+        report_late: false,
+        ..ReportConfig::default()
+    };
+    let check = check_late_and_missing(&config, &submission_with_flags(true, false));
+    assert!(matches!(check, Some(ChangeToLadok::Late)));
+}
+
+#[test]
+fn test_late_under_review_threshold_reported() {
+    let config = ReportConfig {
+        late_review_days: Some(7.),
+        ..ReportConfig::default()
+    };
+    let mut submission = submission_with_flags(true, false);
+    assert!(check_late_review(&config, &submission).is_none());
+    submission.seconds_late = Some(6.5 * 24. * 3600.);
+    assert!(check_late_review(&config, &submission).is_none());
+}
+
+#[test]
+fn test_late_over_review_threshold_held() {
+    let mut config = ReportConfig::default();
+    let mut submission = submission_with_flags(true, false);
+    submission.seconds_late = Some(8. * 24. * 3600.);
+    assert!(check_late_review(&config, &submission).is_none());
+    config.late_review_days = Some(7.);
+    let check = check_late_review(&config, &submission);
+    assert!(matches!(check, Some(ChangeToLadok::HeldForReview)));
+}
+
+/// Ladok results with one student, registered at the given time.
+#[cfg(test)]
+fn test_sokresultat(registered: Option<&str>) -> SokresultatStudieresultatResultat {
+    serde_json::from_value(serde_json::json!({
+        "TotaltAntalPoster": 1,
+        "Resultat": [{
+            "Uid": "sr-1",
+            "Student": {"Uid": "st-1"},
+            "Rapporteringskontext": {
+                "BetygsskalaID": "131657",
+                "KravPaHanvisningTillBeslutshandling": false,
+                "KravPaProjekttitel": false,
+                "UtbildningUID": "u-1",
+                "UtbildningsinstansUID": "m-1"},
+            "ResultatPaUtbildningar": [],
+            "SenastRegistrerad": registered,
+        }],
+    }))
+    .unwrap()
+}
+
+#[test]
+fn test_skip_unregistered_student() {
+    let mut ladok = ladok::test_client_with_scale();
+    let student = CanvasStudent {
+        uid: StudentUid::new("st-1"),
+        rounds: &[],
+    };
+    let moment = MomentData::test("m-1", test_sokresultat(None));
+    let mut submission = submission_with_flags(false, false);
+    submission.grade = Some("B".into());
+    let mut config = ReportConfig::default();
+    let change = prepare_ladok_change(&mut ladok, &config, &student, &moment, None, &submission);
+    assert!(matches!(change, Ok(ChangeToLadok::Create(..))));
+
+    config.skip_unregistered = true;
+    let change = prepare_ladok_change(&mut ladok, &config, &student, &moment, None, &submission);
+    assert!(matches!(change, Ok(ChangeToLadok::NotRegistered)));
+
+    let moment = MomentData {
+        resultat: test_sokresultat(Some("2019-01-14T10:00:00")),
+        ..moment
+    };
+    let change = prepare_ladok_change(&mut ladok, &config, &student, &moment, None, &submission);
+    assert!(matches!(change, Ok(ChangeToLadok::Create(..))));
+}
+
+#[test]
+fn test_skip_student_of_other_larosate() {
+    let mut ladok = ladok::test_client_with_scale();
+    let student = CanvasStudent {
+        uid: StudentUid::new("st-1"),
+        rounds: &[],
+    };
+    let mut foreign = test_sokresultat(None);
+    foreign.Resultat[0] = serde_json::from_value(serde_json::json!({
+        "LarosateID": 27,
+        "Uid": "sr-1",
+        "Student": {"Uid": "st-1"},
+        "Rapporteringskontext": {
+            "BetygsskalaID": "131657",
+            "KravPaHanvisningTillBeslutshandling": false,
+            "KravPaProjekttitel": false,
+            "UtbildningUID": "u-1",
+            "UtbildningsinstansUID": "m-1"},
+    }))
+    .unwrap();
+    let moment = MomentData::test("m-1", foreign);
+    let mut submission = submission_with_flags(false, false);
+    submission.grade = Some("B".into());
+    let mut config = ReportConfig::default();
+    let change = prepare_ladok_change(&mut ladok, &config, &student, &moment, None, &submission);
+    assert!(matches!(change, Ok(ChangeToLadok::OtherLarosate)));
+
+    config.skip_foreign = false;
+    let change = prepare_ladok_change(&mut ladok, &config, &student, &moment, None, &submission);
+    assert!(matches!(change, Ok(ChangeToLadok::Create(..))));
+
+    config.skip_foreign = true;
+    let mut theirs = ladok::test_client_with_scale().with_larosate("27".parse().unwrap());
+    let change = prepare_ladok_change(&mut theirs, &config, &student, &moment, None, &submission);
+    assert!(
+        matches!(change, Ok(ChangeToLadok::Create(..))),
+        "Ours with LADOK_LAROSATE_ID=27"
+    );
+
+    let ours = MomentData {
+        resultat: test_sokresultat(None),
+        ..moment
+    };
+    let change = prepare_ladok_change(&mut ladok, &config, &student, &ours, None, &submission);
+    assert!(matches!(change, Ok(ChangeToLadok::Create(..))));
+}
+
+#[test]
+fn test_unmappable_grade_on_one_moment() {
+    let mut ladok = ladok::test_client_with_scale();
+    let moment = |id: &str| MomentData::test(id, test_sokresultat(None));
+    let graded = |grades: &[&str]| {
+        grades
+            .iter()
+            .map(|grade| {
+                let mut submission = submission_with_flags(false, false);
+                submission.grade = Some(grade.to_string());
+                submission
+            })
+            .collect::<Vec<_>>()
+    };
+    let config = ReportConfig::default();
+    assert!(
+        unmappable_grades(&mut ladok, &config, &moment("m-1"), &graded(&["a", "B"]))
+            .unwrap()
+            .is_empty()
+    );
+    assert_eq!(
+        unmappable_grades(
+            &mut ladok,
+            &config,
+            &moment("m-2"),
+            &graded(&["A", "P", "p"])
+        )
+        .unwrap(),
+        ["Moment m-2: Grade \"P\" not in AF"],
+    );
+}
+
+#[test]
+fn test_skipped_grades_not_checked() {
+    let mut ladok = ladok::test_client_with_scale();
+    let moment = MomentData::test("m-1", test_sokresultat(None));
+    let config = ReportConfig::default();
+    let mut unposted = submission_with_flags(false, false);
+    unposted.posted_at = None;
+    let missing = submission_with_flags(false, true);
+    let submissions = [unposted, missing];
+    assert!(
+        unmappable_grades(&mut ladok, &config, &moment, &submissions)
+            .unwrap()
+            .is_empty()
+    );
+    let student = CanvasStudent {
+        uid: StudentUid::new("st-1"),
+        rounds: &[],
+    };
+    let change = prepare_ladok_change(
+        &mut ladok,
+        &config,
+        &student,
+        &moment,
+        None,
+        &submissions[0],
+    );
+    assert!(
+        matches!(change, Ok(ChangeToLadok::NotPosted)),
+        "{:?}",
+        change
+    );
+
+    let mut ladok = ladok::mock_client(|_| (500, String::new()));
+    let graded = [submission_with_flags(false, false)];
+    assert!(unmappable_grades(&mut ladok, &config, &moment, &graded).is_err());
+}
+
+#[test]
+fn test_numeric_grades_on_moment() {
+    let mut ladok = ladok::test_client_with_scale();
+    let table = serde_json::from_str(r#"{"5": "A", "4": "B", "0": "F"}"#).unwrap();
+    let moment = MomentData {
+        numeric: Some(NumericScale::new(17, table).unwrap()),
+        ..MomentData::test("m-1", test_sokresultat(None))
+    };
+    let config = ReportConfig::default();
+    let mut submissions = vec![];
+    for grade in &["5", "4", "6", "6"] {
+        let mut submission = submission_with_flags(false, false);
+        submission.grade = Some(grade.to_string());
+        submissions.push(submission);
+    }
+    assert_eq!(
+        unmappable_grades(&mut ladok, &config, &moment, &submissions).unwrap(),
+        ["Moment m-1: Grade 6 is not mapped to a Ladok grade for grading scheme 17"],
+    );
+    let student = CanvasStudent {
+        uid: StudentUid::new("st-1"),
+        rounds: &[],
+    };
+    let change = prepare_ladok_change(
+        &mut ladok,
+        &config,
+        &student,
+        &moment,
+        None,
+        &submissions[1],
+    );
+    match change {
+        Ok(ChangeToLadok::Create(data, _)) => {
+            assert_eq!(serde_json::to_value(&data).unwrap()["Betygsgrad"], 131662)
+        }
+        _ => panic!("Expected a create of B"),
+    }
+    assert!(prepare_ladok_change(
+        &mut ladok,
+        &config,
+        &student,
+        &moment,
+        None,
+        &submissions[2]
+    )
+    .is_err());
+}
+
+#[test]
+fn test_create_has_studieresultat_uid() {
+    let mut ladok = ladok::test_client_with_scale();
+    let resultat = test_sokresultat(Some("2019-01-14T10:00:00"));
+    let mut submission = submission_with_flags(false, false);
+    submission.grade = Some("b".into());
+    let change = prepare_ladok_change(
+        &mut ladok,
+        &ReportConfig::default(),
+        &CanvasStudent {
+            uid: StudentUid::new("st-1"),
+            rounds: &[],
+        },
+        &MomentData::test("m-1", resultat),
+        None,
+        &submission,
+    )
+    .unwrap();
+    if let ChangeToLadok::Create(data, grade) = change {
+        assert_eq!(grade, "B");
+        assert_eq!(
+            serde_json::to_value(&data).unwrap(),
+            serde_json::json!({
+                "Uid": null,
+                "Betygsgrad": 131662,
+                "BetygsskalaID": 131657,
+                "Examinationsdatum": "2019-05-24",
+                "StudieresultatUID": "sr-1",
+                "UtbildningsinstansUID": "m-1",
+            }),
+        );
+    } else {
+        panic!("Expected a create, got {:?}", change);
+    }
+}
+
+#[test]
+fn test_exam_date_window() {
+    let window = Giltighetsperiod {
+        Startdatum: NaiveDate::from_ymd_opt(2019, 3, 18),
+        Slutdatum: NaiveDate::from_ymd_opt(2019, 6, 2),
+    };
+    let before = NaiveDate::from_ymd_opt(2019, 3, 1).unwrap();
+    let within = NaiveDate::from_ymd_opt(2019, 5, 24).unwrap();
+    let after = NaiveDate::from_ymd_opt(2019, 8, 20).unwrap();
+    let clamp = ExamDateWindow::Clamp;
+    assert_eq!(
+        clamp.apply(Some(&window), before).unwrap(),
+        window.Startdatum.unwrap()
+    );
+    assert_eq!(clamp.apply(Some(&window), within).unwrap(), within);
+    assert_eq!(
+        clamp.apply(Some(&window), after).unwrap(),
+        window.Slutdatum.unwrap()
+    );
+
+    let error = ExamDateWindow::default();
+    assert_eq!(
+        error
+            .apply(Some(&window), before)
+            .err()
+            .unwrap()
+            .to_string(),
+        "Examination date 2019-03-01 is outside the period of the moment",
+    );
+    assert_eq!(error.apply(Some(&window), within).unwrap(), within);
+    assert!(error.apply(Some(&window), after).is_err());
+    assert_eq!(error.apply(None, after).unwrap(), after, "Unknown period");
+    assert_eq!(
+        ExamDateWindow::Off.apply(Some(&window), after).unwrap(),
+        after
+    );
+}
+
+#[test]
+fn test_dates_in_server_timezone() {
+    let parse = |time| DateTime::parse_from_rfc3339(time).unwrap();
+    // The same instant, as given with different offsets.
+    let stockholm = parse("2019-05-25T00:30:00+02:00");
+    let utc = parse("2019-05-24T22:30:00Z");
+    assert_eq!(local_date(&stockholm), local_date(&utc));
+}
+
+#[test]
+fn test_exam_date_from_due_date() {
+    let assignment: Assignment = serde_json::from_str(
+        r#"{"id": 17, "name": "Lab 1", "integration_id": "m-1",
+            "due_at": "2019-05-20T23:59:00+02:00"}"#,
+    )
+    .unwrap();
+    let today = NaiveDate::from_ymd_opt(2019, 6, 1).unwrap();
+    let fallback = due_date_fallback(&assignment, today);
+    assert_eq!(fallback, NaiveDate::from_ymd_opt(2019, 5, 20));
+
+    let mut submission = submission_with_flags(false, false);
+    let due = fallback.map(FallbackDate::Due);
+    assert_eq!(
+        exam_date(&submission, due),
+        submission.graded_at.map(|d| local_date(&d)),
+        "graded_at has precedence",
+    );
+    submission.graded_at = None;
+    assert_eq!(exam_date(&submission, due), fallback);
+    assert_eq!(exam_date(&submission, None), None);
+}
+
+#[test]
+fn test_future_due_date_not_used() {
+    let assignment: Assignment = serde_json::from_str(
+        r#"{"id": 17, "name": "Lab 1", "due_at": "2019-06-02T08:00:00+02:00"}"#,
+    )
+    .unwrap();
+    let today = NaiveDate::from_ymd_opt(2019, 6, 1).unwrap();
+    assert_eq!(due_date_fallback(&assignment, today), None);
+}
+
+#[test]
+fn test_windows_cover_each_student_once() {
+    let windows = [vec![1, 4, 9], vec![2, 3], vec![5, 8]];
+    let mut pending = (1..=10).collect::<BTreeSet<_>>();
+    let mut seen = vec![];
+    for (i, window) in windows.iter().enumerate() {
+        let last = i == windows.len() - 1;
+        let taken = take_window(&mut pending, last, |id| window.contains(&id));
+        if !last {
+            assert_eq!(&taken, window);
+        }
+        seen.extend(taken);
+    }
+    // 6, 7 and 10 are in no window, and are handled with the last one.
+    seen.sort_unstable();
+    assert_eq!(seen, (1..=10).collect::<Vec<_>>());
+    assert!(pending.is_empty());
+}
+
+#[cfg(test)]
+fn graded_by(id: i32, grade: &str) -> Submission {
+    let mut submission = submission_with_flags(false, false);
+    submission.user_id = Some(id);
+    submission.user =
+        Some(serde_json::from_value(serde_json::json!({"id": id, "name": "Anna"})).unwrap());
+    submission.grade = Some(grade.into());
+    submission
+}
+
+#[test]
+fn test_later_window_checked_before_writing() {
+    let mut ladok = ladok::test_client_with_scale();
+    let moment = MomentData::test("m-1", test_sokresultat(None));
+    let (good, bad) = (graded_by(1, "B"), graded_by(2, "P"));
+    let by_user = BTreeMap::from([(1, vec![&good]), (2, vec![&bad])]);
+    let config = ReportConfig::default();
+    let mut result = ExportResults::new();
+    let mut window = vec![1];
+    check_window(
+        &mut ladok,
+        &config,
+        &moment,
+        &mut window,
+        &by_user,
+        &mut result,
+    );
+    assert_eq!(window, [1]);
+    assert_eq!(result.errors, 0);
+
+    let mut window = vec![1, 2];
+    check_window(
+        &mut ladok,
+        &config,
+        &moment,
+        &mut window,
+        &by_user,
+        &mut result,
+    );
+    assert!(window.is_empty(), "Not written");
+    assert_eq!(result.errors, 2);
+    assert_eq!(
+        result.students[&1],
+        "Anna (1):  Error (Moment m-1: Grade \"P\" not in AF) "
+    );
+    assert!(
+        result.unmappable_grades.is_empty(),
+        "Earlier windows may be written"
+    );
+}
+
+#[test]
+fn test_failed_window_keeps_earlier_results() {
+    let ladok = ladok::mock_client(|_| (503, "Ladok is down".into()));
+    let (written, waiting) = (graded_by(1, "B"), graded_by(2, "C"));
+    let by_user = BTreeMap::from([(1, vec![&written]), (2, vec![&waiting])]);
+    let mut result = ExportResults::new();
+    result.add(written.user.as_ref().unwrap(), " Created (B) ");
+    let e = ladok
+        .sok_studieresultat_page(&[], &MomentUid::new("m-1"), 2, 1)
+        .unwrap_err();
+    let pending = BTreeSet::from([2]);
+    fail_students(&pending, &by_user, &e.to_string(), &mut result);
+    assert_eq!(result.errors, 1);
+    assert_eq!(result.students[&1], "Anna (1):  Created (B) ");
+    assert!(result.students[&2].starts_with("Anna (2):  Error ("));
+}
+
+#[test]
+fn test_filter_by_assignment_group() {
+    let assignments: Vec<Assignment> = serde_json::from_str(
+        r#"[{"id": 17, "name": "Lab 1", "assignment_group_id": 1},
+            {"id": 18, "name": "Tentamen", "assignment_group_id": 2},
+            {"id": 19, "name": "Omtenta", "assignment_group_id": 2},
+            {"id": 20, "name": "Bonus"}]"#,
+    )
+    .unwrap();
+    let groups: Vec<AssignmentGroup> =
+        serde_json::from_str(r#"[{"id": 1, "name": "Labs"}, {"id": 2, "name": "Examination"}]"#)
+            .unwrap();
+    let ids = |group| {
+        in_assignment_group(assignments.clone(), group, &groups)
+            .unwrap()
+            .iter()
+            .map(|a| a.id)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(ids("examination"), [18, 19]);
+    assert_eq!(ids("1"), [17]);
+    assert!(in_assignment_group(assignments.clone(), "Projects", &groups).is_err());
+}
+
+#[test]
+fn test_forbidden_moment_does_not_stop_others() {
+    let ladok = ladok::mock_client(|request| {
+        if request.contains("m-forbidden") {
+            (403, String::new())
+        } else {
+            (
+                200,
+                r#"{"Resultat": [{"Uid": "r-2", "UtbildningsinstansUID": "m-2"}]}"#.into(),
+            )
+        }
+    });
+    let create = |moment: &str| {
+        Change::Create(
+            serde_json::from_value(serde_json::json!({
+                "Uid": null,
+                "Betygsgrad": 131661,
+                "BetygsskalaID": 131657,
+                "Examinationsdatum": "2019-05-24",
+                "StudieresultatUID": "sr-1",
+                "UtbildningsinstansUID": moment,
+            }))
+            .unwrap(),
+        )
+    };
+    let config = ReportConfig::default();
+    let mut result = ExportResults::new();
+    let mut written = vec![];
+    let forbidden = MomentUid::new("m-forbidden");
+    assert_eq!(
+        write_changes(
+            &ladok,
+            &config,
+            &[],
+            &forbidden,
+            vec![create("m-forbidden")],
+            &mut result,
+            &mut written,
+        ),
+        Err(vec!["sr-1".to_string()]),
+    );
+    let student: User = serde_json::from_str(r#"{"id": 17, "name": "Anna"}"#).unwrap();
+    result.not_authorized(&forbidden, &[&student]);
+    assert!(written.is_empty());
+    assert_eq!(result.created, Ok(0));
+
+    let moment = MomentUid::new("m-2");
+    assert!(write_changes(
+        &ladok,
+        &config,
+        &[],
+        &moment,
+        vec![create("m-2")],
+        &mut result,
+        &mut written,
+    )
+    .is_ok());
+    assert_eq!(written.len(), 1);
+    assert_eq!(result.created, Ok(1));
+    assert_eq!(result.not_authorized, [forbidden]);
+    assert_eq!(
+        result.students[&17],
+        "Anna (17):  Not authorized to report this moment "
+    );
+}
+
+#[test]
+fn test_forbidden_write_denies_only_unwritten() {
+    let ladok = ladok::mock_client(|request| {
+        if request.contains("sr-2") {
+            (403, String::new())
+        } else if request.contains("sr-9") {
+            (401, String::new())
+        } else {
+            (
+                200,
+                r#"{"Resultat": [{"Uid": "r-1", "UtbildningsinstansUID": "m-1"}]}"#.into(),
+            )
+        }
+    });
+    let create = |studieresultat: &str| {
+        Change::Create(
+            serde_json::from_value(serde_json::json!({
+                "Uid": null,
+                "Betygsgrad": 131661,
+                "BetygsskalaID": 131657,
+                "Examinationsdatum": "2019-05-24",
+                "StudieresultatUID": studieresultat,
+                "UtbildningsinstansUID": "m-1",
+            }))
+            .unwrap(),
+        )
+    };
+    let config = ReportConfig {
+        commit_order: CommitOrder::PerStudent,
+        ..ReportConfig::default()
+    };
+    let users = [17, 18, 19]
+        .iter()
+        .map(|id| serde_json::from_value(serde_json::json!({"id": id})).unwrap())
+        .collect::<Vec<User>>();
+    let users = users.iter().collect::<Vec<_>>();
+    let owners = ["sr-1", "sr-2", "sr-3"]
+        .iter()
+        .zip(&users)
+        .map(|(uid, user)| (uid.to_string(), user.id))
+        .collect();
+    let moment = MomentUid::new("m-1");
+    let mut result = ExportResults::new();
+    let mut written = vec![];
+    let changes = vec![create("sr-1"), create("sr-2"), create("sr-3")];
+    let denied = write_changes(
+        &ladok,
+        &config,
+        &[],
+        &moment,
+        changes,
+        &mut result,
+        &mut written,
+    )
+    .unwrap_err();
+    assert_eq!(denied, ["sr-2", "sr-3"]);
+    assert_eq!(result.created, Ok(1));
+    let denied = owners_of(&owners, &denied, &users);
+    assert_eq!(denied.iter().map(|u| u.id).collect::<Vec<_>>(), [18, 19]);
+
+    let mut result = ExportResults::new();
+    let refused = write_changes(
+        &ladok,
+        &config,
+        &[],
+        &moment,
+        vec![create("sr-9")],
+        &mut result,
+        &mut written,
+    );
+    assert!(
+        refused.is_ok(),
+        "A refused certificate is not a denied moment"
+    );
+    assert!(result.created.unwrap_err().contains("client certificate"));
+}
+
+#[test]
+fn test_exam_date_today() {
+    let today = NaiveDate::from_ymd_opt(2019, 6, 1).unwrap();
+    let due = NaiveDate::from_ymd_opt(2019, 5, 20);
+    let mut config = ReportConfig::default();
+    assert_eq!(exam_date_fallback(&config, None, today), None);
+    config.today_fallback = true;
+    assert_eq!(
+        exam_date_fallback(&config, None, today),
+        Some(FallbackDate::Today(today))
+    );
+    assert_eq!(
+        exam_date_fallback(&config, due, today),
+        due.map(FallbackDate::Due),
+        "due date has precedence",
+    );
+
+    let mut submission = submission_with_flags(false, false);
+    let fallback = exam_date_fallback(&config, None, today);
+    assert_eq!(
+        exam_date(&submission, fallback),
+        submission.graded_at.map(|d| local_date(&d)),
+        "graded_at has precedence",
+    );
+    submission.graded_at = None;
+    assert_eq!(exam_date(&submission, fallback), Some(today));
+}
+
+#[test]
+fn test_today_fallback_keeps_date_of_draft() {
+    let mut ladok = ladok::test_client_with_scale();
+    let resultat = serde_json::from_value({
+        let mut value = serde_json::to_value(test_sokresultat(None)).unwrap();
+        value["Resultat"][0]["ResultatPaUtbildningar"] = serde_json::json!([{
+            "Arbetsunderlag": {
+                "Uid": "r-1",
+                "Betygsgrad": 131662,
+                "Examinationsdatum": "2019-06-01",
+                "UtbildningsinstansUID": "m-1",
+            },
+        }]);
+        value
+    })
+    .unwrap();
+    let moment = MomentData::test("m-1", resultat);
+    let student = CanvasStudent {
+        uid: StudentUid::new("st-1"),
+        rounds: &[],
+    };
+    let mut submission = submission_with_flags(false, false);
+    submission.graded_at = None;
+    submission.grade = Some("B".into());
+    let config = ReportConfig::default();
+    // A run on the next day finds the draft up to date.
+    let next_day = Some(FallbackDate::Today(
+        NaiveDate::from_ymd_opt(2019, 6, 2).unwrap(),
+    ));
+    let change = prepare_ladok_change(
+        &mut ladok,
+        &config,
+        &student,
+        &moment,
+        next_day,
+        &submission,
+    );
+    assert!(
+        matches!(change, Ok(ChangeToLadok::NoChange(..))),
+        "{:?}",
+        change
+    );
+    // A changed grade keeps the date of the draft.
+    submission.grade = Some("A".into());
+    match prepare_ladok_change(
+        &mut ladok,
+        &config,
+        &student,
+        &moment,
+        next_day,
+        &submission,
+    ) {
+        Ok(ChangeToLadok::Update(data, ..)) => {
+            assert_eq!(data.Examinationsdatum, NaiveDate::from_ymd_opt(2019, 6, 1))
+        }
+        change => panic!("Expected an update, got {:?}", change),
+    }
+    // A due date is not a moving date, so it is applied.
+    let due = Some(FallbackDate::Due(
+        NaiveDate::from_ymd_opt(2019, 5, 20).unwrap(),
+    ));
+    match prepare_ladok_change(&mut ladok, &config, &student, &moment, due, &submission) {
+        Ok(ChangeToLadok::Update(data, ..)) => {
+            assert_eq!(data.Examinationsdatum, NaiveDate::from_ymd_opt(2019, 5, 20))
+        }
+        change => panic!("Expected an update, got {:?}", change),
+    }
+}
+
+#[test]
+fn test_changed_grade_after_attest_needs_correction() {
+    let mut ladok = ladok::test_client_with_scale();
+    let mut resultat = test_sokresultat(Some("2019-01-14T10:00:00"));
+    resultat = serde_json::from_value({
+        let mut value = serde_json::to_value(&resultat).unwrap();
+        value["Resultat"][0]["ResultatPaUtbildningar"] = serde_json::json!([{
+            "SenastAttesteradeResultat": {
+                "Uid": "r-0",
+                "Betygsgrad": 131668,
+                "ProcessStatus": 3,
+                "UtbildningsinstansUID": "m-1",
+            },
+        }]);
+        value
+    })
+    .unwrap();
+    let moment = MomentData::test("m-1", resultat);
+    let student = CanvasStudent {
+        uid: StudentUid::new("st-1"),
+        rounds: &[],
+    };
+    let mut submission = submission_with_flags(false, false);
+    let config = ReportConfig::default();
+    let mut change = |grade: &str| {
+        submission.grade = Some(grade.into());
+        prepare_ladok_change(&mut ladok, &config, &student, &moment, None, &submission).unwrap()
+    };
+    match change("b") {
+        ChangeToLadok::NeedsCorrection(locked, grade) => {
+            assert_eq!(locked, "F");
+            assert!(grade.starts_with('B'), "{:?}", grade);
+        }
+        _ => panic!("Expected a correction to be needed"),
+    }
+    assert!(matches!(change("f"), ChangeToLadok::NoChange(..)));
+}
+
+#[test]
+fn test_normalize_sis_course_id() {
+    assert_eq!(
+        normalize_sis_course_id(" LT1016VT191\n").unwrap(),
+        "LT1016VT191"
+    );
+    assert_eq!(
+        normalize_sis_course_id("LT1016 VT191").unwrap_err(),
+        "' ' is not allowed"
+    );
+    assert!(normalize_sis_course_id("LT1016VT191/../users").is_err());
+    assert!(normalize_sis_course_id("  ").is_err());
+}
+
+#[test]
+fn test_only_named_student_reported() {
+    let submissions: Vec<Submission> = serde_json::from_str(
+        r#"[{"assignment_id": 17, "grade": "A", "user_id": 1,
+             "user": {"id": 1, "name": "Anna", "integration_id": "st-1"}},
+            {"assignment_id": 17, "grade": "B", "user_id": 2,
+             "user": {"id": 2, "name": "Bo", "integration_id": "st-2"}},
+            {"assignment_id": 18, "grade": "C", "user_id": 2,
+             "user": {"id": 2, "name": "Bo", "integration_id": "st-2"}},
+            {"assignment_id": 17, "grade": "D", "user_id": 3, "user": null},
+            {"assignment_id": 17, "grade": "E", "user_id": 4,
+             "user": {"id": 4, "name": "Cai", "sis_user_id": "19900101-1234"}}]"#,
+    )
+    .unwrap();
+    let resultat: SokresultatStudieresultatResultat = serde_json::from_value(serde_json::json!({
+        "TotaltAntalPoster": 1,
+        "Resultat": [{
+            "Uid": "sr-3",
+            "Student": {"Uid": "st-3", "Personnummer": "199001011234"},
+            "ResultatPaUtbildningar": [],
+        }],
+    }))
+    .unwrap();
+    let mut result = ExportResults::new();
+    let all = group_by_user(&submissions, &[], None, Some(&resultat), &mut result);
+    assert_eq!(all.keys().collect::<Vec<_>>(), [&1, &2, &4]);
+    assert_eq!(result.skipped_no_user, 1);
+    assert!(!result.only_student_found);
+
+    let mut result = ExportResults::new();
+    let student = StudentUid::new("st-2");
+    let only = group_by_user(
+        &submissions,
+        &[],
+        Some(&student),
+        Some(&resultat),
+        &mut result,
+    );
+    assert_eq!(only.keys().collect::<Vec<_>>(), [&2]);
+    assert_eq!(only[&2].len(), 2);
+    assert_eq!(result.skipped_no_user, 0);
+    assert!(result.students.is_empty());
+    assert!(result.only_student_found);
+
+    let mut result = ExportResults::new();
+    let by_personnummer = StudentUid::new("st-3");
+    let only = group_by_user(
+        &submissions,
+        &[],
+        Some(&by_personnummer),
+        Some(&resultat),
+        &mut result,
+    );
+    assert_eq!(only.keys().collect::<Vec<_>>(), [&4]);
+    assert!(result.only_student_found);
+
+    let mut result = ExportResults::new();
+    let elsewhere = StudentUid::new("st-9");
+    let only = group_by_user(
+        &submissions,
+        &[],
+        Some(&elsewhere),
+        Some(&resultat),
+        &mut result,
+    );
+    assert!(only.is_empty());
+    assert!(!result.only_student_found, "Not in the course room");
+}
+
+#[test]
+fn test_empty_grade_is_no_grade() {
+    let mut ladok = ladok::test_client_with_scale();
+    let student = CanvasStudent {
+        uid: StudentUid::new("st-1"),
+        rounds: &[],
+    };
+    let moment = MomentData::test("m-1", test_sokresultat(None));
+    let config = ReportConfig::default();
+    let mut submission = submission_with_flags(false, false);
+    for grade in &["", "  "] {
+        submission.grade = Some(grade.to_string());
+        let change =
+            prepare_ladok_change(&mut ladok, &config, &student, &moment, None, &submission);
+        assert!(matches!(change, Ok(ChangeToLadok::NoGrade)), "{:?}", grade);
+    }
+    assert!(
+        unmappable_grades(&mut ladok, &config, &moment, &[submission])
+            .unwrap()
+            .is_empty()
+    );
+}
+
+#[test]
+fn test_replayed_code_rejected() {
+    let codes = UsedCodes::new(StdDuration::from_secs(600));
+    assert!(codes.first_use("c-1"));
+    assert!(!codes.first_use("c-1"));
+    let response = code_replayed(&Branding::default());
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let page = String::from_utf8(response.into_body()).unwrap();
+    assert!(page.contains("already been used, please relaunch"));
+}
+
+#[test]
+fn test_exam_date_in_future_rejected() {
+    let today = NaiveDate::from_ymd_opt(2019, 6, 1).unwrap();
+    let mut config = ReportConfig::default();
+    assert!(check_exam_date_not_future(&config, today, today).is_ok());
+    let tomorrow = today.succ_opt().unwrap();
+    let err = check_exam_date_not_future(&config, tomorrow, today).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Examination date 2019-06-02 is in the future, the latest allowed is 2019-06-01"
+    );
+    config.exam_date_max_future_days = 3;
+    assert!(check_exam_date_not_future(&config, tomorrow, today).is_ok());
+    let far = NaiveDate::from_ymd_opt(2029, 6, 1).unwrap();
+    assert!(check_exam_date_not_future(&config, far, today).is_err());
+}
+
+#[test]
+fn test_reported_round_shown() {
+    let sections: Vec<CourseSection> = serde_json::from_str(
+        r#"[{"id": 101, "name": "LT1016 VT19", "integration_id": "kt-1"},
+            {"id": 102, "name": "LT1016 HT19", "integration_id": "kt-2"}]"#,
+    )
+    .unwrap();
+    let names = round_names(&sections);
+    let resultat = serde_json::from_str(
+        r#"{"TotaltAntalPoster": 2, "Resultat": [
+            {"Uid": "sr-1", "Student": {"Uid": "st-1"}, "AktuelltKurstillfalle": "kt-1"},
+            {"Uid": "sr-2", "Student": {"Uid": "st-1"}, "AktuelltKurstillfalle": "kt-3"}]}"#,
+    )
+    .unwrap();
+    let moment = MomentData::test("m-1", resultat);
+    let rounds = [RoundUid::new("kt-1")];
+    let student = CanvasStudent {
+        uid: StudentUid::new("st-1"),
+        rounds: &rounds,
+    };
+    assert_eq!(
+        reported_round(&moment, &student, &names).as_deref(),
+        Some("LT1016 VT19 (kt-1)"),
+    );
+    let rounds = [RoundUid::new("kt-3")];
+    let student = CanvasStudent {
+        rounds: &rounds,
+        ..student
+    };
+    assert_eq!(
+        reported_round(&moment, &student, &names).as_deref(),
+        Some("kt-3")
+    );
+
+    let user: User = serde_json::from_str(r#"{"id": 1, "name": "Anna"}"#).unwrap();
+    let mut result = ExportResults::new();
+    result.add(&user, " Created (A) ");
+    result.reported_on(user.id, "LT1016 VT19 (kt-1)".into());
+    result.reported_on(user.id, "LT1016 VT19 (kt-1)".into());
+    let mut page = Vec::new();
+    templates::done(
+        &mut page,
+        &Branding::default(),
+        "t",
+        None,
+        "LT1016VT191",
+        false,
+        result,
+    )
+    .unwrap();
+    let page = String::from_utf8(page).unwrap();
+    assert!(page.contains("Anna (1):  Created (A)  – Ladok: LT1016 VT19 (kt-1)</li>"));
+}
+
+#[test]
+fn test_all_or_nothing_error_prevents_writes() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static REQUESTS: AtomicUsize = AtomicUsize::new(0);
+    let ladok = ladok::mock_client(|_| {
+        REQUESTS.fetch_add(1, Ordering::SeqCst);
+        (
+            200,
+            r#"{"Resultat": [{"Uid": "r-1", "UtbildningsinstansUID": "m-1"}]}"#.into(),
+        )
+    });
+    let pending = || {
+        vec![PendingWrite {
+            moment: MomentUid::new("m-1"),
+            changes: vec![Change::Create(
+                serde_json::from_value(serde_json::json!({
+                    "Uid": null,
+                    "Betygsgrad": 131661,
+                    "BetygsskalaID": 131657,
+                    "Examinationsdatum": "2019-05-24",
+                    "StudieresultatUID": "sr-1",
+                    "UtbildningsinstansUID": "m-1",
+                }))
+                .unwrap(),
+            )],
+            users: vec![serde_json::from_str(r#"{"id": 17, "name": "Anna"}"#).unwrap()],
+            owners: BTreeMap::new(),
+            reported: vec![],
+        }]
+    };
+    let config = ReportConfig {
+        all_or_nothing: true,
+        ..ReportConfig::default()
+    };
+    let mut result = ExportResults::new();
+    result.errors = 1;
+    write_all_or_nothing(&ladok, &config, &[], pending(), false, &mut result);
+    assert_eq!(REQUESTS.load(Ordering::SeqCst), 0);
+    assert_eq!(result.created, Ok(0));
+    assert!(result.notice.is_some());
+
+    let mut result = ExportResults::new();
+    write_all_or_nothing(&ladok, &config, &[], pending(), false, &mut result);
+    assert_eq!(REQUESTS.load(Ordering::SeqCst), 1);
+    assert_eq!(result.created, Ok(1));
+
+    let windowed = ReportConfig {
+        window_size: Some(50),
+        ..config
+    };
+    assert!(windowed.checked().is_err());
+}
+
+#[test]
+fn test_grade_histogram() {
+    let moment = MomentData::test("m-1", test_sokresultat(None));
+    let mut ladok = ladok::test_client_with_scale();
+    let mut result = ExportResults::new();
+    for (grade, komplettering) in &[
+        (131661, false),
+        (131662, false),
+        (131661, false),
+        (131668, true),
+    ] {
+        let mut data: SkapaResultat = serde_json::from_value(serde_json::json!({
+            "Uid": null,
+            "Betygsgrad": grade,
+            "BetygsskalaID": 131657,
+            "Examinationsdatum": "2019-05-24",
+            "StudieresultatUID": "sr-1",
+            "UtbildningsinstansUID": "m-1",
+        }))
+        .unwrap();
+        if *komplettering {
+            data.Noteringar = vec![Notering::komplettering()];
+        }
+        let grade = change_grade(&mut ladok, &Change::Create(data)).unwrap();
+        result.count_grade(&moment.id, grade);
+    }
+    let counts = &result.grade_counts["m-1"];
+    assert_eq!(
+        counts.iter().collect::<Vec<_>>(),
+        [
+            (&"A".to_string(), &2),
+            (&"B".to_string(), &1),
+            (&"F (komplettering)".to_string(), &1)
+        ],
+    );
+    let outcome = batch::CourseOutcome::new("LT1016VT191", Ok(&result));
+    assert_eq!(outcome.grades["m-1"]["A"], 2);
+    let mut page = Vec::new();
+    templates::done(
+        &mut page,
+        &Branding::default(),
+        "t",
+        None,
+        "LT1016VT191",
+        false,
+        result,
+    )
+    .unwrap();
+    let page = String::from_utf8(page).unwrap();
+    assert!(page
+        .contains("<tr><th>m-1</th><td>A: 2</td><td>B: 1</td><td>F (komplettering): 1</td></tr>"));
+}
+
+#[test]
+fn test_scope_upgrade_keeps_course() {
+    let next = export2_url(
+        "https://app.example.org/api/report-results-ladok-rs/export",
+        "LT1016VT191",
+        Some("7798".into()),
+        None,
+        true,
+    );
+    let url = oauth_url(
+        "canvas.example.org",
+        "17",
+        &next,
+        Some("url:GET|/api/v1/courses/:course_id/enrollments"),
+    );
+    let (base, query) = url.split_once('?').unwrap();
+    assert_eq!(base, "https://canvas.example.org/login/oauth2/auth");
+    let params: BTreeMap<String, String> = serde_urlencoded::from_str(query).unwrap();
+    assert_eq!(
+        params["scope"],
+        "url:GET|/api/v1/courses/:course_id/enrollments"
+    );
+    let (redirect, back) = params["redirect_uri"].split_once('?').unwrap();
+    assert_eq!(
+        redirect,
+        "https://app.example.org/api/report-results-ladok-rs/export2"
+    );
+    let back: QueryArgs = serde_urlencoded::from_str(back).unwrap();
+    assert_eq!(back.sisCourseId, "LT1016VT191");
+    assert_eq!(back.canvasCourseId.as_deref(), Some("7798"));
+    assert!(back.code.is_none());
+    // Coming back, the scopes are not asked for again.
+    assert_eq!(back.scopeUpgraded, Some(true));
+    let first = export2_url(
+        "https://app.example.org/export",
+        "LT1016VT191",
+        None,
+        None,
+        false,
+    );
+    assert!(!first.contains("scopeUpgraded"));
+    assert!(!oauth_url("canvas.example.org", "17", &next, None).contains("scope="));
+}
+
+#[test]
+fn test_batch_failures_attributed_to_students() {
+    let ladok = ladok::mock_client(|_| {
+        (
+            200,
+            r#"{"Resultat": [{"Uid": "r-1", "StudieresultatUID": "sr-1",
+                              "UtbildningsinstansUID": "m-1"}],
+                "Fel": [{"StudieresultatUID": "sr-2",
+                         "Meddelande": "Examinationsdatum före kursstart"}]}"#
+                .into(),
+        )
+    });
+    let create = |uid: &str| {
+        Change::Create(
+            serde_json::from_value(serde_json::json!({
+                "Uid": null,
+                "Betygsgrad": 131661,
+                "BetygsskalaID": 131657,
+                "Examinationsdatum": "2019-05-24",
+                "StudieresultatUID": uid,
+                "UtbildningsinstansUID": "m-1",
+            }))
+            .unwrap(),
+        )
+    };
+    let anna: User = serde_json::from_str(r#"{"id": 17, "name": "Anna"}"#).unwrap();
+    let bo: User = serde_json::from_str(r#"{"id": 18, "name": "Bo"}"#).unwrap();
+    let changes = vec![create("sr-1"), create("sr-2")];
+    let owners = changes
+        .iter()
+        .zip(&[17, 18])
+        .flat_map(|(change, id)| change.uids().into_iter().map(move |uid| (uid, *id)))
+        .collect();
+    let mut result = ExportResults::new();
+    result.add(&anna, " Created (A) ");
+    result.add(&bo, " Created (A) ");
+    let mut written = vec![];
+    assert!(write_changes(
+        &ladok,
+        &ReportConfig::default(),
+        &[],
+        &MomentUid::new("m-1"),
+        changes,
+        &mut result,
+        &mut written,
+    )
+    .is_ok());
+    result.attribute_failures(&owners, &[&anna, &bo]);
+    assert_eq!(result.created, Ok(1));
+    assert_eq!(written.len(), 1);
+    assert_eq!(result.errors, 1);
+    assert_eq!(result.students[&17], "Anna (17):  Created (A) ");
+    assert_eq!(
+        result.students[&18],
+        "Bo (18):  Created (A)  Failed in Ladok (Examinationsdatum före kursstart) "
+    );
+    let reported = |student, uid: &str| Reported {
+        student,
+        uids: vec![uid.into()],
+        round: Some("LT1016 VT19 (kt-1)".into()),
+        grade: Some("A".into()),
+    };
+    let moment = MomentUid::new("m-1");
+    result.record_written(&moment, vec![reported(17, "sr-1"), reported(18, "sr-2")]);
+    assert_eq!(
+        result.rounds.keys().collect::<Vec<_>>(),
+        [&17],
+        "Only the written result is on a round"
+    );
+    assert_eq!(result.grade_counts["m-1"]["A"], 1, "Only the written grade");
+}
+
+#[test]
+fn test_final_moment_requires_final_grade() {
+    let mut ladok = ladok::test_client_with_scale();
+    let student = CanvasStudent {
+        uid: StudentUid::new("st-1"),
+        rounds: &[],
+    };
+    let mut resultat = serde_json::to_value(test_sokresultat(None)).unwrap();
+    resultat["Resultat"][0]["AktuellKursinstans"] = "ki-1".into();
+    let moment = |id: &str| MomentData::test(id, serde_json::from_value(resultat.clone()).unwrap());
+    let mut submission = submission_with_flags(false, false);
+    submission.grade = Some("FX".into());
+    let mut config = ReportConfig::default();
+
+    let component = moment("m-1");
+    assert!(!component.is_final(&config));
+    let change = prepare_ladok_change(&mut ladok, &config, &student, &component, None, &submission);
+    assert!(matches!(change, Ok(ChangeToLadok::Create(..))));
+    assert!(
+        unmappable_grades(&mut ladok, &config, &component, &[submission.clone()])
+            .unwrap()
+            .is_empty()
+    );
+
+    let kursinstans = moment("ki-1");
+    assert!(kursinstans.is_final(&config));
+    let change = prepare_ladok_change(
+        &mut ladok,
+        &config,
+        &student,
+        &kursinstans,
+        None,
+        &submission,
+    );
+    assert_eq!(
+        change.unwrap_err().to_string(),
+        "Grade \"FX\" is not valid as a final grade"
+    );
+    assert_eq!(
+        unmappable_grades(&mut ladok, &config, &kursinstans, &[submission.clone()]).unwrap(),
+        ["Moment ki-1: Grade \"FX\" is not valid as a final grade"],
+    );
+
+    config.final_moments.insert("m-1".into());
+    assert!(component.is_final(&config));
+    submission.grade = Some("B".into());
+    let change = prepare_ladok_change(&mut ladok, &config, &student, &component, None, &submission);
+    assert!(matches!(change, Ok(ChangeToLadok::Create(..))));
+}
+
+#[test]
+fn test_missing_users_looked_up_once() {
+    let mut by_assignment = HashMap::new();
+    let embedded = Submission {
+        user: serde_json::from_str(r#"{"id": 18, "name": "Bo"}"#).unwrap(),
+        ..submission_with_flags(false, false)
+    };
+    by_assignment.insert(17, vec![submission_with_flags(false, false), embedded]);
+    by_assignment.insert(18, vec![submission_with_flags(false, false)]);
+    let mut lookups = 0;
+    let mut result = ExportResults::new();
+    fill_missing_users(
+        &mut by_assignment,
+        2,
+        StdDuration::from_millis(0),
+        |id| {
+            lookups += 1;
+            if lookups < 3 {
+                Err(format_err!("Canvas hiccup"))
+            } else {
+                Ok(
+                    serde_json::from_value(serde_json::json!({"id": id, "integration_id": "st-1"}))
+                        .unwrap(),
+                )
+            }
+        },
+        &mut result,
+    );
+    assert_eq!(lookups, 3, "One user, retried twice");
+    assert_eq!(result.errors, 0);
+    let users = by_assignment
+        .values()
+        .flatten()
+        .map(|s| s.user.as_ref().map(|u| u.id))
+        .collect::<BTreeSet<_>>();
+    assert_eq!(users, vec![Some(18), Some(4711)].into_iter().collect());
+}
+
+#[test]
+fn test_failed_user_lookup_prevents_writes() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static REQUESTS: AtomicUsize = AtomicUsize::new(0);
+    let ladok = ladok::mock_client(|_| {
+        REQUESTS.fetch_add(1, Ordering::SeqCst);
+        (200, r#"{"Resultat": []}"#.into())
+    });
+    let mut by_assignment = HashMap::new();
+    by_assignment.insert(17, vec![submission_with_flags(false, false)]);
+    let mut lookups = 0;
+    let mut result = ExportResults::new();
+    fill_missing_users(
+        &mut by_assignment,
+        2,
+        StdDuration::from_millis(0),
+        |_| {
+            lookups += 1;
+            Err(format_err!("Canvas hiccup"))
+        },
+        &mut result,
+    );
+    assert_eq!(lookups, 3);
+    assert_eq!(result.uid_lookup_failed, 1);
+    assert_eq!(result.errors, 1);
+    assert!(
+        by_assignment[&17].is_empty(),
+        "Not also skipped as without user"
+    );
+
+    let config = ReportConfig {
+        all_or_nothing: true,
+        ..ReportConfig::default()
+    };
+    let pending = vec![PendingWrite {
+        moment: MomentUid::new("m-1"),
+        changes: vec![],
+        users: vec![serde_json::from_str(r#"{"id": 18, "name": "Bo"}"#).unwrap()],
+        owners: BTreeMap::new(),
+        reported: vec![],
+    }];
+    write_all_or_nothing(&ladok, &config, &[], pending, false, &mut result);
+    assert_eq!(REQUESTS.load(Ordering::SeqCst), 0);
+    assert!(result.notice.is_some());
+}
+
+#[test]
+fn test_uid_by_personnummer() {
+    let user: User =
+        serde_json::from_str(r#"{"id": 17, "name": "Anna", "sis_user_id": "19900101-1234"}"#)
+            .unwrap();
+    let resultat: SokresultatStudieresultatResultat = serde_json::from_value(serde_json::json!({
+        "TotaltAntalPoster": 1,
+        "Resultat": [{
+            "Uid": "sr-1",
+            "Student": {"Uid": "st-1", "Personnummer": "199001011234"},
+            "ResultatPaUtbildningar": [],
+        }],
+    }))
+    .unwrap();
+    assert_eq!(ladok_uid(&user, &resultat), Some("st-1".to_string()));
+    let known: User =
+        serde_json::from_str(r#"{"id": 17, "name": "Anna", "integration_id": "st-2"}"#).unwrap();
+    assert_eq!(ladok_uid(&known, &resultat), Some("st-2".to_string()));
+    let unknown: User =
+        serde_json::from_str(r#"{"id": 18, "sis_user_id": "19900101-9999"}"#).unwrap();
+    assert_eq!(ladok_uid(&unknown, &resultat), None);
+}
+
+#[test]
+fn test_swedish_names_in_outputs() {
+    let user: User = serde_json::from_str(r#"{"id": 17, "name": "Åsa <Öberg> & Väli"}"#).unwrap();
+    let mut result = ExportResults::new();
+    result.add(&user, " Created (A) ");
+    let outcome = StudentOutcome {
+        correlation_id: result.correlation_id.clone(),
+        moments: vec![],
+        outcome: batch::CourseOutcome::new("LT1016VT191", Ok(&result)),
+    };
+    let response = batch::json_response(StatusCode::OK, &outcome);
+    assert_eq!(
+        response.headers()[header::CONTENT_TYPE],
+        "application/json; charset=utf-8"
+    );
+    let response = batch::json_response(StatusCode::OK, &result.students);
+    assert_eq!(
+        response.body().as_slice(),
+        "{\"17\":\"Åsa <Öberg> & Väli (17):  Created (A) \"}".as_bytes()
+    );
+
+    let mut page = Vec::new();
+    templates::done(
+        &mut page,
+        &Branding::default(),
+        "t",
+        None,
+        "LT1016VT191",
+        false,
+        result,
+    )
+    .unwrap();
+    let page = String::from_utf8(page).unwrap();
+    assert!(page.contains("charset=utf-8"));
+    assert!(page.contains("<li>Åsa &lt;Öberg&gt; &amp; Väli (17):  Created (A) </li>"));
+}
+
+#[test]
+fn test_grade_before_submission_suspect() {
+    let mut config = ReportConfig::default();
+    let mut submission = submission_with_flags(false, false);
+    submission.submitted_at = Some("2019-05-24T13:00:00+02:00".parse().unwrap());
+    assert!(check_timestamps(&config, &submission).is_none());
+    config.check_timestamps = true;
+    let check = check_timestamps(&config, &submission);
+    assert!(matches!(check, Some(ChangeToLadok::SuspectTimestamp)));
+
+    submission.submitted_at = Some("2019-05-24T11:00:00+02:00".parse().unwrap());
+    assert!(check_timestamps(&config, &submission).is_none());
+    submission.submitted_at = None;
+    assert!(check_timestamps(&config, &submission).is_none());
+}
+
+#[test]
+fn test_run_exam_date_for_all_students() {
+    let mut ladok = ladok::test_client_with_scale();
+    let student = CanvasStudent {
+        uid: StudentUid::new("st-1"),
+        rounds: &[],
+    };
+    let date = NaiveDate::from_ymd_opt(2019, 5, 20).unwrap();
+    let moment = MomentData {
+        exam_date: Some(date),
+        ..MomentData::test("m-1", test_sokresultat(None))
+    };
+    let config = ReportConfig::default();
+    for graded_at in &["2019-05-24T12:00:00+02:00", "2019-06-02T09:00:00+02:00"] {
+        let mut submission = submission_with_flags(false, false);
+        submission.grade = Some("B".into());
+        submission.graded_at = Some(graded_at.parse().unwrap());
+        match prepare_ladok_change(&mut ladok, &config, &student, &moment, None, &submission) {
+            Ok(ChangeToLadok::Create(data, _)) => assert_eq!(data.Examinationsdatum, Some(date)),
+            other => panic!("Expected a create, got {:?}", other),
+        }
+    }
+}
+
+#[test]
+fn test_run_exam_date_checked_against_window() {
+    let config = ReportConfig::default();
+    let today = NaiveDate::from_ymd_opt(2019, 6, 1).unwrap();
+    let moment = MomentData {
+        window: Some(Giltighetsperiod {
+            Startdatum: NaiveDate::from_ymd_opt(2019, 1, 14),
+            Slutdatum: NaiveDate::from_ymd_opt(2019, 5, 31),
+        }),
+        ..MomentData::test("m-1", test_sokresultat(None))
+    };
+    let inside = NaiveDate::from_ymd_opt(2019, 5, 20).unwrap();
+    assert!(check_run_exam_date(&config, &moment, inside, today).is_ok());
+    let outside = NaiveDate::from_ymd_opt(2019, 1, 2).unwrap();
+    let e = check_run_exam_date(&config, &moment, outside, today).unwrap_err();
+    assert_eq!(
+        e.to_string(),
+        "Examination date 2019-01-02 is outside the period of the moment m-1"
+    );
+    assert!(
+        e.downcast_ref::<BadExamDate>().is_some(),
+        "Shown to the user"
+    );
+    let future = NaiveDate::from_ymd_opt(2019, 6, 2).unwrap();
+    let e = check_run_exam_date(&config, &moment, future, today).unwrap_err();
+    assert!(e.downcast_ref::<BadExamDate>().is_some());
+    assert_eq!(parse_exam_date(Some(" ")).unwrap(), None);
+    assert_eq!(parse_exam_date(Some("2019-05-20")).unwrap(), Some(inside));
+    assert!(parse_exam_date(Some("20 maj")).is_err());
+}
+
+#[test]
+fn test_missing_period_is_an_error() {
+    let ladok = ladok::mock_client(|_| (503, "Ladok is down".into()));
+    let moment_id = MomentUid::new("m-1");
+    let date = NaiveDate::from_ymd_opt(2019, 5, 20);
+    let off = ReportConfig {
+        exam_date_window: ExamDateWindow::Off,
+        ..ReportConfig::default()
+    };
+    assert_eq!(
+        moment_window(&ladok, &off, &moment_id, None).unwrap(),
+        None,
+        "Not needed"
+    );
+    let e = moment_window(&ladok, &off, &moment_id, date).unwrap_err();
+    assert!(e.to_string().starts_with("Failed to get period of m-1"));
+    let config = ReportConfig::default();
+    assert!(moment_window(&ladok, &config, &moment_id, None).is_err());
+}
+
+#[test]
+fn test_ready_betygsskala_configured() {
+    let preload = ["131657".parse().unwrap(), "131658".parse().unwrap()];
+    let id = |s: &str| s.parse::<BetygsskalaID>().unwrap();
+    assert_eq!(
+        ready_betygsskala(Some("4711"), &preload).unwrap(),
+        id("4711")
+    );
+    assert_eq!(ready_betygsskala(None, &preload).unwrap(), id("131657"));
+    assert!(ready_betygsskala(Some("AF"), &preload).is_err());
+    assert_eq!(
+        ready_betygsskala(None, &[]).unwrap_err().to_string(),
+        "READY_BETYGSSKALA or LADOK_PRELOAD_BETYGSSKALOR must be set"
+    );
+}
+
+#[cfg(test)]
+fn test_written() -> Vec<Resultat> {
+    serde_json::from_str(
+        r#"[{"Uid": "r-1", "SenasteResultatandring": "2019-05-24T13:14:15.123",
+             "UtbildningsinstansUID": "m-1"}]"#,
+    )
+    .unwrap()
+}
+
+#[test]
+fn test_attest_mode_stops_when_write_fails() {
+    let ladok = ladok::mock_client(|_| (500, "{}".into()));
+    let moment = MomentUid::new("m-1");
+    let mut result = ExportResults::new();
+    let mut written = vec![];
+    let change = Change::Create(
+        serde_json::from_value(serde_json::json!({
+            "Uid": null,
+            "Betygsgrad": 131661,
+            "BetygsskalaID": 131657,
+            "Examinationsdatum": "2019-05-24",
+            "StudieresultatUID": "sr-1",
+            "UtbildningsinstansUID": "m-1",
+        }))
+        .unwrap(),
+    );
+    let config = ReportConfig::default();
+    assert!(write_changes(
+        &ladok,
+        &config,
+        &[],
+        &moment,
+        vec![change],
+        &mut result,
+        &mut written,
+    )
+    .is_ok());
+    let stop = write_stop(&moment, &result).unwrap();
+    assert_eq!(stop.step, AttestStep::Write);
+
+    let mut rejected = ExportResults::new();
+    assert!(write_stop(&moment, &rejected).is_none());
+    rejected.ladok_failures =
+        serde_json::from_str(r#"[{"StudieresultatUID": "sr-1", "Meddelande": "Ogiltigt datum"}]"#)
+            .unwrap();
+    let stop = write_stop(&moment, &rejected).unwrap();
+    assert_eq!(stop.step, AttestStep::Write);
+}
+
+#[test]
+fn test_attest_mode_stops_when_klarmarkera_fails() {
+    let ladok = ladok::mock_client(|request| {
+        if request.contains("/klarmarkera") {
+            (500, "{}".into())
+        } else {
+            (200, r#"{"Resultat": [{"Uid": "r-1"}]}"#.into())
+        }
+    });
+    let mut result = ExportResults::new();
+    let stop =
+        attest_in_steps(&ladok, &MomentUid::new("m-1"), test_written(), &mut result).unwrap_err();
+    assert_eq!(stop.step, AttestStep::Klarmarkera);
+    assert!(stop.state().contains("utkast"));
+    assert!(!result.undo.attested);
+    assert_eq!(result.attested, None);
+}
+
+#[test]
+fn test_attest_mode_stops_when_attestera_fails() {
+    let ladok = ladok::mock_client(|request| {
+        if request.contains("/attestera") {
+            (500, "{}".into())
+        } else {
+            (
+                200,
+                r#"{"Resultat": [{"Uid": "r-1", "ProcessStatus": 2,
+                    "SenasteResultatandring": "2019-05-24T13:14:16.123"}]}"#
+                    .into(),
+            )
+        }
+    });
+    let mut result = ExportResults::new();
+    let stop =
+        attest_in_steps(&ladok, &MomentUid::new("m-1"), test_written(), &mut result).unwrap_err();
+    assert_eq!(stop.step, AttestStep::Attestera);
+    assert!(stop.state().contains("klarmarkerade"));
+    assert!(result.undo.attested);
+}
+
+#[test]
+fn test_attest_mode_attests_written_results() {
+    let ladok = ladok::mock_client(|request| {
+        let status = if request.contains("/attestera") { 3 } else { 2 };
+        (
+            200,
+            format!(
+                r#"{{"Resultat": [{{"Uid": "r-1", "ProcessStatus": {},
+                    "SenasteResultatandring": "2019-05-24T13:14:16.123"}}]}}"#,
+                status
+            ),
+        )
+    });
+    let mut result = ExportResults::new();
+    attest_in_steps(&ladok, &MomentUid::new("m-1"), test_written(), &mut result).unwrap();
+    attest_in_steps(&ladok, &MomentUid::new("m-2"), test_written(), &mut result).unwrap();
+    assert_eq!(result.attested, Some(Ok(2)));
+    assert!(result.attest_stopped.is_none());
+}
+
+#[test]
+fn test_komplettering_only_on_permitted_moments() {
+    let config = ReportConfig {
+        komplettering_moments: vec!["m-1".to_string()].into_iter().collect(),
+        ..ReportConfig::default()
+    };
+    assert_eq!(split_komplettering(&config, "F+komp"), ("F", true));
+    assert_eq!(split_komplettering(&config, "F"), ("F", false));
+    assert!(check_komplettering(&config, &MomentUid::new("m-1"), true).is_ok());
+    assert!(check_komplettering(&config, &MomentUid::new("m-2"), true).is_err());
+    assert!(check_komplettering(&config, &MomentUid::new("m-2"), false).is_ok());
+}
+
+#[test]
+fn test_count_headers_match_body() {
+    let mut result = ExportResults::new();
+    result.created = Ok(3);
+    result.updated = Err("Ladok failed".into());
+    result.errors = 2;
+    let mut response = batch::json_response(
+        StatusCode::OK,
+        &batch::CourseOutcome::new("LT1016VT191", Ok(&result)),
+    );
+    response.headers_mut().extend(count_headers(&result));
+    let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+    let header = |name| {
+        response
+            .headers()
+            .get(name)
+            .map(|value| value.to_str().unwrap().parse::<u64>().unwrap())
+    };
+    assert_eq!(header("X-Ladok-Created"), body["created"].as_u64());
+    assert_eq!(header("X-Ladok-Updated"), body["updated"].as_u64());
+    assert_eq!(header("X-Ladok-Updated"), None);
+    assert_eq!(header("X-Ladok-Errors"), body["errors"].as_u64());
+    assert_eq!(header("X-Ladok-Errors"), Some(2));
+}
+
+#[test]
+fn test_anonymized_moment_not_reported() {
+    let ladok = ladok::mock_client(|request| {
+        if request.starts_with("PUT /resultat/studieresultat/rapportera") {
+            (
+                200,
+                r#"{"TotaltAntalPoster": 1, "Resultat": [{
+                    "Uid": "sr-1",
+                    "Student": {"Uid": "st-1"},
+                    "Rapporteringskontext": {
+                        "Anonymiseringskod": "AX-17",
+                        "BetygsskalaID": "131657",
+                        "UtbildningsinstansUID": "m-1"},
+                    "ResultatPaUtbildningar": []}]}"#
+                    .into(),
+            )
+        } else {
+            (500, format!("Unexpected request {}", request))
+        }
+    });
+    let mut result = ExportResults::new();
+    let moment = MomentUid::new("m-1");
+    let found = search_moment(
+        &ladok,
+        &ReportConfig::default(),
+        &[],
+        &moment,
+        1,
+        &mut result,
+    );
+    assert!(found.unwrap().is_none());
+    assert_eq!(result.anonymized, std::slice::from_ref(&moment));
+    // A later window is checked as well as the first.
+    let windowed = ReportConfig {
+        window_size: Some(1),
+        ..ReportConfig::default()
+    };
+    let found = search_moment(&ladok, &windowed, &[], &moment, 2, &mut result);
+    assert!(found.unwrap().is_none());
+    assert_eq!(result.anonymized.len(), 2);
+    let outcome = batch::CourseOutcome::new("LT1016VT191", Ok(&result));
+    assert!(!outcome.ok);
+    assert!(outcome.error.unwrap().contains("anonymous marking"));
+}
+
+#[test]
+fn test_export_artifact_matches_run() {
+    let dir = std::env::temp_dir().join(format!("export-artifact-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let artifacts = Artifacts::new(&dir, Duration::days(30));
+    let mut result = ExportResults::new();
+    let student: User = serde_json::from_str(r#"{"id": 17, "name": "Anna"}"#).unwrap();
+    result.add(&student, " Created (A) ");
+    result.created = Ok(1);
+    result.errors = 2;
+    let correlation_id = result.correlation_id.clone();
+    let path = keep_artifact(&artifacts, "LT1016VT191", &Ok(result), Utc::now()).unwrap();
+    assert!(path
+        .file_name()
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .ends_with(&format!("-{}.json", correlation_id)));
+    let kept: serde_json::Value = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+    assert_eq!(kept["sis_course_id"], "LT1016VT191");
+    assert_eq!(kept["error"], serde_json::Value::Null);
+    assert_eq!(kept["result"]["correlation_id"], correlation_id.as_str());
+    assert_eq!(kept["result"]["created"]["Ok"], 1);
+    assert_eq!(kept["result"]["errors"], 2);
+    assert_eq!(
+        kept["result"]["students"],
+        serde_json::json!(["Anna (17):  Created (A) "])
+    );
+
+    let failed = keep_artifact(
+        &artifacts,
+        "LT1016VT191",
+        &Err(format_err!("Canvas is down")),
+        Utc::now(),
+    )
+    .unwrap();
+    let kept: serde_json::Value = serde_json::from_slice(&std::fs::read(&failed).unwrap()).unwrap();
+    assert_eq!(kept["error"], "Canvas is down");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_course_room_by_id_must_have_sis_id() {
+    let course = |json| -> CourseRoom { serde_json::from_str(json).unwrap() };
+    let room = course(r#"{"sis_course_id": "LT1016VT191"}"#);
+    assert!(check_sis_course_id(&room, "LT1016VT191").is_ok());
+    assert!(check_sis_course_id(&room, "SF1625HT182").is_err());
+    assert!(check_sis_course_id(&course("{}"), "LT1016VT191").is_err());
+}
+
+#[test]
+fn test_canvas_course_id_must_be_numeric() {
+    assert!(check_canvas_course_id(None).is_ok());
+    assert!(check_canvas_course_id(Some("12345")).is_ok());
+    for bad in &["", "12a", "1/../2", "sis_course_id:SF1625", " 12"] {
+        assert!(check_canvas_course_id(Some(bad)).is_err(), "{:?}", bad);
+    }
+}
+
+#[test]
+fn test_shared_clients_reuse_connections() {
+    use std::sync::atomic::Ordering;
+    const REQUESTS: usize = 10;
+    let get = |client: &Client, url: &str| {
+        let mut response = client.get(url).send().unwrap();
+        assert_eq!(response.text().unwrap(), "{}");
+    };
+    let build: [fn() -> Client; 2] = [
+        || canvas::http_client(16, HeaderMap::new()).unwrap(),
+        || ladok::http_client(None, 16, false, HeaderMap::new()).unwrap(),
+    ];
+    for build in &build {
+        let (url, connections) = mock_server::serve_keep_alive(|_| (200, "{}".into()));
+        for _ in 0..REQUESTS {
+            get(&build(), &url);
+        }
+        assert_eq!(
+            connections.load(Ordering::SeqCst),
+            REQUESTS,
+            "A client each"
+        );
+
+        let (url, connections) = mock_server::serve_keep_alive(|_| (200, "{}".into()));
+        let shared = build();
+        for _ in 0..REQUESTS {
+            get(&shared, &url);
+        }
+        let reused = connections.load(Ordering::SeqCst);
+        assert!(
+            reused <= 2,
+            "{} connections for {} requests",
+            reused,
+            REQUESTS
+        );
+    }
+}
+
+/// The ructe-generated templates, in a module of their own so lints
+/// on the generated code don't leak into ours.
+#[allow(clippy::all, unexpected_cfgs)]
+mod generated {
+    include!(concat!(env!("OUT_DIR"), "/templates.rs"));
+}
@@ -0,0 +1,277 @@
+//! Mapping of Canvas assignments to Ladok moments.
+//!
+//! The primary source is the integration_id of the Canvas assignment.
+//! As many course rooms lack that, a sidecar table can be configured
+//! as a json file like this:
+//!
+//! ```json
+//! [
+//!   {"sis_course_id": "LT1016VT191", "assignment": 4711, "moment": "<uid>"},
+//!   {"sis_course_id": "LT1016VT191", "assignment": "Tentamen", "moment": "<uid>"}
+//! ]
+//! ```
+//!
+//! where assignment is either the Canvas id or the name of the assignment.
+//!
+//! Last, the moment can be given in the name of the assignment, by a
+//! pattern like `LADOK: {code}`.  The code is the uid of the moment, or
+//! a code looked up in a table like this:
+//!
+//! ```json
+//! [{"sis_course_id": "LT1016VT191", "code": "TEN1", "moment": "<uid>"}]
+//! ```
+use crate::canvas::Assignment;
+use crate::ladok::types::MomentUid;
+use failure::{format_err, Error};
+use log::{info, warn};
+use serde::Deserialize;
+use std::fs::File;
+
+#[derive(Debug, Default)]
+pub struct MomentMapping {
+    entries: Vec<Entry>,
+    name_pattern: Option<NamePattern>,
+    codes: Vec<CodeEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CodeEntry {
+    sis_course_id: String,
+    code: String,
+    moment: MomentUid,
+}
+
+/// A pattern with a `{code}` placeholder, to find in assignment names.
+#[derive(Debug)]
+struct NamePattern {
+    prefix: String,
+    suffix: String,
+}
+
+impl NamePattern {
+    fn new(pattern: &str) -> Result<Self, Error> {
+        let mut parts = pattern.split("{code}");
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(prefix), Some(suffix), None) if !prefix.is_empty() || !suffix.is_empty() => {
+                Ok(NamePattern {
+                    prefix: prefix.into(),
+                    suffix: suffix.into(),
+                })
+            }
+            _ => Err(format_err!(
+                "Bad moment name pattern {:?}, expected text and one {{code}}",
+                pattern
+            )),
+        }
+    }
+
+    /// The code in a name, if the name matches the pattern.
+    fn code<'a>(&self, name: &'a str) -> Option<&'a str> {
+        let rest = &name[name.find(&self.prefix)? + self.prefix.len()..];
+        let code = if self.suffix.is_empty() {
+            rest.split_whitespace().next()?
+        } else {
+            &rest[..rest.find(&self.suffix)?]
+        };
+        Some(code.trim()).filter(|code| !code.is_empty())
+    }
+}
+
+/// True if a code looks like a uid rather than a short code.
+fn is_uid(code: &str) -> bool {
+    code.len() == 36 && code.chars().filter(|c| *c == '-').count() == 4
+}
+
+#[derive(Debug, Deserialize)]
+struct Entry {
+    sis_course_id: String,
+    assignment: AssignmentKey,
+    moment: MomentUid,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum AssignmentKey {
+    Id(i32),
+    Name(String),
+}
+
+impl AssignmentKey {
+    fn matches(&self, assignment: &Assignment) -> bool {
+        match self {
+            AssignmentKey::Id(id) => *id == assignment.id,
+            AssignmentKey::Name(name) => assignment.name.as_ref() == Some(name),
+        }
+    }
+}
+
+impl MomentMapping {
+    pub fn load(path: &str) -> Result<Self, Error> {
+        let file = File::open(path).map_err(|e| format_err!("{}: {}", path, e))?;
+        let entries = serde_json::from_reader(file).map_err(|e| format_err!("{}: {}", path, e))?;
+        Ok(MomentMapping {
+            entries,
+            ..MomentMapping::default()
+        })
+    }
+
+    /// Also find moments in assignment names by `pattern`, with codes
+    /// looked up in the table in `codes_path`, if any.
+    pub fn with_name_pattern(
+        mut self,
+        pattern: &str,
+        codes_path: Option<&str>,
+    ) -> Result<Self, Error> {
+        self.name_pattern = Some(NamePattern::new(pattern)?);
+        if let Some(path) = codes_path {
+            let file = File::open(path).map_err(|e| format_err!("{}: {}", path, e))?;
+            self.codes =
+                serde_json::from_reader(file).map_err(|e| format_err!("{}: {}", path, e))?;
+        }
+        Ok(self)
+    }
+
+    /// Get the Ladok moment uid for a Canvas assignment, if any.
+    pub fn moment_for(&self, sis_course_id: &str, assignment: &Assignment) -> Option<MomentUid> {
+        if let Some(moment) = &assignment.integration_id {
+            info!(
+                "Assignment {} mapped to moment {} by integration_id",
+                assignment.id, moment,
+            );
+            return Some(MomentUid::new(moment.as_str()));
+        }
+        if let Some(entry) = self
+            .entries
+            .iter()
+            .find(|e| e.sis_course_id == sis_course_id && e.assignment.matches(assignment))
+        {
+            info!(
+                "Assignment {} mapped to moment {} by mapping table",
+                assignment.id, entry.moment,
+            );
+            return Some(entry.moment.clone());
+        }
+        self.moment_by_name(sis_course_id, assignment)
+    }
+
+    fn moment_by_name(&self, sis_course_id: &str, assignment: &Assignment) -> Option<MomentUid> {
+        let code = self
+            .name_pattern
+            .as_ref()?
+            .code(assignment.name.as_ref()?)?;
+        let moment = match self
+            .codes
+            .iter()
+            .find(|e| e.sis_course_id == sis_course_id && e.code.eq_ignore_ascii_case(code))
+        {
+            Some(entry) => entry.moment.clone(),
+            None if is_uid(code) => MomentUid::new(code),
+            None => {
+                warn!(
+                    "Assignment {} has moment code {:?}, which is not in the code table",
+                    assignment.id, code,
+                );
+                return None;
+            }
+        };
+        info!(
+            "Assignment {} mapped to moment {} by assignment name",
+            assignment.id, moment,
+        );
+        Some(moment)
+    }
+}
+
+#[cfg(test)]
+fn assignment(id: i32, name: &str, integration_id: Option<&str>) -> Assignment {
+    Assignment {
+        id,
+        name: Some(name.into()),
+        integration_id: integration_id.map(Into::into),
+        due_at: None,
+        assignment_group_id: None,
+        anonymous_grading: None,
+        muted: None,
+        updated_at: None,
+    }
+}
+
+#[test]
+fn test_mapping_table_supplies_moment() {
+    let mapping = MomentMapping {
+        entries: serde_json::from_str(
+            r#"[{"sis_course_id": "LT1016VT191", "assignment": 17, "moment": "m-17"},
+                {"sis_course_id": "LT1016VT191", "assignment": "Tentamen", "moment": "m-ten"},
+                {"sis_course_id": "SF1625HT182", "assignment": 18, "moment": "m-other"}]"#,
+        )
+        .unwrap(),
+        ..MomentMapping::default()
+    };
+    let course = "LT1016VT191";
+    assert_eq!(
+        mapping.moment_for(course, &assignment(17, "Lab", None)),
+        Some(MomentUid::new("m-17"))
+    );
+    assert_eq!(
+        mapping.moment_for(course, &assignment(42, "Tentamen", None)),
+        Some(MomentUid::new("m-ten"))
+    );
+    assert_eq!(
+        mapping.moment_for(course, &assignment(18, "Lab 2", None)),
+        None
+    );
+}
+
+#[test]
+fn test_integration_id_has_precedence() {
+    let mapping = MomentMapping {
+        entries: serde_json::from_str(
+            r#"[{"sis_course_id": "LT1016VT191", "assignment": 17, "moment": "m-17"}]"#,
+        )
+        .unwrap(),
+        ..MomentMapping::default()
+    };
+    assert_eq!(
+        mapping.moment_for("LT1016VT191", &assignment(17, "Lab", Some("m-canvas"))),
+        Some(MomentUid::new("m-canvas"))
+    );
+}
+
+#[test]
+fn test_moment_from_assignment_name() {
+    let mapping = MomentMapping {
+        codes: serde_json::from_str(
+            r#"[{"sis_course_id": "LT1016VT191", "code": "TEN1", "moment": "m-ten1"}]"#,
+        )
+        .unwrap(),
+        ..MomentMapping::default()
+    }
+    .with_name_pattern("LADOK: {code}", None)
+    .unwrap();
+    let course = "LT1016VT191";
+    assert_eq!(
+        mapping.moment_for(course, &assignment(17, "Tentamen LADOK: ten1", None)),
+        Some(MomentUid::new("m-ten1"))
+    );
+    assert_eq!(
+        mapping.moment_for(
+            course,
+            &assignment(18, "LADOK: 2a7c5a57-5b4a-11e7-a1d0-f2c1c4d35f6a", None)
+        ),
+        Some(MomentUid::new("2a7c5a57-5b4a-11e7-a1d0-f2c1c4d35f6a"))
+    );
+    assert_eq!(
+        mapping.moment_for(course, &assignment(19, "LADOK: LAB9", None)),
+        None
+    );
+    assert_eq!(
+        mapping.moment_for(course, &assignment(20, "Tentamen", None)),
+        None
+    );
+
+    let bracketed = NamePattern::new("[{code}]").unwrap();
+    assert_eq!(bracketed.code("Tentamen [ TEN1 ] VT19"), Some("TEN1"));
+    assert_eq!(bracketed.code("Tentamen [TEN1"), None);
+    assert!(NamePattern::new("LADOK").is_err());
+    assert!(NamePattern::new("{code}").is_err());
+}
@@ -0,0 +1,112 @@
+//! A fake http server for tests of the clients of Canvas, Ladok and
+//! webhooks.
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Start a server answering each request with the status and json
+/// body given by `respond` for the request text.  Returns the url of
+/// the server, e.g. `http://127.0.0.1:4711`, without a trailing slash.
+pub fn serve<F>(respond: F) -> String
+where
+    F: Fn(&str) -> (u16, String) + Send + 'static,
+{
+    serve_raw(move |request| {
+        let (status, body) = respond(request);
+        format!(
+            "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body,
+        )
+        .into_bytes()
+    })
+}
+
+/// Start a server answering each request with the whole response
+/// given by `respond`, status line and headers included, e.g. to send
+/// a wrong content-length.  The connection is closed after it.
+pub fn serve_raw<F>(respond: F) -> String
+where
+    F: Fn(&str) -> Vec<u8> + Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let server = format!("http://{}", listener.local_addr().unwrap());
+    std::thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            let request = read_request(&mut stream);
+            let _ = stream.write_all(&respond(&request));
+        }
+    });
+    server
+}
+
+/// Start a server like `serve`, but keeping each connection open for
+/// more requests, as a pooling client expects.  Returns the url of the
+/// server and the number of connections accepted so far.
+pub fn serve_keep_alive<F>(respond: F) -> (String, Arc<AtomicUsize>)
+where
+    F: Fn(&str) -> (u16, String) + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let server = format!("http://{}", listener.local_addr().unwrap());
+    let connections = Arc::new(AtomicUsize::new(0));
+    let accepted = connections.clone();
+    let respond = Arc::new(respond);
+    std::thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            accepted.fetch_add(1, Ordering::SeqCst);
+            let respond = respond.clone();
+            std::thread::spawn(move || loop {
+                let request = read_request(&mut stream);
+                if request.is_empty() {
+                    break;
+                }
+                let (status, body) = respond(&request);
+                let response = format!(
+                    "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\n\
+                     Content-Length: {}\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body,
+                );
+                if stream.write_all(response.as_bytes()).is_err() {
+                    break;
+                }
+            });
+        }
+    });
+    (server, connections)
+}
+
+/// The text of a request, with its body if it has a content-length.
+fn read_request(stream: &mut TcpStream) -> String {
+    let mut request = Vec::new();
+    let mut buf = [0; 4096];
+    while let Ok(n) = stream.read(&mut buf) {
+        request.extend_from_slice(&buf[..n]);
+        let text = String::from_utf8_lossy(&request);
+        if let Some(end) = text.find("\r\n\r\n") {
+            let length = text
+                .lines()
+                .find_map(|line| {
+                    let (name, value) = line.split_once(':')?;
+                    if name.eq_ignore_ascii_case("content-length") {
+                        value.trim().parse().ok()
+                    } else {
+                        None
+                    }
+                })
+                .unwrap_or(0);
+            if request.len() >= end + 4 + length {
+                break;
+            }
+        }
+        if n == 0 {
+            break;
+        }
+    }
+    String::from_utf8_lossy(&request).into_owned()
+}
@@ -0,0 +1,116 @@
+//! Notification of finished exports, e.g. to the course coordinators.
+//!
+//! The summary of each export is posted as json to a webhook, if one
+//! is configured.  This is best effort: a failed notification is
+//! logged, but does not fail the export.  It is sent in the
+//! background, so a slow webhook does not delay the response.
+use crate::batch::CourseOutcome;
+use failure::Error;
+use log::{info, warn};
+use reqwest::header::HeaderMap;
+use reqwest::Client;
+use serde_json::{json, Value};
+use std::time::Duration;
+
+pub struct Notifier {
+    webhook: Option<String>,
+    client: Client,
+}
+
+impl Notifier {
+    /// A notifier posting to `webhook`, with the outbound `headers`
+    /// sent to every service.
+    pub fn new(webhook: Option<String>, headers: HeaderMap) -> Result<Self, Error> {
+        Ok(Notifier {
+            webhook,
+            client: Client::builder()
+                .default_headers(headers)
+                .timeout(Duration::from_secs(10))
+                .build()?,
+        })
+    }
+
+    /// Post the outcome of an export to the webhook, if any, without
+    /// waiting for it.
+    pub fn notify(&self, correlation_id: Option<&str>, outcome: &CourseOutcome) {
+        if let Some(webhook) = &self.webhook {
+            let request = self
+                .client
+                .post(webhook)
+                .json(&payload(correlation_id, outcome));
+            let webhook = webhook.clone();
+            let sis_course_id = outcome.sis_course_id.clone();
+            std::thread::spawn(move || {
+                match request
+                    .send()
+                    .and_then(|response| response.error_for_status())
+                {
+                    Ok(_) => info!("Notified {} of export of {}", webhook, sis_course_id),
+                    Err(e) => warn!(
+                        "Failed to notify {} of export of {}: {}",
+                        webhook, sis_course_id, e,
+                    ),
+                }
+            });
+        }
+    }
+}
+
+fn payload(correlation_id: Option<&str>, outcome: &CourseOutcome) -> Value {
+    json!({
+        "event": "export_completed",
+        "correlation_id": correlation_id,
+        "outcome": outcome,
+    })
+}
+
+#[test]
+fn test_webhook_gets_summary() {
+    use std::sync::mpsc::channel;
+    let (sender, received) = channel();
+    let server = crate::mock_server::serve(move |request| {
+        sender.send(request.to_string()).unwrap();
+        // A slow webhook does not hold up the export.
+        std::thread::sleep(Duration::from_secs(2));
+        (204, String::new())
+    });
+    let webhook = format!("{}/hook", server);
+    let outcome = CourseOutcome {
+        sis_course_id: "LT1016VT191".into(),
+        ok: false,
+        error: Some("Failed".into()),
+        students: 3,
+        errors: 1,
+        created: Some(2),
+        updated: None,
+        notice: None,
+        grades: Default::default(),
+    };
+    let headers = crate::outbound::headers(None, &"X-Larosate: KTH".parse().unwrap()).unwrap();
+    let start = std::time::Instant::now();
+    Notifier::new(Some(webhook), headers)
+        .unwrap()
+        .notify(Some("17f-0"), &outcome);
+    assert!(start.elapsed() < Duration::from_secs(1), "Not waited for");
+    let request = received.recv().unwrap();
+    assert!(request.starts_with("POST /hook "), "{}", request);
+    let lowercase = request.to_lowercase();
+    assert!(lowercase.contains("\r\nx-larosate: kth\r\n"), "{}", request);
+    assert!(
+        lowercase.contains("\r\nuser-agent: report-results-ladok-rs/"),
+        "{}",
+        request
+    );
+    let body = &request[request.find("\r\n\r\n").unwrap() + 4..];
+    let body: Value = serde_json::from_str(body).unwrap();
+    assert_eq!(body["event"], "export_completed");
+    assert_eq!(body["correlation_id"], "17f-0");
+    assert_eq!(body["outcome"]["sis_course_id"], "LT1016VT191");
+    assert_eq!(body["outcome"]["created"], 2);
+    assert_eq!(body["outcome"]["error"], "Failed");
+
+    // Without a webhook, there is nothing to do.
+    Notifier::new(None, HeaderMap::new())
+        .unwrap()
+        .notify(None, &outcome);
+}
@@ -0,0 +1,118 @@
+//! Numeric grades, e.g. on a 1-5 scale, mapped to Ladok grade codes.
+//!
+//! Some courses grade with a numeric Canvas grading scheme.  For those,
+//! a table from number to Ladok grade code can be configured per
+//! grading scheme as a json file like this:
+//!
+//! ```json
+//! {
+//!   "4711": {"5": "A", "4": "B", "3": "C", "2": "D", "1": "E", "0": "F"}
+//! }
+//! ```
+//!
+//! where the key is the Canvas id of the grading scheme (grading
+//! standard) of the course.  A number missing from the table is an
+//! error, rather than reported as anything.
+use failure::{format_err, Error};
+use std::collections::BTreeMap;
+use std::fs::File;
+
+#[derive(Debug, Default)]
+pub struct NumericGrades {
+    tables: BTreeMap<i32, NumericScale>,
+}
+
+impl NumericGrades {
+    pub fn load(path: &str) -> Result<Self, Error> {
+        let file = File::open(path).map_err(|e| format_err!("{}: {}", path, e))?;
+        let tables: BTreeMap<i32, BTreeMap<String, String>> =
+            serde_json::from_reader(file).map_err(|e| format_err!("{}: {}", path, e))?;
+        tables
+            .into_iter()
+            .map(|(id, table)| Ok((id, NumericScale::new(id, table)?)))
+            .collect::<Result<_, Error>>()
+            .map(|tables| NumericGrades { tables })
+            .map_err(|e| format_err!("{}: {}", path, e))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tables.is_empty()
+    }
+
+    /// The mapping for a Canvas grading scheme, if it is numeric.
+    pub fn for_scheme(&self, grading_standard: i32) -> Option<&NumericScale> {
+        self.tables.get(&grading_standard)
+    }
+}
+
+/// The Ladok grade codes of the numbers of one grading scheme.
+#[derive(Clone, Debug)]
+pub struct NumericScale {
+    grading_standard: i32,
+    codes: Vec<(f64, String)>,
+}
+
+impl NumericScale {
+    pub fn new(grading_standard: i32, table: BTreeMap<String, String>) -> Result<Self, Error> {
+        let codes = table
+            .into_iter()
+            .map(|(number, code)| {
+                let number = number.trim().parse().map_err(|_| {
+                    format_err!("Bad number {:?} in scheme {}", number, grading_standard)
+                })?;
+                Ok((number, code))
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(NumericScale {
+            grading_standard,
+            codes,
+        })
+    }
+
+    /// The Ladok grade code for a Canvas grade, which must be a number
+    /// in the table.
+    pub fn code(&self, grade: &str) -> Result<&str, Error> {
+        let number: f64 = grade.trim().parse().map_err(|_| {
+            format_err!(
+                "Grade {:?} is not a number, as required by grading scheme {}",
+                grade,
+                self.grading_standard,
+            )
+        })?;
+        self.codes
+            .iter()
+            .find(|(n, _)| *n == number)
+            .map(|(_, code)| code.as_str())
+            .ok_or_else(|| {
+                format_err!(
+                    "Grade {} is not mapped to a Ladok grade for grading scheme {}",
+                    grade,
+                    self.grading_standard,
+                )
+            })
+    }
+}
+
+#[test]
+fn test_numeric_to_code() {
+    let table = serde_json::from_str(r#"{"5": "A", "4": "B", "3": "C", "0": "F"}"#).unwrap();
+    let scale = NumericScale::new(17, table).unwrap();
+    assert_eq!(scale.code("5").unwrap(), "A");
+    assert_eq!(scale.code("4.0").unwrap(), "B");
+    assert_eq!(scale.code(" 0 ").unwrap(), "F");
+    assert_eq!(
+        scale.code("6").unwrap_err().to_string(),
+        "Grade 6 is not mapped to a Ladok grade for grading scheme 17",
+    );
+    assert!(scale.code("3.5").is_err());
+    assert!(scale.code("A").is_err());
+
+    let table = serde_json::from_str(r#"{"five": "A"}"#).unwrap();
+    assert!(NumericScale::new(17, table).is_err());
+
+    let grades = NumericGrades {
+        tables: vec![(17, scale)].into_iter().collect(),
+    };
+    assert!(grades.for_scheme(17).is_some());
+    assert!(grades.for_scheme(18).is_none());
+}
@@ -0,0 +1,97 @@
+//! Headers sent on every request to Canvas and Ladok.
+//!
+//! Their admins ask integrations to identify themselves, so requests
+//! carry a User-Agent naming this service, and any extra headers an
+//! environment requires, e.g. an institution identifier.
+use failure::{format_err, Error};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, USER_AGENT};
+use std::str::FromStr;
+
+/// Extra headers, as `Name: value` separated by `;`.
+#[derive(Debug, Default)]
+pub struct ExtraHeaders(HeaderMap);
+
+impl FromStr for ExtraHeaders {
+    type Err = Error;
+    fn from_str(value: &str) -> Result<Self, Error> {
+        let mut headers = HeaderMap::new();
+        for entry in value.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+            let (name, value) = entry
+                .split_once(':')
+                .ok_or_else(|| format_err!("Expected \"Name: value\", got {:?}", entry))?;
+            let name = HeaderName::from_bytes(name.trim().as_bytes())
+                .map_err(|e| format_err!("Bad header name {:?}: {}", name, e))?;
+            let value = HeaderValue::from_str(value.trim())
+                .map_err(|e| format_err!("Bad value for {}: {}", name, e))?;
+            headers.append(name, value);
+        }
+        Ok(ExtraHeaders(headers))
+    }
+}
+
+/// The User-Agent of the service, with a way to reach its admins if
+/// `contact` is given.
+pub fn user_agent(contact: Option<&str>) -> String {
+    let name = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+    match contact.map(str::trim).filter(|c| !c.is_empty()) {
+        Some(contact) => format!("{} (+{})", name, contact),
+        None => name.to_string(),
+    }
+}
+
+/// The headers of every request to a service.  An extra User-Agent
+/// replaces the default one.
+pub fn headers(contact: Option<&str>, extra: &ExtraHeaders) -> Result<HeaderMap, Error> {
+    let mut headers = HeaderMap::new();
+    headers.insert(USER_AGENT, HeaderValue::from_str(&user_agent(contact))?);
+    for name in extra.0.keys() {
+        headers.remove(name);
+    }
+    for (name, value) in &extra.0 {
+        headers.append(name, value.clone());
+    }
+    Ok(headers)
+}
+
+#[test]
+fn test_headers_sent_to_canvas_and_ladok() {
+    use std::sync::mpsc::channel;
+
+    /// The request text received by a server.
+    fn received(client: reqwest::Client) -> String {
+        let (sender, received) = channel();
+        let server = crate::mock_server::serve(move |request| {
+            sender.send(request.to_lowercase()).unwrap();
+            (204, String::new())
+        });
+        client.get(&format!("{}/", server)).send().unwrap();
+        received.recv().unwrap()
+    }
+
+    let extra: ExtraHeaders = "X-Larosate: KTH; X-Integration: report-results"
+        .parse()
+        .unwrap();
+    let headers = headers(Some("it-support@kth.se"), &extra).unwrap();
+    let user_agent = format!(
+        "user-agent: report-results-ladok-rs/{} (+it-support@kth.se)\r\n",
+        env!("CARGO_PKG_VERSION"),
+    );
+    for request in &[
+        received(crate::canvas::http_client(1, headers.clone()).unwrap()),
+        received(crate::ladok::http_client(None, 1, false, headers.clone()).unwrap()),
+    ] {
+        assert!(request.contains(&user_agent), "{}", request);
+        assert!(request.contains("x-larosate: kth\r\n"), "{}", request);
+        assert!(request.contains("x-integration: report-results\r\n"));
+    }
+}
+
+#[test]
+fn test_bad_extra_headers() {
+    assert!("X-Larosate".parse::<ExtraHeaders>().is_err());
+    assert!("Bad Name: x".parse::<ExtraHeaders>().is_err());
+    let extra: ExtraHeaders = "User-Agent: custom;".parse().unwrap();
+    let headers = headers(None, &extra).unwrap();
+    assert_eq!(headers.get_all(USER_AGENT).iter().count(), 1);
+    assert_eq!(headers[USER_AGENT], "custom");
+}
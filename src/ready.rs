@@ -0,0 +1,100 @@
+//! Readiness, as opposed to liveness, of the service.
+//!
+//! The service is ready once it has talked to every Ladok environment
+//! successfully, and as long as none of their circuit breakers is
+//! open.
+use crate::ladok::{BreakerState, CircuitBreaker};
+use failure::Error;
+use log::{info, warn};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use warp::http::{header, Response, StatusCode};
+
+#[derive(Default)]
+pub struct Readiness {
+    ladok_ok: AtomicBool,
+}
+
+impl Readiness {
+    /// Run `probe` until it succeeds, waiting `interval` between tries.
+    pub fn probe_until_ready<F>(&self, interval: Duration, probe: F)
+    where
+        F: Fn() -> Result<(), Error>,
+    {
+        loop {
+            match probe() {
+                Ok(()) => {
+                    info!("Ladok responds, service is ready");
+                    self.ladok_ok.store(true, Ordering::SeqCst);
+                    return;
+                }
+                Err(e) => {
+                    warn!("Ladok not ready: {}", e);
+                    thread::sleep(interval);
+                }
+            }
+        }
+    }
+
+    /// Check if ready, giving a reason if not.
+    pub fn check(&self, breakers: &[&CircuitBreaker]) -> Result<(), &'static str> {
+        if !self.ladok_ok.load(Ordering::SeqCst) {
+            Err("waiting for Ladok")
+        } else if breakers.iter().any(|b| b.state() == BreakerState::Open) {
+            Err("Ladok circuit is open")
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn response(&self, breakers: &[&CircuitBreaker]) -> Response<Vec<u8>> {
+        let (status, body) = match self.check(breakers) {
+            Ok(()) => (StatusCode::OK, "READY\n".to_string()),
+            Err(reason) => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                format!("NOT READY: {}\n", reason),
+            ),
+        };
+        Response::builder()
+            .status(status)
+            .header(header::CONTENT_TYPE, "text/plain")
+            .body(body.into_bytes())
+            .unwrap()
+    }
+}
+
+#[test]
+fn test_ready_after_ladok_responds() {
+    use failure::format_err;
+    use std::cell::Cell;
+    let breaker = CircuitBreaker::new(1, Duration::from_secs(60), Duration::from_secs(30));
+    let other = CircuitBreaker::new(1, Duration::from_secs(60), Duration::from_secs(30));
+    let breakers = [&other, &breaker];
+    let readiness = Readiness::default();
+    assert_eq!(
+        readiness.response(&breakers).status(),
+        StatusCode::SERVICE_UNAVAILABLE
+    );
+
+    // A mock Ladok, failing the first time.
+    let calls = Cell::new(0);
+    readiness.probe_until_ready(Duration::from_millis(1), || {
+        calls.set(calls.get() + 1);
+        if calls.get() < 2 {
+            Err(format_err!("Connection refused"))
+        } else {
+            Ok(())
+        }
+    });
+    assert_eq!(calls.get(), 2);
+    assert_eq!(readiness.response(&breakers).status(), StatusCode::OK);
+
+    // An outage of any environment opens its circuit.
+    breaker.record(false);
+    assert_eq!(readiness.check(&breakers), Err("Ladok circuit is open"));
+    assert_eq!(
+        readiness.response(&breakers).status(),
+        StatusCode::SERVICE_UNAVAILABLE
+    );
+}
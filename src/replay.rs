@@ -0,0 +1,48 @@
+//! Protection against replayed OAuth codes.
+//!
+//! Canvas authorization codes are single use, so exchanging one again,
+//! e.g. from a bookmarked or reloaded page, only gives a confusing
+//! error from Canvas.  Used codes are remembered for a while, to tell
+//! the user to relaunch from Canvas instead.
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub struct UsedCodes {
+    ttl: Duration,
+    used: Mutex<HashMap<String, Instant>>,
+}
+
+impl UsedCodes {
+    pub fn new(ttl: Duration) -> Self {
+        UsedCodes {
+            ttl,
+            used: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Mark `code` as used.  Return false if it was already used.
+    pub fn first_use(&self, code: &str) -> bool {
+        self.first_use_at(code, Instant::now())
+    }
+
+    fn first_use_at(&self, code: &str, now: Instant) -> bool {
+        let mut used = self.used.lock().unwrap();
+        let ttl = self.ttl;
+        used.retain(|_, at| now.duration_since(*at) < ttl);
+        used.insert(code.to_string(), now).is_none()
+    }
+}
+
+#[test]
+fn test_code_replayed() {
+    let codes = UsedCodes::new(Duration::from_secs(600));
+    let start = Instant::now();
+    assert!(codes.first_use_at("c-1", start));
+    assert!(codes.first_use_at("c-2", start));
+    assert!(!codes.first_use_at("c-1", start + Duration::from_secs(5)));
+    assert!(
+        codes.first_use_at("c-2", start + Duration::from_secs(601)),
+        "Forgotten after the ttl"
+    );
+}
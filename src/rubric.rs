@@ -0,0 +1,112 @@
+//! Grades computed from rubric totals.
+//!
+//! Some courses keep the authoritative mark in the rubric assessment
+//! of an assignment rather than in its grade.  For such assignments,
+//! a table of thresholds can be configured as a json file like this:
+//!
+//! ```json
+//! {
+//!   "4711": [{"min": 90, "grade": "A"}, {"min": 50, "grade": "E"}, {"min": 0, "grade": "F"}]
+//! }
+//! ```
+//!
+//! where the key is the Canvas id of the assignment.  Each student
+//! gets the grade of the highest threshold their rubric total reaches.
+//! Submissions without a rubric assessment keep their Canvas grade.
+use crate::canvas::Submission;
+use failure::{format_err, Error};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs::File;
+
+#[derive(Debug, Default)]
+pub struct RubricGrades {
+    tables: BTreeMap<i32, Vec<Threshold>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Threshold {
+    min: f64,
+    grade: String,
+}
+
+impl RubricGrades {
+    pub fn load(path: &str) -> Result<Self, Error> {
+        let file = File::open(path).map_err(|e| format_err!("{}: {}", path, e))?;
+        let tables = serde_json::from_reader(file).map_err(|e| format_err!("{}: {}", path, e))?;
+        Ok(RubricGrades::new(tables))
+    }
+
+    /// Rubric grades from a table as in the json file.
+    #[cfg(test)]
+    pub fn test(json: &str) -> Self {
+        RubricGrades::new(serde_json::from_str(json).unwrap())
+    }
+
+    fn new(mut tables: BTreeMap<i32, Vec<Threshold>>) -> Self {
+        for table in tables.values_mut() {
+            table.sort_by(|a, b| b.min.partial_cmp(&a.min).unwrap());
+        }
+        RubricGrades { tables }
+    }
+
+    /// True if the assignment should be graded by its rubric.
+    pub fn is_rubric_graded(&self, assignment: i32) -> bool {
+        self.tables.contains_key(&assignment)
+    }
+
+    /// The grade for a rubric total on an assignment.
+    pub fn grade(&self, assignment: i32, total: f64) -> Option<&str> {
+        self.tables
+            .get(&assignment)?
+            .iter()
+            .find(|t| total >= t.min)
+            .map(|t| t.grade.as_ref())
+    }
+
+    /// Replace the grade of submissions with one from their rubric
+    /// total, where there is one.
+    pub fn apply(&self, assignment: i32, submissions: &mut [Submission]) {
+        for submission in submissions {
+            if let Some(total) = submission.rubric_total() {
+                if let Some(grade) = self.grade(assignment, total) {
+                    submission.grade = Some(grade.into());
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_rubric_to_grade() {
+    let grades = RubricGrades::new(
+        serde_json::from_str(
+            r#"{"17": [{"min": 0, "grade": "F"}, {"min": 90, "grade": "A"},
+                       {"min": 50, "grade": "E"}]}"#,
+        )
+        .unwrap(),
+    );
+    assert!(grades.is_rubric_graded(17));
+    assert!(!grades.is_rubric_graded(18));
+    assert_eq!(grades.grade(17, 95.), Some("A"));
+    assert_eq!(grades.grade(17, 90.), Some("A"));
+    assert_eq!(grades.grade(17, 89.5), Some("E"));
+    assert_eq!(grades.grade(17, 12.), Some("F"));
+    assert_eq!(grades.grade(17, -1.), None);
+    assert_eq!(grades.grade(18, 95.), None);
+
+    let mut submissions: Vec<Submission> = serde_json::from_str(
+        r#"[{"assignment_id": 17, "grade": "F", "user": null,
+             "rubric_assessment": {"c1": {"points": 40}, "c2": {"points": 15.5}}},
+            {"assignment_id": 17, "grade": "B", "user": null},
+            {"assignment_id": 17, "grade": "C", "user": null,
+             "rubric_assessment": {"c1": {"points": null}}}]"#,
+    )
+    .unwrap();
+    grades.apply(17, &mut submissions);
+    let grades = submissions
+        .iter()
+        .map(|s| s.grade.as_ref().unwrap().as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(grades, ["E", "B", "C"]);
+}
@@ -0,0 +1,362 @@
+//! A machine-readable description of the http api, as OpenAPI 3.
+//!
+//! The document is written by hand, next to the routes in `main`, so
+//! remember to update it when adding or changing a route.  The tests
+//! check the response schemas against the serde types they describe.
+use serde_json::{json, Value};
+
+/// The OpenAPI document for the api, with paths relative to `base`.
+pub fn openapi(base: &str) -> Value {
+    let html = json!({"text/html": {"schema": {"type": "string"}}});
+    let text = json!({"text/plain": {"schema": {"type": "string"}}});
+    let count =
+        |description: &str| json!({"description": description, "schema": {"type": "integer"}});
+    let counts = json!({
+        "X-Ladok-Created": count("Results created, unless creating failed"),
+        "X-Ladok-Updated": count("Results updated, unless updating failed"),
+        "X-Ladok-Errors": count("Students that could not be reported"),
+    });
+    let form = |required: &[&str], properties: Value| {
+        json!({
+            "required": true,
+            "content": {"application/x-www-form-urlencoded": {"schema": {
+                "type": "object",
+                "required": required,
+                "properties": properties,
+            }}},
+        })
+    };
+    let grade_mapping = json!({
+        "type": "object",
+        "properties": {
+            "moment": {"type": "string"},
+            "grade": {"type": "string", "description": "As set in Canvas"},
+            "betygsskala": {"type": "integer"},
+            "betygsgrad": {"type": "integer"},
+            "ladok_grade": {"type": "string", "description": "The code and benämning in Ladok"},
+            "valid_as_final": {"type": "boolean"},
+            "is_final": {"type": "boolean", "description": "The moment is the final grade"},
+            "komplettering": {"type": "boolean"},
+        },
+    });
+    let grade_mapping_path = json!({"post": {
+        "summary": "How a Canvas grade of a student maps to Ladok, given a service or audit token, or the Canvas token of a teacher",
+        "security": [{"serviceToken": []}, {"auditToken": []}, {}],
+        "requestBody": {
+            "required": true,
+            "content": {"application/json": {"schema": {
+                "type": "object",
+                "required": ["round", "moment", "student"],
+                "properties": {
+                    "round": {"type": "string", "description": "The integration_id of a section"},
+                    "moment": {"type": "string"},
+                    "student": {"type": "string", "description": "A personnummer or Ladok student uid"},
+                    "grade": {"type": "string", "description": "The grade as set in Canvas, letter or numeric"},
+                    "assignment": {"type": "integer", "description": "A rubric-graded assignment"},
+                    "rubric_total": {"type": "number", "description": "The rubric total on the assignment"},
+                    "sis_course_id": {"type": "string", "description": "For the numeric grading scheme and the Ladok environment"},
+                    "canvas_token": {"type": "string", "description": "The Canvas token of a teacher in sis_course_id"},
+                },
+            }}},
+        },
+        "responses": {
+            "200": {
+                "description": "The grade in Ladok the Canvas grade maps to",
+                "content": {"application/json": {"schema": {
+                    "$ref": "#/components/schemas/GradeMapping",
+                }}},
+            },
+            "400": {"description": "canvas_token given without sis_course_id"},
+            "401": {"description": "No valid service token given"},
+            "403": {"description": "The Canvas user is not a teacher in the course room"},
+            "404": {"description": "No such student in Ladok"},
+            "422": {"description": "The grade cannot be reported, and why"},
+            "502": {"description": "Canvas or Ladok failed"},
+        },
+    }});
+    let mut doc = json!({
+        "openapi": "3.0.0",
+        "info": {
+            "title": env!("CARGO_PKG_NAME"),
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "servers": [{"url": base}],
+        "paths": {
+            "/_about": {"get": {
+                "summary": "Information about the service",
+                "responses": {"200": {"description": "An html page", "content": html}},
+            }},
+            "/_monitor": {"get": {
+                "summary": "Liveness check",
+                "responses": {"200": {"description": "Status lines", "content": text}},
+            }},
+            "/_ready": {"get": {
+                "summary": "Readiness check, ok only when Ladok can be reached",
+                "responses": {
+                    "200": {"description": "READY", "content": text},
+                    "503": {"description": "NOT READY, with a reason", "content": text},
+                },
+            }},
+            "/_schema": {"get": {
+                "summary": "This document",
+                "responses": {"200": {
+                    "description": "An OpenAPI document",
+                    "content": {"application/json": {"schema": {"type": "object"}}},
+                }},
+            }},
+            "/export": {"post": {
+                "summary": "Start an export, as an LTI launch from Canvas",
+                "requestBody": form(
+                    &["lis_course_offering_sourcedid", "custom_canvas_course_id"],
+                    json!({
+                        "lis_course_offering_sourcedid": {"type": "string"},
+                        "custom_canvas_course_id": {"type": "string"},
+                        "code": {"type": "string"},
+                    }),
+                ),
+                "responses": {"200": {"description": "Redirect to Canvas authorization", "content": html}},
+            }},
+            "/export2": {"get": {
+                "summary": "Callback from Canvas authorization",
+                "parameters": [
+                    {"name": "canvasCourseId", "in": "query", "schema": {"type": "string"}},
+                    {"name": "sisCourseId", "in": "query", "required": true, "schema": {"type": "string"}},
+                    {"name": "code", "in": "query", "schema": {"type": "string"}},
+                    {"name": "error", "in": "query", "schema": {"type": "string"}},
+                ],
+                "responses": {"200": {"description": "The moments to export", "content": html}},
+            }},
+            "/export3": {"post": {
+                "summary": "Export the results to Ladok",
+                "requestBody": form(
+                    &["canvas_token", "sis_course_id"],
+                    json!({
+                        "canvas_token": {"type": "string"},
+                        "canvas_course_id": {"type": "string"},
+                        "sis_course_id": {"type": "string"},
+                        "changes_only": {"type": "boolean"},
+                        "assignment_group": {"type": "string"},
+                        "confirm_token": {"type": "string"},
+                        "course_lookup": {"type": "string", "enum": ["sis", "id"]},
+                        "exam_date": {"type": "string", "format": "date"},
+                        "attest": {"type": "boolean"},
+                        "attest_token": {"type": "string"},
+                    }),
+                ),
+                "responses": {
+                    "200": {
+                        "description": "The results of the export, or a confirmation form",
+                        "headers": counts,
+                        "content": html,
+                    },
+                    "500": {"description": "The export failed", "content": html},
+                },
+            }},
+            "/undo": {"post": {
+                "summary": "Undo an export",
+                "requestBody": form(
+                    &["canvas_token", "sis_course_id", "correlation_id"],
+                    json!({
+                        "canvas_token": {"type": "string"},
+                        "sis_course_id": {"type": "string"},
+                        "correlation_id": {"type": "string"},
+                    }),
+                ),
+                "responses": {"200": {"description": "The results of the undo", "content": html}},
+            }},
+            "/_ladok_results": {"get": {
+                "summary": "The Ladok results of a moment, for support, given a service or audit token",
+                "security": [{"serviceToken": []}, {"auditToken": []}],
+                "parameters": [
+                    {"name": "round", "in": "query", "required": true, "schema": {"type": "string"},
+                     "description": "The integration_id of a section"},
+                    {"name": "moment", "in": "query", "required": true, "schema": {"type": "string"}},
+                    {"name": "sis_course_id", "in": "query", "schema": {"type": "string"},
+                     "description": "Selects the Ladok environment, if there are several"},
+                ],
+                "responses": {
+                    "200": {
+                        "description": "The results as returned by Ladok",
+                        "content": {"application/json": {"schema": {"type": "object"}}},
+                    },
+                    "401": {"description": "No valid service token given"},
+                    "502": {"description": "Ladok failed"},
+                },
+            }},
+            "/_diagnostics": {"get": {
+                "summary": "How a course room maps to Ladok, for support, given a service or audit token",
+                "security": [{"serviceToken": []}, {"auditToken": []}],
+                "parameters": [
+                    {"name": "sis_course_id", "in": "query", "required": true, "schema": {"type": "string"}},
+                    {"name": "format", "in": "query", "schema": {"type": "string", "enum": ["json"]},
+                     "description": "json for json rather than an html table"},
+                ],
+                "responses": {
+                    "200": {
+                        "description": "The integration ids of the course room, its sections and assignments",
+                        "content": {
+                            "text/html": {"schema": {"type": "string"}},
+                            "application/json": {"schema": {"type": "object"}},
+                        },
+                    },
+                    "401": {"description": "No valid service token given"},
+                    "502": {"description": "Canvas failed"},
+                    "503": {"description": "No Canvas service token configured"},
+                },
+            }},
+            "/_batch": {"post": {
+                "summary": "Export many course rooms, given a service token",
+                "security": [{"serviceToken": []}],
+                "parameters": [
+                    {"name": "since", "in": "query", "schema": {"type": "string", "format": "date-time"},
+                     "description": "Only report what is graded or changed since then"},
+                ],
+                "requestBody": {
+                    "required": true,
+                    "content": {
+                        "application/json": {"schema": {"type": "array", "items": {"type": "string"}}},
+                        "text/csv": {"schema": {"type": "string"}},
+                    },
+                },
+                "responses": {
+                    "200": {
+                        "description": "The outcome for each course room",
+                        "content": {"application/json": {"schema": {
+                            "type": "array",
+                            "items": {"$ref": "#/components/schemas/CourseOutcome"},
+                        }}},
+                    },
+                    "400": {"description": "No valid course list given"},
+                    "401": {"description": "No valid service token given"},
+                    "403": {"description": "The audit token may not write to Ladok"},
+                    "503": {"description": "No canvas service token configured"},
+                },
+            }},
+            "/_cache/clear": {"post": {
+                "summary": "Forget the cached grading scales, given a service token",
+                "security": [{"serviceToken": []}],
+                "responses": {
+                    "200": {
+                        "description": "The number of scales forgotten",
+                        "content": {"application/json": {"schema": {
+                            "type": "object",
+                            "properties": {"cleared": {"type": "integer"}},
+                        }}},
+                    },
+                    "401": {"description": "No valid service token given"},
+                    "403": {"description": "The audit token may not write to Ladok"},
+                },
+            }},
+            "/_student": {"post": {
+                "summary": "Export the results of one student, given a service token",
+                "security": [{"serviceToken": []}],
+                "requestBody": {
+                    "required": true,
+                    "content": {"application/json": {"schema": {
+                        "type": "object",
+                        "required": ["sis_course_id", "student"],
+                        "properties": {
+                            "sis_course_id": {"type": "string"},
+                            "student": {"type": "string", "description": "A personnummer or Ladok student uid"},
+                        },
+                    }}},
+                },
+                "responses": {
+                    "200": {
+                        "description": "The outcome for the student on each moment",
+                        "headers": counts,
+                        "content": {"application/json": {"schema": {
+                            "type": "object",
+                            "properties": {
+                                "correlation_id": {"type": "string"},
+                                "moments": {"type": "array", "items": {
+                                    "type": "array", "items": {"type": "string"},
+                                }},
+                                "outcome": {"$ref": "#/components/schemas/CourseOutcome"},
+                            },
+                        }}},
+                    },
+                    "401": {"description": "No valid service token given"},
+                    "403": {"description": "The audit token may not write to Ladok"},
+                    "404": {"description": "No such student in Ladok"},
+                    "502": {"description": "The export failed"},
+                    "503": {"description": "No canvas service token configured"},
+                },
+            }},
+        },
+        "components": {
+            "securitySchemes": {
+                "serviceToken": {"type": "http", "scheme": "bearer"},
+                "auditToken": {"type": "http", "scheme": "bearer", "description": "Read only, AUDIT_TOKEN"},
+            },
+            "schemas": {
+                "CourseOutcome": {
+                    "type": "object",
+                    "required": ["sis_course_id", "ok", "error", "students", "errors", "created", "updated", "notice", "grades"],
+                    "properties": {
+                        "sis_course_id": {"type": "string"},
+                        "ok": {"type": "boolean"},
+                        "error": {"type": "string", "nullable": true},
+                        "students": {"type": "integer"},
+                        "errors": {"type": "integer", "description": "Students that could not be reported"},
+                        "created": {"type": "integer", "nullable": true},
+                        "updated": {"type": "integer", "nullable": true},
+                        "notice": {"type": "string", "nullable": true},
+                        "grades": {
+                            "type": "object",
+                            "description": "The number of each grade reported, per moment",
+                            "additionalProperties": {
+                                "type": "object",
+                                "additionalProperties": {"type": "integer"},
+                            },
+                        },
+                    },
+                },
+            },
+        },
+    });
+    // Added apart, as the document is too deep for a single json!.
+    doc["paths"]["/_grade_mapping"] = grade_mapping_path;
+    doc["components"]["schemas"]["GradeMapping"] = grade_mapping;
+    doc
+}
+
+#[test]
+fn test_course_outcome_schema() {
+    use crate::batch::CourseOutcome;
+    let outcome = CourseOutcome {
+        sis_course_id: "LT1016VT191".into(),
+        ok: false,
+        error: Some("Failed".into()),
+        students: 3,
+        errors: 1,
+        created: Some(2),
+        updated: None,
+        notice: Some("Nothing to do".into()),
+        grades: vec![(
+            "m-1".to_string(),
+            vec![("A".to_string(), 2)].into_iter().collect(),
+        )]
+        .into_iter()
+        .collect(),
+    };
+    let outcome = serde_json::to_value(&outcome).unwrap();
+    let schema = &openapi("/api")["components"]["schemas"]["CourseOutcome"];
+    let properties = schema["properties"].as_object().unwrap();
+    let fields = outcome.as_object().unwrap();
+    assert_eq!(
+        properties.keys().collect::<Vec<_>>(),
+        fields.keys().collect::<Vec<_>>(),
+    );
+    for (name, value) in fields {
+        let expected = match value {
+            Value::String(_) => "string",
+            Value::Bool(_) => "boolean",
+            Value::Number(_) => "integer",
+            Value::Object(_) => "object",
+            Value::Null => continue,
+            _ => panic!("Unexpected value {} for {}", value, name),
+        };
+        assert_eq!(properties[name]["type"], expected, "Type of {}", name);
+    }
+}
@@ -0,0 +1,71 @@
+//! Timing of calls to Canvas and Ladok, for performance triage.
+use log::{debug, warn};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+static SLOW_CALL_MS: AtomicU64 = AtomicU64::new(5000);
+
+/// Set the duration above which a call is logged as slow.
+pub fn set_slow_threshold(threshold: Duration) {
+    SLOW_CALL_MS.store(threshold.as_millis() as u64, Ordering::Relaxed);
+}
+
+/// Run `f`, logging how long it took.
+///
+/// The duration is logged at debug level, or as a warning if it
+/// exceeds the slow call threshold.
+pub fn timed<T, F>(what: &str, f: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    let start = Instant::now();
+    let result = f();
+    let elapsed = start.elapsed();
+    if elapsed.as_millis() as u64 > SLOW_CALL_MS.load(Ordering::Relaxed) {
+        warn!("Slow call: {} took {:?}", what, elapsed);
+    } else {
+        debug!("{} took {:?}", what, elapsed);
+    }
+    result
+}
+
+/// Accumulated time spent in a phase of an export.
+#[derive(Debug, Default)]
+pub struct Phase(Duration);
+
+impl Phase {
+    /// Run `f`, adding the time it takes to this phase.
+    pub fn time<T, F>(&mut self, f: F) -> T
+    where
+        F: FnOnce() -> T,
+    {
+        let start = Instant::now();
+        let result = f();
+        self.0 += start.elapsed();
+        result
+    }
+    pub fn total(&self) -> Duration {
+        self.0
+    }
+}
+
+#[test]
+fn test_timed_keeps_result() {
+    assert_eq!(timed("ok", || Ok::<_, String>(17)), Ok(17));
+    assert_eq!(
+        timed("err", || Err::<u8, _>("failed".to_string())),
+        Err("failed".to_string())
+    );
+}
+
+#[test]
+fn test_phase_keeps_result_and_adds_time() {
+    let mut phase = Phase::default();
+    let result = phase.time(|| {
+        std::thread::sleep(Duration::from_millis(2));
+        vec![1, 2, 3]
+    });
+    assert_eq!(result, vec![1, 2, 3]);
+    phase.time(|| std::thread::sleep(Duration::from_millis(2)));
+    assert!(phase.total() >= Duration::from_millis(4));
+}
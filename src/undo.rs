@@ -0,0 +1,203 @@
+//! Undo of recent exports.
+//!
+//! For each export, the results it created and the prior state of the
+//! results it updated are kept in memory for a while, so a teacher
+//! can revert an export made with e.g. a wrong date or scale.
+use crate::ladok::types::{Resultat, ResultatUid, UppdateraResultat};
+use crate::ladok::Ladok;
+use failure::{format_err, Error};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+/// What is needed to undo one export.
+#[derive(Debug, Default)]
+pub struct UndoRecord {
+    pub sis_course_id: String,
+    /// Uids of the results created by the export.
+    pub created: Vec<ResultatUid>,
+    /// Updates restoring the prior state of the updated results.
+    pub reverts: Vec<UppdateraResultat>,
+    /// Attested results cannot be undone.
+    pub attested: bool,
+}
+
+impl UndoRecord {
+    pub fn new(sis_course_id: &str) -> Self {
+        UndoRecord {
+            sis_course_id: sis_course_id.into(),
+            ..UndoRecord::default()
+        }
+    }
+
+    /// Record results created by the export.
+    pub fn add_created(&mut self, written: &[Resultat]) {
+        self.created
+            .extend(written.iter().filter_map(|r| r.Uid.clone()));
+    }
+
+    /// Record the prior state of updated results.
+    ///
+    /// The `written` results are the response from Ladok, which gives
+    /// the current SenasteResultatandring needed to revert each result.
+    pub fn add_updated(&mut self, prior: Vec<UppdateraResultat>, written: &[Resultat]) {
+        for mut revert in prior {
+            if let Some(current) = written
+                .iter()
+                .find(|r| r.Uid.is_some() && r.Uid == revert.ResultatUID)
+            {
+                revert.SenasteResultatandring = current.SenasteResultatandring.clone();
+                self.reverts.push(revert);
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.created.is_empty() && self.reverts.is_empty()
+    }
+
+    /// Delete the created results and revert the updated ones.
+    ///
+    /// Returns the number of deleted and reverted results.  What is
+    /// done is removed from the record, so on failure it holds only
+    /// what is left to undo.
+    pub fn undo(&mut self, ladok: &Ladok) -> Result<(usize, usize), Error> {
+        if self.attested {
+            return Err(format_err!("Attested results cannot be undone"));
+        }
+        let mut deleted = 0;
+        while let Some(uid) = self.created.first() {
+            ladok.ta_bort_resultat(uid)?;
+            self.created.remove(0);
+            deleted += 1;
+        }
+        let reverted = if self.reverts.is_empty() {
+            0
+        } else {
+            let reverted = ladok
+                .uppdatera_studieresultat(self.reverts.clone())?
+                .resultat
+                .len();
+            self.reverts.clear();
+            reverted
+        };
+        Ok((deleted, reverted))
+    }
+}
+
+/// The undo records of the most recent exports, by correlation id.
+pub struct UndoLog {
+    capacity: usize,
+    records: Mutex<VecDeque<(String, UndoRecord)>>,
+}
+
+impl UndoLog {
+    pub fn new(capacity: usize) -> Self {
+        UndoLog {
+            capacity,
+            records: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn push(&self, correlation_id: &str, record: UndoRecord) {
+        if record.is_empty() {
+            return;
+        }
+        let mut records = self.records.lock().unwrap();
+        while records.len() >= self.capacity.max(1) {
+            records.pop_front();
+        }
+        records.push_back((correlation_id.into(), record));
+    }
+
+    /// Take the record for an export of a course room, so it can only
+    /// be undone once.  If the undo fails, the record should be put
+    /// back with `push`, to try again.
+    pub fn take(&self, correlation_id: &str, sis_course_id: &str) -> Option<UndoRecord> {
+        let mut records = self.records.lock().unwrap();
+        let pos = records
+            .iter()
+            .position(|(id, r)| id == correlation_id && r.sis_course_id == sis_course_id)?;
+        records.remove(pos).map(|(_, record)| record)
+    }
+}
+
+#[test]
+fn test_undo_restores_prior_state() {
+    let prior: UppdateraResultat = serde_json::from_value(serde_json::json!({
+        "Uid": "sr-1",
+        "Betygsgrad": 131662,
+        "BetygsskalaID": 131657,
+        "Examinationsdatum": "2019-05-20",
+        "ResultatUID": "r-1",
+        "SenasteResultatandring": "2019-05-20T10:00:00",
+    }))
+    .unwrap();
+    let written: Vec<Resultat> = serde_json::from_str(
+        r#"[{"Uid": "r-1", "Betygsgrad": 131661, "Examinationsdatum": "2019-05-24",
+             "SenasteResultatandring": "2019-05-24T13:14:15.123"},
+            {"Uid": "r-2", "Betygsgrad": 131661}]"#,
+    )
+    .unwrap();
+    let mut record = UndoRecord::new("LT1016VT191");
+    record.add_created(&written[1..]);
+    record.add_updated(vec![prior], &written[..1]);
+    assert_eq!(record.created, vec![ResultatUid::new("r-2")]);
+    assert_eq!(
+        serde_json::to_value(&record.reverts).unwrap(),
+        serde_json::json!([{
+            "Uid": "sr-1",
+            "Betygsgrad": 131662,
+            "BetygsskalaID": 131657,
+            "Examinationsdatum": "2019-05-20",
+            "ResultatUID": "r-1",
+            "SenasteResultatandring": "2019-05-24T13:14:15.123",
+        }]),
+    );
+}
+
+#[test]
+fn test_failed_undo_keeps_the_rest() {
+    let ladok = crate::ladok::mock_client(|request| {
+        if request.starts_with("DELETE /resultat/studieresultat/resultat/r-2 ") {
+            (400, r#"{"Meddelande": "Nej"}"#.into())
+        } else if request.starts_with("DELETE ") {
+            (204, String::new())
+        } else {
+            (500, format!("Unexpected request {}", request))
+        }
+    });
+    let log = UndoLog::new(2);
+    let mut record = UndoRecord::new("LT1016VT191");
+    for uid in &["r-1", "r-2", "r-3"] {
+        record.created.push(ResultatUid::new(*uid));
+    }
+    log.push("a", record);
+    let mut record = log.take("a", "LT1016VT191").unwrap();
+    assert!(record.undo(&ladok).is_err());
+    assert_eq!(
+        record.created,
+        vec![ResultatUid::new("r-2"), ResultatUid::new("r-3")]
+    );
+    log.push("a", record);
+    assert!(log.take("a", "LT1016VT191").is_some(), "Put back to retry");
+}
+
+#[test]
+fn test_undo_log_takes_once() {
+    let log = UndoLog::new(2);
+    for id in &["a", "b", "c"] {
+        let mut record = UndoRecord::new("LT1016VT191");
+        record.created.push(ResultatUid::new(format!("r-{}", id)));
+        log.push(id, record);
+    }
+    assert!(
+        log.take("a", "LT1016VT191").is_none(),
+        "Dropped by capacity"
+    );
+    assert!(log.take("b", "SF1625HT182").is_none(), "Wrong course");
+    assert_eq!(
+        log.take("b", "LT1016VT191").unwrap().created,
+        vec![ResultatUid::new("r-b")]
+    );
+    assert!(log.take("b", "LT1016VT191").is_none(), "Already taken");
+}